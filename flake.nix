@@ -26,6 +26,27 @@
     in rec {
       packages = packagesWith pkgs // {
         default = packages.nixseparatedebuginfod;
+        # OCI image without nix, to run with the store and nix db of the host bind-mounted
+        # read-only
+        image = pkgs.dockerTools.buildLayeredImage {
+          name = "nixseparatedebuginfod";
+          tag = "latest";
+          contents = [ pkgs.cacert ];
+          extraCommands = "mkdir -p tmp var/cache";
+          config = {
+            Entrypoint = [
+              "${packages.nixseparatedebuginfod}/bin/nixseparatedebuginfod"
+              "--store-mounted-readonly"
+              "--listen-address"
+              "0.0.0.0:1949"
+            ];
+            Env = [
+              "XDG_CACHE_HOME=/var/cache"
+              "SSL_CERT_FILE=${pkgs.cacert}/etc/ssl/certs/ca-bundle.crt"
+            ];
+            ExposedPorts."1949/tcp" = {};
+          };
+        };
       };
       devShells.default = pkgs.callPackage ./shell.nix {};
     }
@@ -7,6 +7,20 @@ let
   cfg = config.services.nixseparatedebuginfod;
   url = "127.0.0.1:${toString cfg.port}";
   maybeAdd = x: list: if builtins.elem x list then list else list ++ [ x ];
+  settingsFormat = pkgs.formats.toml { };
+  # unset options are left out of the configuration file
+  settings = lib.filterAttrs (_: value: value != null && value != [ ] && value != false) {
+    listen-address = url;
+    index-allow = cfg.indexAllow;
+    index-deny = cfg.indexDeny;
+    upstream = cfg.upstream;
+    channel-index = cfg.channelIndices;
+    fallback = cfg.fallbacks;
+    debuginfo-dir = map toString cfg.debuginfoBundles;
+    generate-gdb-index = cfg.generateGdbIndex;
+  } // cfg.settings;
+  configFile = settingsFormat.generate "nixseparatedebuginfod.toml" settings;
+  args = [ "--config" configFile ];
   recentNix = lib.lists.findFirst
     (nix: nix != null && lib.versionAtLeast
       nix.version "2.18")
@@ -30,6 +44,86 @@ in
         default = 1949;
         type = lib.types.port;
       };
+      indexAllow = lib.mkOption {
+        description = ''
+          If not empty, only index store paths matching one of these filters.
+          A filter is either `path:REGEX` or `package:NAME`, like `package:qemu`.
+        '';
+        default = [ ];
+        type = lib.types.listOf lib.types.str;
+      };
+      indexDeny = lib.mkOption {
+        description = ''
+          Do not index store paths matching one of these filters, like `path:-texlive-`.
+          Same syntax as `indexAllow`, which it takes precedence over.
+        '';
+        default = [ ];
+        type = lib.types.listOf lib.types.str;
+      };
+      indexOnBuild = lib.mkOption {
+        description = ''
+          Index store paths as soon as they are built, with a nix `post-build-hook`,
+          instead of within a minute. Only one post-build hook can be set.
+        '';
+        default = false;
+        type = lib.types.bool;
+      };
+      upstream = lib.mkOption {
+        description = ''
+          Url of a central nixseparatedebuginfod, or path of its cache db, asked about
+          buildids unknown to this instance before indexing harder.
+        '';
+        default = null;
+        example = "http://debuginfod.example.org:1949";
+        type = lib.types.nullOr lib.types.str;
+      };
+      channelIndices = lib.mkOption {
+        description = ''
+          Urls of buildid indices published for a channel, as written by
+          `nixseparatedebuginfod export`, imported on startup and when they change.
+        '';
+        default = [ ];
+        example = [ "https://ci.example.org/nixos-24.05/buildids.jsonl.zst" ];
+        type = lib.types.listOf lib.types.str;
+      };
+      fallbacks = lib.mkOption {
+        description = ''
+          Urls of other debuginfod servers asked for the files this server does not have.
+        '';
+        default = [ ];
+        example = [ "https://debuginfod.elfutils.org" ];
+        type = lib.types.listOf lib.types.str;
+      };
+      debuginfoBundles = lib.mkOption {
+        description = ''
+          Packages containing debuginfo in `lib/debug/.build-id`, served without network
+          access, typically the debug outputs of the libraries most programs load.
+        '';
+        default = [ ];
+        example = lib.literalExpression "[ pkgs.glibc.debug ]";
+        type = lib.types.listOf lib.types.package;
+      };
+      settings = lib.mkOption {
+        description = ''
+          Further options written to the configuration file of the server, named like its
+          command line options, see `nixseparatedebuginfod --help`. They take precedence over
+          the other options of this module.
+        '';
+        default = { };
+        example = {
+          max-concurrent-requests = 32;
+          substituter = [ "https://cache.nixos.org" ];
+        };
+        type = settingsFormat.type;
+      };
+      generateGdbIndex = lib.mkOption {
+        description = ''
+          Generate the `.gdb_index` section of debug symbols which lack it, so that
+          gdb loads them faster.
+        '';
+        default = false;
+        type = lib.types.bool;
+      };
     };
   };
   config = lib.mkIf cfg.enable {
@@ -37,9 +131,12 @@ in
       wantedBy = [ "multi-user.target" ];
       wants = [ "nix-daemon.service" ];
       after = [ "nix-daemon.service" ];
-      path = [ recentNix ];
+      path = [ recentNix ] ++ lib.optional cfg.generateGdbIndex pkgs.gdb;
       serviceConfig = {
-        ExecStart = [ "${pkgs.nixseparatedebuginfod}/bin/nixseparatedebuginfod -l ${url}" ];
+        Type = "notify";
+        # restart the server if it hangs
+        WatchdogSec = "5min";
+        ExecStart = [ "${pkgs.nixseparatedebuginfod}/bin/nixseparatedebuginfod ${lib.escapeShellArgs args}" ];
         Restart = "on-failure";
         CacheDirectory = "nixseparatedebuginfod";
         # nix does not like DynamicUsers in allowed-users
@@ -92,6 +189,10 @@ in
     # sorry for those who use 2.3
     nix.settings = lib.optionalAttrs (lib.versionAtLeast config.nix.package.version "2.4") {
       extra-allowed-users = [ "nixseparatedebuginfod" ];
+    } // lib.optionalAttrs cfg.indexOnBuild {
+      post-build-hook = pkgs.writeShellScript "nixseparatedebuginfod-index" ''
+        ${pkgs.curl}/bin/curl --silent --max-time 60 --data "$OUT_PATHS" http://${url}/index >/dev/null || true
+      '';
     };
 
     environment.variables.DEBUGINFOD_URLS = "http://${url}";
@@ -0,0 +1,127 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Prebuilt debuginfo bundles given with `--debuginfo-dir`, like a nix package gathering the
+//! debug outputs of glibc and libstdc++.
+//!
+//! They are `.build-id` trees, as found in `lib/debug` of debug outputs, and are scanned once on
+//! startup. Their debuginfo is served before looking in the cache, so that the most common debug
+//! targets never need the network, even when indexation finds them in a store path which is not
+//! realised.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use once_cell::sync::OnceCell;
+
+/// The debuginfo files of the bundles, by buildid
+static BUNDLES: OnceCell<HashMap<String, PathBuf>> = OnceCell::new();
+
+/// The `.build-id` directory of a bundle given as `dir`: `dir/lib/debug/.build-id` for a
+/// package, `dir/.build-id`, or `dir` itself.
+fn build_id_dir(dir: &Path) -> PathBuf {
+    for candidate in [dir.join("lib/debug/.build-id"), dir.join(".build-id")] {
+        if candidate.is_dir() {
+            return candidate;
+        }
+    }
+    dir.to_path_buf()
+}
+
+/// Lists the debuginfo files of the `.build-id` tree of this bundle, by buildid.
+///
+/// Files are named `xx/yyyy.debug` where `xxyyyy` is the buildid.
+fn scan_bundle(dir: &Path) -> anyhow::Result<HashMap<String, PathBuf>> {
+    let root = build_id_dir(dir);
+    let mut result = HashMap::new();
+    let entries =
+        std::fs::read_dir(&root).with_context(|| format!("listing {}", root.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("listing {}", root.display()))?;
+        let prefix = entry.file_name();
+        let Some(prefix) = prefix.to_str() else {
+            continue;
+        };
+        if prefix.len() != 2 || !entry.path().is_dir() {
+            continue;
+        }
+        let files = std::fs::read_dir(entry.path())
+            .with_context(|| format!("listing {}", entry.path().display()))?;
+        for file in files {
+            let file = file.with_context(|| format!("listing {}", entry.path().display()))?;
+            let name = file.file_name();
+            let Some(rest) = name.to_str().and_then(|name| name.strip_suffix(".debug")) else {
+                continue;
+            };
+            let buildid = format!("{}{}", prefix, rest).to_ascii_lowercase();
+            if !buildid.bytes().all(|b| b.is_ascii_hexdigit()) {
+                continue;
+            }
+            // resolve symlinks into the store now, so that the file served is named after its
+            // store path
+            let path = file.path();
+            let path = std::fs::canonicalize(&path).unwrap_or(path);
+            if path.is_file() {
+                result.insert(buildid, path);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Scans the bundles given with `--debuginfo-dir`, and serves their debuginfo from now on.
+///
+/// Bundles which cannot be scanned are skipped with a warning.
+pub fn set_bundles(dirs: &[PathBuf]) {
+    if dirs.is_empty() {
+        return;
+    }
+    let mut bundles = HashMap::new();
+    for dir in dirs {
+        match scan_bundle(dir) {
+            Ok(files) => {
+                tracing::info!(
+                    "{} buildids in debuginfo bundle {}",
+                    files.len(),
+                    dir.display()
+                );
+                for (buildid, path) in files {
+                    // earlier bundles take precedence
+                    bundles.entry(buildid).or_insert(path);
+                }
+            }
+            Err(e) => tracing::warn!("skipping debuginfo bundle {}: {:#}", dir.display(), e),
+        }
+    }
+    if BUNDLES.set(bundles).is_err() {
+        tracing::warn!("debuginfo bundles set twice");
+    }
+}
+
+/// The debuginfo file of this buildid in a bundle given with `--debuginfo-dir`, if any
+pub fn debuginfo(buildid: &str) -> Option<PathBuf> {
+    let path = BUNDLES.get()?.get(&buildid.to_ascii_lowercase())?;
+    // the bundle may have been garbage collected since startup
+    path.exists().then(|| path.clone())
+}
+
+#[test]
+fn test_scan_bundle() {
+    let dir = tempfile::tempdir().unwrap();
+    let tree = dir.path().join("lib/debug/.build-id");
+    std::fs::create_dir_all(tree.join("ab")).unwrap();
+    std::fs::create_dir_all(tree.join("not-a-prefix")).unwrap();
+    std::fs::write(tree.join("ab/CDEF01.debug"), b"elf").unwrap();
+    std::fs::write(tree.join("ab/cdef01"), b"elf").unwrap();
+    std::fs::write(tree.join("ab/zz.debug"), b"elf").unwrap();
+    std::fs::write(tree.join("not-a-prefix/1234.debug"), b"elf").unwrap();
+    let expected = HashMap::from([(
+        "abcdef01".to_owned(),
+        std::fs::canonicalize(tree.join("ab/CDEF01.debug")).unwrap(),
+    )]);
+    assert_eq!(scan_bundle(dir.path()).unwrap(), expected);
+    assert_eq!(scan_bundle(&tree).unwrap(), expected);
+    assert!(scan_bundle(&dir.path().join("missing")).is_err());
+}
@@ -0,0 +1,488 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Import of prebuilt buildid indices published for a channel, with `--channel-index` or
+//! `import URL`.
+//!
+//! A CI job indexing the outputs of a jobset (for example `nixseparatedebuginfod -i` on a
+//! machine where they were built) can publish the output of `nixseparatedebuginfod export`,
+//! possibly compressed. Importing it lets buildids of store paths never present locally resolve
+//! immediately, and their files are realised when requested, like after indexation.
+//!
+//! Such an index can also be generated with `generate-index`, from a list of store paths or from
+//! the debuginfo index of a local binary cache.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use anyhow::Context;
+use reqwest::header::{ETAG, IF_NONE_MATCH};
+use reqwest::{StatusCode, Url};
+use tempfile::NamedTempFile;
+use tokio::runtime::Handle;
+
+use crate::db::{Cache, Entry};
+use crate::index::index_single_store_path_to_cache;
+use crate::log::ResultExt;
+use crate::store::{get_store_path, realise};
+use crate::substituter::{open_decompressed, parse_narinfo, Credentials, HttpClient};
+use crate::Options;
+
+/// Number of entries written to the cache at once
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+/// How often channel indices are checked for a new version
+const REFRESH_INTERVAL: Duration = Duration::from_secs(6 * 3600);
+
+/// Adds the entries written by `export`, one json [Entry] per line, to the cache.
+///
+/// Existing entries are completed, without overwriting what is already known, for example by
+/// local indexation. Entries are restricted to store paths with [Entry::store_paths_only].
+///
+/// This reads `input` synchronously, so must be run in a blocking thread, and writes to the
+/// cache with `runtime`.
+///
+/// Returns the number of imported entries.
+pub fn import_entries(
+    cache: &Cache,
+    runtime: &Handle,
+    input: impl BufRead,
+) -> anyhow::Result<usize> {
+    let mut count = 0;
+    let mut batch = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line.context("reading import")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Entry = serde_json::from_str(&line)
+            .with_context(|| format!("parsing line {} of import", i + 1))?;
+        match entry.store_paths_only() {
+            Some(entry) => batch.push(entry),
+            None => tracing::warn!("ignoring line {} of import without store paths", i + 1),
+        }
+        if batch.len() >= IMPORT_BATCH_SIZE {
+            runtime.block_on(cache.register_missing(&batch))?;
+            count += batch.len();
+            batch.clear();
+        }
+    }
+    runtime.block_on(cache.register_missing(&batch))?;
+    count += batch.len();
+    Ok(count)
+}
+
+/// Whether this is the start of uncompressed json lines
+fn is_uncompressed(start: &[u8]) -> bool {
+    match start.first() {
+        // an empty export
+        None => true,
+        Some(c) => *c == b'{' || c.is_ascii_whitespace(),
+    }
+}
+
+/// Adds the entries in this file written by `export`, possibly compressed with zstd, gzip, xz
+/// or bzip2, to the cache.
+///
+/// Returns the number of imported entries.
+pub async fn import_file(cache: &Cache, path: &Path) -> anyhow::Result<usize> {
+    let cache = cache.clone();
+    let path = path.to_path_buf();
+    let runtime = Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let input = open_decompressed(&path, is_uncompressed)?;
+        import_entries(&cache, &runtime, BufReader::new(input))
+            .with_context(|| format!("importing {}", path.display()))
+    })
+    .await
+    .context("joining import task")?
+}
+
+/// Whether this channel index is to be downloaded, instead of read from the filesystem
+fn is_url(index: &str) -> bool {
+    index.starts_with("http://") || index.starts_with("https://")
+}
+
+/// Downloads the channel index at this url to a temporary file, unless its `ETag` is `etag`.
+///
+/// Returns the file and the new `ETag` if any, or `None` if unchanged.
+pub async fn download(
+    http: &HttpClient,
+    url: &str,
+    etag: Option<&str>,
+) -> anyhow::Result<Option<(NamedTempFile, Option<String>)>> {
+    let parsed = Url::parse(url).with_context(|| format!("parsing url {}", url))?;
+    let mut request = http.get(&parsed);
+    if let Some(etag) = etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    tracing::debug!("getting {}", url);
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("fetching {}", url))?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    let response = response
+        .error_for_status()
+        .with_context(|| format!("fetching {}", url))?;
+    let etag = response
+        .headers()
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(str::to_owned);
+    // indices of large channels do not fit in a tmpfs
+    let dir = crate::db::cache_dir()?;
+    let file = NamedTempFile::new_in(&dir)
+        .with_context(|| format!("creating temporary file in {}", dir.display()))?;
+    let std_file = file.reopen().context("opening temporary file")?;
+    let mut out = tokio::io::BufWriter::new(tokio::fs::File::from_std(std_file));
+    http.download(response, &mut out).await?;
+    Ok(Some((file, etag)))
+}
+
+/// A fingerprint of a local channel index which changes when it is replaced, like an `ETag`
+fn file_version(path: &Path) -> anyhow::Result<String> {
+    let metadata = std::fs::metadata(path).with_context(|| format!("stat({})", path.display()))?;
+    Ok(format!(
+        "{}-{}-{}",
+        metadata.ino(),
+        metadata.mtime(),
+        metadata.len()
+    ))
+}
+
+/// Imports the channel index at this url or path, unless the same version was already
+/// imported.
+///
+/// Returns the number of imported entries, or `None` if unchanged.
+pub async fn import_channel_index(
+    cache: &Cache,
+    http: &HttpClient,
+    index: &str,
+) -> anyhow::Result<Option<usize>> {
+    let previous = cache.get_channel_index_etag(index).await?;
+    let (count, version) = if is_url(index) {
+        let Some((file, etag)) = download(http, index, previous.as_deref()).await? else {
+            return Ok(None);
+        };
+        let count = import_file(cache, file.path()).await?;
+        (count, etag)
+    } else {
+        let path = PathBuf::from(index);
+        let version = file_version(&path)?;
+        if previous.as_deref() == Some(version.as_str()) {
+            return Ok(None);
+        }
+        (import_file(cache, &path).await?, Some(version))
+    };
+    if let Some(version) = version {
+        cache.set_channel_index_etag(index, &version).await?;
+    }
+    Ok(Some(count))
+}
+
+/// The http client used for channel indices, with the proxy and rate limit of the command line
+/// and the credentials of nix.conf
+pub async fn http_client(args: &Options) -> anyhow::Result<HttpClient> {
+    let http = HttpClient::new(
+        args.http_proxy.as_deref(),
+        args.max_download_rate.map(|rate| rate * 1000),
+    )?;
+    Ok(match crate::config::get_nix_config().await {
+        Ok(config) => http.with_credentials(Credentials::from_config(&config).await),
+        Err(e) => {
+            tracing::debug!("no credentials for channel indices: {:#}", e);
+            http
+        }
+    })
+}
+
+/// Starts a task that imports these channel indices, and then new versions of them
+/// periodically.
+///
+/// Returns immediately.
+pub fn watch_channel_indices(cache: Cache, http: HttpClient, indices: Vec<String>) {
+    if indices.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            for index in &indices {
+                match import_channel_index(&cache, &http, index).await {
+                    Ok(None) => tracing::debug!("channel index {} is unchanged", index),
+                    Ok(Some(count)) => {
+                        tracing::info!("imported {} buildids from channel index {}", count, index)
+                    }
+                    Err(e) => Err(e)
+                        .with_context(|| format!("importing channel index {}", index))
+                        .or_warn(),
+                }
+            }
+            tokio::time::sleep(REFRESH_INTERVAL).await;
+        }
+    });
+}
+
+/// Options of the `generate-index` subcommand
+#[derive(clap::Args, Debug)]
+pub struct GenerateIndexOptions {
+    /// File to write the index to. Defaults to stdout.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Index the debug outputs in the debuginfo index of this binary cache, created with
+    /// `?index-debug-info=true`. Must be a directory, or a `file://` url. Can be repeated.
+    #[arg(long, value_name = "URL")]
+    binary_cache: Vec<String>,
+    /// Store paths to index, including debug outputs. They are realised if needed. If there are
+    /// none and no binary cache, they are read from stdin, separated by whitespace.
+    paths: Vec<PathBuf>,
+}
+
+/// Indexes store paths and binary caches in a temporary cache, and writes the result like
+/// `export`.
+pub async fn generate_index(options: GenerateIndexOptions) -> anyhow::Result<ExitCode> {
+    let cache = Cache::open_in_memory().await?;
+    let mut paths = options.paths;
+    if paths.is_empty() && options.binary_cache.is_empty() {
+        let mut input = String::new();
+        std::io::stdin()
+            .read_to_string(&mut input)
+            .context("reading store paths from stdin")?;
+        paths.extend(input.split_whitespace().map(PathBuf::from));
+    }
+    let mut success = true;
+    for path in &paths {
+        let result = async {
+            anyhow::ensure!(
+                get_store_path(path) == Some(path.as_path()),
+                "not a store path"
+            );
+            realise(path).await?;
+            index_single_store_path_to_cache(&cache, path, true).await
+        }
+        .await;
+        if let Err(e) = result {
+            tracing::error!("indexing {}: {:#}", path.display(), e);
+            success = false;
+        }
+    }
+    for url in &options.binary_cache {
+        let dir = binary_cache_dir(url)?;
+        let count = index_binary_cache(&cache, &dir)
+            .await
+            .with_context(|| format!("indexing binary cache {}", url))?;
+        tracing::info!("found {} debuginfo files in {}", count, url);
+    }
+    let count = crate::client::export_to(&cache, options.output.as_deref()).await?;
+    tracing::info!("indexed {} buildids", count);
+    Ok(if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// The directory of a local binary cache given as a directory or `file://` url
+fn binary_cache_dir(url: &str) -> anyhow::Result<PathBuf> {
+    let path = match url.split_once("://") {
+        None => url,
+        Some(("file", path)) => path,
+        Some(_) => anyhow::bail!(
+            "the content of binary cache {} cannot be listed, only local binary caches can be indexed",
+            url
+        ),
+    };
+    // like file:///cache?index-debug-info=true
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    Ok(PathBuf::from(path))
+}
+
+/// Registers the debuginfo files in the debuginfo index of this local binary cache.
+///
+/// Its `debuginfo` directory maps buildids to a member of a nar, and `.narinfo` files map nars
+/// to store paths. Returns the number of registered debuginfo files.
+pub async fn index_binary_cache(cache: &Cache, dir: &Path) -> anyhow::Result<usize> {
+    let dir = dir.to_path_buf();
+    let entries = tokio::task::spawn_blocking(move || binary_cache_entries(&dir))
+        .await
+        .context("joining binary cache indexing task")??;
+    cache.register(&entries).await?;
+    Ok(entries.len())
+}
+
+/// The entries for the debuginfo files in the debuginfo index of this local binary cache, see
+/// [index_binary_cache].
+fn binary_cache_entries(dir: &Path) -> anyhow::Result<Vec<Entry>> {
+    #[derive(serde::Deserialize)]
+    struct DebuginfoMetadata {
+        archive: String,
+        member: String,
+    }
+    let mut store_paths = HashMap::new();
+    for file in std::fs::read_dir(dir).with_context(|| format!("listing {}", dir.display()))? {
+        let path = file
+            .with_context(|| format!("listing {}", dir.display()))?
+            .path();
+        if path.extension() != Some("narinfo".as_ref()) {
+            continue;
+        }
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        match parse_narinfo(&text) {
+            Ok(narinfo) => {
+                store_paths.insert(narinfo.url, narinfo.store_path);
+            }
+            Err(e) => tracing::warn!("{}: {:#}", path.display(), e),
+        }
+    }
+    let index = dir.join("debuginfo");
+    let mut entries = Vec::new();
+    for file in std::fs::read_dir(&index).with_context(|| format!("listing {}", index.display()))? {
+        let path = file
+            .with_context(|| format!("listing {}", index.display()))?
+            .path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let buildid = name.strip_suffix(".debug").unwrap_or(name);
+        let metadata: DebuginfoMetadata = match std::fs::read(&path)
+            .context("reading")
+            .and_then(|text| serde_json::from_slice(&text).context("parsing json"))
+        {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                tracing::warn!("{}: {:#}", path.display(), e);
+                continue;
+            }
+        };
+        // archives are relative to the debuginfo directory, like `../nar/xxxx.nar.xz`
+        let archive = metadata
+            .archive
+            .strip_prefix("../")
+            .unwrap_or(&metadata.archive);
+        let Some(store_path) = store_paths.get(archive) else {
+            tracing::warn!("no narinfo for {} of {}", archive, path.display());
+            continue;
+        };
+        entries.push(Entry {
+            buildid: buildid.to_ascii_lowercase(),
+            executable: None,
+            debuginfo: Some(format!("{}/{}", store_path, metadata.member)),
+            source: None,
+            build_source: None,
+            architecture: None,
+        });
+    }
+    Ok(entries)
+}
+
+#[test]
+fn test_binary_cache_dir() {
+    assert_eq!(
+        binary_cache_dir("file:///var/cache?index-debug-info=true").unwrap(),
+        Path::new("/var/cache")
+    );
+    assert_eq!(
+        binary_cache_dir("/var/cache").unwrap(),
+        Path::new("/var/cache")
+    );
+    assert!(binary_cache_dir("https://cache.nixos.org").is_err());
+}
+
+#[tokio::test]
+async fn test_index_binary_cache() {
+    let dir = tempfile::TempDir::new().unwrap();
+    std::fs::create_dir(dir.path().join("debuginfo")).unwrap();
+    std::fs::write(
+        dir.path().join("aaaa.narinfo"),
+        "StorePath: /nix/store/aaaa-hello-2.12.1-debug\nURL: nar/xxxx.nar.xz\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.path().join("debuginfo/abcd.debug"),
+        r#"{"archive":"../nar/xxxx.nar.xz","member":"lib/debug/.build-id/ab/cd.debug"}"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.path().join("debuginfo/ef01"),
+        r#"{"archive":"../nar/yyyy.nar.xz","member":"lib/debug/.build-id/ef/01.debug"}"#,
+    )
+    .unwrap();
+    let cache = Cache::open_in_memory().await.unwrap();
+    assert_eq!(index_binary_cache(&cache, dir.path()).await.unwrap(), 1);
+    assert_eq!(
+        cache.get_debuginfo("abcd").await.unwrap().as_deref(),
+        Some(Path::new(
+            "/nix/store/aaaa-hello-2.12.1-debug/lib/debug/.build-id/ab/cd.debug"
+        ))
+    );
+}
+
+#[tokio::test]
+async fn test_import_channel_index() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("buildids.jsonl");
+    let entry = |buildid: &str| Entry {
+        buildid: buildid.to_owned(),
+        executable: Some(format!("/nix/store/{buildid}-hello/bin/hello")),
+        debuginfo: None,
+        source: None,
+        build_source: None,
+        architecture: None,
+    };
+    let lines: Vec<String> = [entry("abcd"), entry("ef01")]
+        .iter()
+        .map(|entry| serde_json::to_string(entry).unwrap())
+        .collect();
+    std::fs::write(&path, lines.join("\n\n")).unwrap();
+    let cache = Cache::open_in_memory().await.unwrap();
+    let http = HttpClient::new(None, None).unwrap();
+    let index = path.to_str().unwrap();
+    assert_eq!(
+        import_channel_index(&cache, &http, index).await.unwrap(),
+        Some(2)
+    );
+    assert_eq!(
+        cache.get_entry("ef01").await.unwrap().unwrap().executable,
+        entry("ef01").executable
+    );
+    // the same version is not imported again
+    assert_eq!(
+        import_channel_index(&cache, &http, index).await.unwrap(),
+        None
+    );
+    // entries pointing outside the store are ignored
+    let lines = [
+        serde_json::to_string(&entry("2345")).unwrap(),
+        "{\"buildid\": \"6789\", \"executable\": \"/etc/shadow\"}".to_owned(),
+    ];
+    std::fs::write(&path, lines.join("\n")).unwrap();
+    assert_eq!(
+        import_channel_index(&cache, &http, index).await.unwrap(),
+        Some(1)
+    );
+    assert_eq!(cache.get_entry("6789").await.unwrap(), None);
+    // what is already known is not overwritten
+    let line = serde_json::to_string(&Entry {
+        executable: Some("/nix/store/other-hello/bin/hello".to_owned()),
+        ..entry("abcd")
+    })
+    .unwrap();
+    std::fs::write(&path, line).unwrap();
+    assert_eq!(import_file(&cache, &path).await.unwrap(), 1);
+    assert_eq!(
+        cache.get_entry("abcd").await.unwrap().unwrap().executable,
+        entry("abcd").executable
+    );
+    std::fs::write(&path, "").unwrap();
+    assert_eq!(import_file(&cache, &path).await.unwrap(), 0);
+    std::fs::write(&path, "\nnot json\n").unwrap();
+    assert!(import_file(&cache, &path).await.is_err());
+}
@@ -0,0 +1,603 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Command line client for the endpoints specific to this server, and for the cache.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::coredump::{buildids_in_core_file, mapped_files_in_core_file};
+use crate::db::{Cache, Entry, Metadata};
+use crate::filter::IndexFilter;
+use crate::index::StoreWatcher;
+use crate::resolve::{expand_buildid, extract_archive_member, Resolver};
+use crate::store::{diagnose, get_buildid, get_elf_info, SourceLocation};
+use crate::Options;
+
+/// What the server could fetch for a buildid during a prefetch request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Prefetched {
+    /// elf buildid
+    pub buildid: String,
+    /// whether the executable is available
+    pub executable: bool,
+    /// whether the debuginfo is available
+    pub debuginfo: bool,
+}
+
+/// Options of the `prefetch` subcommand
+#[derive(clap::Args, Debug)]
+pub struct PrefetchOptions {
+    /// Url of the server. Defaults to the first url in `DEBUGINFOD_URLS`, or to the default
+    /// listen address.
+    #[arg(short, long)]
+    url: Option<String>,
+    /// Core dumps, or buildids
+    #[arg(required = true)]
+    targets: Vec<String>,
+}
+
+/// The url of the server to use when none is specified on the command line
+pub fn default_server_url() -> String {
+    std::env::var("DEBUGINFOD_URLS")
+        .ok()
+        .and_then(|urls| urls.split_whitespace().next().map(|url| url.to_owned()))
+        .unwrap_or_else(|| "http://127.0.0.1:1949".to_owned())
+}
+
+/// Asks the server to fetch the executables and debuginfo of the specified buildids, or of the
+/// buildids mapped in the specified core dumps.
+pub async fn prefetch(options: PrefetchOptions) -> anyhow::Result<ExitCode> {
+    let mut buildids = Vec::new();
+    for target in options.targets.iter() {
+        let path = Path::new(target);
+        if path.is_file() {
+            let found = buildids_in_core_file(path)?;
+            tracing::info!("found {} buildids in {}", found.len(), path.display());
+            buildids.extend(found);
+        } else {
+            buildids.push(target.clone());
+        }
+    }
+    let url = options.url.unwrap_or_else(default_server_url);
+    let results = request_prefetch(&url, &buildids).await?;
+    let mut complete = true;
+    for result in results {
+        complete &= result.executable && result.debuginfo;
+        println!(
+            "{} executable:{} debuginfo:{}",
+            result.buildid,
+            if result.executable { "yes" } else { "no" },
+            if result.debuginfo { "yes" } else { "no" },
+        );
+    }
+    Ok(if complete {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// Asks the server at `url` to fetch the executables and debuginfo of these buildids.
+async fn request_prefetch(url: &str, buildids: &[String]) -> anyhow::Result<Vec<Prefetched>> {
+    let url = format!("{}/prefetch", url.trim_end_matches('/'));
+    let response = reqwest::Client::new()
+        .post(&url)
+        .body(buildids.join("\n"))
+        .send()
+        .await
+        .with_context(|| format!("sending prefetch request to {}", &url))?;
+    read_json_answer(&url, response).await
+}
+
+/// Fails if the server answered an error, and otherwise parses its json answer.
+async fn read_json_answer<T: serde::de::DeserializeOwned>(
+    url: &str,
+    response: reqwest::Response,
+) -> anyhow::Result<T> {
+    let status = response.status();
+    if !status.is_success() {
+        let text = response.text().await.unwrap_or_default();
+        anyhow::bail!("{} answered {}: {}", url, status, text);
+    }
+    let body = response
+        .bytes()
+        .await
+        .with_context(|| format!("reading answer of {}", url))?;
+    serde_json::from_slice(&body).with_context(|| format!("parsing answer of {}", url))
+}
+
+/// How many buildids are sent in each request to `/packages`, the maximum the server accepts
+const PACKAGES_BATCH: usize = 1000;
+
+/// Asks the server at `url` what it knows about these buildids, by buildid.
+async fn request_packages(
+    url: &str,
+    buildids: &[String],
+) -> anyhow::Result<BTreeMap<String, Option<Metadata>>> {
+    let mut result = BTreeMap::new();
+    for batch in buildids.chunks(PACKAGES_BATCH) {
+        let query: Vec<String> = batch
+            .iter()
+            .map(|buildid| format!("buildid={buildid}"))
+            .collect();
+        let url = format!("{}/packages?{}", url.trim_end_matches('/'), query.join("&"));
+        let response = reqwest::get(&url)
+            .await
+            .with_context(|| format!("sending request to {}", &url))?;
+        let packages: BTreeMap<String, Option<Metadata>> = read_json_answer(&url, response).await?;
+        result.extend(packages);
+    }
+    Ok(result)
+}
+
+/// Options of the `analyze-core` subcommand
+#[derive(clap::Args, Debug)]
+pub struct AnalyzeCoreOptions {
+    /// Url of the server. Defaults to the first url in `DEBUGINFOD_URLS`, or to the default
+    /// listen address.
+    #[arg(short, long)]
+    url: Option<String>,
+    /// Open the core dump in gdb once everything is fetched
+    #[arg(long)]
+    gdb: bool,
+    /// Core dump, for example written by `coredumpctl dump -o core`
+    core: PathBuf,
+}
+
+/// A line of the table printed by `analyze-core`
+struct AnalyzedFile {
+    buildid: String,
+    /// package name and version, or `?`
+    package: String,
+    debuginfo: bool,
+    /// path of the file on the machine which dumped core, or `?`
+    path: String,
+}
+
+/// Formats the table printed by `analyze-core`, with aligned columns.
+fn format_analysis(files: &[AnalyzedFile]) -> String {
+    let width = |header: &str, column: &dyn Fn(&AnalyzedFile) -> usize| {
+        files.iter().map(column).fold(header.len(), usize::max)
+    };
+    let buildid_width = width("BUILDID", &|file| file.buildid.len());
+    let package_width = width("PACKAGE", &|file| file.package.len());
+    let mut result = format!(
+        "{:buildid_width$}  {:package_width$}  DEBUGINFO  FILE\n",
+        "BUILDID", "PACKAGE"
+    );
+    for file in files {
+        result.push_str(&format!(
+            "{:buildid_width$}  {:package_width$}  {:9}  {}\n",
+            file.buildid,
+            file.package,
+            if file.debuginfo { "yes" } else { "no" },
+            file.path
+        ));
+    }
+    result
+}
+
+#[test]
+fn test_format_analysis() {
+    let files = [
+        AnalyzedFile {
+            buildid: "ab12".to_owned(),
+            package: "hello-2.12.1".to_owned(),
+            debuginfo: true,
+            path: "/nix/store/xxxx-hello-2.12.1/bin/hello".to_owned(),
+        },
+        AnalyzedFile {
+            buildid: "cd34".to_owned(),
+            package: "?".to_owned(),
+            debuginfo: false,
+            path: "?".to_owned(),
+        },
+    ];
+    assert_eq!(
+        format_analysis(&files),
+        "BUILDID  PACKAGE       DEBUGINFO  FILE
+ab12     hello-2.12.1  yes        /nix/store/xxxx-hello-2.12.1/bin/hello
+cd34     ?             no         ?
+"
+    );
+}
+
+/// Finds the buildids of the files mapped in a core dump, has the server fetch their debuginfo,
+/// and prints a table of what was found, with their package. With `--gdb`, then opens the core
+/// dump in gdb configured to fetch from the server.
+///
+/// Without `--gdb`, exits with failure if some debuginfo is missing.
+pub async fn analyze_core(options: AnalyzeCoreOptions) -> anyhow::Result<ExitCode> {
+    let core = options.core.clone();
+    let mapped = tokio::task::spawn_blocking(move || mapped_files_in_core_file(&core)).await??;
+    tracing::info!(
+        "found {} buildids in {}",
+        mapped.len(),
+        options.core.display()
+    );
+    let url = options.url.unwrap_or_else(default_server_url);
+    let buildids: Vec<String> = mapped.iter().map(|file| file.buildid.clone()).collect();
+    let prefetched = request_prefetch(&url, &buildids).await?;
+    let debuginfo: BTreeMap<String, bool> = prefetched
+        .into_iter()
+        .map(|result| (result.buildid, result.debuginfo))
+        .collect();
+    let packages = request_packages(&url, &buildids).await?;
+    let mut complete = true;
+    let files: Vec<AnalyzedFile> = mapped
+        .into_iter()
+        .map(|file| {
+            let metadata = packages.get(&file.buildid).and_then(Option::as_ref);
+            let package = match metadata {
+                Some(Metadata {
+                    package: Some(package),
+                    version: Some(version),
+                    ..
+                }) => format!("{package}-{version}"),
+                Some(Metadata {
+                    package: Some(package),
+                    ..
+                }) => package.clone(),
+                _ => "?".to_owned(),
+            };
+            let found = debuginfo.get(&file.buildid).copied().unwrap_or(false);
+            complete &= found;
+            AnalyzedFile {
+                package,
+                debuginfo: found,
+                path: file
+                    .path
+                    .map_or("?".to_owned(), |path| path.display().to_string()),
+                buildid: file.buildid,
+            }
+        })
+        .collect();
+    print!("{}", format_analysis(&files));
+    if !options.gdb {
+        return Ok(if complete {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
+    }
+    let mut cmd = tokio::process::Command::new("gdb");
+    cmd.env("DEBUGINFOD_URLS", &url)
+        .arg("-iex")
+        .arg("set debuginfod enabled on")
+        .arg("--core")
+        .arg(&options.core);
+    tracing::debug!("Running {:?}", &cmd);
+    let status = cmd
+        .status()
+        .await
+        .with_context(|| format!("running {:?}", cmd))?;
+    Ok(if status.success() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// Options of the `find` subcommand
+#[derive(clap::Args, Debug)]
+pub struct FindOptions {
+    #[command(subcommand)]
+    what: FindWhat,
+}
+
+/// What to find, with the same arguments as `debuginfod-find`
+#[derive(clap::Subcommand, Debug)]
+enum FindWhat {
+    /// Separate debug symbols
+    Debuginfo {
+        /// Buildid, or elf file
+        target: String,
+    },
+    /// Executable or library
+    Executable {
+        /// Buildid, or elf file
+        target: String,
+    },
+    /// Source file
+    Source {
+        /// Buildid, or elf file
+        target: String,
+        /// Path of the source file, as in debug symbols
+        path: PathBuf,
+    },
+}
+
+/// Returns the buildid of `target`, which is either an elf file or a buildid.
+fn target_buildid(target: &str) -> anyhow::Result<String> {
+    let path = Path::new(target);
+    if path.is_file() {
+        return match get_buildid(path)? {
+            Some(buildid) => Ok(buildid),
+            None => anyhow::bail!("{} has no buildid", path.display()),
+        };
+    }
+    if target.is_empty() || !target.bytes().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("{:?} is neither a file nor a buildid", target);
+    }
+    Ok(target.to_ascii_lowercase())
+}
+
+/// Finds a file of a buildid like the server would, but with the cache directly instead of
+/// through http, and prints where it is.
+///
+/// New store paths are indexed first.
+pub async fn find(args: &Options, options: FindOptions) -> anyhow::Result<ExitCode> {
+    let cache = Cache::open().await.context("opening global cache")?;
+    let filter = IndexFilter::from_options(args);
+    let watcher = StoreWatcher::new(cache.clone(), filter);
+    watcher.index_cycle().await?;
+    let resolver = Resolver::from_options(cache.clone(), args).await?;
+    let target = match &options.what {
+        FindWhat::Debuginfo { target }
+        | FindWhat::Executable { target }
+        | FindWhat::Source { target, .. } => target,
+    };
+    let buildid = expand_buildid(&cache, target_buildid(target)?).await;
+    let found = match options.what {
+        FindWhat::Debuginfo { .. } => resolver.debuginfo(&buildid).await?,
+        FindWhat::Executable { .. } => match resolver.executable(&buildid).await? {
+            None => None,
+            Some((tempdir, path)) => {
+                if let Some(tempdir) = tempdir {
+                    // the path would not exist anymore when it is printed otherwise
+                    let dir = tempdir.into_path();
+                    tracing::info!(
+                        "fetched the executable from a substituter, remove {} when done",
+                        dir.display()
+                    );
+                }
+                Some(path)
+            }
+        },
+        FindWhat::Source { path, .. } => match resolver.source(&buildid, &path).await? {
+            None => None,
+            Some(SourceLocation::File(file)) => Some(file),
+            Some(SourceLocation::Archive { archive, member }) => {
+                let dir = crate::db::cache_dir()?.join("sources");
+                std::fs::create_dir_all(&dir)
+                    .with_context(|| format!("creating directory {}", dir.display()))?;
+                Some(extract_archive_member(&cache, &dir, &archive, &member).await?)
+            }
+        },
+    };
+    match found {
+        Some(path) => {
+            println!("{}", path.display());
+            Ok(ExitCode::SUCCESS)
+        }
+        None => {
+            tracing::error!("not found for buildid {}", buildid);
+            Ok(ExitCode::FAILURE)
+        }
+    }
+}
+
+/// Options of the `export` subcommand
+#[derive(clap::Args, Debug)]
+pub struct ExportOptions {
+    /// File to write to. Defaults to stdout.
+    output: Option<PathBuf>,
+}
+
+/// Options of the `import` subcommand
+#[derive(clap::Args, Debug)]
+pub struct ImportOptions {
+    /// File written by `export` to read from, possibly compressed, or its http(s) url, like a
+    /// channel index published by a CI job. Defaults to stdin.
+    input: Option<String>,
+}
+
+/// Options of the `invalidate` subcommand
+#[derive(clap::Args, Debug)]
+pub struct InvalidateOptions {
+    /// The buildid to forget, in hexadecimal
+    buildid: String,
+}
+
+/// Number of entries read from the cache at once by `export`
+const EXPORT_BATCH_SIZE: u32 = 1000;
+
+/// Writes all the entries of the cache as json lines, one [Entry] per line.
+pub async fn export(options: ExportOptions) -> anyhow::Result<ExitCode> {
+    let cache = Cache::open().await.context("opening global cache")?;
+    let count = export_to(&cache, options.output.as_deref()).await?;
+    tracing::info!("exported {} buildids", count);
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Runs the `invalidate` subcommand: forgets a buildid in the cache.
+///
+/// A running server is affected too, as it shares the cache, after it forgets the entries it
+/// keeps in memory for a few seconds.
+pub async fn invalidate(options: InvalidateOptions) -> anyhow::Result<ExitCode> {
+    let buildid = options.buildid.to_ascii_lowercase();
+    anyhow::ensure!(
+        !buildid.is_empty() && buildid.bytes().all(|c| c.is_ascii_hexdigit()),
+        "invalid buildid {:?}",
+        options.buildid
+    );
+    let cache = Cache::open().await.context("opening global cache")?;
+    if crate::resolve::invalidate(&cache, &buildid)
+        .await?
+        .is_some()
+    {
+        tracing::info!("forgot buildid {}", buildid);
+    } else {
+        tracing::info!("buildid {} was not in the cache", buildid);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Writes all the entries of `cache` as json lines to this file, or stdout.
+///
+/// Paths outside the store, like debuginfo fetched with `--private-debuginfo`, are left out,
+/// as they are private to this cache.
+///
+/// Returns the number of written entries.
+pub async fn export_to(cache: &Cache, output: Option<&Path>) -> anyhow::Result<usize> {
+    let out: Box<dyn Write> = match output {
+        None => Box::new(std::io::stdout().lock()),
+        Some(path) => Box::new(
+            std::fs::File::create(path).with_context(|| format!("creating {}", path.display()))?,
+        ),
+    };
+    let mut out = std::io::BufWriter::new(out);
+    let mut count = 0;
+    let mut last = String::new();
+    loop {
+        let entries = cache.get_entries_after(&last, EXPORT_BATCH_SIZE).await?;
+        let Some(entry) = entries.last() else {
+            break;
+        };
+        last = entry.buildid.clone();
+        for entry in entries.into_iter().filter_map(Entry::store_paths_only) {
+            serde_json::to_writer(&mut out, &entry).context("serializing entry")?;
+            writeln!(out).context("writing export")?;
+            count += 1;
+        }
+    }
+    out.flush().context("writing export")?;
+    Ok(count)
+}
+
+/// Adds the entries written by [export] to the cache.
+///
+/// Existing entries are completed, without overwriting what is already known. This does not
+/// replace indexation of the local store, but the imported buildids are found before indexation reaches them.
+pub async fn import(options: ImportOptions, args: &Options) -> anyhow::Result<ExitCode> {
+    let cache = Cache::open().await.context("opening global cache")?;
+    let count = match options.input.as_deref() {
+        None => {
+            let runtime = tokio::runtime::Handle::current();
+            let cache = cache.clone();
+            tokio::task::spawn_blocking(move || {
+                crate::channel::import_entries(&cache, &runtime, std::io::stdin().lock())
+            })
+            .await
+            .context("joining import task")??
+        }
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => {
+            let http = crate::channel::http_client(args).await?;
+            let Some((file, _)) = crate::channel::download(&http, url, None).await? else {
+                anyhow::bail!("{} returned no content", url);
+            };
+            crate::channel::import_file(&cache, file.path()).await?
+        }
+        Some(path) => crate::channel::import_file(&cache, Path::new(path)).await?,
+    };
+    tracing::info!("imported {} buildids", count);
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Options of the `doctor` subcommand
+#[derive(clap::Args, Debug)]
+pub struct DoctorOptions {
+    /// Url of the server. Defaults to the first url in `DEBUGINFOD_URLS`, or to the default
+    /// listen address.
+    #[arg(short, long)]
+    url: Option<String>,
+    /// Executable or library shown without debug symbols in gdb
+    binary: PathBuf,
+}
+
+/// Explains why the debug symbols of a binary are or are not available: whether the debugger
+/// is configured to use a server, what the server knows about the buildid of the binary, and
+/// why the debug output or source of its derivation cannot be found.
+///
+/// Exits with failure if the server does not know the debuginfo of the binary.
+pub async fn doctor(options: DoctorOptions) -> anyhow::Result<ExitCode> {
+    match std::env::var("DEBUGINFOD_URLS") {
+        Ok(urls) if !urls.trim().is_empty() => println!("DEBUGINFOD_URLS is {}", urls),
+        _ => println!(
+            "DEBUGINFOD_URLS is not set: gdb does not know where to fetch debug symbols from. \
+            Set it to {}",
+            default_server_url()
+        ),
+    }
+    let binary = std::fs::canonicalize(&options.binary)
+        .with_context(|| format!("resolving {}", options.binary.display()))?;
+    let binary_clone = binary.clone();
+    let info = tokio::task::spawn_blocking(move || get_elf_info(&binary_clone)).await??;
+    let buildid = match info {
+        None => {
+            println!(
+                "{} has no buildid, or is not an elf file: gdb cannot fetch its debug symbols",
+                binary.display()
+            );
+            return Ok(ExitCode::FAILURE);
+        }
+        Some(info) => info.buildid,
+    };
+    println!("{} has buildid {}", binary.display(), buildid);
+
+    let url = options.url.unwrap_or_else(default_server_url);
+    let url = format!("{}/buildid/{}/status", url.trim_end_matches('/'), buildid);
+    let mut served = false;
+    match reqwest::get(&url).await {
+        Err(e) => println!("cannot reach the server: {:#}", e),
+        Ok(response) if response.status() == reqwest::StatusCode::NOT_FOUND => println!(
+            "the server does not know this buildid: either indexation is not complete, or its \
+            store path was not indexed"
+        ),
+        Ok(response) if !response.status().is_success() => {
+            println!("{} answered {}", url, response.status())
+        }
+        Ok(response) => {
+            let body = response
+                .bytes()
+                .await
+                .with_context(|| format!("reading answer of {}", &url))?;
+            let entry: Entry = serde_json::from_slice(&body)
+                .with_context(|| format!("parsing answer of {}", &url))?;
+            let describe = |path: &Option<String>| path.as_deref().unwrap_or("unknown").to_owned();
+            println!("the server knows:");
+            println!("  executable: {}", describe(&entry.executable));
+            println!("  debuginfo: {}", describe(&entry.debuginfo));
+            println!("  source: {}", describe(&entry.source));
+            served = entry.debuginfo.is_some();
+            if served {
+                println!(
+                    "if gdb still shows no debug symbols, check that it was built with debuginfod \
+                    support and that `set debuginfod enabled on` is in ~/.gdbinit"
+                );
+            }
+        }
+    }
+
+    let findings = tokio::task::spawn_blocking(move || diagnose(&binary, &buildid)).await?;
+    for finding in findings {
+        println!("{}", finding);
+    }
+    Ok(if served {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+#[test]
+fn test_target_buildid() {
+    assert_eq!(
+        target_buildid("483BD7F7229BDB06462222E1E353E4F37E15C293").unwrap(),
+        "483bd7f7229bdb06462222e1e353e4f37e15c293"
+    );
+    assert!(target_buildid("/nonexistent/file").is_err());
+    assert!(target_buildid("").is_err());
+    let exe = std::env::current_exe().unwrap();
+    assert_eq!(
+        target_buildid(exe.to_str().unwrap()).unwrap(),
+        get_buildid(&exe).unwrap().unwrap()
+    );
+}
@@ -0,0 +1,222 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! What clients say about themselves: their `User-Agent` and the `X-DEBUGINFOD-*` headers they
+//! send, set for example by `DEBUGINFOD_HEADERS_FILE`.
+//!
+//! They are logged with each request unless `--no-log-clients` is given, and clients can get a
+//! different timeout than `--request-timeout` with `--client-timeout`, for example a short one
+//! for batch fetchers in CI, and a long one for interactive debuggers.
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http::header::{RETRY_AFTER, USER_AGENT};
+use http::{HeaderMap, HeaderValue, StatusCode};
+
+use crate::Options;
+
+/// A timeout for the clients whose `User-Agent` contains `pattern`, as specified by
+/// `--client-timeout PATTERN=SECONDS`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientTimeout {
+    /// matched case insensitively
+    pub pattern: String,
+    /// replaces `--request-timeout`
+    pub timeout: Duration,
+}
+
+impl FromStr for ClientTimeout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (pattern, seconds) = match s.rsplit_once('=') {
+            Some(x) => x,
+            None => anyhow::bail!("expected PATTERN=SECONDS, got {:?}", s),
+        };
+        anyhow::ensure!(!pattern.is_empty(), "empty user agent pattern in {:?}", s);
+        let seconds: u64 = seconds
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid timeout {:?}: {}", seconds, e))?;
+        Ok(ClientTimeout {
+            pattern: pattern.to_lowercase(),
+            timeout: Duration::from_secs(seconds),
+        })
+    }
+}
+
+/// How requests are logged and timed out depending on their client
+pub struct ClientPolicy {
+    /// whether to log what clients say about themselves
+    pub log: bool,
+    /// the first matching timeout applies
+    pub timeouts: Vec<ClientTimeout>,
+    /// timeout of other clients
+    pub default_timeout: Duration,
+}
+
+impl ClientPolicy {
+    /// The policy set by `--client-timeout`, `--request-timeout` and `--no-log-clients`
+    pub fn from_options(args: &Options) -> Self {
+        ClientPolicy {
+            log: !args.no_log_clients,
+            timeouts: args.client_timeout.clone(),
+            default_timeout: Duration::from_secs(args.request_timeout),
+        }
+    }
+
+    /// The timeout of the client with this `User-Agent`
+    fn timeout(&self, user_agent: &str) -> Duration {
+        let user_agent = user_agent.to_lowercase();
+        self.timeouts
+            .iter()
+            .find(|timeout| user_agent.contains(&timeout.pattern))
+            .map_or(self.default_timeout, |timeout| timeout.timeout)
+    }
+
+    /// The longest timeout of any client
+    pub fn max_timeout(&self) -> Duration {
+        self.timeouts
+            .iter()
+            .map(|timeout| timeout.timeout)
+            .fold(self.default_timeout, Duration::max)
+    }
+}
+
+#[test]
+fn test_client_timeout() {
+    let policy = ClientPolicy {
+        log: true,
+        timeouts: vec![
+            "CI-fetcher=30".parse().unwrap(),
+            "elfutils=900".parse().unwrap(),
+        ],
+        default_timeout: Duration::from_secs(300),
+    };
+    assert_eq!(
+        policy.timeout("ci-fetcher/1.0 elfutils/0.190"),
+        Duration::from_secs(30)
+    );
+    assert_eq!(
+        policy.timeout("elfutils/0.190,Linux/x86_64"),
+        Duration::from_secs(900)
+    );
+    assert_eq!(policy.timeout("curl/8.6.0"), Duration::from_secs(300));
+    assert_eq!(policy.max_timeout(), Duration::from_secs(900));
+    assert!("elfutils".parse::<ClientTimeout>().is_err());
+    assert!("=30".parse::<ClientTimeout>().is_err());
+    assert!("elfutils=soon".parse::<ClientTimeout>().is_err());
+}
+
+/// The `X-DEBUGINFOD-*` headers of a request, formatted for logs.
+///
+/// Other headers set by `DEBUGINFOD_HEADERS_FILE`, like `Authorization`, are not included.
+fn debuginfod_headers(headers: &HeaderMap) -> String {
+    let mut result = String::new();
+    for (name, value) in headers {
+        if !name.as_str().starts_with("x-debuginfod-") {
+            continue;
+        }
+        if !result.is_empty() {
+            result.push_str(", ");
+        }
+        result.push_str(name.as_str());
+        result.push_str(": ");
+        result.push_str(&String::from_utf8_lossy(value.as_bytes()));
+    }
+    result
+}
+
+/// Middleware logging the client of requests and applying its timeout, according to a
+/// [ClientPolicy].
+pub async fn apply_client_policy(
+    State(policy): State<Arc<ClientPolicy>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let user_agent = request
+        .headers()
+        .get(USER_AGENT)
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        .unwrap_or_default();
+    if policy.log {
+        tracing::info!(
+            user_agent = user_agent.as_str(),
+            headers = debuginfod_headers(request.headers()),
+            "{} {}",
+            request.method(),
+            request.uri()
+        );
+    }
+    if policy.timeouts.is_empty() {
+        return next.run(request).await;
+    }
+    let timeout = policy.timeout(&user_agent);
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            tracing::info!(
+                "Responding error 503: request timed out after {:?}",
+                timeout
+            );
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(
+                    RETRY_AFTER,
+                    HeaderValue::from(crate::server::RETRY_AFTER_SECS),
+                )],
+                "request timed out",
+            )
+                .into_response()
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_apply_client_policy() {
+    use tower::ServiceExt;
+    let policy = ClientPolicy {
+        log: false,
+        timeouts: vec!["impatient=0".parse().unwrap()],
+        default_timeout: Duration::from_secs(300),
+    };
+    let app = axum::Router::new()
+        .route(
+            "/",
+            axum::routing::get(|| async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                "slow"
+            }),
+        )
+        .layer(axum::middleware::from_fn_with_state(
+            Arc::new(policy),
+            apply_client_policy,
+        ));
+    let get = |user_agent: &'static str| {
+        let request = Request::builder()
+            .uri("/")
+            .header(USER_AGENT, user_agent)
+            .header("x-debuginfod-client", "test")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+    let response = get("patient/1.0").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = get("impatient/1.0").await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(response.headers().contains_key(RETRY_AFTER));
+}
+
+#[test]
+fn test_debuginfod_headers() {
+    let mut headers = HeaderMap::new();
+    headers.insert("x-debuginfod-client", HeaderValue::from_static("gdb"));
+    headers.insert("authorization", HeaderValue::from_static("secret"));
+    assert_eq!(debuginfod_headers(&headers), "x-debuginfod-client: gdb");
+}
@@ -17,17 +17,97 @@ pub async fn get_nix_config() -> anyhow::Result<NixConfig> {
         "show-config",
     ]);
     let output = cmd.output().await.context("running nix show-config")?;
-    anyhow::ensure!(
-        output.status.success(),
-        "nix show-config failed: {:?} {} {}",
-        output.status,
-        String::from_utf8_lossy(&output.stderr),
-        String::from_utf8_lossy(&output.stdout)
-    );
+    crate::subprocess::check(&cmd, &output)?;
     let out = String::from_utf8(output.stdout).context("nix show-config returned non utf8 data")?;
     parse_nix_config(&out)
 }
 
+/// A login and password in a netrc file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Login {
+    /// user name
+    pub login: String,
+    /// password
+    pub password: String,
+}
+
+/// The content of a netrc file, as used by the `netrc-file` nix setting
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Netrc {
+    /// logins by host name
+    pub machines: HashMap<String, Login>,
+    /// login for hosts not in `machines`
+    pub default: Option<Login>,
+}
+
+/// Parses a netrc file. Macro definitions and accounts are ignored.
+pub fn parse_netrc(text: &str) -> Netrc {
+    // `Some(host)` for machine entries, `None` for the default entry
+    let mut entries: Vec<(Option<String>, Login)> = Vec::new();
+    let lines: Vec<&str> = text
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .collect();
+    let mut tokens = lines.iter().flat_map(|line| line.split_whitespace());
+    let empty = || Login {
+        login: String::new(),
+        password: String::new(),
+    };
+    while let Some(token) = tokens.next() {
+        match (token, entries.last_mut()) {
+            ("machine", _) => {
+                if let Some(host) = tokens.next() {
+                    entries.push((Some(host.to_owned()), empty()));
+                }
+            }
+            ("default", _) => entries.push((None, empty())),
+            ("login", Some((_, login))) => {
+                login.login = tokens.next().unwrap_or_default().to_owned()
+            }
+            ("password", Some((_, login))) => {
+                login.password = tokens.next().unwrap_or_default().to_owned()
+            }
+            ("login" | "password" | "account" | "macdef", _) => {
+                tokens.next();
+            }
+            _ => (),
+        }
+    }
+    let mut result = Netrc::default();
+    for (host, login) in entries {
+        match host {
+            Some(host) => {
+                result.machines.insert(host, login);
+            }
+            None => result.default = Some(login),
+        }
+    }
+    result
+}
+
+#[test]
+fn test_parse_netrc() {
+    let netrc = parse_netrc(
+        "# private caches
+        machine cache.example.com login alice password s3cr3t
+        machine other.example.com
+          login bob
+          password hunter2
+        default login anonymous password guest",
+    );
+    assert_eq!(
+        netrc.machines["cache.example.com"],
+        Login {
+            login: "alice".to_owned(),
+            password: "s3cr3t".to_owned()
+        }
+    );
+    assert_eq!(netrc.machines["other.example.com"].login, "bob");
+    assert_eq!(netrc.machines.len(), 2);
+    assert_eq!(netrc.default.unwrap().password, "guest");
+    assert_eq!(parse_netrc(""), Netrc::default());
+}
+
 fn parse_nix_config(text: &str) -> anyhow::Result<NixConfig> {
     let mut extras = NixConfig::new();
     let mut result = NixConfig::new();
@@ -0,0 +1,223 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Extraction of the buildids of the executables and libraries mapped in a core dump.
+//!
+//! The kernel dumps the first page of each mapped ELF file, which contains the program headers,
+//! and usually the `.note.gnu.build-id` note. The `NT_FILE` note of the core dump tells which
+//! file is mapped where.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use object::elf::{FileHeader32, FileHeader64, ELF_NOTE_GNU, ET_CORE, NT_GNU_BUILD_ID};
+use object::elf::{ELF_NOTE_CORE, NT_FILE, PT_LOAD, PT_NOTE};
+use object::read::elf::{FileHeader, ProgramHeader};
+use object::{Endian, Endianness, FileKind, ReadRef};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+/// How much of the start of each memory segment of a core dump is examined for an ELF header
+const SEGMENT_HEADER_SIZE: u64 = 16 * 4096;
+
+/// An ELF file mapped in a core dump
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappedFile {
+    /// its buildid
+    pub buildid: String,
+    /// its path on the machine which dumped core, if the `NT_FILE` note tells
+    pub path: Option<PathBuf>,
+}
+
+/// Returns the buildids of all ELF files mapped in this core dump, sorted.
+pub fn buildids_in_core_file(path: &Path) -> anyhow::Result<Vec<String>> {
+    Ok(mapped_files_in_core_file(path)?
+        .into_iter()
+        .map(|mapped| mapped.buildid)
+        .collect())
+}
+
+/// Returns all ELF files mapped in this core dump, sorted by buildid.
+pub fn mapped_files_in_core_file(path: &Path) -> anyhow::Result<Vec<MappedFile>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("opening core dump {}", path.display()))?;
+    let reader = object::read::ReadCache::new(file);
+    match FileKind::parse(&reader).with_context(|| format!("parsing {}", path.display()))? {
+        FileKind::Elf32 => buildids_in_core::<FileHeader32<Endianness>, _>(&reader),
+        FileKind::Elf64 => buildids_in_core::<FileHeader64<Endianness>, _>(&reader),
+        _ => anyhow::bail!("{} is not an ELF file", path.display()),
+    }
+    .with_context(|| format!("reading buildids in core dump {}", path.display()))
+}
+
+fn buildids_in_core<'data, Elf: FileHeader<Endian = Endianness>, R: ReadRef<'data>>(
+    data: R,
+) -> anyhow::Result<Vec<MappedFile>> {
+    let header = Elf::parse(data).context("parsing ELF header")?;
+    let endian = header.endian().context("parsing ELF endianness")?;
+    anyhow::ensure!(header.e_type(endian) == ET_CORE, "not a core dump");
+    let segments = header
+        .program_headers(endian, data)
+        .context("parsing program headers")?;
+    let mut files = HashMap::new();
+    for segment in segments {
+        if segment.p_type(endian) != PT_NOTE {
+            continue;
+        }
+        let mut notes = match segment.notes(endian, data) {
+            Ok(Some(notes)) => notes,
+            _ => continue,
+        };
+        while let Ok(Some(note)) = notes.next() {
+            if note.name() == ELF_NOTE_CORE && note.n_type(endian) == NT_FILE {
+                files.extend(parse_nt_file(note.desc(), header.is_type_64(), endian));
+            }
+        }
+    }
+    let mut result = BTreeMap::new();
+    for segment in segments {
+        if segment.p_type(endian) != PT_LOAD {
+            continue;
+        }
+        let offset: u64 = segment.p_offset(endian).into();
+        let size: u64 = segment.p_filesz(endian).into();
+        let start = match data.read_bytes_at(offset, size.min(SEGMENT_HEADER_SIZE)) {
+            Ok(start) => start,
+            Err(()) => continue,
+        };
+        if !start.starts_with(ELF_MAGIC) {
+            continue;
+        }
+        if let Some(buildid) = buildid_in_mapped_elf::<Elf>(start) {
+            let path = files.get(&segment.p_vaddr(endian).into()).cloned();
+            let known = result.entry(buildid).or_insert(None);
+            if known.is_none() {
+                *known = path;
+            }
+        }
+    }
+    Ok(result
+        .into_iter()
+        .map(|(buildid, path)| MappedFile { buildid, path })
+        .collect())
+}
+
+/// Parses the description of a `NT_FILE` note, and returns the files whose start is mapped, by
+/// the address where they are mapped.
+///
+/// The description is the number of mappings and the page size, then the start address, end
+/// address and offset in pages of each mapping, and then the paths of the mapped files, null
+/// terminated, all in words of the size of the architecture.
+fn parse_nt_file(desc: &[u8], is_64: bool, endian: Endianness) -> HashMap<u64, PathBuf> {
+    let word_size = if is_64 { 8 } else { 4 };
+    let word = |i: usize| -> Option<u64> {
+        let bytes = desc.get(i * word_size..(i + 1) * word_size)?;
+        Some(if is_64 {
+            endian.read_u64_bytes(bytes.try_into().ok()?)
+        } else {
+            endian.read_u32_bytes(bytes.try_into().ok()?).into()
+        })
+    };
+    let mut result = HashMap::new();
+    let Some(count) = word(0).and_then(|count| usize::try_from(count).ok()) else {
+        return result;
+    };
+    let Some(names_start) = count
+        .checked_mul(3)
+        .and_then(|words| words.checked_add(2))
+        .and_then(|words| words.checked_mul(word_size))
+    else {
+        return result;
+    };
+    let names = desc.get(names_start..).unwrap_or(&[]).split(|&b| b == 0);
+    for (i, name) in (0..count).zip(names) {
+        let (Some(start), Some(offset)) = (word(2 + 3 * i), word(2 + 3 * i + 2)) else {
+            break;
+        };
+        if offset == 0 {
+            result.insert(start, PathBuf::from(OsStr::from_bytes(name)));
+        }
+    }
+    result
+}
+
+/// Returns the buildid in the notes of the start of a mapped ELF file
+fn buildid_in_mapped_elf<Elf: FileHeader<Endian = Endianness>>(data: &[u8]) -> Option<String> {
+    let header = Elf::parse(data).ok()?;
+    let endian = header.endian().ok()?;
+    for segment in header.program_headers(endian, data).ok()? {
+        if segment.p_type(endian) != PT_NOTE {
+            continue;
+        }
+        let mut notes = match segment.notes(endian, data) {
+            Ok(Some(notes)) => notes,
+            _ => continue,
+        };
+        while let Ok(Some(note)) = notes.next() {
+            if note.name() == ELF_NOTE_GNU && note.n_type(endian) == NT_GNU_BUILD_ID {
+                return Some(base16::encode_lower(note.desc()));
+            }
+        }
+    }
+    None
+}
+
+#[test]
+fn test_buildids_in_core_file() {
+    use crate::store::{make_elf64, make_note};
+    let buildid =
+        b"\x48\x3b\xd7\xf7\x22\x9b\xdb\x06\x46\x22\x22\xe1\xe3\x53\xe4\xf3\x7e\x15\xc2\x93";
+    let note = make_note(ELF_NOTE_GNU, NT_GNU_BUILD_ID, buildid);
+    let mut mapped = make_elf64(
+        object::elf::ET_DYN,
+        &[(PT_NOTE, 120, note.len() as u64)],
+        &[],
+    );
+    mapped.extend_from_slice(&note);
+
+    // NT_FILE mapping the library at address 0, where the first PT_LOAD is
+    let mut desc = Vec::new();
+    for word in [2u64, 4096, 0, 4096, 0, 4096, 8192, 1] {
+        desc.extend_from_slice(&word.to_le_bytes());
+    }
+    desc.extend_from_slice(b"/nix/store/xxxx-foo/lib/libfoo.so\0/nix/store/xxxx-foo/data\0");
+    let nt_file = make_note(ELF_NOTE_CORE, NT_FILE, &desc);
+
+    let mut core = make_elf64(
+        ET_CORE,
+        &[
+            (PT_LOAD, 4096, mapped.len() as u64),
+            (PT_LOAD, 8192, 16),
+            (PT_NOTE, 2048, nt_file.len() as u64),
+        ],
+        &[],
+    );
+    core.resize(2048, 0);
+    core.extend_from_slice(&nt_file);
+    core.resize(4096, 0);
+    core.extend_from_slice(&mapped);
+    core.resize(8192, 0);
+    core.extend_from_slice(&[1; 16]);
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("core");
+    std::fs::write(&path, &core).unwrap();
+    assert_eq!(
+        buildids_in_core_file(&path).unwrap(),
+        vec!["483bd7f7229bdb06462222e1e353e4f37e15c293".to_owned()]
+    );
+    assert_eq!(
+        mapped_files_in_core_file(&path).unwrap(),
+        vec![MappedFile {
+            buildid: "483bd7f7229bdb06462222e1e353e4f37e15c293".to_owned(),
+            path: Some("/nix/store/xxxx-foo/lib/libfoo.so".into()),
+        }]
+    );
+
+    std::fs::write(&path, &mapped).unwrap();
+    assert!(buildids_in_core_file(&path).is_err());
+}
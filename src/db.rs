@@ -4,22 +4,108 @@
 
 //! Cache for buildid -> debuginfo as a sqlite database
 
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use anyhow::{bail, Context};
 use directories::ProjectDirs;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
 use sha2::Digest;
-use sqlx::{sqlite::SqlitePool, Row};
+use sqlx::sqlite::{SqlitePool, SqliteRow};
+use sqlx::Row;
 
 use crate::log::ResultExt;
 
 /// id of the row of a store path in `/nix/var/nix/db/db.sqlite`
 pub type Id = u32;
 
+/// First of the private use characters standing for the bytes `0x80` to `0xff` of paths which
+/// are not valid UTF-8, see [encode_path]
+const RAW_BYTE_BASE: u32 = 0xef00;
+
+/// Encodes a path as a string, so that paths which are not valid UTF-8 can be stored in the
+/// cache.
+///
+/// Valid UTF-8 paths are unchanged, except for the characters `U+EF80` to `U+EFFF`, which are
+/// encoded like invalid bytes. Invalid bytes are mapped to these characters, like in
+/// OPTU-8. Reversed by [decode_path].
+pub fn encode_path(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    let raw = |result: &mut String, bytes: &[u8]| {
+        result.extend(
+            bytes
+                .iter()
+                .filter_map(|&b| char::from_u32(RAW_BYTE_BASE + b as u32)),
+        )
+    };
+    let mut result = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if decode_raw_byte(c).is_some() {
+                raw(&mut result, c.encode_utf8(&mut [0; 4]).as_bytes());
+            } else {
+                result.push(c);
+            }
+        }
+        raw(&mut result, chunk.invalid());
+    }
+    result
+}
+
+/// The byte a character stands for in an encoded path, if any
+fn decode_raw_byte(c: char) -> Option<u8> {
+    let byte = (c as u32).checked_sub(RAW_BYTE_BASE)?;
+    (0x80..=0xff).contains(&byte).then_some(byte as u8)
+}
+
+/// Decodes a path encoded by [encode_path].
+pub fn decode_path(encoded: &str) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    let mut bytes = Vec::with_capacity(encoded.len());
+    for c in encoded.chars() {
+        match decode_raw_byte(c) {
+            Some(byte) => bytes.push(byte),
+            None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[test]
+fn test_encode_path() {
+    use std::os::unix::ffi::OsStrExt;
+    let utf8 = Path::new("/nix/store/xxxx-foo/bin/été");
+    assert_eq!(encode_path(utf8), "/nix/store/xxxx-foo/bin/été");
+    for bytes in [
+        &b"/nix/store/xxxx-foo/bin/\xff\xfeabc"[..],
+        b"/nix/store/xxxx-foo/\xc3",
+        "/nix/store/xxxx-foo/\u{ef80}\u{efff}\u{eeff}".as_bytes(),
+    ] {
+        let path = Path::new(std::ffi::OsStr::from_bytes(bytes));
+        let encoded = encode_path(path);
+        assert!(encoded.starts_with("/nix/store/xxxx-foo/"));
+        assert_eq!(decode_path(&encoded), path);
+    }
+    assert_ne!(
+        encode_path(Path::new("/a/\u{efff}")),
+        encode_path(Path::new(std::ffi::OsStr::from_bytes(b"/a/\xff")))
+    );
+}
+
 /// An entry stored in the cache.
 ///
 /// `executable` is the full path to the executable of this buildid (executable includes .so).
 /// `debuginfo` is the full path to an elf object containing debuginfo.
 /// `source` is the store path of the source, either directory or archive.
-#[derive(Debug, Clone)]
+/// `build_source` is the store path of a capture of the build directory, for generated sources.
+/// `architecture` is the machine architecture of the elf objects, see
+/// [crate::store::architecture_name].
+///
+/// Paths are encoded with [encode_path].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Entry {
     /// elf buildid, in base64 as printed by readelf
     pub buildid: String,
@@ -29,6 +115,264 @@ pub struct Entry {
     pub debuginfo: Option<String>,
     /// store path of the source
     pub source: Option<String>,
+    /// store path of the captured build directory
+    pub build_source: Option<String>,
+    /// machine architecture, like `x86_64-le`
+    pub architecture: Option<String>,
+}
+
+/// What is known about a buildid, as served at `/buildid/BUILDID/metadata`: an [Entry] and what
+/// can be derived from the store path of its files.
+///
+/// Paths are encoded like in [Entry].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// elf buildid, in base64 as printed by readelf
+    pub buildid: String,
+    /// store path of the stripped elf file
+    pub executable: Option<String>,
+    /// store path of the separate debug info
+    pub debuginfo: Option<String>,
+    /// store path of the source
+    pub source: Option<String>,
+    /// store path of the captured build directory
+    pub build_source: Option<String>,
+    /// machine architecture, like `x86_64-le`
+    pub architecture: Option<String>,
+    /// the store path containing the executable, or else the debuginfo
+    pub store_path: Option<String>,
+    /// the derivation that built `store_path`, if known
+    pub deriver: Option<String>,
+    /// package name parsed from the name of the deriver, or else of `store_path`
+    pub package: Option<String>,
+    /// package version parsed from the name of the deriver, or else of `store_path`
+    pub version: Option<String>,
+    /// the Go build ID, for Go executables without a GNU build id, whose buildid is the base16
+    /// encoding of their Go build ID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub go_buildid: Option<String>,
+}
+
+impl Entry {
+    /// This entry without its paths which are not in a store path, like private files in the
+    /// cache directory, or which are not normalized, like `/nix/store/xxx-foo/../../etc/shadow`
+    /// in an entry from an untrusted source.
+    ///
+    /// Returns `None` if there is no path left.
+    pub fn store_paths_only(self) -> Option<Entry> {
+        let keep = |path: Option<String>| path.filter(|path| is_normal_store_path(path));
+        let entry = Entry {
+            buildid: self.buildid,
+            executable: keep(self.executable),
+            debuginfo: keep(self.debuginfo),
+            source: keep(self.source),
+            build_source: keep(self.build_source),
+            architecture: self.architecture,
+        };
+        let has_path = entry.executable.is_some()
+            || entry.debuginfo.is_some()
+            || entry.source.is_some()
+            || entry.build_source.is_some();
+        has_path.then_some(entry)
+    }
+}
+
+/// Whether this path, encoded with [encode_path], is in a store path and only made of normal
+/// components.
+fn is_normal_store_path(path: &str) -> bool {
+    use std::path::Component;
+    let path = decode_path(path);
+    let mut components = path.components();
+    components.next() == Some(Component::RootDir)
+        && components.all(|component| matches!(component, Component::Normal(_)))
+        && crate::store::get_store_path(&path).is_some()
+}
+
+#[test]
+fn test_store_paths_only() {
+    let entry = Entry {
+        buildid: "ab12".to_owned(),
+        executable: Some("/nix/store/xxxx-foo/bin/foo".to_owned()),
+        debuginfo: Some("/root/.cache/nixseparatedebuginfod/debuginfo/ab12".to_owned()),
+        source: Some("/nix/store/xxxx-foo/../../etc/shadow".to_owned()),
+        build_source: Some("/nix/store".to_owned()),
+        architecture: Some("x86_64-le".to_owned()),
+    };
+    assert_eq!(
+        entry.clone().store_paths_only(),
+        Some(Entry {
+            debuginfo: None,
+            source: None,
+            build_source: None,
+            ..entry.clone()
+        })
+    );
+    let private = Entry {
+        executable: Some("/etc/shadow".to_owned()),
+        ..entry
+    };
+    assert_eq!(private.store_paths_only(), None);
+}
+
+impl Metadata {
+    /// Completes an entry with `deriver`, the deriver of the store path of its executable or
+    /// debuginfo, if known.
+    pub fn new(entry: Entry, deriver: Option<PathBuf>) -> Self {
+        let store_path = Metadata::store_path_of(&entry);
+        let named = deriver.as_deref().or(store_path.as_deref());
+        let (package, version) = match named {
+            None => (None, None),
+            Some(named) => (
+                crate::filter::package_name(named),
+                crate::filter::package_version(named),
+            ),
+        };
+        let to_string = |path: Option<&Path>| path.and_then(Path::to_str).map(str::to_owned);
+        Metadata {
+            go_buildid: crate::store::decode_go_buildid(&entry.buildid),
+            store_path: to_string(store_path.as_deref()),
+            deriver: to_string(deriver.as_deref()),
+            package,
+            version,
+            buildid: entry.buildid,
+            executable: entry.executable,
+            debuginfo: entry.debuginfo,
+            source: entry.source,
+            build_source: entry.build_source,
+            architecture: entry.architecture,
+        }
+    }
+
+    /// The store path containing the executable of this entry, or else its debuginfo.
+    pub fn store_path_of(entry: &Entry) -> Option<PathBuf> {
+        entry
+            .executable
+            .iter()
+            .chain(entry.debuginfo.iter())
+            .find_map(|path| {
+                crate::store::get_store_path(&decode_path(path)).map(Path::to_path_buf)
+            })
+    }
+}
+
+/// A split dwarf file (`.dwo` or `.dwp`) in a debug output, for programs compiled with
+/// `-gsplit-dwarf`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitDwarf {
+    /// store path of the debug output
+    pub output: String,
+    /// file name of the split dwarf file
+    pub name: String,
+    /// full path of the split dwarf file
+    pub path: String,
+}
+
+/// Where else source files can be found when they are not in a source store path, like the
+/// other `srcs` and the `patches` of its derivation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceRoots {
+    /// the source store path, as in [Entry::source]
+    pub source: String,
+    /// the other store paths
+    pub roots: Vec<String>,
+}
+
+/// The directory where the source of a buildid was unpacked during its build, like
+/// `/build/glibc-2.37`, found in its debug info.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourcePrefix {
+    /// elf buildid, in base64 as printed by readelf
+    pub buildid: String,
+    /// absolute path, as in the paths of source files in the debug info, encoded with
+    /// [encode_path]
+    pub prefix: String,
+}
+
+/// What indexation of a store path finds
+#[derive(Debug, Clone)]
+pub enum Indexed {
+    /// information about a buildid
+    Build(Entry),
+    /// a split dwarf file
+    SplitDwarf(SplitDwarf),
+    /// other source store paths
+    SourceRoots(SourceRoots),
+    /// where the source of a buildid was unpacked
+    SourcePrefix(SourcePrefix),
+    /// this store path was deleted during indexation, so what was found in it must be forgotten
+    Deleted(String),
+}
+
+/// A buildid which was requested but could not be served.
+#[derive(Debug, Clone, Serialize)]
+pub struct Miss {
+    /// elf buildid, in base64 as printed by readelf
+    pub buildid: String,
+    /// unix timestamp of the last time this buildid was missing
+    pub missed: i64,
+    /// unix timestamp of when indexation found this buildid, if it did
+    pub found: Option<i64>,
+}
+
+/// Something whose last occurrence is remembered in the cache, so that operators can tell
+/// whether indexation silently stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// indexation started to index new store paths
+    IndexStart,
+    /// an indexation cycle completed successfully
+    IndexEnd,
+    /// the cache was created, or wiped because its schema changed
+    Created,
+}
+
+impl Event {
+    /// The name of the event in the cache db
+    fn name(self) -> &'static str {
+        match self {
+            Event::IndexStart => "index-start",
+            Event::IndexEnd => "index-end",
+            Event::Created => "created",
+        }
+    }
+}
+
+/// Unix timestamps of the last occurrence of each [Event], if any
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Events {
+    /// [Event::IndexStart]
+    pub index_start: Option<i64>,
+    /// [Event::IndexEnd]
+    pub index_end: Option<i64>,
+    /// [Event::Created]
+    pub created: Option<i64>,
+}
+
+/// How long found misses are remembered, in seconds
+const FOUND_MISS_RETENTION: i64 = 24 * 3600;
+
+/// How long misses which were never found are remembered, in seconds
+const MISS_RETENTION: i64 = 30 * 24 * 3600;
+
+/// How many misses are remembered at most, the most recent ones
+const MAX_MISSES: i64 = 10_000;
+
+/// How long the absence of a file in a substituter is remembered, in seconds
+const SUBSTITUTER_MISS_TTL: i64 = 3600;
+
+/// How long a redirection of a file in a substituter is remembered, in seconds
+const SUBSTITUTER_REDIRECT_TTL: i64 = 7 * 24 * 3600;
+
+/// How long files and directories created outside the store (debuginfo fetched from
+/// substituters, extracted source files) are kept after their last use, in seconds
+const PRIVATE_PATH_RETENTION: i64 = 30 * 24 * 3600;
+
+/// Current unix timestamp
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
 }
 
 /// A cache storing the executable, debuginfo and source location for each buildid.
@@ -38,7 +382,201 @@ pub struct Entry {
 pub struct Cache {
     /// A connection to a backing sqlite db.
     sqlite: SqlitePool,
+    /// The entries of the buildids looked up recently, as a debugger requests several files for
+    /// each buildid
+    recent: Arc<Mutex<RecentEntries>>,
+}
+
+/// How many entries [RecentEntries] keeps
+const RECENT_ENTRIES: usize = 1024;
+
+/// How long [RecentEntries] keeps an entry, so that changes to the cache db by other processes,
+/// like the `invalidate` subcommand, are eventually seen
+const RECENT_ENTRY_LIFETIME: Duration = Duration::from_secs(10);
+
+/// The entries of the [RECENT_ENTRIES] buildids looked up most recently, or `None` for buildids
+/// not in the cache db.
+///
+/// Entries are removed when their buildid is registered or forgotten.
+#[derive(Default)]
+struct RecentEntries {
+    /// when each entry was last used, when it was read from the cache db, and the entry
+    entries: HashMap<String, (u64, Instant, Option<Entry>)>,
+    /// buildids by when they were last used
+    by_use: BTreeMap<u64, String>,
+    /// incremented on each use
+    clock: u64,
+    /// incremented each time entries are removed, so that an entry read from the cache db
+    /// before it changed is not added afterwards
+    generation: u64,
+}
+
+impl RecentEntries {
+    /// The entry of this buildid, if it is known
+    fn get(&mut self, buildid: &str) -> Option<Option<Entry>> {
+        let (used, read, entry) = self.entries.get_mut(buildid)?;
+        if read.elapsed() > RECENT_ENTRY_LIFETIME {
+            self.remove(buildid);
+            return None;
+        }
+        self.by_use.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        let entry = entry.clone();
+        self.by_use.insert(self.clock, buildid.to_owned());
+        Some(entry)
+    }
+
+    /// Remembers the entry of this buildid, read from the cache db when [RecentEntries::generation]
+    /// was `generation`.
+    fn insert(&mut self, buildid: &str, entry: Option<Entry>, generation: u64) {
+        if generation != self.generation {
+            return;
+        }
+        self.remove(buildid);
+        self.clock += 1;
+        self.entries
+            .insert(buildid.to_owned(), (self.clock, Instant::now(), entry));
+        self.by_use.insert(self.clock, buildid.to_owned());
+        while self.entries.len() > RECENT_ENTRIES {
+            let Some((_, oldest)) = self.by_use.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    /// Forgets the entry of this buildid
+    fn remove(&mut self, buildid: &str) {
+        self.generation += 1;
+        if let Some((used, _, _)) = self.entries.remove(buildid) {
+            self.by_use.remove(&used);
+        }
+    }
+
+    /// Forgets all entries
+    fn clear(&mut self) {
+        self.generation += 1;
+        self.entries.clear();
+        self.by_use.clear();
+    }
 }
+
+#[test]
+fn test_recent_entries() {
+    let mut recent = RecentEntries::default();
+    for i in 0..RECENT_ENTRIES + 1 {
+        let generation = recent.generation;
+        recent.insert(&i.to_string(), None, generation);
+        // keep the first one in use
+        assert_eq!(recent.get("0"), Some(None));
+    }
+    assert_eq!(recent.entries.len(), RECENT_ENTRIES);
+    assert_eq!(recent.get("1"), None);
+    assert_eq!(recent.get("2"), Some(None));
+    // an entry read before a change is not kept
+    let generation = recent.generation;
+    recent.remove("0");
+    recent.insert("0", Some(test_entry("0")), generation);
+    assert_eq!(recent.get("0"), None);
+    recent.clear();
+    assert_eq!(recent.get("2"), None);
+}
+
+/// The cache directory given with `--cache-dir`, if any
+static CACHE_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+/// Stores the cache db and other state in `dir` instead of the default cache directory. Must be
+/// called before [cache_dir].
+pub fn set_cache_dir(dir: PathBuf) {
+    if CACHE_DIR.set(dir).is_err() {
+        tracing::warn!("cache directory set twice");
+    }
+}
+
+/// The directory where the cache db and other state of this program are stored, created if
+/// needed.
+pub fn cache_dir() -> anyhow::Result<PathBuf> {
+    let path = match CACHE_DIR.get() {
+        Some(dir) => dir.clone(),
+        None => {
+            let dirs = ProjectDirs::from("eu", "xlumurb", "nixseparatedebuginfod");
+            let dirs = match dirs {
+                Some(d) => d,
+                None => bail!("could not determine cache dir in $HOME"),
+            };
+            dirs.cache_dir().to_owned()
+        }
+    };
+    std::fs::create_dir_all(&path)
+        .with_context(|| format!("creating cache directory {}", path.display()))?;
+    Ok(path)
+}
+
+/// The directory given with `--temp-dir`, if any
+static TEMP_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+/// Creates temporary files in `dir` instead of the default of [temp_dir]. Must be called before
+/// [temp_dir].
+pub fn set_temp_dir(dir: PathBuf) {
+    if TEMP_DIR.set(dir).is_err() {
+        tracing::warn!("temporary directory set twice");
+    }
+}
+
+/// [temp_dir], once created
+static CREATED_TEMP_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+/// The directory where temporary files, like nars downloaded from substituters, are created,
+/// created on first use.
+///
+/// As nars can be several GB and `/tmp` is often a small tmpfs, this is `tmp` in the
+/// [cache_dir] unless `--temp-dir` is given.
+pub fn temp_dir() -> anyhow::Result<PathBuf> {
+    CREATED_TEMP_DIR
+        .get_or_try_init(|| {
+            let path = match TEMP_DIR.get() {
+                Some(dir) => dir.clone(),
+                None => cache_dir()?.join("tmp"),
+            };
+            std::fs::create_dir_all(&path)
+                .with_context(|| format!("creating temporary directory {}", path.display()))?;
+            Ok(path)
+        })
+        .cloned()
+}
+
+/// Deletes the temporary files left in [temp_dir] by a previous run, for example one killed
+/// while downloading a nar.
+///
+/// A directory given with `--temp-dir` is left alone, as it may be shared.
+pub async fn clean_temp_dir() -> anyhow::Result<()> {
+    if TEMP_DIR.get().is_some() {
+        return Ok(());
+    }
+    let dir = temp_dir()?;
+    tokio::task::spawn_blocking(move || {
+        let entries = std::fs::read_dir(&dir)
+            .with_context(|| format!("listing temporary directory {}", dir.display()))?;
+        for entry in entries {
+            let entry =
+                entry.with_context(|| format!("listing temporary directory {}", dir.display()))?;
+            let path = entry.path();
+            let result = match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => std::fs::remove_dir_all(&path),
+                Ok(_) => std::fs::remove_file(&path),
+                Err(e) => Err(e),
+            };
+            result
+                .with_context(|| format!("deleting leftover {}", path.display()))
+                .or_warn();
+        }
+        Ok(())
+    })
+    .await
+    .context("joining cleaning task")?
+}
+
 /// The schema of the sqlite db backing [Cache].
 const SCHEMA: &str = include_str!("./schema.sql");
 
@@ -83,26 +621,31 @@ async fn populate_pool(pool: &SqlitePool) -> anyhow::Result<()> {
         .execute(&mut *transaction)
         .await
         .context("setting schema default timestamps on cache db")?;
-    sqlx::query("insert into id values (0);")
+    sqlx::query("insert into id (next) values (0);")
         .execute(&mut *transaction)
         .await
         .context("setting schema default next id on cache db")?;
+    sqlx::query("insert into events (event, timestamp) values ($1, $2);")
+        .bind(Event::Created.name())
+        .bind(now())
+        .execute(&mut *transaction)
+        .await
+        .context("recording creation time of cache db")?;
     transaction.commit().await?;
     Ok(())
 }
 
 impl Cache {
-    /// Attempts to open the cache from disk. Does not try very hard.
-    async fn open_weak() -> anyhow::Result<Cache> {
-        let dirs = ProjectDirs::from("eu", "xlumurb", "nixseparatedebuginfod");
-        let dirs = match dirs {
-            Some(d) => d,
-            None => bail!("could not determine cache dir in $HOME"),
-        };
-        let mut path = dirs.cache_dir().to_owned();
-        std::fs::create_dir_all(&path)
-            .with_context(|| format!("creating cache directory {}", path.display()))?;
-        path.push("cache.sqlite3");
+    fn new(sqlite: SqlitePool) -> Cache {
+        Cache {
+            sqlite,
+            recent: Arc::default(),
+        }
+    }
+
+    /// Attempts to open the cache from disk at `path`. Does not try very hard.
+    async fn open_weak(path: anyhow::Result<PathBuf>) -> anyhow::Result<Cache> {
+        let path = path?;
         let cache_exists = path.exists();
         let path_utf8 = match path.to_str() {
             Some(p) => p,
@@ -135,72 +678,202 @@ impl Cache {
                 pool
             }
         };
-        Ok(Cache { sqlite: pool })
+        Ok(Cache::new(pool))
+    }
+
+    /// Opens the cache of another instance at `path` read only, for lookups only.
+    pub async fn open_read_only(path: &Path) -> anyhow::Result<Cache> {
+        let path_utf8 = match path.to_str() {
+            Some(p) => p,
+            None => bail!("cache path {} is not utf8", path.display()),
+        };
+        let url = format!("file:{}?mode=ro", path_utf8);
+        let pool = SqlitePool::connect(&url)
+            .await
+            .with_context(|| format!("failed to connect to {} with sqlite3", &url))?;
+        pool_is_valid(&pool).await?;
+        Ok(Cache::new(pool))
+    }
+
+    /// Opens an empty cache in memory.
+    pub async fn open_in_memory() -> anyhow::Result<Cache> {
+        let pool = SqlitePool::connect(":memory:")
+            .await
+            .context("opening in memory sql db")?;
+        populate_pool(&pool)
+            .await
+            .context("populating empty cache")?;
+        Ok(Cache::new(pool))
     }
 
     /// Opens a cache, either from disk, or it it fails, in memory.
     pub async fn open() -> anyhow::Result<Cache> {
-        match Cache::open_weak().await {
+        Cache::open_at(cache_dir().map(|dir| dir.join("cache.sqlite3"))).await
+    }
+
+    /// Opens the cache of the store named `name` with `--store`, separate from the cache of the
+    /// local store so that the same buildid can refer to different files in each store.
+    pub async fn open_store(name: &str) -> anyhow::Result<Cache> {
+        let path = cache_dir().and_then(|dir| {
+            let dir = dir.join("stores").join(name);
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("creating directory {}", dir.display()))?;
+            Ok(dir.join("cache.sqlite3"))
+        });
+        Cache::open_at(path).await
+    }
+
+    /// Opens a cache at this path, either from disk, or it it fails, in memory.
+    async fn open_at(path: anyhow::Result<PathBuf>) -> anyhow::Result<Cache> {
+        match Cache::open_weak(path).await {
             Err(e) => {
                 tracing::warn!(
                     "could not use on disk cache ({:#}), running cache in memory",
                     e
                 );
-                let pool = SqlitePool::connect(":memory:")
-                    .await
-                    .context("opening in memory sql db")?;
-                populate_pool(&pool)
-                    .await
-                    .context("populating empty cache")?;
-                Ok(Cache { sqlite: pool })
+                Cache::open_in_memory().await
             }
             Ok(cache) => Ok(cache),
         }
     }
 
+    /// Finds the only buildid in the cache that starts with this prefix.
+    ///
+    /// If `prefix` is a complete buildid in the cache, returns it. Returns `None` if there is no
+    /// such buildid or several.
+    pub async fn expand_buildid_prefix(&self, prefix: &str) -> anyhow::Result<Option<String>> {
+        // hex digits are all smaller than g
+        let rows = sqlx::query(
+            "select buildid from builds where buildid >= $1 and buildid < $2 order by buildid limit 2;",
+        )
+        .bind(prefix)
+        .bind(format!("{prefix}g"))
+        .fetch_all(&self.sqlite)
+        .await
+        .context("looking for buildid prefix in cache db")?;
+        let mut buildids = Vec::with_capacity(rows.len());
+        for row in rows {
+            let buildid: String = row.try_get("buildid")?;
+            buildids.push(buildid);
+        }
+        Ok(match buildids.as_slice() {
+            [only] => Some(only.clone()),
+            [first, _] if first == prefix => Some(first.clone()),
+            _ => None,
+        })
+    }
+
     /// Get the path of an elf object containing debuginfo for this buildid.
     ///
     /// The path may have been gc-ed, you are responsible to ensure it exists.
-    pub async fn get_debuginfo(&self, buildid: &str) -> anyhow::Result<Option<String>> {
-        let row = sqlx::query("select debuginfo from builds where buildid = $1;")
-            .bind(buildid)
-            .fetch_optional(&self.sqlite)
-            .await
-            .context("reading debuginfo from cache db")?;
-        Ok(match row {
-            None => None,
-            Some(r) => r.try_get("debuginfo")?,
-        })
+    pub async fn get_debuginfo(&self, buildid: &str) -> anyhow::Result<Option<PathBuf>> {
+        Ok(self
+            .get_entry(buildid)
+            .await?
+            .and_then(|entry| entry.debuginfo)
+            .as_deref()
+            .map(decode_path))
     }
 
     /// Get the path of an elf object containing text for this buildid.
     ///
     /// The path may have been gc-ed, you are responsible to ensure it exists.
-    pub async fn get_executable(&self, buildid: &str) -> anyhow::Result<Option<String>> {
-        let row = sqlx::query("select executable from builds where buildid = $1;")
-            .bind(buildid)
-            .fetch_optional(&self.sqlite)
-            .await
-            .context("reading executable from cache db")?;
-        Ok(match row {
-            None => None,
-            Some(r) => r.try_get("executable")?,
-        })
+    pub async fn get_executable(&self, buildid: &str) -> anyhow::Result<Option<PathBuf>> {
+        Ok(self
+            .get_entry(buildid)
+            .await?
+            .and_then(|entry| entry.executable)
+            .as_deref()
+            .map(decode_path))
     }
 
     /// Get the store path where the source of this buildid is.
     ///
     /// The path may have been gc-ed, you are responsible to ensure it exists.
-    pub async fn get_source(&self, buildid: &str) -> anyhow::Result<Option<String>> {
-        let row = sqlx::query("select source from builds where buildid = $1;")
+    pub async fn get_source(&self, buildid: &str) -> anyhow::Result<Option<PathBuf>> {
+        Ok(self
+            .get_entry(buildid)
+            .await?
+            .and_then(|entry| entry.source)
+            .as_deref()
+            .map(decode_path))
+    }
+
+    /// Get the store path where the build directory of this buildid was captured.
+    ///
+    /// The path may have been gc-ed, you are responsible to ensure it exists.
+    pub async fn get_build_source(&self, buildid: &str) -> anyhow::Result<Option<PathBuf>> {
+        Ok(self
+            .get_entry(buildid)
+            .await?
+            .and_then(|entry| entry.build_source)
+            .as_deref()
+            .map(decode_path))
+    }
+
+    /// Get everything known about this buildid.
+    ///
+    /// The paths may have been gc-ed, you are responsible to ensure they exist.
+    pub async fn get_entry(&self, buildid: &str) -> anyhow::Result<Option<Entry>> {
+        let generation = {
+            let mut recent = self.recent.lock().unwrap();
+            if let Some(entry) = recent.get(buildid) {
+                return Ok(entry);
+            }
+            recent.generation
+        };
+        let row = sqlx::query("select * from builds where buildid = $1;")
             .bind(buildid)
             .fetch_optional(&self.sqlite)
             .await
-            .context("reading executable from cache db")?;
-        Ok(match row {
-            None => None,
-            Some(r) => r.try_get("source")?,
-        })
+            .context("reading entry from cache db")?;
+        let entry = row.as_ref().map(entry_from_row).transpose()?;
+        self.recent
+            .lock()
+            .unwrap()
+            .insert(buildid, entry.clone(), generation);
+        Ok(entry)
+    }
+
+    /// Get the entries whose executable or debuginfo is `path` or inside `path`, at most `limit`
+    /// of them.
+    pub async fn get_entries_in(&self, path: &str, limit: u32) -> anyhow::Result<Vec<Entry>> {
+        let rows = sqlx::query(
+            "select * from builds where executable = $1 or debuginfo = $1
+            or substr(executable, 1, length($2)) = $2 or substr(debuginfo, 1, length($2)) = $2
+            order by buildid limit $3;",
+        )
+        .bind(path)
+        .bind(format!("{}/", path.trim_end_matches('/')))
+        .bind(limit)
+        .fetch_all(&self.sqlite)
+        .await
+        .context("reading entries in path from cache db")?;
+        rows.iter().map(entry_from_row).collect()
+    }
+
+    /// Get the entries whose buildid comes after `after` in lexicographic order, at most `limit`
+    /// of them, ordered by buildid.
+    ///
+    /// This allows iterating over the whole cache without loading it in memory.
+    pub async fn get_entries_after(&self, after: &str, limit: u32) -> anyhow::Result<Vec<Entry>> {
+        let rows =
+            sqlx::query("select * from builds where buildid > $1 order by buildid limit $2;")
+                .bind(after)
+                .bind(limit)
+                .fetch_all(&self.sqlite)
+                .await
+                .context("reading entries from cache db")?;
+        rows.iter().map(entry_from_row).collect()
+    }
+
+    /// Number of buildids in the cache
+    pub async fn count_buildids(&self) -> anyhow::Result<i64> {
+        let row = sqlx::query("select count(*) as count from builds;")
+            .fetch_one(&self.sqlite)
+            .await
+            .context("counting buildids in cache db")?;
+        row.try_get("count").context("parsing buildid count")
     }
 
     /// Register information for a buildid
@@ -208,52 +881,1368 @@ impl Cache {
     /// Only one of the each entry fields is stored for each buildid, if register is called several times
     /// for a single buildid, only the latest `Some` provided one is retained.
     pub async fn register(&self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.insert_entries(
+            entries,
+            "insert into builds
+                values ($1, $2, $3, $4, $5, $6)
+                on conflict(buildid) do update set
+                executable = coalesce(excluded.executable, executable),
+                debuginfo = coalesce(excluded.debuginfo, debuginfo),
+                source = coalesce(excluded.source, source),
+                buildsource = coalesce(excluded.buildsource, buildsource),
+                architecture = coalesce(excluded.architecture, architecture)
+                ;",
+        )
+        .await
+    }
+
+    /// Like [Cache::register], but only sets the fields which are not known yet, for entries
+    /// from less trusted sources than local indexation, like an upstream cache.
+    pub async fn register_missing(&self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.insert_entries(
+            entries,
+            "insert into builds
+                values ($1, $2, $3, $4, $5, $6)
+                on conflict(buildid) do update set
+                executable = coalesce(executable, excluded.executable),
+                debuginfo = coalesce(debuginfo, excluded.debuginfo),
+                source = coalesce(source, excluded.source),
+                buildsource = coalesce(buildsource, excluded.buildsource),
+                architecture = coalesce(architecture, excluded.architecture)
+                ;",
+        )
+        .await
+    }
+
+    /// Inserts these entries in `builds` with this query, whose parameters are the fields of
+    /// the entry.
+    async fn insert_entries(&self, entries: &[Entry], query: &str) -> anyhow::Result<()> {
         if entries.is_empty() {
             return Ok(());
         }
         let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
         for entry in entries {
-            sqlx::query(
-                "insert into builds
-                    values ($1, $2, $3, $4)
-                    on conflict(buildid) do update set
-                    executable = coalesce(excluded.executable, executable),
-                    debuginfo = coalesce(excluded.debuginfo, debuginfo),
-                    source = coalesce(excluded.source, source)
-                    ;",
-            )
-            .bind(&entry.buildid)
-            .bind(&entry.executable)
-            .bind(&entry.debuginfo)
-            .bind(&entry.source)
-            .execute(&mut *transaction)
-            .await
-            .context("inserting build")?;
+            sqlx::query(query)
+                .bind(entry.buildid.to_ascii_lowercase())
+                .bind(&entry.executable)
+                .bind(&entry.debuginfo)
+                .bind(&entry.source)
+                .bind(&entry.build_source)
+                .bind(&entry.architecture)
+                .execute(&mut *transaction)
+                .await
+                .context("inserting build")?;
+            let found =
+                sqlx::query("update misses set found = $2 where buildid = $1 and found is null;")
+                    .bind(&entry.buildid)
+                    .bind(now())
+                    .execute(&mut *transaction)
+                    .await
+                    .context("marking miss as found")?;
+            if found.rows_affected() > 0 {
+                tracing::info!(
+                    "buildid {} was previously requested and is now indexed",
+                    &entry.buildid
+                );
+            }
         }
         transaction
             .commit()
             .await
             .context("committing entry insert")?;
+        let mut recent = self.recent.lock().unwrap();
+        for entry in entries {
+            recent.remove(&entry.buildid.to_ascii_lowercase());
+        }
         Ok(())
     }
 
-    /// Store the next store path id to read from the nix db
-    pub async fn set_next_id(&self, id: Id) -> anyhow::Result<()> {
-        sqlx::query("update id set next = max(next, $1);")
-            .bind(id)
-            .execute(&self.sqlite)
+    /// Remember that this buildid was requested but could not be served
+    pub async fn record_miss(&self, buildid: &str) -> anyhow::Result<()> {
+        let now = now();
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        sqlx::query("delete from misses where found < $1 or missed < $2;")
+            .bind(now - FOUND_MISS_RETENTION)
+            .bind(now - MISS_RETENTION)
+            .execute(&mut *transaction)
             .await
-            .context("advancing next registered id in cache db")?;
+            .context("pruning old misses")?;
+        sqlx::query(
+            "insert into misses values ($1, $2, null)
+                on conflict(buildid) do update set
+                missed = excluded.missed,
+                found = null
+                ;",
+        )
+        .bind(buildid)
+        .bind(now)
+        .execute(&mut *transaction)
+        .await
+        .context("inserting miss")?;
+        sqlx::query(
+            "delete from misses where buildid not in
+                (select buildid from misses order by missed desc limit $1);",
+        )
+        .bind(MAX_MISSES)
+        .execute(&mut *transaction)
+        .await
+        .context("pruning excess misses")?;
+        transaction
+            .commit()
+            .await
+            .context("committing miss insert")?;
         Ok(())
     }
 
-    /// get the next store path id to read from the nix db
-    pub async fn get_next_id(&self) -> anyhow::Result<Id> {
-        let row = sqlx::query("select next from id")
-            .fetch_one(&self.sqlite)
+    /// List buildids which were requested but could not be served, and whether they were found
+    /// since.
+    pub async fn get_misses(&self) -> anyhow::Result<Vec<Miss>> {
+        let rows = sqlx::query("select buildid, missed, found from misses order by missed desc;")
+            .fetch_all(&self.sqlite)
             .await
-            .context("reading next registered id in cache db")?;
-        row.try_get("next")
-            .context("parsing next registered id from cache db")
+            .context("reading misses from cache db")?;
+        let mut result = Vec::with_capacity(rows.len());
+        for row in rows {
+            result.push(Miss {
+                buildid: row.try_get("buildid")?,
+                missed: row.try_get("missed")?,
+                found: row.try_get("found")?,
+            });
+        }
+        Ok(result)
+    }
+
+    /// Get the remembered result of fetching `path` from `substituter`, if it is still fresh.
+    ///
+    /// Returns `Some(None)` if the file was missing, and `Some(Some(target))` if the file was a
+    /// redirection to `target`.
+    pub async fn get_substituter_lookup(
+        &self,
+        substituter: &str,
+        path: &str,
+    ) -> anyhow::Result<Option<Option<String>>> {
+        let row = sqlx::query(
+            "select target from substituterlookups
+                where substituter = $1 and path = $2 and timestamp >= $3 - (case
+                    when target is null then $4
+                    else $5
+                end);",
+        )
+        .bind(substituter)
+        .bind(path)
+        .bind(now())
+        .bind(SUBSTITUTER_MISS_TTL)
+        .bind(SUBSTITUTER_REDIRECT_TTL)
+        .fetch_optional(&self.sqlite)
+        .await
+        .context("reading substituter lookup from cache db")?;
+        Ok(match row {
+            None => None,
+            Some(r) => Some(r.try_get("target")?),
+        })
+    }
+
+    /// Remember the result of fetching `path` from `substituter`: `None` if it was missing,
+    /// `Some(target)` if it was a redirection to `target`.
+    pub async fn record_substituter_lookup(
+        &self,
+        substituter: &str,
+        path: &str,
+        target: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let now = now();
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        sqlx::query("delete from substituterlookups where timestamp < $1;")
+            .bind(now - SUBSTITUTER_REDIRECT_TTL.max(SUBSTITUTER_MISS_TTL))
+            .execute(&mut *transaction)
+            .await
+            .context("pruning old substituter lookups")?;
+        sqlx::query(
+            "insert into substituterlookups values ($1, $2, $3, $4)
+                on conflict(substituter, path) do update set
+                target = excluded.target,
+                timestamp = excluded.timestamp
+                ;",
+        )
+        .bind(substituter)
+        .bind(path)
+        .bind(target)
+        .bind(now)
+        .execute(&mut *transaction)
+        .await
+        .context("inserting substituter lookup")?;
+        transaction
+            .commit()
+            .await
+            .context("committing substituter lookup insert")?;
+        Ok(())
     }
+
+    /// Count `bytes` more downloaded by this client in the current hour, for per client quotas.
+    pub async fn add_client_bytes(&self, client: &str, bytes: u64) -> anyhow::Result<()> {
+        let hour = now() / 3600;
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        sqlx::query("delete from clientdownloads where hour < $1;")
+            .bind(hour)
+            .execute(&mut *transaction)
+            .await
+            .context("pruning old client downloads")?;
+        sqlx::query(
+            "insert into clientdownloads values ($1, $2, $3)
+                on conflict(client, hour) do update set
+                bytes = bytes + excluded.bytes
+                ;",
+        )
+        .bind(client)
+        .bind(hour)
+        .bind(i64::try_from(bytes).unwrap_or(i64::MAX))
+        .execute(&mut *transaction)
+        .await
+        .context("inserting client downloads")?;
+        transaction
+            .commit()
+            .await
+            .context("committing client downloads insert")?;
+        Ok(())
+    }
+
+    /// How many bytes this client downloaded in the current hour, see
+    /// [Cache::add_client_bytes].
+    pub async fn get_client_bytes(&self, client: &str) -> anyhow::Result<u64> {
+        let row = sqlx::query("select bytes from clientdownloads where client = $1 and hour = $2;")
+            .bind(client)
+            .bind(now() / 3600)
+            .fetch_optional(&self.sqlite)
+            .await
+            .context("reading client downloads from cache db")?;
+        let bytes: i64 = match row {
+            None => 0,
+            Some(row) => row.try_get("bytes").context("parsing client downloads")?,
+        };
+        Ok(bytes.max(0) as u64)
+    }
+
+    /// The `ETag` of the last version of the channel index at this url which was imported, if
+    /// any.
+    pub async fn get_channel_index_etag(&self, url: &str) -> anyhow::Result<Option<String>> {
+        let row = sqlx::query("select etag from channelindices where url = $1;")
+            .bind(url)
+            .fetch_optional(&self.sqlite)
+            .await
+            .context("reading channel index etag from cache db")?;
+        Ok(match row {
+            None => None,
+            Some(r) => Some(r.try_get("etag")?),
+        })
+    }
+
+    /// Remember that the version of the channel index at this url with this `ETag` was
+    /// imported.
+    pub async fn set_channel_index_etag(&self, url: &str, etag: &str) -> anyhow::Result<()> {
+        sqlx::query("insert or replace into channelindices values ($1, $2);")
+            .bind(url)
+            .bind(etag)
+            .execute(&self.sqlite)
+            .await
+            .context("writing channel index etag to cache db")?;
+        Ok(())
+    }
+
+    /// Remember that this debug output was realised in advance and takes `size` bytes on disk,
+    /// so that the limit of `--eager-debuginfo-mb` holds across restarts.
+    pub async fn register_eager_realised(&self, path: &str, size: u64) -> anyhow::Result<()> {
+        sqlx::query("insert or replace into eagerrealised values ($1, $2);")
+            .bind(path)
+            .bind(i64::try_from(size).unwrap_or(i64::MAX))
+            .execute(&self.sqlite)
+            .await
+            .context("writing eagerly realised path to cache db")?;
+        Ok(())
+    }
+
+    /// The debug outputs registered by [Cache::register_eager_realised], with their size.
+    pub async fn get_eager_realised(&self) -> anyhow::Result<Vec<(String, u64)>> {
+        let rows = sqlx::query("select path, size from eagerrealised;")
+            .fetch_all(&self.sqlite)
+            .await
+            .context("reading eagerly realised paths from cache db")?;
+        let mut result = Vec::with_capacity(rows.len());
+        for row in rows {
+            let size: i64 = row.try_get("size")?;
+            result.push((row.try_get("path")?, size.max(0) as u64));
+        }
+        Ok(result)
+    }
+
+    /// Forget a debug output registered by [Cache::register_eager_realised], typically because
+    /// it was garbage collected.
+    pub async fn forget_eager_realised(&self, path: &str) -> anyhow::Result<()> {
+        sqlx::query("delete from eagerrealised where path = $1;")
+            .bind(path)
+            .execute(&self.sqlite)
+            .await
+            .context("deleting eagerly realised path from cache db")?;
+        Ok(())
+    }
+
+    /// Register what indexation found, see [Cache::register] and [Cache::register_split_dwarf].
+    pub async fn register_indexed(&self, indexed: &[Indexed]) -> anyhow::Result<()> {
+        let mut entries = Vec::with_capacity(indexed.len());
+        let mut split_dwarf = Vec::new();
+        let mut source_roots = Vec::new();
+        let mut source_prefixes = Vec::new();
+        let mut deleted = Vec::new();
+        for item in indexed {
+            match item {
+                Indexed::Build(entry) => entries.push(entry.clone()),
+                Indexed::SplitDwarf(file) => split_dwarf.push(file.clone()),
+                Indexed::SourceRoots(roots) => source_roots.push(roots.clone()),
+                Indexed::SourcePrefix(prefix) => source_prefixes.push(prefix.clone()),
+                Indexed::Deleted(path) => deleted.push(path.as_str()),
+            }
+        }
+        self.register(&entries).await?;
+        self.register_split_dwarf(&split_dwarf).await?;
+        self.register_source_roots(&source_roots).await?;
+        self.register_source_prefixes(&source_prefixes).await?;
+        // entries of deleted store paths are sent before they are found to be deleted
+        for path in deleted {
+            self.forget_store_path(path).await?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the executables, debuginfo and split dwarf files in this store path, for example
+    /// because it was garbage collected while being indexed.
+    ///
+    /// Buildids with neither executable nor debuginfo left are removed.
+    pub async fn forget_store_path(&self, storepath: &str) -> anyhow::Result<()> {
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        // store paths contain _, which is a wildcard for like
+        let inside = |column: &str| format!("substr({column}, 1, length($1) + 1) = $1 || '/'");
+        sqlx::query(&format!(
+            "delete from builds where ({} or {})
+                and (executable is null or {})
+                and (debuginfo is null or {});",
+            inside("executable"),
+            inside("debuginfo"),
+            inside("executable"),
+            inside("debuginfo")
+        ))
+        .bind(storepath)
+        .execute(&mut *transaction)
+        .await
+        .context("removing builds of deleted store path")?;
+        for column in ["executable", "debuginfo"] {
+            sqlx::query(&format!(
+                "update builds set {column} = null where {};",
+                inside(column)
+            ))
+            .bind(storepath)
+            .execute(&mut *transaction)
+            .await
+            .with_context(|| format!("removing {column} in deleted store path"))?;
+        }
+        sqlx::query(&format!("delete from splitdwarf where {};", inside("path")))
+            .bind(storepath)
+            .execute(&mut *transaction)
+            .await
+            .context("removing split dwarf files of deleted store path")?;
+        transaction
+            .commit()
+            .await
+            .context("committing removal of deleted store path")?;
+        self.recent.lock().unwrap().clear();
+        Ok(())
+    }
+
+    /// Forgets everything known about this buildid, the split dwarf files of its debug output,
+    /// whether it was missed, and the results of looking it up in substituters at these paths of
+    /// their index, so that it is resolved again when it is next requested.
+    ///
+    /// Returns the forgotten entry, if the buildid was known.
+    pub async fn forget_buildid(
+        &self,
+        buildid: &str,
+        lookups: &[String],
+    ) -> anyhow::Result<Option<Entry>> {
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        let row = sqlx::query("select * from builds where buildid = $1;")
+            .bind(buildid)
+            .fetch_optional(&mut *transaction)
+            .await
+            .context("reading buildid")?;
+        let entry = row.as_ref().map(entry_from_row).transpose()?;
+        sqlx::query("delete from builds where buildid = $1;")
+            .bind(buildid)
+            .execute(&mut *transaction)
+            .await
+            .context("removing buildid")?;
+        let output = entry
+            .as_ref()
+            .and_then(|entry| entry.debuginfo.as_deref())
+            .and_then(|debuginfo| {
+                crate::store::get_store_path(&decode_path(debuginfo)).map(encode_path)
+            });
+        if let Some(output) = output {
+            sqlx::query("delete from splitdwarf where output = $1;")
+                .bind(output)
+                .execute(&mut *transaction)
+                .await
+                .context("removing split dwarf files of buildid")?;
+        }
+        sqlx::query("delete from misses where buildid = $1;")
+            .bind(buildid)
+            .execute(&mut *transaction)
+            .await
+            .context("removing misses of buildid")?;
+        sqlx::query("delete from sourceprefixes where buildid = $1;")
+            .bind(buildid)
+            .execute(&mut *transaction)
+            .await
+            .context("removing source prefix of buildid")?;
+        for path in lookups {
+            sqlx::query("delete from substituterlookups where path = $1;")
+                .bind(path)
+                .execute(&mut *transaction)
+                .await
+                .context("removing substituter lookups of buildid")?;
+        }
+        transaction
+            .commit()
+            .await
+            .context("committing removal of buildid")?;
+        self.recent.lock().unwrap().remove(buildid);
+        Ok(entry)
+    }
+
+    /// Register where the source of buildids was unpacked during their build
+    pub async fn register_source_prefixes(&self, prefixes: &[SourcePrefix]) -> anyhow::Result<()> {
+        if prefixes.is_empty() {
+            return Ok(());
+        }
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        for prefix in prefixes {
+            sqlx::query("insert or replace into sourceprefixes values ($1, $2);")
+                .bind(&prefix.buildid)
+                .bind(&prefix.prefix)
+                .execute(&mut *transaction)
+                .await
+                .context("inserting source prefix")?;
+        }
+        transaction
+            .commit()
+            .await
+            .context("committing source prefixes insert")?;
+        Ok(())
+    }
+
+    /// Get the directory where the source of this buildid was unpacked during its build, if
+    /// indexation found it.
+    pub async fn get_source_prefix(&self, buildid: &str) -> anyhow::Result<Option<String>> {
+        let row = sqlx::query("select prefix from sourceprefixes where buildid = $1;")
+            .bind(buildid)
+            .fetch_optional(&self.sqlite)
+            .await
+            .context("reading source prefix from cache db")?;
+        row.map(|row| row.try_get("prefix").context("parsing source prefix"))
+            .transpose()
+    }
+
+    /// Register the other source store paths of source store paths
+    pub async fn register_source_roots(&self, roots: &[SourceRoots]) -> anyhow::Result<()> {
+        if roots.is_empty() {
+            return Ok(());
+        }
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        for roots in roots {
+            for path in &roots.roots {
+                sqlx::query("insert or ignore into sourceroots values ($1, $2);")
+                    .bind(&roots.source)
+                    .bind(path)
+                    .execute(&mut *transaction)
+                    .await
+                    .context("inserting source root")?;
+            }
+        }
+        transaction
+            .commit()
+            .await
+            .context("committing source roots insert")?;
+        Ok(())
+    }
+
+    /// Get the other source store paths of this source store path, like patches.
+    ///
+    /// The paths may have been gc-ed, you are responsible to ensure they exist.
+    pub async fn get_source_roots(&self, source: &str) -> anyhow::Result<Vec<String>> {
+        let rows = sqlx::query("select path from sourceroots where source = $1 order by rowid;")
+            .bind(source)
+            .fetch_all(&self.sqlite)
+            .await
+            .context("reading source roots from cache db")?;
+        rows.iter()
+            .map(|row| row.try_get("path").context("parsing source root"))
+            .collect()
+    }
+
+    /// Remember the files in a source archive, as listing a large tarball is slow.
+    pub async fn register_archive_members(
+        &self,
+        archive: &str,
+        members: &[String],
+    ) -> anyhow::Result<()> {
+        // file names cannot contain nul bytes
+        let members = members.join("\0");
+        sqlx::query("insert or replace into archivemembers values ($1, $2);")
+            .bind(archive)
+            .bind(members.as_bytes())
+            .execute(&self.sqlite)
+            .await
+            .context("inserting archive members")?;
+        Ok(())
+    }
+
+    /// Get the files in a source archive, if they were registered with
+    /// [Cache::register_archive_members].
+    pub async fn get_archive_members(&self, archive: &str) -> anyhow::Result<Option<Vec<String>>> {
+        let row = sqlx::query("select members from archivemembers where archive = $1;")
+            .bind(archive)
+            .fetch_optional(&self.sqlite)
+            .await
+            .context("reading archive members from cache db")?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let members: Vec<u8> = row.try_get("members").context("parsing archive members")?;
+        let members = String::from_utf8(members).context("archive members are not utf8")?;
+        if members.is_empty() {
+            return Ok(Some(Vec::new()));
+        }
+        Ok(Some(members.split('\0').map(str::to_owned).collect()))
+    }
+
+    /// Register split dwarf files found in debug outputs
+    pub async fn register_split_dwarf(&self, files: &[SplitDwarf]) -> anyhow::Result<()> {
+        if files.is_empty() {
+            return Ok(());
+        }
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        for file in files {
+            sqlx::query("insert or ignore into splitdwarf values ($1, $2, $3);")
+                .bind(&file.output)
+                .bind(&file.name)
+                .bind(&file.path)
+                .execute(&mut *transaction)
+                .await
+                .context("inserting split dwarf file")?;
+        }
+        transaction
+            .commit()
+            .await
+            .context("committing split dwarf insert")?;
+        Ok(())
+    }
+
+    /// Get the paths of the split dwarf files named `name` in this debug output.
+    ///
+    /// The paths may have been gc-ed, you are responsible to ensure they exist.
+    pub async fn get_split_dwarf(&self, output: &str, name: &str) -> anyhow::Result<Vec<String>> {
+        let rows = sqlx::query(
+            "select path from splitdwarf where output = $1 and name = $2 order by path;",
+        )
+        .bind(output)
+        .bind(name)
+        .fetch_all(&self.sqlite)
+        .await
+        .context("reading split dwarf files from cache db")?;
+        let mut result = Vec::with_capacity(rows.len());
+        for row in rows {
+            result.push(row.try_get("path")?);
+        }
+        Ok(result)
+    }
+
+    /// Remember the hash of the nar serialisation of these store paths, as `(storepath, hash)`
+    pub async fn register_nar_hashes(&self, hashes: &[(String, String)]) -> anyhow::Result<()> {
+        if hashes.is_empty() {
+            return Ok(());
+        }
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        for (storepath, hash) in hashes {
+            sqlx::query(
+                "insert into narhashes values ($1, $2)
+                    on conflict(storepath) do update set
+                    narhash = excluded.narhash
+                    ;",
+            )
+            .bind(storepath)
+            .bind(hash)
+            .execute(&mut *transaction)
+            .await
+            .context("inserting nar hash")?;
+        }
+        transaction
+            .commit()
+            .await
+            .context("committing nar hashes insert")?;
+        Ok(())
+    }
+
+    /// Get the hash of the nar serialisation of this store path recorded at indexation time.
+    pub async fn get_nar_hash(&self, storepath: &str) -> anyhow::Result<Option<String>> {
+        let row = sqlx::query("select narhash from narhashes where storepath = $1;")
+            .bind(storepath)
+            .fetch_optional(&self.sqlite)
+            .await
+            .context("reading nar hash from cache db")?;
+        Ok(match row {
+            None => None,
+            Some(r) => Some(r.try_get("narhash")?),
+        })
+    }
+
+    /// Remember that this file or directory outside the store was created or used now, so that
+    /// it is deleted by [Cache::remove_expired_private_paths] when it is unused for too long.
+    pub async fn register_private_path(&self, path: &str) -> anyhow::Result<()> {
+        sqlx::query(
+            "insert into privatepaths values ($1, $2)
+                on conflict(path) do update set
+                timestamp = excluded.timestamp
+                ;",
+        )
+        .bind(path)
+        .bind(now())
+        .execute(&self.sqlite)
+        .await
+        .context("inserting private path")?;
+        Ok(())
+    }
+
+    /// Forget the paths registered with [Cache::register_private_path] which are too old,
+    /// and return them so that the caller deletes them.
+    pub async fn expire_private_paths(&self) -> anyhow::Result<Vec<String>> {
+        let limit = now() - PRIVATE_PATH_RETENTION;
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        let rows = sqlx::query("select path from privatepaths where timestamp < $1;")
+            .bind(limit)
+            .fetch_all(&mut *transaction)
+            .await
+            .context("reading expired private paths")?;
+        sqlx::query("delete from privatepaths where timestamp < $1;")
+            .bind(limit)
+            .execute(&mut *transaction)
+            .await
+            .context("deleting expired private paths")?;
+        transaction
+            .commit()
+            .await
+            .context("committing private paths expiration")?;
+        let mut result = Vec::with_capacity(rows.len());
+        for row in rows {
+            result.push(row.try_get("path")?);
+        }
+        Ok(result)
+    }
+
+    /// Delete the files and directories registered with [Cache::register_private_path] which
+    /// are unused for too long.
+    pub async fn remove_expired_private_paths(&self) -> anyhow::Result<()> {
+        for expired in self.expire_private_paths().await? {
+            tracing::info!("deleting expired {}", &expired);
+            let result = match tokio::fs::symlink_metadata(&expired).await {
+                Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(&expired).await,
+                Ok(_) => tokio::fs::remove_file(&expired).await,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e),
+            };
+            result
+                .with_context(|| format!("deleting expired {}", &expired))
+                .or_warn();
+        }
+        Ok(())
+    }
+
+    /// Store the next store path id to read from the nix db
+    pub async fn set_next_id(&self, id: Id) -> anyhow::Result<()> {
+        sqlx::query("update id set next = max(next, $1);")
+            .bind(id)
+            .execute(&self.sqlite)
+            .await
+            .context("advancing next registered id in cache db")?;
+        Ok(())
+    }
+
+    /// Records how the ids of store paths are obtained, see [crate::newpaths::NewPaths::name].
+    ///
+    /// If this changed, the next id is reset, as ids of different sources are unrelated. Caches
+    /// which did not record it used ids of the nix db.
+    pub async fn set_id_source(&self, source: &str) -> anyhow::Result<()> {
+        sqlx::query(
+            "update id set next = case when coalesce(source, 'nixdb') = $1 then next else 0 end,
+                source = $1;",
+        )
+        .bind(source)
+        .execute(&self.sqlite)
+        .await
+        .context("setting the source of store path ids in cache db")?;
+        Ok(())
+    }
+
+    /// get the next store path id to read from the nix db
+    pub async fn get_next_id(&self) -> anyhow::Result<Id> {
+        let row = sqlx::query("select next from id")
+            .fetch_one(&self.sqlite)
+            .await
+            .context("reading next registered id in cache db")?;
+        row.try_get("next")
+            .context("parsing next registered id from cache db")
+    }
+
+    /// Records that this event occurred now.
+    pub async fn record_event(&self, event: Event) -> anyhow::Result<()> {
+        sqlx::query(
+            "insert into events (event, timestamp) values ($1, $2)
+            on conflict(event) do update set timestamp = excluded.timestamp;",
+        )
+        .bind(event.name())
+        .bind(now())
+        .execute(&self.sqlite)
+        .await
+        .with_context(|| format!("recording {} in cache db", event.name()))?;
+        Ok(())
+    }
+
+    /// When each event last occurred
+    pub async fn get_events(&self) -> anyhow::Result<Events> {
+        let rows = sqlx::query("select event, timestamp from events;")
+            .fetch_all(&self.sqlite)
+            .await
+            .context("reading events in cache db")?;
+        let mut events = Events::default();
+        for row in rows {
+            let event: String = row.try_get("event")?;
+            let timestamp: i64 = row.try_get("timestamp")?;
+            let field = match event.as_str() {
+                "index-start" => &mut events.index_start,
+                "index-end" => &mut events.index_end,
+                "created" => &mut events.created,
+                _ => continue,
+            };
+            *field = Some(timestamp);
+        }
+        Ok(events)
+    }
+}
+
+/// Reads a row of the `builds` table
+fn entry_from_row(row: &SqliteRow) -> anyhow::Result<Entry> {
+    Ok(Entry {
+        buildid: row.try_get("buildid")?,
+        executable: row.try_get("executable")?,
+        debuginfo: row.try_get("debuginfo")?,
+        source: row.try_get("source")?,
+        build_source: row.try_get("buildsource")?,
+        architecture: row.try_get("architecture")?,
+    })
+}
+
+#[cfg(test)]
+fn test_entry(buildid: &str) -> Entry {
+    Entry {
+        buildid: buildid.to_owned(),
+        executable: Some(format!("/nix/store/{buildid}-exe")),
+        debuginfo: None,
+        source: None,
+        build_source: None,
+        architecture: None,
+    }
+}
+
+#[test]
+fn test_metadata() {
+    let entry = Entry {
+        buildid: "abcd".to_owned(),
+        executable: Some("/nix/store/aaaa-hello-2.12.1/bin/hello".to_owned()),
+        debuginfo: Some(
+            "/nix/store/bbbb-hello-2.12.1-debug/lib/debug/.build-id/ab/cd.debug".to_owned(),
+        ),
+        source: None,
+        build_source: None,
+        architecture: None,
+    };
+    let metadata = Metadata::new(entry.clone(), None);
+    assert_eq!(
+        metadata.store_path.as_deref(),
+        Some("/nix/store/aaaa-hello-2.12.1")
+    );
+    assert_eq!(metadata.package.as_deref(), Some("hello"));
+    assert_eq!(metadata.version.as_deref(), Some("2.12.1"));
+    let debug_only = Entry {
+        executable: None,
+        ..entry.clone()
+    };
+    let deriver = PathBuf::from("/nix/store/cccc-hello-2.12.2.drv");
+    let metadata = Metadata::new(debug_only, Some(deriver));
+    assert_eq!(
+        metadata.store_path.as_deref(),
+        Some("/nix/store/bbbb-hello-2.12.1-debug")
+    );
+    assert_eq!(
+        metadata.deriver.as_deref(),
+        Some("/nix/store/cccc-hello-2.12.2.drv")
+    );
+    assert_eq!(metadata.version.as_deref(), Some("2.12.2"));
+}
+
+#[tokio::test]
+async fn test_forget_buildid() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let output = "/nix/store/aaaa-foo-debug";
+    let entry = Entry {
+        debuginfo: Some(format!("{output}/lib/debug/.build-id/ab/cd.debug")),
+        ..test_entry("abcd")
+    };
+    cache
+        .register(&[entry.clone(), test_entry("ef01")])
+        .await
+        .unwrap();
+    cache
+        .register_split_dwarf(&[SplitDwarf {
+            output: output.to_owned(),
+            name: "foo.dwo".to_owned(),
+            path: format!("{output}/lib/debug/foo.dwo"),
+        }])
+        .await
+        .unwrap();
+    cache
+        .register_source_prefixes(&[SourcePrefix {
+            buildid: "abcd".to_owned(),
+            prefix: "/build/foo".to_owned(),
+        }])
+        .await
+        .unwrap();
+    let url = "https://cache.example.org";
+    cache
+        .record_substituter_lookup(url, "debuginfo/abcd", None)
+        .await
+        .unwrap();
+    cache.record_miss("abcd").await.unwrap();
+    let lookups = ["debuginfo/abcd".to_owned()];
+    assert_eq!(
+        cache.forget_buildid("abcd", &lookups).await.unwrap(),
+        Some(entry)
+    );
+    assert!(cache.get_misses().await.unwrap().is_empty());
+    assert!(cache
+        .get_split_dwarf(output, "foo.dwo")
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(cache.get_entry("abcd").await.unwrap().is_none());
+    assert!(cache.get_source_prefix("abcd").await.unwrap().is_none());
+    assert_eq!(
+        cache
+            .get_substituter_lookup(url, "debuginfo/abcd")
+            .await
+            .unwrap(),
+        None
+    );
+    assert!(cache.get_entry("ef01").await.unwrap().is_some());
+    assert_eq!(cache.forget_buildid("abcd", &lookups).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_record_miss() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    sqlx::query("insert into misses values ('old', $1, null), ('found', $2, $2);")
+        .bind(now() - MISS_RETENTION - 1)
+        .bind(now() - FOUND_MISS_RETENTION - 1)
+        .execute(&cache.sqlite)
+        .await
+        .unwrap();
+    cache.record_miss("abcd").await.unwrap();
+    let misses = cache.get_misses().await.unwrap();
+    assert_eq!(misses.len(), 1);
+    assert_eq!(misses[0].buildid, "abcd");
+}
+
+#[tokio::test]
+async fn test_events() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let events = cache.get_events().await.unwrap();
+    assert!(events.created.is_some());
+    assert_eq!(events.index_start, None);
+    assert_eq!(events.index_end, None);
+    cache.record_event(Event::IndexEnd).await.unwrap();
+    cache.record_event(Event::IndexEnd).await.unwrap();
+    let events = cache.get_events().await.unwrap();
+    assert!(events.index_end >= events.created);
+    assert_eq!(events.index_start, None);
+}
+
+#[tokio::test]
+async fn test_non_utf8_path() {
+    use std::os::unix::ffi::OsStrExt;
+    let cache = Cache::open_in_memory().await.unwrap();
+    let exe = Path::new(std::ffi::OsStr::from_bytes(b"/nix/store/xxxx-foo/bin/\xff"));
+    let mut entry = test_entry("abcd");
+    entry.executable = Some(encode_path(exe));
+    cache.register(&[entry]).await.unwrap();
+    assert_eq!(
+        cache.get_executable("abcd").await.unwrap().as_deref(),
+        Some(exe)
+    );
+    let found = cache
+        .get_entries_in("/nix/store/xxxx-foo", 10)
+        .await
+        .unwrap();
+    assert_eq!(found.len(), 1);
+}
+
+#[tokio::test]
+async fn test_set_id_source() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    cache.set_next_id(42).await.unwrap();
+    cache.set_id_source("nixdb").await.unwrap();
+    assert_eq!(cache.get_next_id().await.unwrap(), 42);
+    cache.set_id_source("nixdb").await.unwrap();
+    assert_eq!(cache.get_next_id().await.unwrap(), 42);
+    cache.set_id_source("path-info").await.unwrap();
+    assert_eq!(cache.get_next_id().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_register_normalizes_case() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    cache.register(&[test_entry("ABCDef0123")]).await.unwrap();
+    assert_eq!(
+        cache.get_executable("abcdef0123").await.unwrap(),
+        Some(PathBuf::from("/nix/store/ABCDef0123-exe"))
+    );
+}
+
+#[tokio::test]
+async fn test_register_missing() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    cache.register(&[test_entry("abcd")]).await.unwrap();
+    let upstream = Entry {
+        executable: Some("/nix/store/other-exe".to_owned()),
+        debuginfo: Some("/nix/store/other-debug".to_owned()),
+        ..test_entry("abcd")
+    };
+    cache.register_missing(&[upstream]).await.unwrap();
+    let entry = cache.get_entry("abcd").await.unwrap().unwrap();
+    assert_eq!(entry.executable, test_entry("abcd").executable);
+    assert_eq!(entry.debuginfo.as_deref(), Some("/nix/store/other-debug"));
+}
+
+#[tokio::test]
+async fn test_open_read_only() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("cache.sqlite3");
+    let cache = Cache::open_weak(Ok(path.clone())).await.unwrap();
+    cache.register(&[test_entry("abcd")]).await.unwrap();
+    let read_only = Cache::open_read_only(&path).await.unwrap();
+    assert_eq!(
+        read_only.get_executable("abcd").await.unwrap().as_deref(),
+        Some(Path::new("/nix/store/abcd-exe"))
+    );
+    assert!(read_only.register(&[test_entry("ef")]).await.is_err());
+    assert!(Cache::open_read_only(&dir.path().join("missing"))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_get_entries_after() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    cache
+        .register(&[test_entry("cc"), test_entry("aa"), test_entry("bb")])
+        .await
+        .unwrap();
+    let buildids = |entries: Vec<Entry>| {
+        entries
+            .into_iter()
+            .map(|entry| entry.buildid)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        buildids(cache.get_entries_after("", 2).await.unwrap()),
+        vec!["aa", "bb"]
+    );
+    assert_eq!(
+        buildids(cache.get_entries_after("bb", 2).await.unwrap()),
+        vec!["cc"]
+    );
+    assert!(cache.get_entries_after("cc", 2).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_expand_buildid_prefix() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    cache
+        .register(&[
+            test_entry("abcdef0123"),
+            test_entry("abcdef0123aa"),
+            test_entry("abcdef4567"),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(
+        cache.expand_buildid_prefix("abcdef45").await.unwrap(),
+        Some("abcdef4567".to_owned())
+    );
+    assert_eq!(
+        cache.expand_buildid_prefix("abcdef0123").await.unwrap(),
+        Some("abcdef0123".to_owned())
+    );
+    assert_eq!(cache.expand_buildid_prefix("abcdef01").await.unwrap(), None);
+    assert_eq!(cache.expand_buildid_prefix("012345").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_substituter_lookup() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let url = "https://cache.example.org";
+    assert_eq!(
+        cache
+            .get_substituter_lookup(url, "debuginfo/aa")
+            .await
+            .unwrap(),
+        None
+    );
+    cache
+        .record_substituter_lookup(url, "debuginfo/aa", None)
+        .await
+        .unwrap();
+    cache
+        .record_substituter_lookup(url, "debuginfo/bb", Some("nar/bb.nar.xz"))
+        .await
+        .unwrap();
+    assert_eq!(
+        cache
+            .get_substituter_lookup(url, "debuginfo/aa")
+            .await
+            .unwrap(),
+        Some(None)
+    );
+    assert_eq!(
+        cache
+            .get_substituter_lookup(url, "debuginfo/bb")
+            .await
+            .unwrap(),
+        Some(Some("nar/bb.nar.xz".to_owned()))
+    );
+    assert_eq!(
+        cache
+            .get_substituter_lookup("file:///other", "debuginfo/bb")
+            .await
+            .unwrap(),
+        None
+    );
+    sqlx::query("update substituterlookups set timestamp = timestamp - $1;")
+        .bind(SUBSTITUTER_MISS_TTL + 1)
+        .execute(&cache.sqlite)
+        .await
+        .unwrap();
+    assert_eq!(
+        cache
+            .get_substituter_lookup(url, "debuginfo/aa")
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        cache
+            .get_substituter_lookup(url, "debuginfo/bb")
+            .await
+            .unwrap(),
+        Some(Some("nar/bb.nar.xz".to_owned()))
+    );
+}
+
+#[tokio::test]
+async fn test_expire_private_paths() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    cache.register_private_path("/old").await.unwrap();
+    sqlx::query("update privatepaths set timestamp = timestamp - $1;")
+        .bind(PRIVATE_PATH_RETENTION + 1)
+        .execute(&cache.sqlite)
+        .await
+        .unwrap();
+    cache.register_private_path("/new").await.unwrap();
+    assert_eq!(
+        cache.expire_private_paths().await.unwrap(),
+        vec!["/old".to_owned()]
+    );
+    assert!(cache.expire_private_paths().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_nar_hashes() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    assert_eq!(
+        cache.get_nar_hash("/nix/store/aaaa-foo").await.unwrap(),
+        None
+    );
+    cache
+        .register_nar_hashes(&[
+            ("/nix/store/aaaa-foo".to_owned(), "sha256:aa".to_owned()),
+            ("/nix/store/bbbb-bar".to_owned(), "sha256:bb".to_owned()),
+        ])
+        .await
+        .unwrap();
+    cache
+        .register_nar_hashes(&[("/nix/store/aaaa-foo".to_owned(), "sha256:cc".to_owned())])
+        .await
+        .unwrap();
+    assert_eq!(
+        cache.get_nar_hash("/nix/store/aaaa-foo").await.unwrap(),
+        Some("sha256:cc".to_owned())
+    );
+    assert_eq!(
+        cache.get_nar_hash("/nix/store/bbbb-bar").await.unwrap(),
+        Some("sha256:bb".to_owned())
+    );
+}
+
+#[tokio::test]
+async fn test_get_entry() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    assert!(cache.get_entry("abcdef0123").await.unwrap().is_none());
+    let mut entry = test_entry("abcdef0123");
+    entry.architecture = Some("aarch64-le".to_owned());
+    cache.register(&[entry]).await.unwrap();
+    // registering again without architecture keeps the known one
+    let mut entry = test_entry("abcdef0123");
+    entry.debuginfo = Some("/nix/store/abcdef0123-debug".to_owned());
+    cache.register(&[entry]).await.unwrap();
+    let entry = cache.get_entry("abcdef0123").await.unwrap().unwrap();
+    assert_eq!(entry.architecture.as_deref(), Some("aarch64-le"));
+    assert_eq!(
+        entry.debuginfo.as_deref(),
+        Some("/nix/store/abcdef0123-debug")
+    );
+}
+
+#[tokio::test]
+async fn test_get_entries_in() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let mut entries = vec![test_entry("aaaa"), test_entry("bbbb")];
+    entries[0].executable = Some("/nix/store/xxxx-foo/bin/foo".to_owned());
+    entries[1].executable = Some("/nix/store/xxxx-foobar/bin/foobar".to_owned());
+    cache.register(&entries).await.unwrap();
+    assert_eq!(cache.count_buildids().await.unwrap(), 2);
+    let found = cache
+        .get_entries_in("/nix/store/xxxx-foo", 10)
+        .await
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].buildid, "aaaa");
+    let found = cache
+        .get_entries_in("/nix/store/xxxx-foo/bin/foo", 10)
+        .await
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    assert!(cache
+        .get_entries_in("/nix/store/yyyy", 10)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_source_roots() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let source = "/nix/store/aaaa-source";
+    let roots = SourceRoots {
+        source: source.to_owned(),
+        roots: vec![
+            "/nix/store/bbbb-fix.patch".to_owned(),
+            "/nix/store/cccc-data".to_owned(),
+        ],
+    };
+    // indexing several store paths of the same derivation records them again
+    cache
+        .register_indexed(&[
+            Indexed::SourceRoots(roots.clone()),
+            Indexed::SourceRoots(roots.clone()),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(cache.get_source_roots(source).await.unwrap(), roots.roots);
+    assert!(cache
+        .get_source_roots("/nix/store/dddd-source")
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_archive_members() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let archive = "/nix/store/aaaa-glibc-2.37.tar.xz";
+    assert_eq!(cache.get_archive_members(archive).await.unwrap(), None);
+    let members = vec![
+        "glibc-2.37/".to_owned(),
+        "glibc-2.37/io/openat64.c".to_owned(),
+    ];
+    cache
+        .register_archive_members(archive, &members)
+        .await
+        .unwrap();
+    assert_eq!(
+        cache.get_archive_members(archive).await.unwrap(),
+        Some(members)
+    );
+    let empty = "/nix/store/bbbb-unsupported.tar.lz";
+    cache.register_archive_members(empty, &[]).await.unwrap();
+    assert_eq!(
+        cache.get_archive_members(empty).await.unwrap(),
+        Some(vec![])
+    );
+}
+
+#[tokio::test]
+async fn test_source_prefix() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    assert_eq!(cache.get_source_prefix("abcd").await.unwrap(), None);
+    let prefix = SourcePrefix {
+        buildid: "abcd".to_owned(),
+        prefix: "/build/source".to_owned(),
+    };
+    cache
+        .register_indexed(&[Indexed::SourcePrefix(prefix)])
+        .await
+        .unwrap();
+    assert_eq!(
+        cache.get_source_prefix("abcd").await.unwrap().as_deref(),
+        Some("/build/source")
+    );
+    assert_eq!(cache.get_source_prefix("ef01").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_forget_store_path() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let entry = |buildid: &str, executable: Option<&str>, debuginfo: Option<&str>| Entry {
+        executable: executable.map(str::to_owned),
+        debuginfo: debuginfo.map(str::to_owned),
+        ..test_entry(buildid)
+    };
+    let exe = "/nix/store/aaaa-foo_1/bin/foo";
+    let debug = "/nix/store/bbbb-foo_1-debug/lib/debug/.build-id/ab/cd.debug";
+    let lookalike = "/nix/store/aaaa-foo_12/bin/foo";
+    cache
+        .register_indexed(&[
+            Indexed::Build(entry("abcd", Some(exe), Some(debug))),
+            Indexed::Build(entry("ef01", Some(exe), None)),
+            Indexed::Build(entry("2345", Some(lookalike), None)),
+            Indexed::Deleted("/nix/store/aaaa-foo_1".to_owned()),
+        ])
+        .await
+        .unwrap();
+    let abcd = cache.get_entry("abcd").await.unwrap().unwrap();
+    assert_eq!(abcd.executable, None);
+    assert_eq!(abcd.debuginfo.as_deref(), Some(debug));
+    assert!(cache.get_entry("ef01").await.unwrap().is_none());
+    assert!(cache.get_entry("2345").await.unwrap().is_some());
+    cache
+        .forget_store_path("/nix/store/bbbb-foo_1-debug")
+        .await
+        .unwrap();
+    assert!(cache.get_entry("abcd").await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_channel_index_etag() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let url = "https://example.org/nixos-24.05/buildids.jsonl.zst";
+    assert_eq!(cache.get_channel_index_etag(url).await.unwrap(), None);
+    cache.set_channel_index_etag(url, "\"1\"").await.unwrap();
+    cache.set_channel_index_etag(url, "\"2\"").await.unwrap();
+    assert_eq!(
+        cache.get_channel_index_etag(url).await.unwrap().as_deref(),
+        Some("\"2\"")
+    );
+}
+
+#[tokio::test]
+async fn test_client_bytes() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    assert_eq!(cache.get_client_bytes("192.0.2.1").await.unwrap(), 0);
+    cache.add_client_bytes("192.0.2.1", 1000).await.unwrap();
+    cache.add_client_bytes("192.0.2.1", 24).await.unwrap();
+    cache.add_client_bytes("2001:db8::1", 1).await.unwrap();
+    assert_eq!(cache.get_client_bytes("192.0.2.1").await.unwrap(), 1024);
+    assert_eq!(cache.get_client_bytes("2001:db8::1").await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_eager_realised() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    assert_eq!(cache.get_eager_realised().await.unwrap(), vec![]);
+    cache
+        .register_eager_realised("/nix/store/aaaa-foo-debug", 1000)
+        .await
+        .unwrap();
+    cache
+        .register_eager_realised("/nix/store/bbbb-bar-debug", 24)
+        .await
+        .unwrap();
+    cache
+        .forget_eager_realised("/nix/store/bbbb-bar-debug")
+        .await
+        .unwrap();
+    assert_eq!(
+        cache.get_eager_realised().await.unwrap(),
+        vec![("/nix/store/aaaa-foo-debug".to_owned(), 1000)]
+    );
+}
+
+#[tokio::test]
+async fn test_split_dwarf() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let output = "/nix/store/aaaa-foo-debug";
+    let file = |path: &str| SplitDwarf {
+        output: output.to_owned(),
+        name: std::path::Path::new(path)
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned(),
+        path: format!("{output}/{path}"),
+    };
+    cache
+        .register_indexed(&[
+            Indexed::Build(test_entry("abcd")),
+            Indexed::SplitDwarf(file("lib/debug/dwo/a/main.dwo")),
+            Indexed::SplitDwarf(file("lib/debug/dwo/b/main.dwo")),
+            Indexed::SplitDwarf(file("lib/debug/foo.dwp")),
+        ])
+        .await
+        .unwrap();
+    assert!(cache.get_entry("abcd").await.unwrap().is_some());
+    assert_eq!(
+        cache.get_split_dwarf(output, "main.dwo").await.unwrap(),
+        vec![
+            format!("{output}/lib/debug/dwo/a/main.dwo"),
+            format!("{output}/lib/debug/dwo/b/main.dwo")
+        ]
+    );
+    assert_eq!(
+        cache
+            .get_split_dwarf(output, "foo.dwp")
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+    assert!(cache
+        .get_split_dwarf("/nix/store/bbbb-bar-debug", "foo.dwp")
+        .await
+        .unwrap()
+        .is_empty());
 }
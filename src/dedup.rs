@@ -0,0 +1,186 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Deduplication of the files written to the cache directory: debuginfo fetched with
+//! `--private-debuginfo` and source files extracted from archives are often identical, like
+//! the same header in several versions of a source archive.
+//!
+//! Each file is hardlinked to `objects/SHA256` next to the directory it was written to, named
+//! after the hash of its content, or replaced by a hardlink to the existing object with the
+//! same content. Objects no file links to anymore are deleted by [remove_unused_objects].
+
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+/// The directory of content addressed files for files written to `dir`.
+///
+/// It must be on the same filesystem as `dir` for hardlinks to work.
+pub fn objects_dir(dir: &Path) -> PathBuf {
+    dir.with_file_name("objects")
+}
+
+/// The sha256 of the content of this file, in base16
+fn hash_file(path: &Path) -> anyhow::Result<String> {
+    use sha2::Digest;
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut hasher = sha2::Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = file
+            .read(&mut buffer)
+            .with_context(|| format!("reading {}", path.display()))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(base16::encode_lower(&hasher.finalize()))
+}
+
+/// Replaces `file` by a hardlink to the object in `objects` with the same content, or makes it
+/// the object for its content if there is none.
+pub fn deduplicate(objects: &Path, file: &Path) -> anyhow::Result<()> {
+    let metadata =
+        std::fs::symlink_metadata(file).with_context(|| format!("stat({})", file.display()))?;
+    if !metadata.is_file() || metadata.nlink() > 1 {
+        // already deduplicated
+        return Ok(());
+    }
+    std::fs::create_dir_all(objects)
+        .with_context(|| format!("creating directory {}", objects.display()))?;
+    let hash = hash_file(file)?;
+    let object = objects.join(&hash);
+    match std::fs::hard_link(file, &object) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("linking {} to {}", file.display(), object.display()))
+        }
+    }
+    let object_metadata =
+        std::fs::metadata(&object).with_context(|| format!("stat({})", object.display()))?;
+    if object_metadata.len() != metadata.len() || object_metadata.mode() != metadata.mode() {
+        // a truncated object from a crash, or different permissions: keep the file as is
+        return Ok(());
+    }
+    // replace the file atomically
+    let temp = file.with_file_name(format!(".{}.dedup", hash));
+    std::fs::hard_link(&object, &temp)
+        .with_context(|| format!("linking {} to {}", object.display(), temp.display()))?;
+    std::fs::rename(&temp, file)
+        .with_context(|| format!("moving {} to {}", temp.display(), file.display()))?;
+    Ok(())
+}
+
+/// Calls [deduplicate] on `path` if it is a file, or on all the files it contains if it is a
+/// directory.
+pub fn deduplicate_all(objects: &Path, path: &Path) -> anyhow::Result<()> {
+    for file in walkdir::WalkDir::new(path) {
+        let file = file.with_context(|| format!("walking {}", path.display()))?;
+        if file.file_type().is_file() {
+            deduplicate(objects, file.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the objects which are not hardlinked from anywhere else anymore.
+pub fn remove_unused_objects(objects: &Path) -> anyhow::Result<()> {
+    let entries = match std::fs::read_dir(objects) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        entries => entries.with_context(|| format!("listing {}", objects.display()))?,
+    };
+    for entry in entries {
+        let entry = entry.with_context(|| format!("listing {}", objects.display()))?;
+        let metadata = entry
+            .metadata()
+            .with_context(|| format!("stat({})", entry.path().display()))?;
+        if metadata.nlink() == 1 {
+            tracing::debug!("deleting unused {}", entry.path().display());
+            std::fs::remove_file(entry.path())
+                .with_context(|| format!("deleting {}", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// How often [remove_unused_objects] scans the objects of the cache directory
+const REMOVE_UNUSED_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// When [remove_unused_objects] last ran in [deduplicate_in_cache], if ever. Also serializes
+/// deduplication tasks, so that at most one thread hashes files at a time.
+static LAST_REMOVE_UNUSED: tokio::sync::Mutex<Option<Instant>> =
+    tokio::sync::Mutex::const_new(None);
+
+/// Starts deduplicating `path`, written to the cache directory, in the background, and deletes
+/// unused objects if it was not done for [REMOVE_UNUSED_INTERVAL]. Failures are only logged, as
+/// they only cost disk space.
+pub fn deduplicate_in_cache(path: PathBuf) {
+    let Some(parent) = path.parent() else {
+        return;
+    };
+    let objects = objects_dir(parent);
+    tokio::spawn(async move {
+        let mut last_remove_unused = LAST_REMOVE_UNUSED.lock().await;
+        let remove_unused = match *last_remove_unused {
+            None => true,
+            Some(last) => last.elapsed() >= REMOVE_UNUSED_INTERVAL,
+        };
+        let result = tokio::task::spawn_blocking(move || {
+            deduplicate_all(&objects, &path)
+                .with_context(|| format!("deduplicating {}", path.display()))?;
+            if remove_unused {
+                remove_unused_objects(&objects)
+                    .with_context(|| format!("deleting unused files in {}", objects.display()))?;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .await;
+        if remove_unused {
+            *last_remove_unused = Some(Instant::now());
+        }
+        match result {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => tracing::warn!("{:#}", e),
+            Err(e) => tracing::warn!("deduplication task failed: {:#}", e),
+        }
+    });
+}
+
+#[test]
+fn test_deduplicate() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let written = dir.path().join("debuginfo");
+    let objects = objects_dir(&written);
+    std::fs::create_dir_all(written.join("a")).unwrap();
+    std::fs::create_dir_all(written.join("b")).unwrap();
+    std::fs::write(written.join("a/libfoo.debug"), "same").unwrap();
+    std::fs::write(written.join("b/libfoo.debug"), "same").unwrap();
+    std::fs::write(written.join("b/libbar.debug"), "different").unwrap();
+    deduplicate_all(&objects, &written).unwrap();
+    let inode = |path: &str| std::fs::metadata(written.join(path)).unwrap().ino();
+    assert_eq!(inode("a/libfoo.debug"), inode("b/libfoo.debug"));
+    assert_ne!(inode("a/libfoo.debug"), inode("b/libbar.debug"));
+    assert_eq!(
+        std::fs::read_to_string(written.join("b/libfoo.debug")).unwrap(),
+        "same"
+    );
+    assert_eq!(std::fs::read_dir(&objects).unwrap().count(), 2);
+    // deduplicating again changes nothing
+    deduplicate_all(&objects, &written).unwrap();
+    assert_eq!(std::fs::read_dir(written.join("b")).unwrap().count(), 2);
+
+    std::fs::remove_dir_all(written.join("b")).unwrap();
+    remove_unused_objects(&objects).unwrap();
+    assert_eq!(std::fs::read_dir(&objects).unwrap().count(), 1);
+    std::fs::remove_dir_all(written.join("a")).unwrap();
+    remove_unused_objects(&objects).unwrap();
+    assert_eq!(std::fs::read_dir(&objects).unwrap().count(), 0);
+}
@@ -0,0 +1,186 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Diagnostics of the tokio runtime served at `/debug/tasks` with `--debug-tasks`.
+//!
+//! When gdb seems to hang, this tells whether the server is still handling the request, what
+//! else it is doing, and whether long blocking sections starve the runtime: a probe task
+//! measures how late it is woken up, which is the scheduling lag every request suffers.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use tokio::sync::Semaphore;
+
+/// Tasks and semaphores shown by [report]
+#[derive(Default)]
+struct Diagnostics {
+    /// Whether tasks are tracked, see [enable]
+    enabled: AtomicBool,
+    /// The tracked tasks still running, by id: what they do and since when
+    tasks: Mutex<BTreeMap<u64, (String, Instant)>>,
+    next_id: AtomicU64,
+    /// The semaphores registered by [register_semaphore]: what they limit, the semaphore and its
+    /// number of permits
+    semaphores: Mutex<Vec<(String, Weak<Semaphore>, usize)>>,
+}
+
+static DIAGNOSTICS: Lazy<Diagnostics> = Lazy::new(Default::default);
+
+/// Scheduling lag measured by the last probe, in microseconds
+static LAST_LAG: AtomicU64 = AtomicU64::new(0);
+
+/// Maximum scheduling lag since startup, in microseconds
+static MAX_LAG: AtomicU64 = AtomicU64::new(0);
+
+/// How often the scheduling lag is measured
+const LAG_PROBE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Tracks tasks and measures scheduling lag from now on.
+///
+/// Must be called within the tokio runtime.
+pub fn enable() {
+    DIAGNOSTICS.enabled.store(true, Ordering::SeqCst);
+    tokio::spawn(async {
+        loop {
+            let start = Instant::now();
+            tokio::time::sleep(LAG_PROBE_INTERVAL).await;
+            let lag = start.elapsed().saturating_sub(LAG_PROBE_INTERVAL);
+            let lag = lag.as_micros() as u64;
+            LAST_LAG.store(lag, Ordering::Relaxed);
+            MAX_LAG.fetch_max(lag, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Removes its task from the tracked tasks when dropped
+#[must_use]
+pub struct TaskGuard<'a>(Option<(&'a Diagnostics, u64)>);
+
+impl Drop for TaskGuard<'_> {
+    fn drop(&mut self) {
+        if let Some((diagnostics, id)) = self.0 {
+            diagnostics.tasks.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+impl Diagnostics {
+    /// See [track]
+    fn track(&self, name: impl FnOnce() -> String) -> TaskGuard<'_> {
+        if !self.enabled.load(Ordering::SeqCst) {
+            return TaskGuard(None);
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.tasks
+            .lock()
+            .unwrap()
+            .insert(id, (name(), Instant::now()));
+        TaskGuard(Some((self, id)))
+    }
+
+    /// See [register_semaphore]
+    fn register_semaphore(&self, name: String, semaphore: &Arc<Semaphore>, permits: usize) {
+        let mut semaphores = self.semaphores.lock().unwrap();
+        semaphores.retain(|(_, semaphore, _)| semaphore.strong_count() > 0);
+        semaphores.push((name, Arc::downgrade(semaphore), permits));
+    }
+
+    /// See [report]
+    fn report(&self, details: &str) -> String {
+        let mut result = String::new();
+        let workers = tokio::runtime::Handle::current().metrics().num_workers();
+        let ms = |lag: &AtomicU64| lag.load(Ordering::Relaxed) / 1000;
+        writeln!(result, "runtime worker threads: {}", workers).unwrap();
+        writeln!(
+            result,
+            "scheduling lag: {} ms now, {} ms at most since startup",
+            ms(&LAST_LAG),
+            ms(&MAX_LAG)
+        )
+        .unwrap();
+        result.push_str(details);
+        writeln!(result, "semaphores:").unwrap();
+        for (name, semaphore, permits) in self.semaphores.lock().unwrap().iter() {
+            if let Some(semaphore) = semaphore.upgrade() {
+                let taken = permits.saturating_sub(semaphore.available_permits());
+                writeln!(
+                    result,
+                    "{:>5} of {:<5} permits taken: {}",
+                    taken, permits, name
+                )
+                .unwrap();
+            }
+        }
+        let now = Instant::now();
+        let mut tasks: Vec<(String, Duration)> = self
+            .tasks
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(name, start)| (name.clone(), now.duration_since(*start)))
+            .collect();
+        tasks.sort_by_key(|(_, duration)| std::cmp::Reverse(*duration));
+        writeln!(result, "running tasks: {}", tasks.len()).unwrap();
+        for (name, duration) in tasks {
+            writeln!(result, "{:>10.1}s {}", duration.as_secs_f64(), name).unwrap();
+        }
+        result
+    }
+}
+
+/// Tracks a task described by `name` until the returned guard is dropped.
+///
+/// `name` is only called when diagnostics are enabled.
+pub fn track(name: impl FnOnce() -> String) -> TaskGuard<'static> {
+    DIAGNOSTICS.track(name)
+}
+
+/// Shows in the report how many of the `permits` of `semaphore` are taken, as `name`, as long as
+/// the semaphore exists.
+pub fn register_semaphore(name: impl Into<String>, semaphore: &Arc<Semaphore>, permits: usize) {
+    DIAGNOSTICS.register_semaphore(name.into(), semaphore, permits)
+}
+
+/// Middleware tracking requests while they are handled
+pub async fn track_requests(
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let _guard = track(|| format!("{} {}", request.method(), request.uri()));
+    next.run(request).await
+}
+
+/// A human readable summary of the runtime, of the registered semaphores and of the tracked
+/// tasks, oldest first, with `details` about other resources.
+pub fn report(details: &str) -> String {
+    DIAGNOSTICS.report(details)
+}
+
+#[tokio::test]
+async fn test_track() {
+    let diagnostics = Diagnostics::default();
+    let disabled = diagnostics.track(|| unreachable!("diagnostics are disabled"));
+    assert!(disabled.0.is_none());
+    diagnostics.enabled.store(true, Ordering::SeqCst);
+    let first = diagnostics.track(|| "first task".to_owned());
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let second = diagnostics.track(|| "second task".to_owned());
+    let semaphore = Arc::new(Semaphore::new(4));
+    diagnostics.register_semaphore("test permits".to_owned(), &semaphore, 4);
+    let _permit = semaphore.acquire().await.unwrap();
+    let summary = diagnostics.report("indexation: idle\n");
+    assert!(summary.contains("indexation: idle\n"));
+    assert!(summary.contains("    1 of 4     permits taken: test permits\n"));
+    let first_line = summary.find("first task").unwrap();
+    let second_line = summary.find("second task").unwrap();
+    assert!(first_line < second_line);
+    drop(first);
+    drop(second);
+    assert!(!diagnostics.report("").contains("first task"));
+}
@@ -0,0 +1,218 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Reading derivations without nix, so that indexation does not run `nix-store` for each
+//! environment binding it needs, and so that the derivations of the stores of other machines
+//! given with `--store`, or with `--store-mounted-readonly`, can be read.
+//!
+//! Derivations are stored in the ATerm format, like
+//! `Derive([outputs],[input derivations],[input sources],"system","builder",[args],[env])`,
+//! where `env` is a list of `("name","value")` pairs. Newer versions of nix write
+//! `DrvWithVersion("version",...)` instead, but the environment is always the last argument.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+/// A parsed ATerm
+#[derive(Debug, PartialEq, Eq)]
+enum Term {
+    String(Vec<u8>),
+    List(Vec<Term>),
+    Tuple(Vec<Term>),
+    /// a constructor applied to its arguments, like `Derive(...)`
+    Apply(String, Vec<Term>),
+}
+
+/// Parser of the ATerm at the start of `input`
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> anyhow::Result<u8> {
+        let c = *self
+            .input
+            .get(self.pos)
+            .context("unexpected end of derivation")?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn term(&mut self) -> anyhow::Result<Term> {
+        match self.next()? {
+            b'"' => self.string().map(Term::String),
+            b'[' => self.items(b']').map(Term::List),
+            b'(' => self.items(b')').map(Term::Tuple),
+            c if c.is_ascii_alphabetic() => {
+                let start = self.pos - 1;
+                while self.next()?.is_ascii_alphanumeric() {}
+                let name = String::from_utf8_lossy(&self.input[start..self.pos - 1]).into_owned();
+                anyhow::ensure!(
+                    self.input[self.pos - 1] == b'(',
+                    "expected arguments of {} at byte {}",
+                    name,
+                    self.pos - 1
+                );
+                Ok(Term::Apply(name, self.items(b')')?))
+            }
+            c => anyhow::bail!("unexpected {:?} at byte {}", c as char, self.pos - 1),
+        }
+    }
+
+    /// The rest of a string whose opening quote was read
+    fn string(&mut self) -> anyhow::Result<Vec<u8>> {
+        let mut result = Vec::new();
+        loop {
+            match self.next()? {
+                b'"' => return Ok(result),
+                b'\\' => result.push(match self.next()? {
+                    b'n' => b'\n',
+                    b'r' => b'\r',
+                    b't' => b'\t',
+                    c => c,
+                }),
+                c => result.push(c),
+            }
+        }
+    }
+
+    /// The rest of comma separated terms whose opening delimiter was read, up to `end`
+    fn items(&mut self, end: u8) -> anyhow::Result<Vec<Term>> {
+        let mut items = Vec::new();
+        if self.input.get(self.pos) == Some(&end) {
+            self.pos += 1;
+            return Ok(items);
+        }
+        loop {
+            items.push(self.term()?);
+            match self.next()? {
+                b',' => (),
+                c if c == end => return Ok(items),
+                c => anyhow::bail!("unexpected {:?} at byte {}", c as char, self.pos - 1),
+            }
+        }
+    }
+}
+
+/// What indexation needs to know about a derivation, read once instead of querying nix for
+/// each environment binding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Derivation {
+    /// path of the derivation
+    pub path: PathBuf,
+    /// paths of the outputs, or `None` if some are only known once built, as for content
+    /// addressed derivations
+    pub outputs: Option<Vec<PathBuf>>,
+    /// environment bindings
+    env: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Derivation {
+    /// Reads and parses this derivation.
+    ///
+    /// The derivation must exist.
+    pub fn read(drvpath: &Path) -> anyhow::Result<Self> {
+        let drv =
+            std::fs::read(drvpath).with_context(|| format!("reading {}", drvpath.display()))?;
+        Derivation::parse(drvpath, &drv).with_context(|| format!("parsing {}", drvpath.display()))
+    }
+
+    /// Parses the content of the derivation at `path`.
+    fn parse(path: &Path, drv: &[u8]) -> anyhow::Result<Self> {
+        let Term::Apply(_, args) = Parser { input: drv, pos: 0 }.term()? else {
+            anyhow::bail!("not a derivation");
+        };
+        // outputs are the first list, after the version with `DrvWithVersion`
+        let outputs = match args.iter().find(|arg| matches!(arg, Term::List(_))) {
+            Some(Term::List(outputs)) => outputs
+                .iter()
+                .map(|output| match output {
+                    Term::Tuple(fields) => match fields.get(1) {
+                        Some(Term::String(path)) if !path.is_empty() => {
+                            Some(PathBuf::from(OsString::from_vec(path.clone())))
+                        }
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect(),
+            _ => None,
+        };
+        let Some(Term::List(env)) = args.into_iter().last() else {
+            anyhow::bail!("derivation without environment");
+        };
+        let env = env
+            .into_iter()
+            .filter_map(|binding| match binding {
+                Term::Tuple(pair) => match <[Term; 2]>::try_from(pair) {
+                    Ok([Term::String(key), Term::String(value)]) => Some((key, value)),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        Ok(Derivation {
+            path: path.to_path_buf(),
+            outputs,
+            env,
+        })
+    }
+
+    /// The value of the environment binding `name`, if any, like `nix-store --query --binding`.
+    pub fn binding(&self, name: &str) -> Option<OsString> {
+        self.env
+            .get(name.as_bytes())
+            .map(|value| OsString::from_vec(value.clone()))
+    }
+}
+
+/// The value of the environment binding `name` in the content of a derivation, if any
+#[cfg(test)]
+fn binding_in(drv: &[u8], name: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    let drv = Derivation::parse(Path::new("/nix/store/test.drv"), drv)?;
+    Ok(drv.binding(name).map(OsString::into_vec))
+}
+
+#[test]
+fn test_binding_in() {
+    let drv = br#"Derive([("out","/nix/store/aaaa-hello","","")],[("/nix/store/bbbb-bash.drv",["out"])],["/nix/store/cccc-builder.sh"],"x86_64-linux","/nix/store/dddd-bash/bin/bash",["-e","/nix/store/cccc-builder.sh"],[("builder","/nix/store/dddd-bash/bin/bash"),("empty",""),("script","echo \"hi\"\nexit 0\\"),("src","/nix/store/eeee-hello.tar.gz")])"#;
+    assert_eq!(
+        binding_in(drv, "src").unwrap(),
+        Some(b"/nix/store/eeee-hello.tar.gz".to_vec())
+    );
+    assert_eq!(
+        binding_in(drv, "script").unwrap(),
+        Some(b"echo \"hi\"\nexit 0\\".to_vec())
+    );
+    assert_eq!(binding_in(drv, "empty").unwrap(), Some(vec![]));
+    assert_eq!(binding_in(drv, "srcs").unwrap(), None);
+    let versioned = br#"DrvWithVersion("xp-dyn-drv",[],[],[],"x86_64-linux","/bin/sh",[],[("src","/nix/store/eeee-hello.tar.gz")])"#;
+    assert_eq!(
+        binding_in(versioned, "src").unwrap(),
+        Some(b"/nix/store/eeee-hello.tar.gz".to_vec())
+    );
+    assert!(binding_in(&drv[..100], "src").is_err());
+    assert!(binding_in(b"[]", "src").is_err());
+}
+
+#[test]
+fn test_derivation_outputs() {
+    let drv = br#"Derive([("debug","/nix/store/aaaa-hello-debug","",""),("out","/nix/store/bbbb-hello","","")],[],[],"x86_64-linux","/bin/sh",[],[("src","/nix/store/eeee-hello.tar.gz")])"#;
+    let drv = Derivation::parse(Path::new("/nix/store/cccc-hello.drv"), drv).unwrap();
+    assert_eq!(
+        drv.outputs,
+        Some(vec![
+            PathBuf::from("/nix/store/aaaa-hello-debug"),
+            PathBuf::from("/nix/store/bbbb-hello")
+        ])
+    );
+    let floating = br#"Derive([("out","","r:sha256","")],[],[],"x86_64-linux","/bin/sh",[],[])"#;
+    let floating = Derivation::parse(Path::new("/nix/store/dddd-hello.drv"), floating).unwrap();
+    assert_eq!(floating.outputs, None);
+}
@@ -0,0 +1,468 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Just enough of a DWARF parser to read the compilation directory (`DW_AT_comp_dir`) of the
+//! first compilation unit of an executable or debuginfo file, which tells where the source
+//! was unpacked during the build.
+//!
+//! Only the start of the sections is read and decompressed, so this is cheap even for huge
+//! debuginfo files.
+
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Context;
+use object::read::Object;
+use object::{CompressionFormat, ObjectSection};
+
+/// How much of `.debug_info` and `.debug_abbrev` is read: the first unit header and the
+/// description of its first entry are at the start.
+const MAX_PREFIX: u64 = 64 * 1024;
+
+/// Longest compilation directory read from a string section
+const MAX_STRING_LEN: u64 = 4096;
+
+const DW_AT_COMP_DIR: u64 = 0x1b;
+const DW_AT_STR_OFFSETS_BASE: u64 = 0x72;
+const DW_FORM_IMPLICIT_CONST: u64 = 0x21;
+
+/// Reads the byte range `offset..end` of a file without moving its cursor.
+struct RangeReader<'a> {
+    file: &'a File,
+    offset: u64,
+    end: u64,
+}
+
+impl Read for RangeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min((self.end - self.offset) as usize);
+        let n = self.file.read_at(&mut buf[..len], self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// Reads at most `len` bytes at `offset` in the uncompressed content of the section `name`,
+/// decompressing only what is needed.
+///
+/// Returns `None` if there is no such section.
+fn read_section<'data, R: object::ReadRef<'data>>(
+    file: &File,
+    object: &object::read::File<'data, R>,
+    name: &str,
+    offset: u64,
+    len: u64,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(section) = object.section_by_name(name) else {
+        return Ok(None);
+    };
+    let range = section
+        .compressed_file_range()
+        .with_context(|| format!("locating section {}", name))?;
+    let raw = RangeReader {
+        file,
+        offset: range.offset,
+        end: range.offset + range.compressed_size,
+    };
+    let mut reader: Box<dyn Read + '_> = match range.format {
+        CompressionFormat::None => Box::new(raw),
+        CompressionFormat::Zlib => Box::new(flate2::read::ZlibDecoder::new(raw)),
+        CompressionFormat::Zstandard => Box::new(
+            ruzstd::StreamingDecoder::new(raw)
+                .map_err(|e| anyhow::anyhow!("{}", e))
+                .with_context(|| format!("reading zstd header of section {}", name))?,
+        ),
+        other => anyhow::bail!("unsupported compression {:?} of section {}", other, name),
+    };
+    std::io::copy(&mut reader.by_ref().take(offset), &mut std::io::sink())
+        .with_context(|| format!("reading section {}", name))?;
+    let mut result = Vec::new();
+    reader
+        .take(len)
+        .read_to_end(&mut result)
+        .with_context(|| format!("reading section {}", name))?;
+    Ok(Some(result))
+}
+
+/// Reads values from a DWARF section. Methods return `None` at the end of the data.
+struct Reader<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (result, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(result)
+    }
+
+    /// An unsigned integer of `len` bytes, at most 8
+    fn uint(&mut self, len: usize) -> Option<u64> {
+        let bytes = self.bytes(len)?;
+        let mut result = 0;
+        for i in 0..len {
+            let byte = if self.little_endian {
+                bytes[len - 1 - i]
+            } else {
+                bytes[i]
+            };
+            result = (result << 8) | u64::from(byte);
+        }
+        Some(result)
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let mut result = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.bytes(1)?[0];
+            if shift < 64 {
+                result |= u64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(result);
+            }
+        }
+    }
+
+    /// A nul terminated string, without the nul byte
+    fn cstr(&mut self) -> Option<&'a [u8]> {
+        let len = self.data.iter().position(|&b| b == 0)?;
+        let result = self.bytes(len)?;
+        self.bytes(1)?;
+        Some(result)
+    }
+}
+
+/// The value of an attribute, as far as we care
+enum Value<'a> {
+    /// an inline string
+    String(&'a [u8]),
+    /// an offset in `.debug_str`
+    Strp(u64),
+    /// an offset in `.debug_line_str`
+    LineStrp(u64),
+    /// an index in `.debug_str_offsets`
+    Strx(u64),
+    /// a constant or section offset
+    Number(u64),
+    /// anything else
+    Other,
+}
+
+/// Reads the value of an attribute of this form.
+///
+/// `offset_size` is 4 for 32 bit DWARF and 8 for 64 bit DWARF.
+fn read_value<'a>(
+    reader: &mut Reader<'a>,
+    form: u64,
+    offset_size: usize,
+    address_size: usize,
+    version: u16,
+) -> Option<Value<'a>> {
+    let skip = |reader: &mut Reader<'a>, len: u64| {
+        reader.bytes(usize::try_from(len).ok()?)?;
+        Some(Value::Other)
+    };
+    Some(match form {
+        // addr
+        0x01 => skip(reader, address_size as u64)?,
+        // block2, block4, block, block1, exprloc
+        0x03 => {
+            let len = reader.uint(2)?;
+            skip(reader, len)?
+        }
+        0x04 => {
+            let len = reader.uint(4)?;
+            skip(reader, len)?
+        }
+        0x09 | 0x18 => {
+            let len = reader.uleb()?;
+            skip(reader, len)?
+        }
+        0x0a => {
+            let len = reader.uint(1)?;
+            skip(reader, len)?
+        }
+        // data1, data2, data4, data8, flag, ref1, ref2, ref4, ref8, strx1..4, addrx1..4,
+        // ref_sup4, ref_sig8, ref_sup8, data16
+        0x0b | 0x0c | 0x11 | 0x29 => Value::Number(reader.uint(1)?),
+        0x05 | 0x12 | 0x2a => Value::Number(reader.uint(2)?),
+        0x2b => Value::Number(reader.uint(3)?),
+        0x06 | 0x13 | 0x1c | 0x2c => Value::Number(reader.uint(4)?),
+        0x07 | 0x14 | 0x20 | 0x24 => Value::Number(reader.uint(8)?),
+        0x1e => skip(reader, 16)?,
+        0x25 => Value::Strx(reader.uint(1)?),
+        0x26 => Value::Strx(reader.uint(2)?),
+        0x27 => Value::Strx(reader.uint(3)?),
+        0x28 => Value::Strx(reader.uint(4)?),
+        // string
+        0x08 => Value::String(reader.cstr()?),
+        // sdata, udata, ref_udata, addrx, loclistx, rnglistx, GNU_addr_index
+        0x0d | 0x0f | 0x15 | 0x1b | 0x22 | 0x23 | 0x1f01 => Value::Number(reader.uleb()?),
+        // strx, GNU_str_index
+        0x1a | 0x1f02 => Value::Strx(reader.uleb()?),
+        // strp
+        0x0e => Value::Strp(reader.uint(offset_size)?),
+        // line_strp
+        0x1f => Value::LineStrp(reader.uint(offset_size)?),
+        // ref_addr is address sized in DWARF 2
+        0x10 if version == 2 => skip(reader, address_size as u64)?,
+        // ref_addr, sec_offset, strp_sup, GNU_ref_alt, GNU_strp_alt
+        0x10 | 0x17 | 0x1d | 0x1f20 | 0x1f21 => Value::Number(reader.uint(offset_size)?),
+        // flag_present, implicit_const
+        0x19 | DW_FORM_IMPLICIT_CONST => Value::Other,
+        // indirect
+        0x16 => {
+            let form = reader.uleb()?;
+            read_value(reader, form, offset_size, address_size, version)?
+        }
+        _ => return None,
+    })
+}
+
+/// The compilation directory of the first compilation unit of this file, if it has DWARF
+/// debug info.
+pub fn compilation_directory(path: &Path) -> anyhow::Result<Option<String>> {
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let cache = object::read::ReadCache::new(&file);
+    let Ok(object) = object::read::File::parse(&cache) else {
+        return Ok(None);
+    };
+    let little_endian = object.is_little_endian();
+    let read = |name: &str, offset: u64, len: u64| read_section(&file, &object, name, offset, len);
+    let Some(info) = read(".debug_info", 0, MAX_PREFIX)? else {
+        return Ok(None);
+    };
+    let mut reader = Reader {
+        data: &info,
+        little_endian,
+    };
+    let Some((header, code)) = unit_header(&mut reader) else {
+        return Ok(None);
+    };
+    let Some(abbrev) = read(".debug_abbrev", header.abbrev_offset, MAX_PREFIX)? else {
+        return Ok(None);
+    };
+    let Some(specs) = abbreviation(
+        &mut Reader {
+            data: &abbrev,
+            little_endian,
+        },
+        code,
+    ) else {
+        return Ok(None);
+    };
+    let mut comp_dir = None;
+    let mut str_offsets_base = None;
+    for (attribute, form) in specs {
+        let Some(value) = read_value(
+            &mut reader,
+            form,
+            header.offset_size,
+            header.address_size,
+            header.version,
+        ) else {
+            return Ok(None);
+        };
+        match (attribute, value) {
+            (DW_AT_COMP_DIR, value) => comp_dir = Some(value),
+            (DW_AT_STR_OFFSETS_BASE, Value::Number(base)) => str_offsets_base = Some(base),
+            _ => (),
+        }
+    }
+    let (section, offset) = match comp_dir {
+        None | Some(Value::Number(_)) | Some(Value::Other) => return Ok(None),
+        Some(Value::String(s)) => return Ok(std::str::from_utf8(s).ok().map(str::to_owned)),
+        Some(Value::Strp(offset)) => (".debug_str", offset),
+        Some(Value::LineStrp(offset)) => (".debug_line_str", offset),
+        Some(Value::Strx(index)) => {
+            // without the attribute, the offsets start after the header of the section
+            let base = str_offsets_base.unwrap_or(2 * header.offset_size as u64);
+            let size = header.offset_size as u64;
+            let Some(offset) = read(".debug_str_offsets", base + index * size, size)? else {
+                return Ok(None);
+            };
+            let Some(offset) = (Reader {
+                data: &offset,
+                little_endian,
+            })
+            .uint(header.offset_size) else {
+                return Ok(None);
+            };
+            (".debug_str", offset)
+        }
+    };
+    let Some(string) = read(section, offset, MAX_STRING_LEN)? else {
+        return Ok(None);
+    };
+    let Some(string) = (Reader {
+        data: &string,
+        little_endian,
+    })
+    .cstr() else {
+        return Ok(None);
+    };
+    Ok(std::str::from_utf8(string).ok().map(str::to_owned))
+}
+
+/// What is needed from the header of a unit
+struct UnitHeader {
+    version: u16,
+    offset_size: usize,
+    address_size: usize,
+    abbrev_offset: u64,
+}
+
+/// Reads the header of the first unit in `.debug_info`, and the abbreviation code of its
+/// first entry.
+fn unit_header(reader: &mut Reader) -> Option<(UnitHeader, u64)> {
+    let offset_size = match reader.uint(4)? {
+        0xffff_ffff => {
+            reader.uint(8)?;
+            8
+        }
+        _ => 4,
+    };
+    let version = reader.uint(2)? as u16;
+    let header = match version {
+        2..=4 => {
+            let abbrev_offset = reader.uint(offset_size)?;
+            let address_size = reader.uint(1)? as usize;
+            UnitHeader {
+                version,
+                offset_size,
+                address_size,
+                abbrev_offset,
+            }
+        }
+        5 => {
+            let unit_type = reader.uint(1)?;
+            let address_size = reader.uint(1)? as usize;
+            let abbrev_offset = reader.uint(offset_size)?;
+            match unit_type {
+                // type units: signature and type offset
+                0x02 | 0x06 => {
+                    reader.bytes(8 + offset_size)?;
+                }
+                // skeleton and split units: dwo id
+                0x04 | 0x05 => {
+                    reader.bytes(8)?;
+                }
+                _ => (),
+            }
+            UnitHeader {
+                version,
+                offset_size,
+                address_size,
+                abbrev_offset,
+            }
+        }
+        _ => return None,
+    };
+    let code = reader.uleb()?;
+    Some((header, code))
+}
+
+/// Finds the abbreviation `code` in an abbreviation table, and returns its attributes and
+/// their forms.
+fn abbreviation(reader: &mut Reader, code: u64) -> Option<Vec<(u64, u64)>> {
+    loop {
+        let current = reader.uleb()?;
+        if current == 0 {
+            return None;
+        }
+        // tag
+        reader.uleb()?;
+        // whether it has children
+        reader.bytes(1)?;
+        let mut specs = Vec::new();
+        loop {
+            let attribute = reader.uleb()?;
+            let form = reader.uleb()?;
+            if form == DW_FORM_IMPLICIT_CONST {
+                // the value, as a sleb128
+                reader.uleb()?;
+            }
+            if attribute == 0 && form == 0 {
+                break;
+            }
+            specs.push((attribute, form));
+        }
+        if current == code {
+            return Some(specs);
+        }
+    }
+}
+
+/// The directory where nix unpacked the source of a build, like `/build/glibc-2.37`, from the
+/// compilation directory, which may be a subdirectory like `/build/source/build` with cmake.
+///
+/// This is the directory right below the build directory of nix: `/build` in the sandbox, or
+/// `/tmp/nix-build-NAME.drv-0` without.
+pub fn source_prefix(comp_dir: &str) -> Option<String> {
+    let mut prefix = PathBuf::new();
+    let mut components = Path::new(comp_dir).components();
+    while let Some(component) = components.next() {
+        prefix.push(component);
+        let is_build_top = match component {
+            Component::Normal(name) => {
+                prefix == Path::new("/build")
+                    || name.to_str().is_some_and(|name| {
+                        name.starts_with("nix-build-") && name.contains(".drv-")
+                    })
+            }
+            _ => false,
+        };
+        if is_build_top {
+            return match components.next()? {
+                Component::Normal(root) => prefix.join(root).to_str().map(str::to_owned),
+                _ => None,
+            };
+        }
+    }
+    None
+}
+
+#[test]
+fn test_source_prefix() {
+    assert_eq!(
+        source_prefix("/build/source/build").as_deref(),
+        Some("/build/source")
+    );
+    assert_eq!(
+        source_prefix("/build/glibc-2.37").as_deref(),
+        Some("/build/glibc-2.37")
+    );
+    assert_eq!(
+        source_prefix("/tmp/nix-build-hello-2.12.1.drv-0/hello-2.12.1/src").as_deref(),
+        Some("/tmp/nix-build-hello-2.12.1.drv-0/hello-2.12.1")
+    );
+    assert_eq!(source_prefix("/build"), None);
+    assert_eq!(source_prefix("/home/alice/src/hello"), None);
+}
+
+#[test]
+fn test_compilation_directory() {
+    // tests are built with debug info
+    let exe = std::env::current_exe().unwrap();
+    let comp_dir = compilation_directory(&exe).unwrap();
+    assert!(
+        comp_dir.as_deref().is_some_and(|dir| !dir.is_empty()),
+        "{:?}",
+        comp_dir
+    );
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let not_elf = dir.path().join("not_elf");
+    std::fs::write(&not_elf, "hello").unwrap();
+    assert_eq!(compilation_directory(&not_elf).unwrap(), None);
+}
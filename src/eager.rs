@@ -0,0 +1,215 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Realisation in the background of the debug outputs of the executables found by indexation,
+//! with `--eager-debuginfo-mb`, so that debuginfo is already local when a debugger attaches.
+//!
+//! Debug outputs are realised one at a time, most recently queued first, as long as the total
+//! size they take on disk stays below the limit. Debug outputs whose size is not known by
+//! substituters are not realised, as they could exceed it. What was realised is recorded in the
+//! cache db so that the limit holds across restarts.
+
+use std::collections::{HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use tokio::sync::Notify;
+
+use crate::db::Cache;
+use crate::log::ResultExt;
+use crate::resolve::Resolver;
+use crate::store::realise;
+
+/// How many debug outputs can wait for realisation. When more are queued, the oldest are
+/// dropped.
+const QUEUE_SIZE: usize = 1000;
+
+/// How many debug outputs are remembered as already considered. When more are seen, they are
+/// all forgotten.
+const MAX_SEEN: usize = 10000;
+
+/// Debug outputs waiting for realisation, the most recent at the back
+#[derive(Default)]
+struct Queue {
+    paths: Mutex<VecDeque<PathBuf>>,
+    queued: Notify,
+}
+
+impl Queue {
+    /// Waits for the most recently queued debug output
+    async fn pop(&self) -> PathBuf {
+        loop {
+            if let Some(path) = self.paths.lock().unwrap().pop_back() {
+                return path;
+            }
+            self.queued.notified().await;
+        }
+    }
+}
+
+/// Queue of debug outputs to realise in the background
+#[derive(Clone)]
+pub struct EagerRealiser {
+    queue: Arc<Queue>,
+}
+
+impl EagerRealiser {
+    /// Starts a task realising the queued debug outputs, until they take `limit` bytes on disk.
+    ///
+    /// Sizes are asked to the substituters of `resolver`, and realised debug outputs are
+    /// recorded in `cache`.
+    pub fn start(resolver: Resolver, cache: Cache, limit: u64) -> Self {
+        let queue = Arc::new(Queue::default());
+        tokio::spawn(realise_queued(resolver, cache, limit, queue.clone()));
+        Self { queue }
+    }
+
+    /// A queue whose debug outputs are not realised, see [EagerRealiser::take_queued]
+    #[cfg(test)]
+    pub fn test_queue() -> Self {
+        Self {
+            queue: Arc::new(Queue::default()),
+        }
+    }
+
+    /// The debug outputs waiting for realisation, most recent first
+    #[cfg(test)]
+    pub fn take_queued(&self) -> Vec<PathBuf> {
+        let mut paths = self.queue.paths.lock().unwrap();
+        paths.drain(..).rev().collect()
+    }
+
+    /// Queues this debug output for realisation, dropping the oldest one if too many are
+    /// already waiting.
+    pub fn queue(&self, output: &Path) {
+        let mut paths = self.queue.paths.lock().unwrap();
+        if paths.len() >= QUEUE_SIZE {
+            if let Some(dropped) = paths.pop_front() {
+                tracing::debug!(
+                    "too many debug outputs waiting, not realising {} in advance",
+                    dropped.display()
+                );
+            }
+        }
+        paths.push_back(output.to_path_buf());
+        drop(paths);
+        self.queue.queued.notify_one();
+    }
+}
+
+/// Size taken on disk by the debug outputs realised in advance by a previous run and which
+/// still exist.
+async fn already_realised(cache: &Cache) -> anyhow::Result<u64> {
+    let mut used = 0;
+    for (path, size) in cache.get_eager_realised().await? {
+        if tokio::fs::try_exists(&path).await.unwrap_or(true) {
+            used += size;
+        } else {
+            cache.forget_eager_realised(&path).await?;
+        }
+    }
+    Ok(used)
+}
+
+/// Total size of the regular files in this directory
+fn disk_usage(path: &Path) -> anyhow::Result<u64> {
+    let mut size = 0;
+    for entry in walkdir::WalkDir::new(path) {
+        let entry = entry.with_context(|| format!("walking {}", path.display()))?;
+        let metadata = entry
+            .metadata()
+            .with_context(|| format!("stat {}", entry.path().display()))?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Realises the debug outputs of `queue` which are missing, one at a time, until they take
+/// `limit` bytes on disk.
+async fn realise_queued(resolver: Resolver, cache: Cache, limit: u64, queue: Arc<Queue>) {
+    let mut seen = HashSet::new();
+    let mut used = match already_realised(&cache).await {
+        Ok(used) => used,
+        Err(e) => {
+            tracing::warn!("not realising debug outputs in advance: {:#}", e);
+            return;
+        }
+    };
+    loop {
+        let output = queue.pop().await;
+        if seen.len() >= MAX_SEEN {
+            seen.clear();
+        }
+        if !seen.insert(output.clone()) || output.exists() {
+            continue;
+        }
+        let Some(size) = resolver.nar_size(&output).await else {
+            tracing::debug!(
+                "size of {} is unknown, not realising it in advance",
+                output.display()
+            );
+            continue;
+        };
+        if used + size > limit {
+            tracing::info!(
+                "not realising {} in advance, it would exceed --eager-debuginfo-mb",
+                output.display()
+            );
+            continue;
+        }
+        tracing::info!("realising debug output {} in advance", output.display());
+        if let Err(e) = realise(&output).await {
+            tracing::warn!("realising {} in advance: {:#}", output.display(), e);
+            continue;
+        }
+        let measured = output.clone();
+        let realised = tokio::task::spawn_blocking(move || disk_usage(&measured))
+            .await
+            .context("joining measuring task")
+            .and_then(|result| result);
+        let size = match realised {
+            Ok(realised) => realised,
+            Err(e) => {
+                tracing::warn!("measuring {}: {:#}", output.display(), e);
+                size
+            }
+        };
+        used += size;
+        if let Some(path) = output.to_str() {
+            cache
+                .register_eager_realised(path, size)
+                .await
+                .context("recording debug output realised in advance")
+                .or_warn();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_queue_keeps_most_recent() {
+    let eager = EagerRealiser::test_queue();
+    for i in 0..QUEUE_SIZE + 2 {
+        eager.queue(Path::new(&format!("/nix/store/{i}-debug")));
+    }
+    assert_eq!(
+        eager.queue.pop().await,
+        PathBuf::from(format!("/nix/store/{}-debug", QUEUE_SIZE + 1))
+    );
+    let queued = eager.take_queued();
+    assert_eq!(queued.len(), QUEUE_SIZE - 1);
+    assert_eq!(queued.last().unwrap(), Path::new("/nix/store/2-debug"));
+}
+
+#[test]
+fn test_disk_usage() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("lib")).unwrap();
+    std::fs::write(dir.path().join("lib/a.debug"), [0u8; 100]).unwrap();
+    std::fs::write(dir.path().join("b.debug"), [0u8; 24]).unwrap();
+    std::os::unix::fs::symlink("b.debug", dir.path().join("c.debug")).unwrap();
+    assert_eq!(disk_usage(dir.path()).unwrap(), 124);
+}
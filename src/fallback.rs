@@ -0,0 +1,218 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Other debuginfod servers asked for the files this server does not know, with `--fallback`,
+//! like the federation of the debuginfod server of elfutils.
+//!
+//! Requests are forwarded with their `X-DEBUGINFOD-*` headers, and answers are streamed back with
+//! theirs (`X-DEBUGINFOD-SIZE`, `X-DEBUGINFOD-FILE`, `X-DEBUGINFOD-ARCHIVE`), so that clients
+//! display the progress and names of files as if they talked to the upstream server directly.
+
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE, VIA};
+use http::{HeaderMap, HeaderValue, Method, StatusCode};
+use reqwest::Url;
+
+use crate::substituter::HttpClient;
+
+/// Added to forwarded requests, so that two servers falling back to each other do not forward
+/// requests forever
+const VIA_VALUE: &str = "1.1 nixseparatedebuginfod";
+
+/// The debuginfod servers to forward requests to
+pub struct Fallbacks {
+    http: HttpClient,
+    /// urls of the servers, with a trailing slash
+    urls: Vec<Url>,
+}
+
+impl Fallbacks {
+    /// Forwards requests to these servers, in order.
+    pub fn new(http: HttpClient, urls: &[String]) -> anyhow::Result<Self> {
+        let urls = urls
+            .iter()
+            .map(|url| {
+                let with_slash = format!("{}/", url.trim_end_matches('/'));
+                Url::parse(&with_slash).map_err(|e| anyhow::anyhow!("invalid url {}: {}", url, e))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { http, urls })
+    }
+}
+
+/// Whether this is a request for a file from the debuginfod protocol, which other servers may
+/// have
+fn is_debuginfod_request(path: &str) -> bool {
+    let mut components = path.trim_start_matches('/').split('/');
+    if components.next() != Some("buildid") || components.next().is_none() {
+        return false;
+    }
+    let rest: Vec<&str> = components.collect();
+    match rest.as_slice() {
+        ["debuginfo"] | ["executable"] => true,
+        ["source" | "section", name, ..] => !name.is_empty(),
+        _ => false,
+    }
+}
+
+#[test]
+fn test_is_debuginfod_request() {
+    assert!(is_debuginfod_request("/buildid/abcd/debuginfo"));
+    assert!(is_debuginfod_request("/buildid/abcd/executable"));
+    assert!(is_debuginfod_request(
+        "/buildid/abcd/source/build/source/main.c"
+    ));
+    assert!(is_debuginfod_request("/buildid/abcd/section/.gdb_index"));
+    assert!(!is_debuginfod_request("/buildid/abcd/source/"));
+    assert!(!is_debuginfod_request("/buildid/abcd/status"));
+    assert!(!is_debuginfod_request("/buildid/abcd/tree/main.c"));
+    assert!(!is_debuginfod_request("/metrics"));
+}
+
+/// The headers of a request or response which are forwarded
+fn debuginfod_headers(
+    headers: &HeaderMap,
+) -> impl Iterator<Item = (&http::HeaderName, &HeaderValue)> {
+    headers
+        .iter()
+        .filter(|(name, _)| name.as_str().starts_with("x-debuginfod-"))
+}
+
+/// Middleware asking the [Fallbacks] for the files this server answered `404 Not Found` for.
+pub async fn fall_back(
+    State(fallbacks): State<Arc<Fallbacks>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let forwarded = request.headers().get_all(VIA).iter().any(|via| {
+        via.to_str()
+            .is_ok_and(|via| via.contains("nixseparatedebuginfod"))
+    });
+    if request.method() != Method::GET || forwarded || !is_debuginfod_request(request.uri().path())
+    {
+        return next.run(request).await;
+    }
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("", |path| path.as_str())
+        .trim_start_matches('/')
+        .to_owned();
+    let headers = request.headers().clone();
+    let response = next.run(request).await;
+    if response.status() != StatusCode::NOT_FOUND {
+        return response;
+    }
+    for base in &fallbacks.urls {
+        match forward(&fallbacks.http, base, &path, &headers).await {
+            Ok(Some(response)) => return response,
+            Ok(None) => tracing::debug!("{} not found in {}", path, base),
+            Err(e) => tracing::warn!("asking {} for {}: {:#}", base, path, e),
+        }
+    }
+    response
+}
+
+/// Asks the debuginfod server at `base` for `path`, forwarding the `X-DEBUGINFOD-*` headers of
+/// the request.
+///
+/// Returns its answer, or `None` if it does not have the file.
+async fn forward(
+    http: &HttpClient,
+    base: &Url,
+    path: &str,
+    headers: &HeaderMap,
+) -> anyhow::Result<Option<Response>> {
+    let url = base
+        .join(path)
+        .map_err(|e| anyhow::anyhow!("joining {} to {}: {}", path, base, e))?;
+    let mut request = http.get(&url).header(VIA, VIA_VALUE);
+    for (name, value) in debuginfod_headers(headers) {
+        request = request.header(name, value);
+    }
+    tracing::debug!("asking {} for {}", base, path);
+    let upstream = request.send().await?;
+    if upstream.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let upstream = upstream.error_for_status()?;
+    tracing::info!("forwarding {} from {}", path, base);
+    let mut response = Response::builder().status(StatusCode::OK);
+    for (name, value) in debuginfod_headers(upstream.headers()) {
+        response = response.header(name, value);
+    }
+    for name in [CONTENT_TYPE, CONTENT_LENGTH] {
+        if let Some(value) = upstream.headers().get(&name) {
+            response = response.header(name, value);
+        }
+    }
+    Ok(Some(
+        response.body(Body::from_stream(upstream.bytes_stream()))?,
+    ))
+}
+
+#[tokio::test]
+async fn test_fall_back() {
+    use tower::ServiceExt;
+    // an upstream server with a single file, echoing the headers it receives
+    let upstream = axum::Router::new().route(
+        "/buildid/abcd/debuginfo",
+        axum::routing::get(|headers: HeaderMap| async move {
+            let client = headers
+                .get("x-debuginfod-client")
+                .cloned()
+                .unwrap_or(HeaderValue::from_static("none"));
+            (
+                [("x-debuginfod-file", "/usr/lib/debug/abcd.debug")],
+                format!("debuginfo for {}", client.to_str().unwrap()),
+            )
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, upstream).await });
+    let fallbacks = Fallbacks::new(
+        HttpClient::new(None, None).unwrap(),
+        &[format!("http://{}", address)],
+    )
+    .unwrap();
+    let app = axum::Router::new()
+        .route(
+            "/buildid/:buildid/debuginfo",
+            axum::routing::get(|| async { (StatusCode::NOT_FOUND, "not found") }),
+        )
+        .layer(axum::middleware::from_fn_with_state(
+            Arc::new(fallbacks),
+            fall_back,
+        ));
+    let get = |buildid: &str, via: Option<&'static str>| {
+        let mut request = Request::builder()
+            .uri(format!("/buildid/{}/debuginfo", buildid))
+            .header("x-debuginfod-client", "gdb");
+        if let Some(via) = via {
+            request = request.header(VIA, via);
+        }
+        app.clone().oneshot(request.body(Body::empty()).unwrap())
+    };
+    let response = get("abcd", None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("x-debuginfod-file").unwrap(),
+        "/usr/lib/debug/abcd.debug"
+    );
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&body[..], b"debuginfo for gdb");
+    let response = get("ef01", None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    // not forwarded again
+    let response = get("abcd", Some(VIA_VALUE)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
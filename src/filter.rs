@@ -0,0 +1,406 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Selection of the store paths to index, configured by `--index-allow`, `--index-deny` and
+//! `--index-all-outputs`, and of the store paths and buildids which are never fetched nor served,
+//! configured by `--block`
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::str::FromStr;
+
+use once_cell::sync::OnceCell;
+use once_cell::unsync::Lazy;
+use regex::Regex;
+
+use crate::store::get_store_path;
+use crate::Options;
+
+/// A criterion on store paths
+#[derive(Debug, Clone)]
+pub enum Filter {
+    /// `path:REGEX`: the store path matches this regex (not anchored)
+    Path(Regex),
+    /// `package:NAME`: the package name of the deriver of the store path is exactly this
+    Package(String),
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if let Some(regex) = s.strip_prefix("path:") {
+            Ok(Filter::Path(Regex::new(regex)?))
+        } else if let Some(name) = s.strip_prefix("package:") {
+            Ok(Filter::Package(name.to_owned()))
+        } else {
+            anyhow::bail!("expected path:REGEX or package:NAME, got {:?}", s)
+        }
+    }
+}
+
+/// Which store paths should be indexed
+#[derive(Debug, Clone, Default)]
+pub struct IndexFilter {
+    /// if not empty, only store paths matching one of these are indexed
+    allow: Vec<Filter>,
+    /// store paths matching one of these are not indexed
+    deny: Vec<Filter>,
+    /// whether to index outputs like `-dev` without executables anyway, see
+    /// [is_without_executables]
+    all_outputs: bool,
+}
+
+impl IndexFilter {
+    /// Creates a filter. Denying takes precedence over allowing.
+    pub fn new(allow: Vec<Filter>, deny: Vec<Filter>) -> Self {
+        Self {
+            allow,
+            deny,
+            all_outputs: false,
+        }
+    }
+
+    /// The filter configured by `--index-allow`, `--index-deny` and `--index-all-outputs`
+    pub fn from_options(args: &Options) -> Self {
+        Self {
+            all_outputs: args.index_all_outputs,
+            ..Self::new(args.index_allow.clone(), args.index_deny.clone())
+        }
+    }
+
+    /// Whether this store path should be indexed.
+    ///
+    /// `deriver` is only called if a `package:` filter needs it. If it returns `None`, the
+    /// package name is taken from the name of the store path instead.
+    pub fn allows<'a>(&self, storepath: &Path, deriver: impl FnOnce() -> Option<&'a Path>) -> bool {
+        if is_blocked_path(storepath) {
+            return false;
+        }
+        if !self.all_outputs && is_without_executables(storepath) {
+            return false;
+        }
+        if self.allow.is_empty() && self.deny.is_empty() {
+            return true;
+        }
+        let package = Lazy::new(|| package_name(deriver().unwrap_or(storepath)));
+        let matches = |filter: &Filter| match filter {
+            Filter::Path(regex) => storepath.to_str().is_some_and(|s| regex.is_match(s)),
+            Filter::Package(name) => package.as_deref() == Some(name.as_str()),
+        };
+        if self.deny.iter().any(matches) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(matches)
+    }
+}
+
+/// Suffixes of the names of outputs which rarely contain executables
+const LOW_PRIORITY_OUTPUTS: &[&str] = &["-dev", "-doc", "-devdoc", "-man", "-info"];
+
+/// Directories of a store path where executables usually are
+const EXECUTABLE_DIRS: &[&str] = &["bin", "sbin", "libexec"];
+
+/// Directories of a store path where shared libraries usually are
+const LIBRARY_DIRS: &[&str] = &["lib", "lib64"];
+
+/// Whether this store path is an output like `-dev` or `-man` which is not worth walking: it has
+/// no directory for executables, and no shared library at the top of its library directories.
+///
+/// Only a few directories are probed, which is much faster than walking headers and
+/// documentation.
+fn is_without_executables(storepath: &Path) -> bool {
+    let Some(name) = storepath.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    if !LOW_PRIORITY_OUTPUTS
+        .iter()
+        .any(|suffix| name.ends_with(suffix))
+    {
+        return false;
+    }
+    if EXECUTABLE_DIRS
+        .iter()
+        .any(|dir| storepath.join(dir).is_dir())
+    {
+        return false;
+    }
+    for dir in LIBRARY_DIRS {
+        let Ok(entries) = std::fs::read_dir(storepath.join(dir)) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let is_file = entry.file_type().is_ok_and(|kind| kind.is_file());
+            if is_file && entry.file_name().to_string_lossy().contains(".so") {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+#[test]
+fn test_is_without_executables() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let output = |name: &str, files: &[&str]| {
+        let storepath = dir.path().join(name);
+        std::fs::create_dir(&storepath).unwrap();
+        for file in files {
+            let file = storepath.join(file);
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(file, "").unwrap();
+        }
+        storepath
+    };
+    let headers = output(
+        "aaaa-zlib-1.3-dev",
+        &["include/zlib.h", "lib/pkgconfig/zlib.pc"],
+    );
+    assert!(is_without_executables(&headers));
+    let man = output("bbbb-zlib-1.3-man", &["share/man/man3/zlib.3.gz"]);
+    assert!(is_without_executables(&man));
+    let tools = output("cccc-openssl-3.0-dev", &["bin/c_rehash", "include/ssl.h"]);
+    assert!(!is_without_executables(&tools));
+    let library = output("dddd-foo-1.0-dev", &["lib/libfoo.so.1"]);
+    assert!(!is_without_executables(&library));
+    let out = output("eeee-zlib-1.3", &["share/man/man3/zlib.3.gz"]);
+    assert!(!is_without_executables(&out));
+    let all_outputs = IndexFilter {
+        all_outputs: true,
+        ..IndexFilter::default()
+    };
+    assert!(all_outputs.allows(&headers, || None));
+    assert!(!IndexFilter::default().allows(&headers, || None));
+}
+
+/// A pattern given to `--block`
+#[derive(Debug, Clone)]
+pub enum Block {
+    /// `buildid:HEX`: this buildid
+    Buildid(String),
+    /// `path:REGEX` or `package:NAME`: the store paths matching this filter, where the package
+    /// name is taken from the name of the store path
+    StorePath(Filter),
+}
+
+impl FromStr for Block {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if let Some(buildid) = s.strip_prefix("buildid:") {
+            anyhow::ensure!(
+                !buildid.is_empty() && buildid.chars().all(|c| c.is_ascii_hexdigit()),
+                "invalid buildid {:?}",
+                buildid
+            );
+            Ok(Block::Buildid(buildid.to_ascii_lowercase()))
+        } else if s.starts_with("path:") || s.starts_with("package:") {
+            Ok(Block::StorePath(s.parse()?))
+        } else {
+            anyhow::bail!(
+                "expected path:REGEX, package:NAME or buildid:HEX, got {:?}",
+                s
+            )
+        }
+    }
+}
+
+/// Store paths and buildids which are never indexed, fetched nor served
+#[derive(Debug, Clone, Default)]
+pub struct Blocklist {
+    buildids: HashSet<String>,
+    store_paths: Vec<Filter>,
+}
+
+impl Blocklist {
+    /// Creates a blocklist of all these patterns.
+    pub fn new(blocks: Vec<Block>) -> Self {
+        let mut result = Self::default();
+        for block in blocks {
+            match block {
+                Block::Buildid(buildid) => {
+                    result.buildids.insert(buildid);
+                }
+                Block::StorePath(filter) => result.store_paths.push(filter),
+            }
+        }
+        result
+    }
+
+    /// Whether this buildid is blocked
+    pub fn blocks_buildid(&self, buildid: &str) -> bool {
+        self.buildids.contains(buildid)
+    }
+
+    /// Whether some store paths are blocked
+    pub fn blocks_store_paths(&self) -> bool {
+        !self.store_paths.is_empty()
+    }
+
+    /// Whether the store path containing `path` is blocked
+    pub fn blocks_path(&self, path: &Path) -> bool {
+        let Some(storepath) = get_store_path(path) else {
+            return false;
+        };
+        let package = Lazy::new(|| package_name(storepath));
+        self.store_paths.iter().any(|filter| match filter {
+            Filter::Path(regex) => storepath.to_str().is_some_and(|s| regex.is_match(s)),
+            Filter::Package(name) => package.as_deref() == Some(name.as_str()),
+        })
+    }
+}
+
+/// The blocklist given with `--block`
+static BLOCKLIST: OnceCell<Blocklist> = OnceCell::new();
+
+/// Blocks the store paths and buildids of `blocklist` from now on.
+pub fn set_blocklist(blocklist: Blocklist) {
+    if BLOCKLIST.set(blocklist).is_err() {
+        tracing::warn!("blocklist set twice");
+    }
+}
+
+/// Whether this buildid was blocked with `--block`
+pub fn is_blocked_buildid(buildid: &str) -> bool {
+    BLOCKLIST
+        .get()
+        .is_some_and(|blocklist| blocklist.blocks_buildid(buildid))
+}
+
+/// Whether the store path containing `path` was blocked with `--block`
+pub fn is_blocked_path(path: &Path) -> bool {
+    BLOCKLIST
+        .get()
+        .is_some_and(|blocklist| blocklist.blocks_path(path))
+}
+
+/// Whether some store paths were blocked with `--block`
+pub fn blocks_store_paths() -> bool {
+    BLOCKLIST.get().is_some_and(Blocklist::blocks_store_paths)
+}
+
+/// The package name of a store path or derivation, like `nix` for
+/// `/nix/store/xxx-nix-2.18.1.drv` or `python3.11-requests` for
+/// `/nix/store/xxx-python3.11-requests-2.31.0-dist`.
+///
+/// Like `builtins.parseDrvName`, this is everything up to the first dash not followed by a
+/// letter.
+pub fn package_name(path: &Path) -> Option<String> {
+    parse_name(path).map(|(name, _)| name.to_owned())
+}
+
+/// The package version of a store path or derivation, like `2.18.1` for
+/// `/nix/store/xxx-nix-2.18.1.drv`, or `None` if its name has no version.
+///
+/// The version of an output other than `out` includes the name of the output, like
+/// `2.18.1-dev`, so prefer the derivation when it is known.
+pub fn package_version(path: &Path) -> Option<String> {
+    parse_name(path)
+        .and_then(|(_, version)| version)
+        .map(str::to_owned)
+}
+
+/// Splits the name of a store path or derivation into package name and version, like
+/// `builtins.parseDrvName`.
+fn parse_name(path: &Path) -> Option<(&str, Option<&str>)> {
+    let name = path.file_name()?.to_str()?;
+    let name = name.strip_suffix(".drv").unwrap_or(name);
+    // remove the hash
+    let (_, name) = name.split_once('-')?;
+    for (i, _) in name.match_indices('-') {
+        if !name[i + 1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            return Some((&name[..i], Some(&name[i + 1..])));
+        }
+    }
+    Some((name, None))
+}
+
+#[test]
+fn test_package_name() {
+    let name = |s: &str| package_name(Path::new(s));
+    assert_eq!(
+        name("/nix/store/9ry3ra4mf9s7c0ah5syxc6kq6jk2c5c2-nix-2.18.1.drv").as_deref(),
+        Some("nix")
+    );
+    assert_eq!(
+        name("/nix/store/9ry3ra4mf9s7c0ah5syxc6kq6jk2c5c2-python3.11-requests-2.31.0-dist")
+            .as_deref(),
+        Some("python3.11-requests")
+    );
+    assert_eq!(
+        name("/nix/store/9ry3ra4mf9s7c0ah5syxc6kq6jk2c5c2-hello").as_deref(),
+        Some("hello")
+    );
+    assert_eq!(name("/nix/store/.links"), None);
+}
+
+#[test]
+fn test_package_version() {
+    let version = |s: &str| package_version(Path::new(s));
+    assert_eq!(
+        version("/nix/store/9ry3ra4mf9s7c0ah5syxc6kq6jk2c5c2-nix-2.18.1.drv").as_deref(),
+        Some("2.18.1")
+    );
+    assert_eq!(
+        version("/nix/store/9ry3ra4mf9s7c0ah5syxc6kq6jk2c5c2-python3.11-requests-2.31.0-dist")
+            .as_deref(),
+        Some("2.31.0-dist")
+    );
+    assert_eq!(
+        version("/nix/store/9ry3ra4mf9s7c0ah5syxc6kq6jk2c5c2-hello").as_deref(),
+        None
+    );
+}
+
+#[test]
+fn test_index_filter() {
+    let filter = |allow: &[&str], deny: &[&str]| {
+        IndexFilter::new(
+            allow.iter().map(|s| s.parse().unwrap()).collect(),
+            deny.iter().map(|s| s.parse().unwrap()).collect(),
+        )
+    };
+    let texlive = Path::new("/nix/store/aaaa-texlive-combined-2023");
+    let hello = Path::new("/nix/store/bbbb-hello-2.12.1");
+    let hello_drv = Path::new("/nix/store/cccc-hello-2.12.1.drv");
+    let no_deriver = || None;
+
+    let default = IndexFilter::default();
+    assert!(default.allows(texlive, || panic!("deriver is not needed")));
+
+    let deny = filter(&[], &["path:-texlive-"]);
+    assert!(!deny.allows(texlive, no_deriver));
+    assert!(deny.allows(hello, no_deriver));
+
+    let allow = filter(&["package:hello"], &[]);
+    assert!(!allow.allows(texlive, no_deriver));
+    assert!(allow.allows(hello, no_deriver));
+    assert!(allow.allows(texlive, || Some(hello_drv)));
+
+    let both = filter(&["package:hello"], &["path:^/nix/store/bbbb-"]);
+    assert!(!both.allows(hello, no_deriver));
+
+    assert!("hello".parse::<Filter>().is_err());
+    assert!("path:(".parse::<Filter>().is_err());
+}
+
+#[test]
+fn test_blocklist() {
+    let blocks = ["buildid:ABCD", "package:texlive-combined", "path:-unfree-"];
+    let blocklist = Blocklist::new(blocks.iter().map(|s| s.parse().unwrap()).collect());
+    assert!(blocklist.blocks_buildid("abcd"));
+    assert!(!blocklist.blocks_buildid("abce"));
+    assert!(blocklist.blocks_store_paths());
+    assert!(!Blocklist::new(vec!["buildid:abcd".parse().unwrap()]).blocks_store_paths());
+    assert!(blocklist.blocks_path(Path::new(
+        "/nix/store/aaaa-texlive-combined-2023/bin/pdftex"
+    )));
+    assert!(blocklist.blocks_path(Path::new("/nix/store/bbbb-unfree-thing-1.0-debug")));
+    assert!(!blocklist.blocks_path(Path::new("/nix/store/cccc-hello-2.12.1")));
+    assert!(!blocklist.blocks_path(Path::new("/tmp/aaaa-texlive-combined-2023")));
+
+    assert!("buildid:xyz".parse::<Block>().is_err());
+    assert!("hello".parse::<Block>().is_err());
+    assert!("path:(".parse::<Block>().is_err());
+}
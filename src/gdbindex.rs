@@ -0,0 +1,158 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Generation of `.gdb_index` sections for `--generate-gdb-index`.
+//!
+//! Without this index, gdb reads all the debug info of a library on startup, which takes a long
+//! time for large debuginfo files. Debug outputs in nixpkgs usually lack it, so it is generated
+//! with `gdb` itself, like `gdb-add-index` does, and served through the section endpoint.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+
+use crate::db::Cache;
+use crate::log::ResultExt;
+use crate::resolve::Coalescer;
+
+/// Name of the section
+pub const SECTION: &str = ".gdb_index";
+
+/// Generates `.gdb_index` sections and keeps them in a directory of the cache.
+#[derive(Clone)]
+pub struct GdbIndexer {
+    cache: Cache,
+    /// where the generated sections are stored, named after their buildid
+    dir: PathBuf,
+    /// generations in progress, so that concurrent requests run gdb only once
+    requests: Arc<Coalescer<PathBuf>>,
+}
+
+impl GdbIndexer {
+    /// Creates an indexer storing sections in `dir`, which must exist. They are deleted when
+    /// unused for 30 days, like other files of the cache directory.
+    pub fn new(cache: Cache, dir: PathBuf) -> Self {
+        Self {
+            cache,
+            dir,
+            requests: Arc::new(Coalescer::default()),
+        }
+    }
+
+    /// Returns the path of a file containing the `.gdb_index` section for the debug info of
+    /// this buildid in `debuginfo`, generating it if needed.
+    pub async fn index(&self, buildid: &str, debuginfo: &Path) -> anyhow::Result<PathBuf> {
+        let target = self.dir.join(buildid);
+        if tokio::fs::metadata(&target).await.is_err() {
+            self.requests
+                .run(buildid, generate(debuginfo, &target))
+                .await?;
+        }
+        let target_str = target
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("non utf8 cache directory"))?;
+        self.cache
+            .register_private_path(target_str)
+            .await
+            .context("registering generated gdb index")?;
+        self.cache
+            .remove_expired_private_paths()
+            .await
+            .context("expiring generated gdb indices")
+            .or_warn();
+        Ok(target)
+    }
+}
+
+/// Runs gdb to write the `.gdb_index` section for `debuginfo` to `target`.
+async fn generate(debuginfo: &Path, target: &Path) -> anyhow::Result<PathBuf> {
+    let dir = target
+        .parent()
+        .context("no parent directory for gdb index")?;
+    let tempdir = tempfile::TempDir::new_in(dir)
+        .with_context(|| format!("creating temporary directory in {}", dir.display()))?;
+    let mut cmd = tokio::process::Command::new("gdb");
+    cmd.args(["-batch", "-nx"])
+        .args(["-iex", "set auto-load no"])
+        .args(["-iex", "set debuginfod enabled off"])
+        .arg("-ex")
+        .arg(format!("file {}", gdb_quote(debuginfo)))
+        // the directory is not parsed, so not quoted
+        .arg("-ex")
+        .arg(format!("save gdb-index {}", tempdir.path().display()));
+    cmd.stdin(std::process::Stdio::null());
+    // do not leave gdb running if the request is cancelled
+    cmd.kill_on_drop(true);
+    tracing::debug!("Running {:?}", &cmd);
+    let output = cmd
+        .output()
+        .await
+        .with_context(|| format!("running {:?}", cmd))?;
+    anyhow::ensure!(
+        output.status.success(),
+        "{:?} failed: {:?}: {}",
+        cmd,
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let name = debuginfo
+        .file_name()
+        .context("debuginfo has no file name")?;
+    let mut generated = tempdir.path().join(name).into_os_string();
+    generated.push(".gdb-index");
+    let generated = PathBuf::from(generated);
+    // gdb writes nothing when there is no debug info to index
+    anyhow::ensure!(
+        generated.is_file(),
+        "gdb generated no index for {}: {}",
+        debuginfo.display(),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    tokio::fs::rename(&generated, target)
+        .await
+        .with_context(|| format!("moving {} to {}", generated.display(), target.display()))?;
+    tracing::info!("generated gdb index of {}", debuginfo.display());
+    Ok(target.to_path_buf())
+}
+
+/// Quotes a path as the argument of the `file` command of gdb
+fn gdb_quote(path: &Path) -> String {
+    let mut result = String::from("\"");
+    for c in path.to_string_lossy().chars() {
+        if c == '"' || c == '\\' {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result.push('"');
+    result
+}
+
+#[test]
+fn test_gdb_quote() {
+    assert_eq!(
+        gdb_quote(Path::new("/nix/store/aaaa-foo/lib/debug/x.debug")),
+        r#""/nix/store/aaaa-foo/lib/debug/x.debug""#
+    );
+    assert_eq!(
+        gdb_quote(Path::new(r#"/tmp/a "b"\c"#)),
+        r#""/tmp/a \"b\"\\c""#
+    );
+}
+
+#[tokio::test]
+async fn test_existing_index_is_reused() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let cache = Cache::open_in_memory().await.unwrap();
+    let indexer = GdbIndexer::new(cache, dir.path().to_path_buf());
+    let existing = dir.path().join("abcd");
+    std::fs::write(&existing, "index").unwrap();
+    // gdb is not run, so the debuginfo need not exist
+    let index = indexer
+        .index("abcd", Path::new("/nonexistent.debug"))
+        .await
+        .unwrap();
+    assert_eq!(index, existing);
+}
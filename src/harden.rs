@@ -0,0 +1,343 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Hardening of the whole process on startup, with `--harden`.
+//!
+//! Capabilities are dropped, then [landlock](https://docs.kernel.org/userspace-api/landlock.html)
+//! restricts the filesystem to reading the nix store and system configuration, and writing the
+//! cache directory and temporary files, and finally a seccomp filter forbids system calls a
+//! debuginfod server has no business making, like loading kernel modules or tracing other
+//! processes.
+//!
+//! Restrictions are inherited by `nix-store`, `gdb` and other commands the server runs, so store
+//! paths can only be realised through the nix daemon.
+//!
+//! Landlock and seccomp only restrict the calling thread, so this must be applied before other
+//! threads are started.
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use crate::sandbox::LANDLOCK_ACCESS_FS_READ;
+use crate::Options;
+
+/// Applies all the restrictions to the current thread and what it starts.
+///
+/// Fails if the kernel does not support them, rather than running unhardened.
+pub fn apply(args: &Options) -> anyhow::Result<()> {
+    drop_capabilities().context("dropping capabilities")?;
+    crate::sandbox::landlock(&allowed_paths(args)?, false)
+        .context("restricting filesystem access")?;
+    install_seccomp_filter().context("installing seccomp filter")?;
+    // commands we run cannot write to $TMPDIR anymore
+    std::env::set_var("TMPDIR", crate::db::temp_dir()?);
+    Ok(())
+}
+
+/// The paths the server may access, with their landlock access rights
+fn allowed_paths(args: &Options) -> anyhow::Result<Vec<(PathBuf, u64)>> {
+    let mut allowed: Vec<(PathBuf, u64)> = [
+        // the store, its db and gc roots, and the daemon socket
+        "/nix",
+        "/etc",
+        // /run/current-system and sockets of journald and nscd
+        "/run",
+        "/proc",
+        "/dev/urandom",
+        // binaries run on distributions other than NixOS
+        "/usr",
+        "/bin",
+        "/lib",
+        "/lib64",
+    ]
+    .into_iter()
+    .map(|path| (PathBuf::from(path), LANDLOCK_ACCESS_FS_READ))
+    .collect();
+    allowed.push(("/dev/null".into(), u64::MAX));
+    allowed.push((crate::db::cache_dir()?, u64::MAX));
+    allowed.push((crate::db::temp_dir()?, u64::MAX));
+    if let Some(dirs) = directories::BaseDirs::new() {
+        // nix commands read their configuration and write their cache there
+        allowed.push((dirs.config_dir().join("nix"), LANDLOCK_ACCESS_FS_READ));
+        allowed.push((dirs.cache_dir().join("nix"), u64::MAX));
+    }
+    if let Some(config) = &args.config {
+        allowed.push((config.clone(), LANDLOCK_ACCESS_FS_READ));
+    }
+    let source_maps = args.source_map.iter().map(|map| &map.to);
+    for dir in args
+        .debuginfo_dir
+        .iter()
+        .chain(&args.extra_source_dir)
+        .chain(source_maps)
+    {
+        allowed.push((dir.clone(), LANDLOCK_ACCESS_FS_READ));
+    }
+    for store in &args.store {
+        allowed.push((store.root.clone(), LANDLOCK_ACCESS_FS_READ));
+    }
+    // cache dbs of other instances, with their journals
+    for db in args.from_cache.iter().chain(&args.upstream) {
+        if let Some(dir) = std::path::Path::new(db).parent() {
+            allowed.push((dir.to_path_buf(), LANDLOCK_ACCESS_FS_READ));
+        }
+    }
+    Ok(allowed)
+}
+
+/// `_LINUX_CAPABILITY_VERSION_3`
+const CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+/// `struct __user_cap_header_struct`
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+/// `struct __user_cap_data_struct`
+#[repr(C)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Drops all capabilities of the current thread, and as many as allowed from the bounding set.
+fn drop_capabilities() -> anyhow::Result<()> {
+    // SAFETY: prctl with integer arguments. Fails on kernels without ambient capabilities, which
+    // then have none to clear.
+    unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL,
+            0,
+            0,
+            0,
+        )
+    };
+    // stops at the first capability unknown to the kernel, or without CAP_SETPCAP
+    // SAFETY: prctl with integer arguments
+    for capability in 0.. {
+        if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, capability, 0, 0, 0) } != 0 {
+            break;
+        }
+    }
+    let header = CapHeader {
+        version: CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [
+        CapData {
+            effective: 0,
+            permitted: 0,
+            inheritable: 0,
+        },
+        CapData {
+            effective: 0,
+            permitted: 0,
+            inheritable: 0,
+        },
+    ];
+    // SAFETY: header and data are valid for version 3, which reads two data structs
+    let result =
+        unsafe { libc::syscall(libc::SYS_capset, &header as *const CapHeader, data.as_ptr()) };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error()).context("capset");
+    }
+    Ok(())
+}
+
+/// `AUDIT_ARCH_*` of the current architecture, as reported in `struct seccomp_data`
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc00000b7;
+
+/// System calls which fail with `EPERM` with `--harden`
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_acct,
+    libc::SYS_add_key,
+    libc::SYS_bpf,
+    libc::SYS_chroot,
+    libc::SYS_clock_settime,
+    libc::SYS_delete_module,
+    libc::SYS_finit_module,
+    libc::SYS_init_module,
+    libc::SYS_kexec_load,
+    libc::SYS_keyctl,
+    libc::SYS_mount,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_perf_event_open,
+    libc::SYS_pivot_root,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_ptrace,
+    libc::SYS_reboot,
+    libc::SYS_request_key,
+    libc::SYS_settimeofday,
+    libc::SYS_swapoff,
+    libc::SYS_swapon,
+    libc::SYS_umount2,
+    libc::SYS_userfaultfd,
+];
+
+/// `__X32_SYSCALL_BIT`, set in the number of x32 system calls
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x40000000;
+
+/// Offsets of the fields of `struct seccomp_data`
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const SECCOMP_DATA_NR: u32 = 0;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn bpf_stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// A seccomp program failing [DENIED_SYSCALLS] and system calls of other architectures,
+/// including x32 on x86_64, with `EPERM`, and allowing everything else.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn seccomp_program() -> Vec<libc::sock_filter> {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+    let deny = bpf_stmt(
+        BPF_RET | BPF_K,
+        libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32 & libc::SECCOMP_RET_DATA),
+    );
+    let allow = bpf_stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW);
+    let mut program = vec![
+        bpf_stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_ARCH),
+        // skip the next instruction if the architecture is ours
+        bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+        deny,
+        bpf_stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_NR),
+    ];
+    // x32 system calls have the architecture of x86_64, and their number has this bit set
+    #[cfg(target_arch = "x86_64")]
+    program.push(bpf_jump(
+        BPF_JMP | libc::BPF_JGE | BPF_K,
+        X32_SYSCALL_BIT,
+        (DENIED_SYSCALLS.len() + 1) as u8,
+        0,
+    ));
+    for (i, &syscall) in DENIED_SYSCALLS.iter().enumerate() {
+        // jump to the final deny if equal
+        let to_deny = (DENIED_SYSCALLS.len() - i) as u8;
+        program.push(bpf_jump(
+            BPF_JMP | BPF_JEQ | BPF_K,
+            syscall as u32,
+            to_deny,
+            0,
+        ));
+    }
+    program.push(allow);
+    program.push(deny);
+    program
+}
+
+/// Installs the seccomp filter on the current thread and what it starts.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn install_seccomp_filter() -> anyhow::Result<()> {
+    let mut program = seccomp_program();
+    let fprog = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_mut_ptr(),
+    };
+    // SAFETY: prctl with integer arguments
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("setting no_new_privs");
+    }
+    // SAFETY: fprog points to a valid program of len instructions, which the kernel copies
+    let result = unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            libc::SECCOMP_MODE_FILTER,
+            &fprog as *const libc::sock_fprog,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error()).context("enabling seccomp filter");
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn install_seccomp_filter() -> anyhow::Result<()> {
+    anyhow::bail!("no seccomp filter for this architecture")
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[test]
+fn test_seccomp_filter() {
+    // seccomp only applies to the calling thread
+    std::thread::spawn(|| {
+        let vm_readv = || unsafe {
+            libc::syscall(
+                libc::SYS_process_vm_readv,
+                libc::getpid(),
+                std::ptr::null::<libc::iovec>(),
+                0,
+                std::ptr::null::<libc::iovec>(),
+                0,
+                0,
+            )
+        };
+        if vm_readv() != 0 {
+            eprintln!("skipping: process_vm_readv is already forbidden");
+            return;
+        }
+        if let Err(e) = install_seccomp_filter() {
+            eprintln!("skipping: {:#}", e);
+            return;
+        }
+        assert_eq!(vm_readv(), -1);
+        assert_eq!(
+            std::io::Error::last_os_error().raw_os_error(),
+            Some(libc::EPERM)
+        );
+        // nor through the x32 ABI
+        #[cfg(target_arch = "x86_64")]
+        {
+            let x32 = unsafe {
+                libc::syscall(
+                    libc::SYS_process_vm_readv | X32_SYSCALL_BIT as libc::c_long,
+                    libc::getpid(),
+                    std::ptr::null::<libc::iovec>(),
+                    0,
+                    std::ptr::null::<libc::iovec>(),
+                    0,
+                    0,
+                )
+            };
+            assert_eq!(x32, -1);
+            assert_eq!(
+                std::io::Error::last_os_error().raw_os_error(),
+                Some(libc::EPERM)
+            );
+        }
+        // other system calls still work
+        assert!(std::fs::read_dir("/").is_ok());
+    })
+    .join()
+    .unwrap();
+}
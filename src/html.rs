@@ -0,0 +1,247 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! The html page served at `/`, to check what the server knows about a buildid or store path
+//! from a browser when troubleshooting a debugger setup.
+
+use std::fmt::Write;
+
+use crate::db::{decode_path, Entry};
+
+/// What the page says about the server itself
+#[derive(Debug, Default)]
+pub struct Status {
+    /// whether indexation of new store paths is in progress
+    pub indexing: bool,
+    /// how many buildids are in the cache, if known
+    pub buildids: Option<i64>,
+    /// how many buildids could not be served, if known
+    pub misses: Option<usize>,
+    /// how many seconds ago the last indexation cycle completed, if known
+    pub last_indexation: Option<i64>,
+}
+
+/// Escapes text for inclusion in html, including attribute values.
+pub fn escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Escapes a file name for use as a relative link.
+pub fn percent_encode(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            result.push(byte as char);
+        } else {
+            let _ = write!(result, "%{:02X}", byte);
+        }
+    }
+    result
+}
+
+/// Renders the listing of a directory of a source store path, for `--browse-sources`.
+///
+/// `entries` are the names of the files in the directory, and whether they are directories.
+/// Links are relative, so the url of the page must end with a slash.
+pub fn directory_page(title: &str, entries: &[(String, bool)]) -> String {
+    let title = escape(title);
+    let mut page = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+</head>
+<body>
+<h1>{title}</h1>
+<ul>
+<li><a href="../">../</a></li>
+"#
+    );
+    for (name, is_dir) in entries {
+        let slash = if *is_dir { "/" } else { "" };
+        let _ = writeln!(
+            page,
+            r#"<li><a href="{}{slash}">{}{slash}</a></li>"#,
+            escape(&percent_encode(name)),
+            escape(name),
+        );
+    }
+    page.push_str("</ul>\n</body>\n</html>\n");
+    page
+}
+
+/// Renders the index page.
+///
+/// `query` is what the user looked up with the form, if anything, and `result` the entries
+/// of the cache matching it.
+pub fn index_page(
+    status: &Status,
+    query: Option<&str>,
+    result: &anyhow::Result<Vec<Entry>>,
+) -> String {
+    let mut page = String::new();
+    let unknown = || "unknown".to_owned();
+    // writing to a String cannot fail
+    let _ = write!(
+        page,
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>nixseparatedebuginfod</title>
+</head>
+<body>
+<h1>nixseparatedebuginfod {version}</h1>
+<ul>
+<li>Indexation: {indexation}, last completed: {last_indexation}</li>
+<li>Buildids in cache: {buildids}</li>
+<li>Buildids that could not be served: <a href="/missing">{misses}</a></li>
+<li>API: <a href="/webapi">/webapi</a>, metrics: <a href="/metrics">/metrics</a>, status: <a href="/status">/status</a></li>
+</ul>
+<form method="get" action="/">
+<label>Buildid, file or store path: <input name="q" size="80" value="{query}"></label>
+<button type="submit">Look up</button>
+</form>
+"#,
+        version = env!("CARGO_PKG_VERSION"),
+        indexation = if status.indexing {
+            "in progress"
+        } else {
+            "idle"
+        },
+        last_indexation = status
+            .last_indexation
+            .map_or_else(|| "never".to_owned(), |n| format!("{n} seconds ago")),
+        buildids = status.buildids.map_or_else(unknown, |n| n.to_string()),
+        misses = status.misses.map_or_else(unknown, |n| n.to_string()),
+        query = escape(query.unwrap_or_default()),
+    );
+    if let Some(query) = query {
+        let _ = writeln!(page, "<h2>Results for <code>{}</code></h2>", escape(query));
+        match result {
+            Err(e) => {
+                let _ = writeln!(page, "<p>Error: {}</p>", escape(&format!("{:#}", e)));
+            }
+            Ok(entries) if entries.is_empty() => {
+                page.push_str("<p>Nothing is known about this in the cache.</p>\n");
+            }
+            Ok(entries) => {
+                for entry in entries {
+                    write_entry(&mut page, entry);
+                }
+            }
+        }
+    }
+    page.push_str("</body>\n</html>\n");
+    page
+}
+
+/// Renders what is known about a buildid as a table.
+fn write_entry(page: &mut String, entry: &Entry) {
+    let buildid = escape(&entry.buildid);
+    let _ = write!(
+        page,
+        r#"<h3>Buildid <a href="/buildid/{buildid}/status">{buildid}</a></h3>
+<table>
+<tr><th>Architecture</th><td colspan="2">{}</td></tr>
+"#,
+        escape(entry.architecture.as_deref().unwrap_or("unknown")),
+    );
+    let files = [
+        ("Executable", &entry.executable, Some("executable")),
+        ("Debuginfo", &entry.debuginfo, Some("debuginfo")),
+        ("Source", &entry.source, None),
+        ("Build directory", &entry.build_source, None),
+    ];
+    for (name, path, endpoint) in files {
+        let (path, on_disk) = match path {
+            None => ("unknown".to_owned(), String::new()),
+            Some(path) => {
+                let path = decode_path(path);
+                let on_disk = if path.exists() {
+                    "on disk"
+                } else {
+                    "not on disk, will be fetched on request"
+                };
+                (
+                    format!("<code>{}</code>", escape(&path.to_string_lossy())),
+                    on_disk.to_owned(),
+                )
+            }
+        };
+        let name = match endpoint {
+            Some(endpoint) => format!(r#"<a href="/buildid/{buildid}/{endpoint}">{name}</a>"#),
+            None => name.to_owned(),
+        };
+        let _ = writeln!(
+            page,
+            "<tr><th>{name}</th><td>{path}</td><td>{on_disk}</td></tr>"
+        );
+    }
+    page.push_str("</table>\n");
+}
+
+#[test]
+fn test_escape() {
+    assert_eq!(
+        escape(r#"<a href="x">&'</a>"#),
+        "&lt;a href=&quot;x&quot;&gt;&amp;&#39;&lt;/a&gt;"
+    );
+}
+
+#[test]
+fn test_directory_page() {
+    assert_eq!(percent_encode("a b#?.c"), "a%20b%23%3F.c");
+    let page = directory_page(
+        "/nix/store/aaaa-source/src",
+        &[("include".to_owned(), true), ("<main>.c".to_owned(), false)],
+    );
+    assert!(page.contains(r#"<a href="include/">include/</a>"#));
+    assert!(page.contains(r#"<a href="%3Cmain%3E.c">&lt;main&gt;.c</a>"#));
+}
+
+#[test]
+fn test_index_page() {
+    let status = Status {
+        indexing: true,
+        buildids: Some(42),
+        misses: None,
+        last_indexation: Some(7),
+    };
+    let page = index_page(&status, None, &Ok(vec![]));
+    assert!(page.contains("in progress"));
+    assert!(page.contains("Buildids in cache: 42"));
+    assert!(page.contains("last completed: 7 seconds ago"));
+    assert!(!page.contains("Results"));
+
+    let entry = Entry {
+        buildid: "abcd".to_owned(),
+        executable: Some("/nix/store/aaaa-<x>/bin/x".to_owned()),
+        debuginfo: None,
+        source: None,
+        build_source: None,
+        architecture: Some("x86_64-le".to_owned()),
+    };
+    let page = index_page(&status, Some("<abcd>"), &Ok(vec![entry]));
+    assert!(page.contains("value=\"&lt;abcd&gt;\""));
+    assert!(page.contains("/buildid/abcd/status"));
+    assert!(page.contains("/nix/store/aaaa-&lt;x&gt;/bin/x"));
+    assert!(page.contains("not on disk"));
+    assert!(!page.contains("<x>"));
+
+    let page = index_page(&status, Some("abcd"), &Err(anyhow::anyhow!("oops")));
+    assert!(page.contains("Error: oops"));
+}
@@ -4,24 +4,90 @@
 
 //! Utilities to scan new store paths for buildids as they appear and populate the cache with them
 
-use crate::db::{Cache, Entry, Id};
+use crate::db::{decode_path, encode_path, Cache, Entry, Event, Id, Indexed, SourceRoots};
+use crate::eager::EagerRealiser;
+use crate::filter::IndexFilter;
 use crate::log::ResultExt;
-use crate::store::{get_store_path, index_store_path};
+use crate::newpaths::NewPaths;
+use crate::nixdb::PathInfo;
+use crate::store::{get_store_path, index_store_path, ParsedInodes};
 use anyhow::Context;
 use futures_util::{future::join_all, stream::FuturesOrdered, FutureExt, StreamExt};
-use sqlx::sqlite::SqliteConnectOptions;
-use sqlx::{ConnectOptions, Connection, Row};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::Mutex;
-use tokio::sync::{mpsc::Sender, Semaphore};
-use tokio::task::JoinHandle;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{watch, Semaphore};
 
 /// enqueue indexing of this many store paths at the same time
 const BATCH_SIZE: usize = 100;
 /// index at most thie many store paths at the same time
 const N_WORKERS: usize = 8;
+/// remember at most this many store paths indexed out of order
+const MAX_PRIORITIZED: usize = 100_000;
+
+/// Progress of the indexation task started by [StoreWatcher::watch_store]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Progress {
+    /// how many indexation cycles completed since startup
+    generation: u64,
+    /// whether a cycle is in progress
+    indexing: bool,
+    /// whether the last completed cycle succeeded
+    ok: bool,
+}
+
+/// Another machine's store, whose filesystem is mounted (possibly read only) at `root`, as
+/// specified by `--store NAME=ROOT`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForeignStore {
+    /// name of the store, used in urls and host names
+    pub name: String,
+    /// the directory containing `nix/store` and `nix/var/nix/db` of this store
+    pub root: PathBuf,
+}
+
+impl FromStr for ForeignStore {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (name, root) = match s.split_once('=') {
+            Some(x) => x,
+            None => anyhow::bail!("expected NAME=ROOT, got {:?}", s),
+        };
+        anyhow::ensure!(
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+            "store name {:?} should only contain letters, digits, - and _",
+            name
+        );
+        let root = PathBuf::from(root);
+        anyhow::ensure!(
+            root.is_absolute(),
+            "store root {} should be absolute",
+            root.display()
+        );
+        Ok(ForeignStore {
+            name: name.to_owned(),
+            root,
+        })
+    }
+}
+
+/// Where `path`, as recorded in the nix db of a store whose filesystem is mounted at `root`, can
+/// be found on the local filesystem.
+pub fn relocate(root: &Path, path: &Path) -> PathBuf {
+    match path.strip_prefix("/nix/store") {
+        Ok(relative) if root != Path::new("/") => root.join("nix/store").join(relative),
+        _ => path.to_path_buf(),
+    }
+}
 
 #[derive(Clone)]
 /// A helper to examine all new store paths in parallel.
@@ -33,67 +99,295 @@ pub struct StoreWatcher {
     ///
     /// this prevents too many open file errors
     semaphore: Arc<Semaphore>,
-    /// Locked when self.index_new_paths is running.
-    working: Arc<Mutex<()>>,
+    /// wakes up the indexation task, see [StoreWatcher::wait_for_cycle]
+    wake: Sender<()>,
+    /// receiving end of `wake`, taken by the indexation task
+    woken: Arc<std::sync::Mutex<Option<Receiver<()>>>>,
+    /// published by the indexation task
+    progress: Arc<watch::Sender<Progress>>,
+    /// whether all the store paths present on startup were indexed
+    ready: Arc<AtomicBool>,
+    /// which store paths to index
+    filter: Arc<IndexFilter>,
+    /// store paths already indexed by [StoreWatcher::index_roots], to skip when bulk indexation
+    /// reaches them
+    ///
+    /// Cleared after each successful cycle, because bulk indexation never reaches paths older
+    /// than where it started; newer paths are then at worst indexed twice.
+    prioritized: Arc<std::sync::Mutex<HashSet<PathBuf>>>,
+    /// store paths deleted while they were indexed, to check again on the next cycle
+    requeued: Arc<std::sync::Mutex<HashSet<PathBuf>>>,
+    /// where the filesystem of the indexed store is mounted, `/` for the local store
+    root: Arc<PathBuf>,
+    /// how new store paths are found, chosen when indexation starts
+    new_paths: Arc<tokio::sync::OnceCell<NewPaths>>,
+    /// realises the missing debug outputs of indexed executables, if enabled
+    eager: Option<EagerRealiser>,
+    /// when the last indexation cycle completed, and the next id to index at that time
+    last_cycle: Arc<std::sync::Mutex<Option<(Instant, Id)>>>,
 }
 
 impl StoreWatcher {
-    /// Creates a [`StoreWatcher`] that populates the specified cache.
+    /// Creates a [`StoreWatcher`] that populates the specified cache with the store paths
+    /// allowed by `filter`.
     ///
     /// To start it call [StoreWatcher::watch_store].
-    pub fn new(cache: Cache) -> Self {
+    pub fn new(cache: Cache, filter: IndexFilter) -> Self {
+        // a single pending wake up is enough for any number of requests
+        let (wake, woken) = tokio::sync::mpsc::channel(1);
         Self {
             cache,
             semaphore: Arc::new(Semaphore::new(N_WORKERS)),
-            working: Arc::new(Mutex::new(())),
+            wake,
+            woken: Arc::new(std::sync::Mutex::new(Some(woken))),
+            progress: Arc::new(watch::Sender::new(Progress::default())),
+            ready: Arc::new(AtomicBool::new(false)),
+            filter: Arc::new(filter),
+            prioritized: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            requeued: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            root: Arc::new(PathBuf::from("/")),
+            new_paths: Arc::new(tokio::sync::OnceCell::new()),
+            eager: None,
+            last_cycle: Arc::new(std::sync::Mutex::new(None)),
         }
     }
 
-    /// Index new store paths if there are new store paths.
+    /// Creates a [`StoreWatcher`] that populates the specified cache with the store paths of
+    /// another machine's store, read from its nix db without `nix-store`.
     ///
-    /// If there are none, returns Ok(None).
-    /// If there are some, starts a future to index them, and returns a JoinHandle to
-    /// optionnally wait for completion of the indexation.
-    pub async fn maybe_index_new_paths(&self) -> anyhow::Result<Option<JoinHandle<()>>> {
+    /// Paths in the cache are where the files can be found on this machine.
+    pub fn new_foreign(cache: Cache, filter: IndexFilter, store: &ForeignStore) -> Self {
+        Self {
+            root: Arc::new(store.root.clone()),
+            ..Self::new(cache, filter)
+        }
+    }
+
+    /// Realises the missing debug outputs of the executables indexed from now on in the
+    /// background, with `--eager-debuginfo-mb`.
+    pub fn with_eager_realiser(self, eager: EagerRealiser) -> Self {
+        Self {
+            eager: Some(eager),
+            ..self
+        }
+    }
+
+    /// Where the filesystem of the indexed store is mounted: `/` for the local store
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Whether this watcher indexes the local store
+    fn is_local(&self) -> bool {
+        self.root.as_path() == Path::new("/")
+    }
+
+    /// Queues the realisation of the debug outputs of these executables which are not in the
+    /// store, if enabled.
+    fn queue_missing_debug_outputs(&self, indexed: &[Indexed]) {
+        let Some(eager) = &self.eager else {
+            return;
+        };
+        for indexed in indexed {
+            let Indexed::Build(Entry {
+                executable: Some(_),
+                debuginfo: Some(debuginfo),
+                ..
+            }) = indexed
+            else {
+                continue;
+            };
+            if let Some(output) = get_store_path(&decode_path(debuginfo)) {
+                if !output.exists() {
+                    eager.queue(output);
+                }
+            }
+        }
+    }
+
+    /// Registers entries in the cache, after relocating the store paths they refer to if the
+    /// store is not local.
+    async fn register_indexed(&self, indexed: &[Indexed]) -> anyhow::Result<()> {
+        if self.is_local() {
+            self.queue_missing_debug_outputs(indexed);
+            return self.cache.register_indexed(indexed).await;
+        }
+        let relocate_str = |path: &str| encode_path(&relocate(&self.root, &decode_path(path)));
+        let relocate = |path: &Option<String>| path.as_deref().map(relocate_str);
+        let relocated: Vec<Indexed> = indexed
+            .iter()
+            .map(|indexed| match indexed {
+                Indexed::Build(entry) => Indexed::Build(Entry {
+                    source: relocate(&entry.source),
+                    build_source: relocate(&entry.build_source),
+                    ..entry.clone()
+                }),
+                Indexed::SourceRoots(roots) => Indexed::SourceRoots(SourceRoots {
+                    source: relocate_str(&roots.source),
+                    roots: roots.roots.iter().map(|root| relocate_str(root)).collect(),
+                }),
+                other => other.clone(),
+            })
+            .collect();
+        self.cache.register_indexed(&relocated).await
+    }
+
+    /// Whether indexation of new store paths is in progress
+    pub fn is_indexing(&self) -> bool {
+        self.progress.borrow().indexing
+    }
+
+    /// Whether the store paths present on startup were all indexed, so that buildids are not
+    /// missed because indexation has not reached them yet
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    /// How long ago the last indexation cycle completed, and the id of the last store path it
+    /// indexed, or `None` if no cycle has completed since startup
+    pub fn last_cycle(&self) -> Option<(Duration, Id)> {
+        let last_cycle = *self.last_cycle.lock().unwrap();
+        last_cycle.map(|(at, next_id)| (at.elapsed(), next_id.saturating_sub(1)))
+    }
+
+    /// Records that an indexation cycle completed, up to `next_id` excluded.
+    fn record_cycle(&self, next_id: Id) {
+        *self.last_cycle.lock().unwrap() = Some((Instant::now(), next_id));
+    }
+
+    /// How new store paths are found, chosen on first use.
+    async fn new_paths(&self) -> &NewPaths {
+        self.new_paths
+            .get_or_init(|| async {
+                let new_paths = NewPaths::detect(&self.root).await;
+                tracing::debug!("finding new store paths with {}", new_paths.name());
+                self.cache
+                    .set_id_source(new_paths.name())
+                    .await
+                    .context("recording how new store paths are found")
+                    .or_warn();
+                new_paths
+            })
+            .await
+    }
+
+    /// Reads the next store paths to index, of id greater or equal to `from_id`.
+    ///
+    /// Returns the id you should call this function with for the "next" paths.
+    async fn get_new_store_path_batch(&self, from_id: Id) -> anyhow::Result<(Vec<PathBuf>, Id)> {
+        self.new_paths()
+            .await
+            .batch(&self.root, from_id, BATCH_SIZE)
+            .await
+    }
+
+    /// Shows the progress of indexation of the local store in `systemctl status`.
+    fn notify_status(&self, status: &str) {
+        if self.is_local() {
+            crate::notify::status(status);
+        }
+    }
+
+    /// Indexes all the store paths registered since the last cycle.
+    ///
+    /// Cycles must not run concurrently: the server only runs them in the task started by
+    /// [StoreWatcher::watch_store], and requests wait for them with
+    /// [StoreWatcher::wait_for_cycle].
+    pub async fn index_cycle(&self) -> anyhow::Result<()> {
+        // choosing how to find new store paths may reset the next id
+        self.new_paths().await;
         let start = self
             .cache
             .get_next_id()
             .await
             .context("reading cache next id")?;
-        let (paths, end) = get_new_store_path_batch(start)
+        let (paths, end) = self
+            .get_new_store_path_batch(start)
             .await
             .context("looking for new paths registered in the nix store")?;
-        if paths.is_empty() {
-            Ok(None)
-        } else {
-            let cloned_self = self.clone();
-            Ok(Some(tokio::spawn(async move {
-                let guard = cloned_self.working.lock().await;
-                // it's possible that we had to wait a lot for this lock and that more indexation
-                // was done in between.
-                match cloned_self.cache.get_next_id().await {
-                    Err(e) => tracing::warn!(
-                        "reading next id from sqlite db: {:#}, dropping indexation request",
-                        e
-                    ),
-                    Ok(new_start) => {
-                        if new_start == start {
-                            cloned_self.index_new_paths(paths, end).await;
-                        } else {
-                            // indexation was already in progress when we started waiting for the
-                            // lock. Now that we got the lock, indexation is complete and there is
-                            // nothing to do.
-                            tracing::info!("indexation already complete");
-                        }
-                    }
-                }
-                drop(guard);
-            })))
+        if !paths.is_empty() {
+            self.cache.record_event(Event::IndexStart).await.or_warn();
         }
+        self.index_new_paths(paths, end).await;
+        self.ready.store(true, Ordering::SeqCst);
+        let next_id = self
+            .cache
+            .get_next_id()
+            .await
+            .context("reading cache next id")?;
+        self.record_cycle(next_id);
+        self.cache.record_event(Event::IndexEnd).await.or_warn();
+        Ok(())
+    }
+
+    /// Wakes up the task started by [StoreWatcher::watch_store] so that it indexes new store
+    /// paths, and waits until it completes a cycle which started after this call: a cycle
+    /// already in progress may have missed the newest store paths.
+    ///
+    /// Concurrent calls share the same cycle. Returns whether it succeeded.
+    pub async fn wait_for_cycle(&self) -> bool {
+        let mut progress = self.progress.subscribe();
+        let (seen, indexing) = {
+            let progress = progress.borrow_and_update();
+            (progress.generation, progress.indexing)
+        };
+        let target = if indexing { seen + 2 } else { seen + 1 };
+        // if the channel is full, a wake up is already pending
+        let _ = self.wake.try_send(());
+        // the sender is in self, so this only fails if the cycle failed
+        progress
+            .wait_for(|progress| progress.generation >= target)
+            .await
+            .is_ok_and(|progress| progress.ok)
+    }
+
+    /// Reads what the nix db knows about the derivers of these store paths, to avoid running
+    /// `nix-store` for each of them during indexation, and records their nar hashes in the
+    /// cache.
+    ///
+    /// Returns an empty map on failure, so that indexation falls back to `nix-store`.
+    async fn get_path_infos(&self, paths: &[PathBuf]) -> HashMap<PathBuf, PathInfo> {
+        let infos = match crate::nixdb::get_path_infos(&self.root, paths).await {
+            Ok(infos) => infos,
+            Err(e) => {
+                tracing::warn!(
+                    "cannot read derivers in nix db, falling back to nix-store: {:#}",
+                    e
+                );
+                return HashMap::new();
+            }
+        };
+        let hashes: Vec<(String, String)> = infos
+            .iter()
+            .filter_map(|(path, info)| {
+                let path = relocate(&self.root, path);
+                Some((path.to_str()?.to_owned(), info.nar_hash.clone()?))
+            })
+            .collect();
+        self.cache
+            .register_nar_hashes(&hashes)
+            .await
+            .context("registering nar hashes")
+            .or_warn();
+        infos
     }
 
     /// Indexes a single store path, and sends found buildids to this sender
-    async fn index_store_path(&self, path: PathBuf, sendto: Sender<Entry>) {
+    ///
+    /// `parsed` is shared by the store paths of a batch.
+    ///
+    /// Does nothing if the store path was already indexed by [StoreWatcher::index_roots] or
+    /// [StoreWatcher::index_now].
+    async fn index_store_path(
+        &self,
+        path: PathBuf,
+        info: Option<PathInfo>,
+        sendto: Sender<Indexed>,
+        parsed: ParsedInodes,
+    ) {
+        if self.prioritized.lock().unwrap().remove(&path) {
+            return;
+        }
         let path2 = path.clone();
         let permit = self
             .semaphore
@@ -101,23 +395,187 @@ impl StoreWatcher {
             .acquire_owned()
             .await
             .expect("closed semaphore");
-        tokio::task::spawn_blocking(move || {
-            index_store_path(path.as_path(), sendto, true);
+        let semaphore = self.semaphore.clone();
+        let runtime = tokio::runtime::Handle::current();
+        let filter = self.filter.clone();
+        let path = relocate(&self.root, &path);
+        let info = info.map(|info| PathInfo {
+            deriver: info.deriver.map(|deriver| relocate(&self.root, &deriver)),
+            deriver_outputs: info.deriver_outputs.map(|outputs| {
+                outputs
+                    .iter()
+                    .map(|output| relocate(&self.root, output))
+                    .collect()
+            }),
+            ..info
+        });
+        let complete = tokio::task::spawn_blocking(move || {
+            let _task = crate::diagnostics::track(|| format!("indexing {}", path.display()));
+            let mut permit = Some(permit);
+            // give our turn to store paths waiting for a permit, as the semaphore is fair
+            let mut pause = || {
+                permit = None;
+                permit = Some(
+                    runtime
+                        .block_on(semaphore.clone().acquire_owned())
+                        .expect("closed semaphore"),
+                );
+            };
+            let complete = index_store_path(
+                path.as_path(),
+                sendto,
+                true,
+                info,
+                &filter,
+                &parsed,
+                &mut pause,
+            );
             drop(permit);
+            complete
         })
         .await
-        .with_context(|| format!("examining {} failed", path2.as_path().display()))
-        .or_warn();
+        .with_context(|| format!("examining {} failed", path2.as_path().display()));
+        match complete {
+            Ok(true) => (),
+            Ok(false) => {
+                self.requeued.lock().unwrap().insert(path2);
+            }
+            Err(e) => tracing::warn!("{:#}", e),
+        }
+    }
+
+    /// Indexes again the store paths which were deleted while they were indexed, if they
+    /// exist again, for example because they were substituted again.
+    ///
+    /// Their id in the nix db changed, but indexation may already be past it.
+    async fn index_requeued(&self) {
+        let requeued = std::mem::take(&mut *self.requeued.lock().unwrap());
+        let paths: Vec<PathBuf> = requeued
+            .into_iter()
+            .filter(|path| relocate(&self.root, path).exists())
+            .collect();
+        if paths.is_empty() {
+            return;
+        }
+        tracing::info!(
+            "Indexing again {} store paths deleted during indexation",
+            paths.len()
+        );
+        self.index_paths(paths).await;
+    }
+
+    /// Indexes the store paths reachable from profiles and gc roots whose id is at least
+    /// `from_id`, so that the software users are most likely to debug is available before the
+    /// rest of the store is indexed.
+    async fn index_roots(&self, from_id: Id) {
+        // ids are those of the nix db
+        if !self.is_local() || !matches!(self.new_paths().await, NewPaths::NixDb) {
+            return;
+        }
+        let roots = match tokio::task::spawn_blocking(gc_roots).await {
+            Ok(roots) => roots,
+            Err(e) => {
+                tracing::warn!("listing gc roots: {:#}", e);
+                return;
+            }
+        };
+        let paths = match crate::nixdb::get_closure(&roots, from_id).await {
+            Ok(paths) => paths,
+            Err(e) => {
+                tracing::warn!("reading closure of gc roots in nix db: {:#}", e);
+                return;
+            }
+        };
+        if paths.is_empty() {
+            return;
+        }
+        tracing::info!(
+            "Indexing {} store paths reachable from profiles and gc roots first",
+            paths.len()
+        );
+        if self.index_prioritized(paths).await {
+            tracing::info!("Done indexing store paths reachable from profiles and gc roots");
+        }
+    }
+
+    /// Indexes these store paths immediately, for example because they were just built, instead
+    /// of waiting for indexation to find them in the nix db.
+    pub async fn index_now(&self, paths: Vec<PathBuf>) -> anyhow::Result<()> {
+        tracing::info!("Indexing {} store paths on request", paths.len());
+        anyhow::ensure!(
+            self.index_prioritized(paths).await,
+            "cannot write entries to sqlite db"
+        );
+        Ok(())
+    }
+
+    /// Indexes these store paths, and remembers them so that indexation of new store paths
+    /// skips them.
+    ///
+    /// Returns whether all entries could be written to the cache.
+    async fn index_prioritized(&self, paths: Vec<PathBuf>) -> bool {
+        let ok = self.index_paths(paths.clone()).await;
+        if ok {
+            let mut prioritized = self.prioritized.lock().unwrap();
+            if prioritized.len() + paths.len() > MAX_PRIORITIZED {
+                // at worst these paths are indexed twice
+                prioritized.clear();
+            }
+            prioritized.extend(paths);
+        }
+        ok
+    }
+
+    /// Indexes these store paths now.
+    ///
+    /// Returns whether all entries could be written to the cache.
+    async fn index_paths(&self, paths: Vec<PathBuf>) -> bool {
+        let mut infos = HashMap::new();
+        for chunk in paths.chunks(BATCH_SIZE) {
+            infos.extend(self.get_path_infos(chunk).await);
+        }
+        let (entries_tx, mut entries_rx) = tokio::sync::mpsc::channel(3 * BATCH_SIZE);
+        let parsed = ParsedInodes::default();
+        let batch: Vec<_> = paths
+            .iter()
+            .map(|path| {
+                let info = infos.remove(path);
+                self.index_store_path(path.clone(), info, entries_tx.clone(), parsed.clone())
+            })
+            .collect();
+        drop(entries_tx);
+        let register = async {
+            let mut ok = true;
+            let mut entry_buffer = Vec::with_capacity(BATCH_SIZE);
+            loop {
+                let entry = entries_rx.recv().await;
+                let done = entry.is_none();
+                entry_buffer.extend(entry);
+                if done || entry_buffer.len() >= BATCH_SIZE {
+                    if let Err(e) = self.register_indexed(&entry_buffer).await {
+                        tracing::warn!("cannot write entries to sqlite db: {:#}", e);
+                        ok = false;
+                    }
+                    entry_buffer.clear();
+                }
+                if done {
+                    return ok;
+                }
+            }
+        };
+        let (_, ok) = tokio::join!(join_all(batch), register);
+        ok
     }
 
     /// Indexes all new store paths in the store by batches.
     ///
-    /// Arguments are the first batch, as returned by [get_new_store_path_batch]
+    /// Arguments are the first batch, as returned by [StoreWatcher::get_new_store_path_batch]
     async fn index_new_paths(&self, paths: Vec<PathBuf>, id: Id) {
         if paths.is_empty() {
             return;
         };
         tracing::info!("Starting indexation of new store paths");
+        self.notify_status("indexing new store paths");
         let start = self.cache.get_next_id().await.unwrap_or(0);
         if start >= id {
             tracing::error!(
@@ -128,14 +586,25 @@ impl StoreWatcher {
             );
             return;
         }
+        if paths.len() >= BATCH_SIZE {
+            // there is a lot to index, let's start with what users are likely to debug
+            self.index_roots(start).await;
+        }
         tracing::debug!(size = paths.len(), end = id, start = start, "First batch");
         let (entries_tx, mut entries_rx) = tokio::sync::mpsc::channel(3 * BATCH_SIZE);
+        let mut infos = self.get_path_infos(&paths).await;
+        let parsed = ParsedInodes::default();
         let batch: Vec<_> = paths
             .into_iter()
-            .map(|path| self.index_store_path(path, entries_tx.clone()))
+            .map(|path| {
+                let info = infos.remove(&path);
+                self.index_store_path(path, info, entries_tx.clone(), parsed.clone())
+            })
             .collect();
-        let batch_handle = join_all(batch).map(move |_| id).boxed();
+        let batch_len = batch.len();
+        let batch_handle = join_all(batch).map(move |_| (id, batch_len)).boxed();
         let mut max_id = id;
+        let mut indexed = 0;
         let mut unfinished_batches = FuturesOrdered::new();
         unfinished_batches.push_back(batch_handle);
         let mut entry_buffer = Vec::with_capacity(BATCH_SIZE);
@@ -147,7 +616,7 @@ impl StoreWatcher {
                         Some(entry) => {
                             entry_buffer.push(entry);
                             if entry_buffer.len() >= BATCH_SIZE {
-                                match self.cache.register(&entry_buffer).await {
+                                match self.register_indexed(&entry_buffer).await {
                                     Ok(()) => entry_buffer.clear(),
                                     Err(e) => tracing::warn!("cannot write entries to sqlite db: {:#}", e),
                                 }
@@ -156,23 +625,26 @@ impl StoreWatcher {
                         None => tracing::warn!("entries_rx closed"),
                     }
                 }
-                id = unfinished_batches.next() => {
-                    match id {
-                        Some(id) => {
-                            match self.cache.register(&entry_buffer).await {
+                batch = unfinished_batches.next() => {
+                    match batch {
+                        Some((id, batch_len)) => {
+                            match self.register_indexed(&entry_buffer).await {
                                 Ok(()) => {
                                     entry_buffer.clear();
                                     self.cache.set_next_id(id).await.context("writing next id").or_warn();
                                     tracing::debug!("batch {} complete", id);
+                                    indexed += batch_len;
+                                    self.notify_status(&format!("indexing new store paths, {} done", indexed));
                                 },
                                 Err(e) => tracing::warn!("cannot write entries to sqlite db: {:#}", e),
                             }
                         },
                         None => {
                             // there are no more running batches
-                            self.cache.register(&entry_buffer).await.context("registering entries").or_warn();
+                            self.register_indexed(&entry_buffer).await.context("registering entries").or_warn();
                             entry_buffer.clear();
                             tracing::info!("Done indexing new store paths");
+                            self.notify_status(&format!("indexed {} new store paths", indexed));
                             return;
                         },
                     }
@@ -180,16 +652,21 @@ impl StoreWatcher {
             }
             if get_new_batches && self.semaphore.available_permits() > 0 {
                 tracing::debug!("considering starting a new batch of store paths to index");
-                let (paths, id) = match get_new_store_path_batch(max_id).await {
+                let (paths, id) = match self.get_new_store_path_batch(max_id).await {
                     Ok(x) => x,
                     Err(e) => {
                         tracing::warn!("cannot read nix store db: {:#}", e);
                         continue;
                     }
                 };
+                let mut infos = self.get_path_infos(&paths).await;
+                let parsed = ParsedInodes::default();
                 let batch: Vec<_> = paths
                     .into_iter()
-                    .map(|path| self.index_store_path(path, entries_tx.clone()))
+                    .map(|path| {
+                        let info = infos.remove(&path);
+                        self.index_store_path(path, info, entries_tx.clone(), parsed.clone())
+                    })
                     .collect();
                 if batch.is_empty() {
                     tracing::debug!("batch is empty");
@@ -201,7 +678,8 @@ impl StoreWatcher {
                         end = id,
                         "Indexing new batch of paths"
                     );
-                    let batch_handle = join_all(batch).map(move |_| id).boxed();
+                    let batch_len = batch.len();
+                    let batch_handle = join_all(batch).map(move |_| (id, batch_len)).boxed();
                     max_id = id;
                     unfinished_batches.push_back(batch_handle);
                 }
@@ -209,78 +687,95 @@ impl StoreWatcher {
         }
     }
 
-    /// starts a task that periodically indexes new store paths in the store.
+    /// starts a task that indexes new store paths in the store every minute, or when woken up
+    /// by [StoreWatcher::wait_for_cycle].
     ///
     /// Returns immediately.
     pub fn watch_store(&self) {
+        let Some(mut woken) = self.woken.lock().unwrap().take() else {
+            tracing::warn!("store {} is already watched", self.root.display());
+            return;
+        };
+        crate::diagnostics::register_semaphore(
+            format!("indexation of store paths in {}", self.root.display()),
+            &self.semaphore,
+            N_WORKERS,
+        );
         let self_clone = self.clone();
         tokio::spawn(async move {
             loop {
-                match self_clone.maybe_index_new_paths().await {
-                    Ok(None) => tokio::time::sleep(Duration::from_secs(60)).await,
-                    Ok(Some(handle)) => {
-                        handle.await.context("waiting for indexation").or_warn();
-                        tokio::time::sleep(Duration::from_secs(60)).await;
-                    }
-                    Err(e) => {
-                        tracing::warn!("while watching store for new paths: {:#}", e);
-                        tokio::time::sleep(Duration::from_secs(1)).await;
-                    }
+                self_clone.index_requeued().await;
+                self_clone
+                    .progress
+                    .send_modify(|progress| progress.indexing = true);
+                let result = self_clone.index_cycle().await;
+                if result.is_ok() {
+                    self_clone.prioritized.lock().unwrap().clear();
+                }
+                if let Err(e) = &result {
+                    tracing::warn!("while watching store for new paths: {:#}", e);
+                }
+                self_clone.progress.send_modify(|progress| {
+                    progress.generation += 1;
+                    progress.indexing = false;
+                    progress.ok = result.is_ok();
+                });
+                let pause = if result.is_ok() { 60 } else { 1 };
+                tokio::select! {
+                    _ = woken.recv() => (),
+                    _ = tokio::time::sleep(Duration::from_secs(pause)) => (),
                 }
             }
         });
     }
 }
 
-/// Reads the nix db to find new store paths.
-///
-/// New store paths are paths of id greater or equal to `from_id`.
-///
-/// Returns the id you should call this function with for the "next" paths.
-async fn get_new_store_path_batch(from_id: Id) -> anyhow::Result<(Vec<PathBuf>, Id)> {
-    // note: this is a hack. One cannot open a sqlite db read only with WAL if the underlying
-    // file is not writable. So we promise sqlite that the db will not be modified with
-    // immutable=1, but it's false.
-    let mut db = SqliteConnectOptions::new()
-        .filename("/nix/var/nix/db/db.sqlite")
-        .immutable(true)
-        .read_only(true)
-        .connect()
-        .await
-        .context("opening nix db")?;
-    let rows =
-        sqlx::query("select path, id from ValidPaths where id >= $1 order by id asc limit $2")
-            .bind(from_id)
-            .bind(BATCH_SIZE as u32)
-            .fetch_all(&mut db)
-            .await
-            .context("reading nix db")?;
-    let mut paths = Vec::new();
-    let mut max_id = 0;
-    for row in rows {
-        let path: &str = row.try_get("path").context("parsing path in nix db")?;
-        let path = match get_store_path(Path::new(path)) {
-            Some(path) => path,
-            None => anyhow::bail!(
-                "read corrupted stuff from nix db: {}, concurrent write?",
-                path
-            ),
-        };
-        paths.push(PathBuf::from(path));
-        let id: Id = row.try_get("id").context("parsing id in nix db")?;
-        max_id = id.max(max_id);
+/// Adds the symlinks in this directory to `out`, except generations of profiles, which are
+/// superseded by the profile itself.
+fn push_links(dir: &Path, out: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        if !entry.file_name().as_bytes().ends_with(b"-link") {
+            out.push(entry.path());
+        }
+    }
+}
+
+/// Store paths that users are likely to debug: the current system, profiles, and gc roots
+/// like `result` symlinks of `nix-build`.
+pub fn gc_roots() -> Vec<PathBuf> {
+    let mut links = vec![
+        PathBuf::from("/run/current-system"),
+        PathBuf::from("/run/booted-system"),
+    ];
+    push_links(Path::new("/nix/var/nix/profiles"), &mut links);
+    if let Ok(users) = std::fs::read_dir("/nix/var/nix/profiles/per-user") {
+        for user in users.flatten() {
+            push_links(&user.path(), &mut links);
+        }
     }
-    // As we lie about the database being immutable let's not keep the connection open
-    db.close().await.context("closing nix db").or_warn();
-    if (max_id == 0) ^ paths.is_empty() {
-        anyhow::bail!("read paths with id == 0...");
+    push_links(Path::new("/etc/profiles/per-user"), &mut links);
+    push_links(Path::new("/nix/var/nix/gcroots/auto"), &mut links);
+    if let Some(home) = std::env::var_os("HOME") {
+        let home = PathBuf::from(home);
+        links.push(home.join(".nix-profile"));
+        push_links(&home.join(".local/state/nix/profiles"), &mut links);
     }
-    Ok((paths, max_id + 1))
+    let roots: BTreeSet<PathBuf> = links
+        .iter()
+        .filter_map(|link| std::fs::canonicalize(link).ok())
+        .filter_map(|target| get_store_path(&target).map(Path::to_path_buf))
+        .collect();
+    roots.into_iter().collect()
 }
 
 /// Index this path, but harder than automatic indexation
 ///
-/// Specifically, this is allowed to download the .drv file from a cache.
+/// Specifically, this is allowed to download the .drv file from a cache, and
+/// `--index-allow`/`--index-deny` do not apply.
 pub async fn index_single_store_path_to_cache(
     cache: &Cache,
     path: &Path,
@@ -288,22 +783,133 @@ pub async fn index_single_store_path_to_cache(
 ) -> anyhow::Result<()> {
     let (tx, mut rx) = tokio::sync::mpsc::channel(BATCH_SIZE);
     let path = path.to_path_buf();
-    let handle = tokio::task::spawn_blocking(move || index_store_path(&path, tx, !online));
+    let handle = tokio::task::spawn_blocking(move || {
+        index_store_path(
+            &path,
+            tx,
+            !online,
+            None,
+            &IndexFilter::default(),
+            &ParsedInodes::default(),
+            &mut || (),
+        )
+    });
     let mut batch = Vec::new();
     while let Some(entry) = rx.recv().await {
         batch.push(entry);
         if batch.len() > BATCH_SIZE {
             cache
-                .register(&batch)
+                .register_indexed(&batch)
                 .await
                 .context("registering new entries")?;
             batch.clear();
         }
     }
     cache
-        .register(&batch)
+        .register_indexed(&batch)
         .await
         .context("registering new entries")?;
     handle.await?;
     Ok(())
 }
+
+#[test]
+fn test_foreign_store() {
+    let store: ForeignStore = "alice=/mnt/alice".parse().unwrap();
+    assert_eq!(
+        store,
+        ForeignStore {
+            name: "alice".to_owned(),
+            root: PathBuf::from("/mnt/alice"),
+        }
+    );
+    assert!("alice".parse::<ForeignStore>().is_err());
+    assert!("alice=mnt".parse::<ForeignStore>().is_err());
+    assert!("a.b=/mnt".parse::<ForeignStore>().is_err());
+
+    let path = Path::new("/nix/store/aaaa-hello/bin/hello");
+    assert_eq!(
+        relocate(&store.root, path),
+        Path::new("/mnt/alice/nix/store/aaaa-hello/bin/hello")
+    );
+    assert_eq!(relocate(Path::new("/"), path), path);
+    assert_eq!(
+        relocate(&store.root, Path::new("/mnt/alice/nix/store/aaaa-hello")),
+        Path::new("/mnt/alice/nix/store/aaaa-hello")
+    );
+}
+
+#[tokio::test]
+async fn test_wait_for_cycle() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let watcher = StoreWatcher::new(cache, IndexFilter::default());
+    let empty = serde_json::from_str("[]").unwrap();
+    let listing = tokio::sync::Mutex::new(Some(crate::newpaths::Listed::new(&watcher.root, empty)));
+    assert!(watcher.new_paths.set(NewPaths::PathInfo(listing)).is_ok());
+    watcher.watch_store();
+    let waits: Vec<_> = (0..10).map(|_| watcher.wait_for_cycle()).collect();
+    assert!(join_all(waits).await.into_iter().all(|ok| ok));
+    assert!(watcher.is_ready());
+    // concurrent requests share cycles
+    assert!(watcher.progress.borrow().generation <= 2);
+    assert!(watcher.wait_for_cycle().await);
+}
+
+#[tokio::test]
+async fn test_wait_for_cycle_in_progress() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let watcher = StoreWatcher::new(cache, IndexFilter::default());
+    watcher
+        .progress
+        .send_modify(|progress| progress.indexing = true);
+    let wait = tokio::spawn({
+        let watcher = watcher.clone();
+        async move { watcher.wait_for_cycle().await }
+    });
+    let complete_cycle = || {
+        watcher.progress.send_modify(|progress| {
+            progress.generation += 1;
+            progress.ok = true;
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    // the cycle in progress may have missed new store paths
+    complete_cycle();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!wait.is_finished());
+    complete_cycle();
+    assert!(wait.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_queue_missing_debug_outputs() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let eager = EagerRealiser::test_queue();
+    let watcher =
+        StoreWatcher::new(cache, IndexFilter::default()).with_eager_realiser(eager.clone());
+    let entry = |executable: Option<&str>, debuginfo: &str| {
+        Indexed::Build(Entry {
+            buildid: "abcd".to_owned(),
+            executable: executable.map(str::to_owned),
+            debuginfo: Some(debuginfo.to_owned()),
+            source: None,
+            build_source: None,
+            architecture: None,
+        })
+    };
+    watcher.queue_missing_debug_outputs(&[
+        entry(
+            Some("/nix/store/aaaa-foo/bin/foo"),
+            "/nix/store/bbbb-foo-debug/lib/debug/.build-id/ab/cd.debug",
+        ),
+        // debug outputs found by themselves are already present
+        entry(
+            None,
+            "/nix/store/cccc-bar-debug/lib/debug/.build-id/ab/cd.debug",
+        ),
+    ]);
+    assert_eq!(
+        eager.take_queued(),
+        vec![PathBuf::from("/nix/store/bbbb-foo-debug")]
+    );
+}
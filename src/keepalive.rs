@@ -0,0 +1,312 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Keeping clients alive while the file they requested is realised, with `--keep-alive-after`.
+//!
+//! Debuginfod clients give up when they receive too little for too long: libdebuginfod aborts
+//! downloads slower than 100KiB/s for `DEBUGINFOD_TIMEOUT` seconds, and realising a large debug
+//! output takes longer than that. When the response is not ready in time, the client accepts
+//! gzip, and computing the response started to [realise](crate::store::realise) a store path,
+//! the response starts right away with `Content-Encoding: gzip`, and empty deflate blocks,
+//! which decompress to nothing, are sent until the file is available. The file then follows in
+//! stored (uncompressed) deflate blocks.
+//!
+//! The status and headers of such a response are sent before the file is known to exist: it is
+//! `200 OK`, and if the file turns out not to be served, the transfer is aborted, and the client
+//! sees a failed download. Headers like `X-Debuginfod-Size` and `Content-Length` are lost. Other
+//! responses, even slow ones, are passed through unchanged.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::{Body, Bytes};
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use futures_util::StreamExt;
+use http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, RANGE, VARY};
+use http::{HeaderMap, HeaderValue, Method, StatusCode};
+use tokio::sync::mpsc::Sender;
+use tracing::Instrument;
+
+tokio::task_local! {
+    /// Set when computing the response of the current request starts a realise
+    static REALISE_STARTED: Arc<AtomicBool>;
+}
+
+/// Records that computing the response of the current request started a realise, so that the
+/// client may be kept alive.
+pub fn realise_started() {
+    REALISE_STARTED
+        .try_with(|started| started.store(true, Ordering::Relaxed))
+        .ok();
+}
+
+/// Padding sent per second while waiting, above the 100KiB/s libdebuginfod requires
+const PADDING_RATE: usize = 128 * 1024;
+
+/// How often padding is sent
+const PADDING_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Header of a gzip member without name nor timestamp, compressed with deflate
+const GZIP_HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+
+/// Largest amount of data in a stored deflate block
+const MAX_STORED_BLOCK: usize = 0xffff;
+
+/// A deflate block storing `data`, at most [MAX_STORED_BLOCK] bytes, which is the last one of
+/// the stream if `last`.
+///
+/// All blocks written here are stored blocks, so the stream is always aligned on a byte
+/// boundary, and the 3 bits of header of each block take a whole byte.
+fn stored_block(data: &[u8], last: bool, out: &mut Vec<u8>) {
+    let len = data.len() as u16;
+    out.push(last as u8);
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(&(!len).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+/// Empty deflate blocks, about `len` bytes of them
+fn padding(len: usize) -> Bytes {
+    let mut out = Vec::with_capacity(len + 5);
+    while out.len() < len {
+        stored_block(&[], false, &mut out);
+    }
+    out.into()
+}
+
+/// Whether the client sending these headers accepts `Content-Encoding: gzip`
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let refused = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+#[test]
+fn test_accepts_gzip() {
+    let accepts = |value: &'static str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+        accepts_gzip(&headers)
+    };
+    assert!(accepts("deflate, gzip"));
+    assert!(accepts("br;q=1.0, GZIP;q=0.5"));
+    assert!(accepts("*"));
+    assert!(!accepts("gzip;q=0"));
+    assert!(!accepts("identity"));
+    assert!(!accepts_gzip(&HeaderMap::new()));
+}
+
+/// Middleware starting the response of GET requests which are not answered within `after`
+/// because they are realising a store path, and keeping the client alive with padding until
+/// they are, as described in the [module documentation](self).
+pub async fn keep_alive(State(after): State<Duration>, request: Request, next: Next) -> Response {
+    if request.method() != Method::GET
+        || request.headers().contains_key(RANGE)
+        || !accepts_gzip(request.headers())
+    {
+        return next.run(request).await;
+    }
+    let started = Arc::new(AtomicBool::new(false));
+    let mut response = tokio::spawn(
+        REALISE_STARTED
+            .scope(started.clone(), next.run(request))
+            .in_current_span(),
+    );
+    let deadline = tokio::time::Instant::now() + after;
+    let mut interval = tokio::time::interval_at(deadline, PADDING_INTERVAL);
+    let response = loop {
+        tokio::select! {
+            response = &mut response => {
+                return response.unwrap_or_else(|e| task_failed(e).into_response());
+            }
+            _ = interval.tick() => {
+                if started.load(Ordering::Relaxed) {
+                    break response;
+                }
+            }
+        }
+    };
+    tracing::debug!(
+        "response not ready after {:?}, keeping the client alive",
+        after
+    );
+    let (sender, receiver) = tokio::sync::mpsc::channel(4);
+    tokio::spawn(
+        async move {
+            if let Err(e) = send_when_ready(response, &sender).await {
+                tracing::info!("aborting response kept alive: {:#}", e);
+                sender.send(Err(e)).await.ok();
+            }
+        }
+        .in_current_span(),
+    );
+    let body = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+    (
+        [
+            (
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            ),
+            (CONTENT_ENCODING, HeaderValue::from_static("gzip")),
+            (VARY, HeaderValue::from_static("accept-encoding")),
+        ],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
+
+/// What to answer when the task computing the response panicked
+fn task_failed(e: tokio::task::JoinError) -> (StatusCode, String) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("computing response: {}", e),
+    )
+}
+
+/// Sends a gzip stream of padding to `sender` until `response` is ready, then of its body.
+///
+/// Fails if the response is not `200 OK`. Stops early if the client went away.
+async fn send_when_ready(
+    mut response: tokio::task::JoinHandle<Response>,
+    sender: &Sender<anyhow::Result<Bytes>>,
+) -> anyhow::Result<()> {
+    let send = |chunk: Bytes| sender.send(Ok(chunk));
+    if send(Bytes::from_static(&GZIP_HEADER)).await.is_err() {
+        return Ok(());
+    }
+    let padding = padding(PADDING_RATE * PADDING_INTERVAL.as_millis() as usize / 1000);
+    let mut interval = tokio::time::interval(PADDING_INTERVAL);
+    let response = loop {
+        tokio::select! {
+            response = &mut response => {
+                break response.map_err(|e| anyhow::anyhow!(task_failed(e).1))?;
+            }
+            _ = interval.tick() => {
+                if send(padding.clone()).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    };
+    if response.status() != StatusCode::OK {
+        let body = axum::body::to_bytes(response.into_body(), 10_000)
+            .await
+            .unwrap_or_default();
+        anyhow::bail!("answer was not 200 OK: {}", String::from_utf8_lossy(&body));
+    }
+    let mut crc = flate2::Crc::new();
+    let mut data = response.into_body().into_data_stream();
+    while let Some(chunk) = data.next().await {
+        let chunk = chunk.map_err(|e| anyhow::anyhow!("reading response: {}", e))?;
+        crc.update(&chunk);
+        let mut blocks = Vec::with_capacity(chunk.len() + chunk.len() / MAX_STORED_BLOCK * 5 + 5);
+        for block in chunk.chunks(MAX_STORED_BLOCK) {
+            stored_block(block, false, &mut blocks);
+        }
+        if send(blocks.into()).await.is_err() {
+            return Ok(());
+        }
+    }
+    let mut end = Vec::new();
+    stored_block(&[], true, &mut end);
+    end.extend_from_slice(&crc.sum().to_le_bytes());
+    end.extend_from_slice(&crc.amount().to_le_bytes());
+    send(end.into()).await.ok();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_keep_alive() {
+    use std::io::Read;
+    use tower::ServiceExt;
+    let app = axum::Router::new()
+        .route(
+            "/slow",
+            axum::routing::get(|| async {
+                realise_started();
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                vec![42u8; 200_000]
+            }),
+        )
+        .route(
+            "/missing",
+            axum::routing::get(|| async {
+                realise_started();
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                StatusCode::NOT_FOUND
+            }),
+        )
+        .route(
+            "/unrealised",
+            axum::routing::get(|| async {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                (
+                    StatusCode::NOT_FOUND,
+                    [("x-debuginfod-size", "0")],
+                    "missing",
+                )
+            }),
+        )
+        .route("/fast", axum::routing::get(|| async { "fast" }))
+        .layer(axum::middleware::from_fn_with_state(
+            Duration::from_millis(50),
+            keep_alive,
+        ));
+    let get = |uri: &'static str, gzip: bool| {
+        let mut request = Request::builder().uri(uri);
+        if gzip {
+            request = request.header(ACCEPT_ENCODING, "gzip");
+        }
+        app.clone().oneshot(request.body(Body::empty()).unwrap())
+    };
+
+    let response = get("/slow", true).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    // padding was sent while waiting
+    assert!(body.len() > 200_000 + 10_000);
+    let mut decoded = Vec::new();
+    flate2::read::GzDecoder::new(&body[..])
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, vec![42u8; 200_000]);
+
+    let response = get("/missing", true).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .is_err());
+
+    // slow responses which do not realise anything are passed through
+    let response = get("/unrealised", true).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.headers()["x-debuginfod-size"], "0");
+    assert!(!response.headers().contains_key(CONTENT_ENCODING));
+
+    let response = get("/slow", false).await.unwrap();
+    assert!(!response.headers().contains_key(CONTENT_ENCODING));
+    let response = get("/fast", true).await.unwrap();
+    assert!(!response.headers().contains_key(CONTENT_ENCODING));
+}
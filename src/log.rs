@@ -5,6 +5,12 @@
 //! Logging utilities
 
 use std::fmt::Display;
+use std::os::unix::fs::MetadataExt;
+
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::Layer;
+use tracing_subscriber::registry::LookupSpan;
 
 /// Adds a way to log errors to [Result]
 pub trait ResultExt {
@@ -22,3 +28,110 @@ impl<T: Display> ResultExt for Result<(), T> {
         }
     }
 }
+
+/// How to write logs to stderr
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines, or the native protocol of journald when running as a systemd
+    /// service
+    #[default]
+    Text,
+    /// One json object per line, for log shippers
+    Json,
+}
+
+/// The filter of logged events for this many `-v` and `-q` flags, in the syntax of `RUST_LOG`.
+pub fn default_filter(verbose: u8, quiet: u8) -> &'static str {
+    match i16::from(verbose) - i16::from(quiet) {
+        ..=-2 => "error",
+        -1 => "warn",
+        0 => "nixseparatedebuginfod=info,tower_http=debug,sqlx=warn,warn",
+        1 => "nixseparatedebuginfod=debug,tower_http=debug,sqlx=warn,info",
+        2.. => "nixseparatedebuginfod=trace,tower_http=trace,sqlx=info,debug",
+    }
+}
+
+#[test]
+fn test_default_filter() {
+    assert_eq!(default_filter(0, 3), "error");
+    assert_eq!(default_filter(1, 1), default_filter(0, 0));
+    assert!(default_filter(1, 0).starts_with("nixseparatedebuginfod=debug,"));
+    assert!(default_filter(5, 0).starts_with("nixseparatedebuginfod=trace,"));
+    for verbose in 0..3 {
+        for quiet in 0..3 {
+            let filter = default_filter(verbose, quiet);
+            assert!(filter.parse::<tracing_subscriber::EnvFilter>().is_ok());
+        }
+    }
+}
+
+/// Whether stderr is connected to journald, as when running as a systemd service.
+///
+/// systemd sets `JOURNAL_STREAM` to the device and inode of the stream it connects to
+/// stderr.
+pub fn stderr_is_journald() -> bool {
+    let Some(stream) = std::env::var_os("JOURNAL_STREAM") else {
+        return false;
+    };
+    let Ok(metadata) = std::fs::metadata("/proc/self/fd/2") else {
+        return false;
+    };
+    let expected = format!("{}:{}", metadata.dev(), metadata.ino());
+    stream.to_str() == Some(expected.as_str())
+}
+
+/// A [Layer] sending events to journald with the native protocol, so that they keep their
+/// severity and fields.
+pub fn journald_layer() -> std::io::Result<tracing_journald::Layer> {
+    Ok(tracing_journald::layer()?.with_syslog_identifier(env!("CARGO_PKG_NAME").to_owned()))
+}
+
+/// A [Layer] writing events to `writer` as json objects, one per line, for `--log-format json`.
+///
+/// Objects have the fields of the event, its `level`, `target` and `timestamp`, and the `spans`
+/// it is in with their fields, from the outermost.
+pub fn json_layer<S, W>(writer: W) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_current_span(false)
+        .with_writer(writer)
+}
+
+#[test]
+fn test_json_layer() {
+    use tracing_subscriber::layer::SubscriberExt as _;
+    #[derive(Clone, Default)]
+    struct Buffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let buffer = Buffer::default();
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::registry().with(json_layer(move || writer.clone()));
+    tracing::subscriber::with_default(subscriber, || {
+        let span = tracing::info_span!("indexing", storepath = "/nix/store/aaaa-hello");
+        let _guard = span.enter();
+        tracing::info!(count = 3, "hello \"world\"");
+    });
+    let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 1);
+    let line: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+    assert_eq!(line["message"], "hello \"world\"");
+    assert_eq!(line["count"], 3);
+    assert_eq!(line["level"], "INFO");
+    assert_eq!(line["target"], module_path!());
+    assert!(line["timestamp"].is_string());
+    assert_eq!(line["spans"][0]["name"], "indexing");
+    assert_eq!(line["spans"][0]["storepath"], "/nix/store/aaaa-hello");
+}
@@ -12,40 +12,325 @@
 //! to populate the [db::Cache].
 //!
 //! Finally the [server] module provides server that serves the populated [db::Cache].
+//!
+//! The [client] module implements subcommands talking to a running server.
 
-use std::{net::SocketAddr, process::ExitCode};
+use std::{net::SocketAddr, path::PathBuf, process::ExitCode};
 
-use clap::Parser;
+use anyhow::Context;
+use clap::{Parser, Subcommand};
 
 use tikv_jemallocator::Jemalloc;
-use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _};
+use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _, Layer};
 
 // makes RSS decrease after initial indexation, and decreases peak RSS during indexation
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+pub mod bundle;
+pub mod channel;
+pub mod client;
+pub mod clients;
 pub mod config;
+pub mod coredump;
 pub mod db;
+pub mod dedup;
+pub mod diagnostics;
+pub mod drv;
+pub mod dwarf;
+pub mod eager;
+pub mod fallback;
+pub mod filter;
+pub mod gdbindex;
+pub mod harden;
+pub mod html;
 pub mod index;
+pub mod keepalive;
 pub mod log;
+pub mod metrics;
+pub mod minidebuginfo;
+pub mod nar;
+pub mod newpaths;
+pub mod nixdb;
+pub mod notify;
+pub mod quota;
+pub mod resolve;
+pub mod sandbox;
 pub mod server;
+pub mod settings;
 pub mod store;
+pub mod subprocess;
 pub mod substituter;
+pub mod upstream;
 
 /// A debuginfod implementation that fetches debuginfo and sources from nix binary caches
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Options {
+    /// Read options from this TOML file, whose keys are the long names of the options below,
+    /// like `listen-address = "127.0.0.1:1949"`. Options given on the command line take
+    /// precedence.
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
     /// Address for the server
     #[arg(short, long, default_value = "127.0.0.1:1949")]
     listen_address: SocketAddr,
+    /// Log more, can be repeated. Ignored if `RUST_LOG` is set.
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Log less, can be repeated. Ignored if `RUST_LOG` is set.
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    quiet: u8,
+    /// How to write logs
+    #[arg(long, value_enum, default_value_t = log::LogFormat::Text, global = true)]
+    log_format: log::LogFormat,
     /// Only index the store and quit without serving
     #[arg(short, long)]
     index_only: bool,
+    /// Maximum number of requests served concurrently by each endpoint. Further requests are
+    /// answered with 503 Service Unavailable
+    #[arg(long, default_value_t = 16)]
+    max_concurrent_requests: usize,
+    /// Time in seconds after which requests are answered with 503 Service Unavailable
+    #[arg(long, default_value_t = 300)]
+    request_timeout: u64,
+    /// Time in seconds after which the response to a request which is not ready yet because a
+    /// store path is being realised is started anyway, and the client kept alive with padding
+    /// until it is, if the client accepts gzip. 0, the default, disables this.
+    #[arg(long, default_value_t = 0)]
+    keep_alive_after: u64,
+    /// Time in seconds after which requests of clients whose User-Agent contains PATTERN, case
+    /// insensitively, are answered with 503 Service Unavailable instead of `--request-timeout`,
+    /// like `elfutils=900` for debuggers. Can be repeated; the first matching pattern applies.
+    #[arg(long, value_name = "PATTERN=SECONDS")]
+    client_timeout: Vec<clients::ClientTimeout>,
+    /// Do not log the User-Agent and X-DEBUGINFOD-* headers of clients with each request
+    #[arg(long)]
+    no_log_clients: bool,
+    /// Time in seconds to wait for indexation of new store paths before looking up a request
+    #[arg(long, default_value_t = 1)]
+    indexing_timeout: u64,
+    /// What to answer when a file is not found while indexation is still running
+    #[arg(long, value_enum, default_value_t = server::WhileIndexing::NonCachingError)]
+    while_indexing: server::WhileIndexing,
+    /// Read derivers and outputs of store paths directly from the nix database instead of asking
+    /// nix-daemon, which refuses some queries from untrusted users
+    #[arg(long)]
+    read_nix_db: bool,
+    /// How to open the nix database, which nix may be writing to at the same time
+    #[arg(long, value_enum, default_value_t = nixdb::NixDbAccess::Immutable)]
+    nix_db_access: nixdb::NixDbAccess,
+    /// Store debuginfo fetched from substituters in the cache directory instead of adding it to
+    /// the nix store. It is deleted after 30 days.
+    #[arg(long)]
+    private_debuginfo: bool,
+    /// Serve debuginfo out of this binary cache only, without a local nix store: buildids are
+    /// looked up in the debuginfo index of the cache, and nothing is indexed. Implies
+    /// `--private-debuginfo`. Can be repeated.
+    #[arg(long, value_name = "URL")]
+    from_cache: Vec<String>,
+    /// Run without nix, in a container where `/nix/store` and `/nix/var/nix/db` are bind-mounted
+    /// read-only: read derivers and outputs in the nix database and derivations in the store,
+    /// never realise store paths, and fetch what is missing from the substituters given with
+    /// `--substituter` instead. Implies `--read-nix-db` and `--private-debuginfo`.
+    #[arg(long, conflicts_with_all = ["from_cache", "read_only", "verify"])]
+    store_mounted_readonly: bool,
+    /// Only serve what is already in the cache db: never index, never read the nix database nor
+    /// run nix, and never realise store paths nor contact substituters. Files which are not in
+    /// the store are not found.
+    #[arg(long, conflicts_with_all = [
+        "from_cache",
+        "index_only",
+        "read_nix_db",
+        "verify",
+        "substituter",
+        "upstream",
+        "fallback",
+        "warm_debuginfo_lookups",
+        "eager_debuginfo_mb",
+        "store",
+        "channel_index",
+        "allow_register",
+        "allow_invalidate",
+    ])]
+    read_only: bool,
+    /// Also fetch debuginfo and sources from this binary cache, before the substituters of
+    /// nix.conf. Can be repeated.
+    #[arg(long, value_name = "URL", conflicts_with = "from_cache")]
+    substituter: Vec<String>,
+    /// Do not use the substituters of nix.conf, only those given with `--substituter`
+    #[arg(long, conflicts_with = "from_cache")]
+    no_default_substituters: bool,
+    /// Where to store the cache db and files, instead of `$XDG_CACHE_HOME/nixseparatedebuginfod`
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+    /// Where to create temporary files, like nars downloaded from substituters which can be
+    /// several GB, instead of `tmp` in the cache directory
+    #[arg(long, value_name = "DIR")]
+    temp_dir: Option<PathBuf>,
+    /// Before indexing harder or asking substituters for a buildid missing from the cache, ask
+    /// this central instance, either the url of another nixseparatedebuginfod or the path of
+    /// its cache db, and copy what it knows to the local cache
+    #[arg(long, value_name = "URL_OR_PATH")]
+    upstream: Option<String>,
+    /// Ask this other debuginfod server, for example https://debuginfod.elfutils.org, for
+    /// files this server does not have. Can be repeated; servers are asked in order.
+    #[arg(long, value_name = "URL")]
+    fallback: Vec<String>,
+    /// Once indexation is complete, look up in substituters where the debuginfo of the
+    /// executables reachable from profiles and gc roots is, without downloading it, so that the
+    /// first request for it is faster
+    #[arg(long)]
+    warm_debuginfo_lookups: bool,
+    /// Serve the whole source store path of buildids at `/buildid/BUILDID/tree/`, with
+    /// directory listings, so that editors can browse the files next to the ones they requested
+    #[arg(long)]
+    browse_sources: bool,
+    /// Generate the `.gdb_index` section of debuginfo files which lack it with `gdb`, and serve
+    /// it at `/buildid/BUILDID/section/.gdb_index`, so that gdb loads large debug info faster
+    #[arg(long)]
+    generate_gdb_index: bool,
+    /// Look for source files under FROM which are not found otherwise under TO, for binaries
+    /// built outside nix builds, like in `nix develop`. Can be repeated.
+    #[arg(long, value_name = "FROM=TO")]
+    source_map: Vec<resolve::SourceMap>,
+    /// Look for source files which are not found otherwise in this checkout, at the same path
+    /// relative to the root of the source, for example a git worktree of a locally patched
+    /// package. When the root of the source is not known, at least the last two components of
+    /// the path of the file must match. Can be repeated.
+    #[arg(long, value_name = "DIR")]
+    extra_source_dir: Vec<PathBuf>,
+    /// Accept requests to `/register` making the server serve a local executable and its
+    /// source directory. Any local process can then make the server read these files.
+    #[arg(long)]
+    allow_register: bool,
+    /// Accept `DELETE` requests to `/buildid/BUILDID` from local clients, making the server
+    /// forget what it knows about this buildid, so that it is looked up again on the next
+    /// request. Any local process can then make the server forget buildids.
+    #[arg(long)]
+    allow_invalidate: bool,
+    /// Serve at `/debug/tasks` a summary of the requests being handled, the store paths being
+    /// indexed, the semaphores and the scheduling lag of the runtime, to diagnose stalls. When
+    /// built with the `console` feature, also serve tokio-console on 127.0.0.1:6669.
+    #[arg(long)]
+    debug_tasks: bool,
+    /// Decompress source archives and nars in a separate process without access to the
+    /// filesystem and network, to contain exploits of bugs in libarchive
+    #[arg(long)]
+    sandbox: bool,
+    /// On startup, drop capabilities, restrict filesystem access to reading the nix store and
+    /// system configuration and writing the cache directory, and forbid dangerous system calls.
+    /// Store paths can then only be realised through the nix daemon. Conflicts with
+    /// `--allow-register`, as registered files could be anywhere
+    #[arg(long, conflicts_with = "allow_register")]
+    harden: bool,
+    /// Before serving a file from the store, check that the store path containing it still has
+    /// the nar hash recorded in the nix db when it was indexed
+    #[arg(long)]
+    verify: bool,
+    /// Serve the MiniDebugInfo of executables already in the store as their debuginfo, when
+    /// they have no debug output. As the debug output may appear later, such responses must
+    /// not be cached
+    #[arg(long)]
+    serve_minidebuginfo: bool,
+    /// Only index store paths matching one of these filters. A filter is either `path:REGEX`,
+    /// matching store paths containing a match of this regular expression, or `package:NAME`,
+    /// matching store paths whose deriver has this package name, like `nix` for
+    /// `nix-2.18.1.drv`. Can be repeated.
+    #[arg(long, value_name = "FILTER")]
+    index_allow: Vec<filter::Filter>,
+    /// Do not index store paths matching one of these filters, with the same syntax as
+    /// `--index-allow`. Takes precedence over `--index-allow`. Can be repeated.
+    #[arg(long, value_name = "FILTER")]
+    index_deny: Vec<filter::Filter>,
+    /// Index outputs named like `-dev`, `-doc` or `-man` even when they have no `bin`, `sbin`
+    /// nor `libexec` directory, and no shared library in `lib`. They are skipped otherwise, as
+    /// headers and documentation contain no executable.
+    #[arg(long)]
+    index_all_outputs: bool,
+    /// Never index, fetch nor serve store paths matching one of these filters, with the same
+    /// syntax as `--index-allow` except that package names are taken from the store path, nor
+    /// buildids given as `buildid:HEX`. Requests for them get a 404 immediately. Can be
+    /// repeated.
+    #[arg(long, value_name = "FILTER")]
+    block: Vec<filter::Block>,
+    /// Serve the debuginfo of this prebuilt bundle before looking in the cache, so that it never
+    /// needs the network. This is a `.build-id` tree of `xx/yyyy.debug` files, or a package with
+    /// one in `lib/debug`, scanned on startup. Can be repeated.
+    #[arg(long, value_name = "DIR")]
+    debuginfo_dir: Vec<PathBuf>,
+    /// Do not download more than this many MB of source store paths to answer a single request.
+    /// The size of store paths is estimated from their narinfo in substituters.
+    #[arg(long, value_name = "MB")]
+    max_source_size: Option<u64>,
+    /// Do not download more than this many MB of source store paths in total since startup
+    #[arg(long, value_name = "MB")]
+    source_quota: Option<u64>,
+    /// After indexing an executable whose debug output is not in the store, realise this debug
+    /// output in the background, until debug outputs realised this way take this many MB on
+    /// disk, so that debuginfo is already there when a debugger needs it
+    #[arg(long, value_name = "MB", conflicts_with = "from_cache")]
+    eager_debuginfo_mb: Option<u64>,
+    /// Answer 429 Too Many Requests to clients (identified by IP address) making more than this
+    /// many requests per minute
+    #[arg(long, value_name = "N")]
+    max_requests_per_minute: Option<u64>,
+    /// Answer 429 Too Many Requests to clients (identified by IP address) which downloaded more
+    /// than this many MB in the current hour, or whose response would exceed it
+    #[arg(long, value_name = "MB")]
+    max_mb_per_hour: Option<u64>,
+    /// Proxy for downloads from http substituters, like `http://proxy:3128`. Defaults to the
+    /// `https_proxy`, `http_proxy` and `all_proxy` environment variables.
+    #[arg(long, value_name = "URL")]
+    http_proxy: Option<String>,
+    /// Limit the total rate of downloads from http substituters, in kB/s
+    #[arg(long, value_name = "KBPS")]
+    max_download_rate: Option<u64>,
+    /// Also index the store of another machine, whose filesystem is mounted at ROOT (containing
+    /// `nix/store` and `nix/var/nix/db`). Its buildids are served separately under
+    /// `/store/NAME/`, and to requests for virtual host `NAME.*`. Can be repeated.
+    #[arg(long, value_name = "NAME=ROOT")]
+    store: Vec<index::ForeignStore>,
+    /// Import this buildid index published for a channel, as written by `export` and possibly
+    /// compressed, on startup and then every 6 hours if it changed. Buildids of store paths
+    /// never present locally are then found immediately. Can be an http(s) url or a path, and
+    /// can be repeated.
+    #[arg(long, value_name = "URL")]
+    channel_index: Vec<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Subcommands that do something else than running a server
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Fetch in advance the executables and debuginfo of some buildids, or of all the libraries
+    /// mapped in some core dumps, with a running server
+    Prefetch(client::PrefetchOptions),
+    /// Fetch the debuginfo of all the files mapped in a core dump with a running server, print
+    /// which were found and their packages, and optionally open the core dump in gdb
+    AnalyzeCore(client::AnalyzeCoreOptions),
+    /// Print where the debuginfo, executable or a source file of a buildid is, like
+    /// `debuginfod-find`, without a running server
+    Find(client::FindOptions),
+    /// Explain why gdb cannot find the debug symbols of an executable or library
+    Doctor(client::DoctorOptions),
+    /// Write the buildids known by the cache as json lines, for `import` on another machine
+    Export(client::ExportOptions),
+    /// Add the buildids written by `export` to the cache, to find them before indexation does
+    Import(client::ImportOptions),
+    /// Forget what the cache knows about a buildid, for example when it was associated with the
+    /// wrong files, so that it is looked up again on the next request
+    Invalidate(client::InvalidateOptions),
+    /// Index some store paths, or the debug outputs in a local binary cache, and write the
+    /// result like `export`, to publish a buildid index for `--channel-index`
+    GenerateIndex(channel::GenerateIndexOptions),
+    /// Decompress stdin to stdout in a sandbox, for `--sandbox`
+    #[command(hide = true)]
+    Decompress(sandbox::WorkerOptions),
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<ExitCode> {
+fn main() -> anyhow::Result<ExitCode> {
     if let (None, Some(dir)) = (
         std::env::var_os("XDG_CACHE_HOME"),
         std::env::var_os("CACHE_DIRECTORY"),
@@ -53,25 +338,111 @@ async fn main() -> anyhow::Result<ExitCode> {
         // this env var is set by systemd
         std::env::set_var("XDG_CACHE_HOME", dir);
     }
-    if std::env::var_os("RUST_LOG").is_none() {
-        std::env::set_var(
-            "RUST_LOG",
-            "nixseparatedebuginfod=info,tower_http=debug,sqlx=warn,warn",
-        )
+    let args = settings::parse_options()?;
+    if let Some(dir) = &args.cache_dir {
+        db::set_cache_dir(dir.clone());
+    }
+    if let Some(dir) = &args.temp_dir {
+        db::set_temp_dir(dir.clone());
+    }
+    nixdb::set_access(args.nix_db_access);
+    filter::set_blocklist(filter::Blocklist::new(args.block.clone()));
+    if let Some(Command::Decompress(options)) = &args.command {
+        // before the tokio runtime starts threads, which would not be sandboxed
+        return sandbox::worker(options);
     }
-    let args = Options::parse();
-    let fmt_layer = tracing_subscriber::fmt::layer().without_time();
+    if args.harden {
+        // before the tokio runtime starts threads, which would not be restricted
+        harden::apply(&args).context("hardening with --harden")?;
+    }
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("starting tokio runtime")?
+        .block_on(run(args))
+}
+
+async fn run(mut args: Options) -> anyhow::Result<ExitCode> {
+    // RUST_LOG takes precedence over -v and -q
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        tracing_subscriber::EnvFilter::new(log::default_filter(args.verbose, args.quiet))
+    });
+    // under systemd, log natively to journald to keep severities
+    let journald_layer = if args.log_format == log::LogFormat::Text && log::stderr_is_journald() {
+        log::journald_layer()
+            .map_err(|e| eprintln!("cannot log to journald, using stderr: {:#}", e))
+            .ok()
+    } else {
+        None
+    };
+    let json_layer = match args.log_format {
+        log::LogFormat::Json => Some(log::json_layer(std::io::stderr)),
+        log::LogFormat::Text => None,
+    };
+    let fmt_layer = match (&journald_layer, &json_layer) {
+        (None, None) => Some(tracing_subscriber::fmt::layer().without_time()),
+        _ => None,
+    };
+    let log_layer = Layer::and_then(journald_layer, json_layer)
+        .and_then(fmt_layer)
+        .with_filter(filter);
+    // not filtered by RUST_LOG, as it needs the traces of tokio
+    #[cfg(feature = "console")]
+    let console_layer = args.debug_tasks.then(console_subscriber::spawn);
+    #[cfg(not(feature = "console"))]
+    let console_layer: Option<tracing_subscriber::layer::Identity> = None;
     tracing_subscriber::registry()
-        .with(fmt_layer)
-        .with(tracing_subscriber::EnvFilter::from_default_env())
+        .with(console_layer)
+        .with(log_layer)
         .init();
 
-    // check that nix-store is present
-    match store::detect_nix() {
+    if args.sandbox {
+        sandbox::enable();
+    }
+
+    let command = match args.command.take() {
+        Some(Command::Prefetch(options)) => return client::prefetch(options).await,
+        Some(Command::AnalyzeCore(options)) => return client::analyze_core(options).await,
+        Some(Command::Export(options)) => return client::export(options).await,
+        Some(Command::Import(options)) => return client::import(options, &args).await,
+        Some(Command::Invalidate(options)) => return client::invalidate(options).await,
+        Some(Command::GenerateIndex(options)) => return channel::generate_index(options).await,
+        command => command,
+    };
+
+    if args.read_only {
+        store::read_only();
+    }
+    bundle::set_bundles(&args.debuginfo_dir);
+
+    if !args.from_cache.is_empty() || args.read_only {
+        return match command {
+            None => server::run_server(args).await,
+            Some(_) => {
+                tracing::error!("--from-cache and --read-only only apply to the server");
+                Ok(ExitCode::FAILURE)
+            }
+        };
+    }
+
+    if args.read_nix_db {
+        store::read_nix_db();
+    }
+    if args.store_mounted_readonly {
+        store::store_mounted_readonly();
+        args.private_debuginfo = true;
+    }
+
+    // check that nix-store is present, or only the nix db with --store-mounted-readonly
+    match tokio::task::block_in_place(store::detect_nix) {
         Err(e) => {
             tracing::error!("nix is not available: {:#}", e);
-            return Ok(ExitCode::FAILURE);
+            Ok(ExitCode::FAILURE)
         }
-        Ok(()) => server::run_server(args).await,
+        Ok(()) => match command {
+            Some(Command::Find(options)) => client::find(&args, options).await,
+            Some(Command::Doctor(options)) => client::doctor(options).await,
+            _ => server::run_server(args).await,
+        },
     }
 }
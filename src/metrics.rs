@@ -0,0 +1,80 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Counters exposed in Prometheus text format, with the same names as `elfutils`' debuginfod
+//! where it makes sense.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+/// A set of monotonic counters, identified by their name and labels.
+#[derive(Default, Debug)]
+pub struct Metrics {
+    /// maps `name` to a map from `{labels}` to value
+    counters: Mutex<BTreeMap<&'static str, BTreeMap<String, u64>>>,
+}
+
+impl Metrics {
+    /// Increments counter `name` with these labels by `value`
+    pub fn add(&self, name: &'static str, labels: &[(&str, &str)], value: u64) {
+        let mut labels_text = String::new();
+        for (i, (key, label)) in labels.iter().enumerate() {
+            if i > 0 {
+                labels_text.push(',');
+            }
+            let label = label.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = write!(&mut labels_text, "{key}=\"{label}\"");
+        }
+        let mut counters = self.counters.lock().unwrap();
+        *counters
+            .entry(name)
+            .or_default()
+            .entry(labels_text)
+            .or_default() += value;
+    }
+
+    /// Increments counter `name` with these labels by one
+    pub fn inc(&self, name: &'static str, labels: &[(&str, &str)]) {
+        self.add(name, labels, 1)
+    }
+
+    /// Renders all counters in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let counters = self.counters.lock().unwrap();
+        let mut result = String::new();
+        for (name, values) in counters.iter() {
+            let _ = writeln!(&mut result, "# TYPE {name} counter");
+            for (labels, value) in values.iter() {
+                if labels.is_empty() {
+                    let _ = writeln!(&mut result, "{name} {value}");
+                } else {
+                    let _ = writeln!(&mut result, "{name}{{{labels}}} {value}");
+                }
+            }
+        }
+        result
+    }
+}
+
+#[test]
+fn test_render() {
+    let metrics = Metrics::default();
+    metrics.inc("http_requests_total", &[("type", "debuginfo")]);
+    metrics.inc("http_requests_total", &[("type", "debuginfo")]);
+    metrics.inc("http_requests_total", &[("type", "source")]);
+    metrics.add("errors_total", &[], 3);
+    metrics.inc("weird_total", &[("a", "\"b\""), ("c", "d")]);
+    assert_eq!(
+        metrics.render(),
+        r#"# TYPE errors_total counter
+errors_total 3
+# TYPE http_requests_total counter
+http_requests_total{type="debuginfo"} 2
+http_requests_total{type="source"} 1
+# TYPE weird_total counter
+weird_total{a="\"b\"",c="d"} 1
+"#
+    );
+}
@@ -0,0 +1,61 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! MiniDebugInfo: a small xz compressed ELF file containing the symbol table of functions,
+//! embedded in the `.gnu_debugdata` section of some stripped executables.
+//!
+//! When an executable has no debug output, its MiniDebugInfo is served as its debuginfo, so
+//! that backtraces at least have function names. gdb reads the symbols of the served file like
+//! it would read the section.
+
+use std::fs::File;
+use std::io::{BufReader, Seek};
+use std::path::Path;
+
+use anyhow::Context;
+use object::read::Object;
+use object::ObjectSection;
+
+/// Name of the section
+pub const SECTION: &str = ".gnu_debugdata";
+
+/// Decompresses the MiniDebugInfo of `executable` to `target`.
+///
+/// Returns `false` if `executable` has none.
+pub fn extract(executable: &Path, target: &Path) -> anyhow::Result<bool> {
+    let file =
+        File::open(executable).with_context(|| format!("opening {}", executable.display()))?;
+    let cache = object::read::ReadCache::new(&file);
+    let Ok(object) = object::read::File::parse(&cache) else {
+        return Ok(false);
+    };
+    let Some(section) = object.section_by_name(SECTION) else {
+        return Ok(false);
+    };
+    let data = section
+        .data()
+        .with_context(|| format!("reading {} of {}", SECTION, executable.display()))?;
+    // the sandbox reads its input from a file
+    let mut compressed = tempfile::tempfile().context("creating temporary file")?;
+    std::io::Write::write_all(&mut compressed, data)
+        .and_then(|()| compressed.rewind())
+        .context("writing compressed MiniDebugInfo")?;
+    let mut out = File::create(target).with_context(|| format!("creating {}", target.display()))?;
+    crate::sandbox::uncompress_data(BufReader::new(compressed), &mut out)
+        .with_context(|| format!("decompressing {} of {}", SECTION, executable.display()))?;
+    Ok(true)
+}
+
+#[test]
+fn test_extract_without_minidebuginfo() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let target = dir.path().join("minidebuginfo");
+    // tests are not built with MiniDebugInfo
+    let exe = std::env::current_exe().unwrap();
+    assert!(!extract(&exe, &target).unwrap());
+    let not_elf = dir.path().join("not_elf");
+    std::fs::write(&not_elf, "hello").unwrap();
+    assert!(!extract(&not_elf, &target).unwrap());
+    assert!(!target.exists());
+}
@@ -0,0 +1,339 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Unpacking of nar archives, the serialisation of store paths used by binary caches, without
+//! `nix-store --restore`.
+//!
+//! A nar is a sequence of strings, each made of its length as a little endian u64 followed by
+//! its bytes padded with zeros to a multiple of 8 bytes. The format is:
+//! ```text
+//! nar = "nix-archive-1" node
+//! node = "(" "type" ( "regular" ["executable" ""] "contents" CONTENTS
+//!                   | "symlink" "target" TARGET
+//!                   | "directory" ("entry" "(" "name" NAME "node" node ")")* ) ")"
+//! ```
+
+use std::ffi::OsStr;
+use std::io::{BufReader, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path};
+
+use anyhow::Context;
+
+/// Longest string other than file contents we accept, to avoid allocating absurd amounts of
+/// memory on corrupted nars
+const MAX_STRING_LEN: u64 = 4096;
+
+/// Deepest nesting of directories we accept, far more than in any store path, so that a
+/// corrupted nar cannot overflow the stack
+const MAX_DEPTH: usize = 256;
+
+/// Reads a nar from `reader` and unpacks it to `target`, which must not exist.
+///
+/// If `member` is specified, only this file (or directory, recursively) relative to the root of
+/// the nar is written to disk, and the rest of the nar is skipped. It is not an error if `member`
+/// is not in the nar: it is just not created.
+pub fn restore(reader: impl Read, target: &Path, member: Option<&Path>) -> anyhow::Result<()> {
+    let mut components = vec![];
+    if let Some(member) = member {
+        for component in member.components() {
+            match component {
+                Component::Normal(name) => components.push(name.as_bytes()),
+                Component::CurDir => (),
+                _ => anyhow::bail!("invalid nar member {}", member.display()),
+            }
+        }
+    }
+    let mut parser = Parser {
+        reader: BufReader::new(reader),
+    };
+    parser.expect("nix-archive-1")?;
+    parser.node(Some(target), &components, 0)
+}
+
+/// Reads a nar from a reader
+struct Parser<R: Read> {
+    reader: BufReader<R>,
+}
+
+impl<R: Read> Parser<R> {
+    fn read_u64(&mut self) -> anyhow::Result<u64> {
+        let mut buf = [0u8; 8];
+        self.reader
+            .read_exact(&mut buf)
+            .context("unexpected end of nar")?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// Reads the zeros padding a string of length `len`
+    fn padding(&mut self, len: u64) -> anyhow::Result<()> {
+        let padding = (8 - len % 8) % 8;
+        let mut buf = [0u8; 8];
+        let buf = &mut buf[..padding as usize];
+        self.reader
+            .read_exact(buf)
+            .context("unexpected end of nar")?;
+        anyhow::ensure!(buf.iter().all(|&b| b == 0), "non zero padding in nar");
+        Ok(())
+    }
+
+    /// Reads a string which is not file contents
+    fn string(&mut self) -> anyhow::Result<Vec<u8>> {
+        let len = self.read_u64()?;
+        anyhow::ensure!(len <= MAX_STRING_LEN, "string of length {} in nar", len);
+        let mut result = vec![0; len as usize];
+        self.reader
+            .read_exact(&mut result)
+            .context("unexpected end of nar")?;
+        self.padding(len)?;
+        Ok(result)
+    }
+
+    fn expect(&mut self, expected: &str) -> anyhow::Result<()> {
+        let found = self.string()?;
+        anyhow::ensure!(
+            found == expected.as_bytes(),
+            "expected {:?} in nar, found {:?}",
+            expected,
+            String::from_utf8_lossy(&found)
+        );
+        Ok(())
+    }
+
+    /// Reads the contents of a regular file, and copies them to `out`.
+    fn contents(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        let len = self.read_u64()?;
+        let copied = std::io::copy(&mut (&mut self.reader).take(len), out)
+            .context("copying file contents from nar")?;
+        anyhow::ensure!(copied == len, "unexpected end of nar");
+        self.padding(len)
+    }
+
+    /// Reads a node, and writes it to `target` if specified.
+    ///
+    /// `member` is the path of the part of this node that must be written, as components.
+    /// Everything is written if it is empty. `depth` is the number of directories containing
+    /// this node.
+    fn node(
+        &mut self,
+        target: Option<&Path>,
+        member: &[&[u8]],
+        depth: usize,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(depth <= MAX_DEPTH, "directories nested too deep in nar");
+        self.expect("(")?;
+        self.expect("type")?;
+        let kind = self.string()?;
+        // regular files and symlinks can only be written if they are the member
+        let target = target.filter(|_| kind == b"directory" || member.is_empty());
+        match &kind[..] {
+            b"regular" => {
+                let mut tag = self.string()?;
+                let executable = tag == b"executable";
+                if executable {
+                    self.expect("")?;
+                    tag = self.string()?;
+                }
+                anyhow::ensure!(tag == b"contents", "expected contents in nar");
+                match target {
+                    None => self.contents(&mut std::io::sink())?,
+                    Some(target) => {
+                        let file = std::fs::File::create(target)
+                            .with_context(|| format!("creating {}", target.display()))?;
+                        let mut out = std::io::BufWriter::new(file);
+                        self.contents(&mut out)?;
+                        let file = out
+                            .into_inner()
+                            .with_context(|| format!("writing {}", target.display()))?;
+                        if executable {
+                            file.set_permissions(std::fs::Permissions::from_mode(0o755))
+                                .with_context(|| {
+                                    format!("making {} executable", target.display())
+                                })?;
+                        }
+                    }
+                }
+                self.expect(")")
+            }
+            b"symlink" => {
+                self.expect("target")?;
+                let link = self.string()?;
+                if let Some(target) = target {
+                    std::os::unix::fs::symlink(OsStr::from_bytes(&link), target)
+                        .with_context(|| format!("creating symlink {}", target.display()))?;
+                }
+                self.expect(")")
+            }
+            b"directory" => {
+                if let Some(target) = target {
+                    std::fs::create_dir(target)
+                        .with_context(|| format!("creating {}", target.display()))?;
+                }
+                let mut previous: Option<Vec<u8>> = None;
+                loop {
+                    match &self.string()?[..] {
+                        b")" => return Ok(()),
+                        b"entry" => (),
+                        other => anyhow::bail!(
+                            "expected entry in nar, found {:?}",
+                            String::from_utf8_lossy(other)
+                        ),
+                    }
+                    self.expect("(")?;
+                    self.expect("name")?;
+                    let name = self.string()?;
+                    anyhow::ensure!(
+                        !name.is_empty()
+                            && name != b"."
+                            && name != b".."
+                            && !name.contains(&b'/')
+                            && !name.contains(&0),
+                        "invalid file name {:?} in nar",
+                        String::from_utf8_lossy(&name)
+                    );
+                    if let Some(previous) = &previous {
+                        anyhow::ensure!(*previous < name, "nar directory is not sorted");
+                    }
+                    self.expect("node")?;
+                    let (wanted, rest) = match member.split_first() {
+                        None => (true, member),
+                        Some((first, rest)) => (*first == &name[..], rest),
+                    };
+                    let child = target
+                        .filter(|_| wanted)
+                        .map(|target| target.join(OsStr::from_bytes(&name)));
+                    self.node(child.as_deref(), rest, depth + 1)?;
+                    self.expect(")")?;
+                    previous = Some(name);
+                }
+            }
+            other => anyhow::bail!(
+                "unknown node type {:?} in nar",
+                String::from_utf8_lossy(other)
+            ),
+        }
+    }
+}
+
+/// Serialises strings as in a nar
+#[cfg(test)]
+fn nar_strings(strings: &[&[u8]]) -> Vec<u8> {
+    let mut result = vec![];
+    for s in strings {
+        result.extend((s.len() as u64).to_le_bytes());
+        result.extend(*s);
+        result.resize(result.len() + (8 - s.len() % 8) % 8, 0);
+    }
+    result
+}
+
+#[test]
+fn test_restore() {
+    let nar = nar_strings(&[
+        b"nix-archive-1",
+        b"(",
+        b"type",
+        b"directory",
+        b"entry",
+        b"(",
+        b"name",
+        b"bin",
+        b"node",
+        b"(",
+        b"type",
+        b"directory",
+        b"entry",
+        b"(",
+        b"name",
+        b"hello",
+        b"node",
+        b"(",
+        b"type",
+        b"regular",
+        b"executable",
+        b"",
+        b"contents",
+        b"#!/bin/sh\necho hello\n",
+        b")",
+        b")",
+        b")",
+        b")",
+        b"entry",
+        b"(",
+        b"name",
+        b"lib",
+        b"node",
+        b"(",
+        b"type",
+        b"symlink",
+        b"target",
+        b"bin",
+        b")",
+        b")",
+        b")",
+    ]);
+    let dir = tempfile::TempDir::new().unwrap();
+
+    let all = dir.path().join("all");
+    restore(&nar[..], &all, None).unwrap();
+    assert_eq!(
+        std::fs::read(all.join("bin/hello")).unwrap(),
+        b"#!/bin/sh\necho hello\n"
+    );
+    let mode = std::fs::metadata(all.join("bin/hello"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o111, 0o111);
+    assert_eq!(
+        std::fs::read_link(all.join("lib")).unwrap(),
+        Path::new("bin")
+    );
+
+    let member = dir.path().join("member");
+    restore(&nar[..], &member, Some(Path::new("bin/hello"))).unwrap();
+    assert!(member.join("bin/hello").is_file());
+    assert!(std::fs::symlink_metadata(member.join("lib")).is_err());
+
+    let missing = dir.path().join("missing");
+    restore(&nar[..], &missing, Some(Path::new("share"))).unwrap();
+    assert!(std::fs::read_dir(&missing).unwrap().next().is_none());
+
+    let truncated = dir.path().join("truncated");
+    assert!(restore(&nar[..nar.len() - 8], &truncated, None).is_err());
+    let garbage = dir.path().join("garbage");
+    assert!(restore(
+        &b"\x0d\x00\x00\x00\x00\x00\x00\x00nix-archive-2"[..],
+        &garbage,
+        None
+    )
+    .is_err());
+}
+
+#[test]
+fn test_restore_too_deep() {
+    let mut strings: Vec<&[u8]> = vec![b"nix-archive-1"];
+    let depth = 100_000;
+    for _ in 0..depth {
+        strings.extend([
+            &b"("[..],
+            b"type",
+            b"directory",
+            b"entry",
+            b"(",
+            b"name",
+            b"a",
+            b"node",
+        ]);
+    }
+    strings.extend([&b"("[..], b"type", b"directory", b")"]);
+    for _ in 0..depth {
+        strings.extend([&b")"[..], b")"]);
+    }
+    let nar = nar_strings(&strings);
+    let dir = tempfile::TempDir::new().unwrap();
+    let error = restore(&nar[..], &dir.path().join("deep"), None).unwrap_err();
+    assert!(format!("{error:#}").contains("nested too deep"));
+}
@@ -0,0 +1,333 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! How indexation finds the store paths registered since it last ran.
+//!
+//! Reading the `ValidPaths` table of the nix db is fast, but breaks when nix changes its schema,
+//! and does not work for stores without a sqlite db. When indexation starts, the schema of the
+//! nix db is checked, and if it is not as expected, store paths are listed with
+//! `nix path-info --all --json` instead, using their registration time as id. This listing is
+//! only done again when the store directory changed.
+
+use std::fmt;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use serde::de::{Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+use crate::db::Id;
+use crate::store::get_store_path;
+
+/// How long a listing of `nix path-info` is reused at most, even if the store directory did not
+/// change, in case its modification time is not updated
+const LISTING_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// How many times the schema of the nix db is checked before giving up on errors which are not
+/// schema mismatches
+const SCHEMA_CHECK_ATTEMPTS: u32 = 5;
+
+/// How long to wait before checking the schema of the nix db again
+const SCHEMA_CHECK_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// A listing of `nix path-info`, and when it was made
+pub struct Listed {
+    at: Instant,
+    /// modification time of the store directory when the listing was made
+    store_mtime: Option<SystemTime>,
+    listing: Listing,
+}
+
+impl Listed {
+    /// Records that `listing` was just made of the store mounted at `root`.
+    pub fn new(root: &Path, listing: Listing) -> Self {
+        Listed {
+            at: Instant::now(),
+            store_mtime: store_mtime(root),
+            listing,
+        }
+    }
+
+    /// Whether the store mounted at `root` may have new paths since this listing
+    fn is_stale(&self, root: &Path) -> bool {
+        self.at.elapsed() > LISTING_LIFETIME || self.store_mtime != store_mtime(root)
+    }
+}
+
+/// The modification time of the store directory of the store mounted at `root`, which changes
+/// when store paths are added or removed.
+fn store_mtime(root: &Path) -> Option<SystemTime> {
+    std::fs::metadata(root.join("nix/store"))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// A method to find new store paths, and what the ids of store paths are for this method
+pub enum NewPaths {
+    /// ids of the `ValidPaths` table of the nix db
+    NixDb,
+    /// registration times of store paths as listed by `nix path-info`, in seconds since the
+    /// epoch
+    PathInfo(Mutex<Option<Listed>>),
+}
+
+impl NewPaths {
+    /// Chooses how to find new store paths in the store mounted at `root`.
+    ///
+    /// `nix path-info` is only used if the nix db is missing or has an unexpected schema: other
+    /// errors are retried, and if they persist, the nix db is used anyway, so that its ids are
+    /// kept.
+    pub async fn detect(root: &Path) -> Self {
+        let mut attempt = 1;
+        let result = loop {
+            match crate::nixdb::check_schema(root).await {
+                Err(e)
+                    if attempt < SCHEMA_CHECK_ATTEMPTS
+                        && !crate::nixdb::is_schema_mismatch(root, &e) =>
+                {
+                    tracing::info!("checking the schema of the nix db, retrying: {:#}", e);
+                    attempt += 1;
+                    tokio::time::sleep(SCHEMA_CHECK_RETRY_DELAY).await;
+                }
+                result => break result,
+            }
+        };
+        match result {
+            Ok(()) => NewPaths::NixDb,
+            Err(e) if !crate::nixdb::is_schema_mismatch(root, &e) => {
+                tracing::warn!(
+                    "cannot check the schema of nix db, using it anyway: {:#}",
+                    e
+                );
+                NewPaths::NixDb
+            }
+            Err(e) if crate::store::is_store_mounted_readonly() => {
+                tracing::error!(
+                    "cannot read new store paths in nix db, and there is no nix path-info with --store-mounted-readonly: {:#}",
+                    e
+                );
+                NewPaths::NixDb
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "cannot read new store paths in nix db, listing them with nix path-info instead: {:#}",
+                    e
+                );
+                NewPaths::PathInfo(Mutex::new(None))
+            }
+        }
+    }
+
+    /// A name for the kind of ids of this method, as ids of different methods cannot be
+    /// compared
+    pub fn name(&self) -> &'static str {
+        match self {
+            NewPaths::NixDb => "nixdb",
+            NewPaths::PathInfo(_) => "path-info",
+        }
+    }
+
+    /// Finds about `limit` store paths of id greater or equal to `from_id` in the store mounted
+    /// at `root`.
+    ///
+    /// Returns the id you should call this function with for the "next" paths.
+    pub async fn batch(
+        &self,
+        root: &Path,
+        from_id: Id,
+        limit: usize,
+    ) -> anyhow::Result<(Vec<PathBuf>, Id)> {
+        match self {
+            NewPaths::NixDb => crate::nixdb::get_new_store_path_batch(root, from_id, limit).await,
+            NewPaths::PathInfo(listing) => {
+                let mut listing = listing.lock().await;
+                let stale = match &*listing {
+                    Some(listed) => listed.is_stale(root),
+                    None => true,
+                };
+                if stale {
+                    let paths = list_store_paths(root).await?;
+                    *listing = Some(Listed::new(root, paths));
+                }
+                let paths = listing
+                    .as_ref()
+                    .map_or(&[][..], |listed| listed.listing.0.as_slice());
+                Ok(batch_of(paths, from_id, limit))
+            }
+        }
+    }
+}
+
+/// Takes at least `limit` paths of id greater or equal to `from_id` in `paths`, sorted by id,
+/// and all the paths with the same id as the last one taken.
+///
+/// Returns the id you should call this function with for the "next" paths.
+fn batch_of(paths: &[(Id, PathBuf)], from_id: Id, limit: usize) -> (Vec<PathBuf>, Id) {
+    let start = paths.partition_point(|(id, _)| *id < from_id);
+    let rest = &paths[start..];
+    let end = match rest.get(limit.max(1) - 1) {
+        None => rest.len(),
+        Some((last, _)) => rest.partition_point(|(id, _)| id <= last),
+    };
+    let next = match rest[..end].last() {
+        None => from_id,
+        Some((last, _)) => last.saturating_add(1),
+    };
+    let batch = rest[..end].iter().map(|(_, path)| path.clone()).collect();
+    (batch, next)
+}
+
+#[test]
+fn test_batch_of() {
+    let paths: Vec<(Id, PathBuf)> = [(1, "a"), (3, "b"), (3, "c"), (3, "d"), (7, "e")]
+        .into_iter()
+        .map(|(id, name)| (id, PathBuf::from(format!("/nix/store/{name}"))))
+        .collect();
+    let names = |(batch, next): (Vec<PathBuf>, Id)| {
+        let names: Vec<String> = batch
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        (names.join(""), next)
+    };
+    assert_eq!(names(batch_of(&paths, 0, 2)), ("abcd".to_owned(), 4));
+    assert_eq!(names(batch_of(&paths, 2, 10)), ("bcde".to_owned(), 8));
+    assert_eq!(names(batch_of(&paths, 4, 1)), ("e".to_owned(), 8));
+    assert_eq!(names(batch_of(&paths, 8, 1)), (String::new(), 8));
+}
+
+/// Lists the store paths of the store mounted at `root` with `nix path-info --all --json`,
+/// sorted by registration time.
+async fn list_store_paths(root: &Path) -> anyhow::Result<Listing> {
+    let mut cmd = std::process::Command::new("nix");
+    cmd.args([
+        "--extra-experimental-features",
+        "nix-command",
+        "path-info",
+        "--all",
+        "--json",
+    ]);
+    if root != Path::new("/") {
+        cmd.arg("--store").arg(root);
+    }
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    tracing::debug!("Running {:?}", &cmd);
+    let mut listing = tokio::task::spawn_blocking(move || {
+        let mut child = cmd.spawn().with_context(|| format!("running {:?}", cmd))?;
+        let stdout = child.stdout.take().context("stdout of nix path-info")?;
+        let mut stderr = child.stderr.take().context("stderr of nix path-info")?;
+        // concurrently, so that nix does not block writing to stderr
+        let stderr = std::thread::spawn(move || {
+            let mut buffer = Vec::new();
+            std::io::Read::read_to_end(&mut stderr, &mut buffer).map(|_| buffer)
+        });
+        // the output for a large store is large, only keep the paths and their time
+        let listing: anyhow::Result<Listing> = serde_json::from_reader(BufReader::new(stdout))
+            .with_context(|| format!("parsing output of {:?}", cmd));
+        let status = child.wait().with_context(|| format!("running {:?}", cmd))?;
+        let stderr = stderr
+            .join()
+            .map_err(|_| anyhow::anyhow!("reading stderr of {:?} panicked", cmd))?
+            .with_context(|| format!("reading stderr of {:?}", cmd))?;
+        let output = std::process::Output {
+            status,
+            stdout: Vec::new(),
+            stderr,
+        };
+        // on failure, stderr explains the unparsable output
+        crate::subprocess::check(&cmd, &output)?;
+        listing
+    })
+    .await??;
+    listing.0.sort();
+    Ok(listing)
+}
+
+/// What `nix path-info --json` prints about a store path
+#[derive(Deserialize)]
+struct PathInfoJson {
+    /// only for nix < 2.19
+    path: Option<String>,
+    #[serde(rename = "registrationTime")]
+    registration_time: Option<u64>,
+}
+
+/// The registration times of the store paths listed by `nix path-info --all --json`, either a
+/// list of [PathInfoJson], or since nix 2.19 an object whose keys are the paths.
+pub struct Listing(Vec<(Id, PathBuf)>);
+
+impl Listing {
+    fn push(&mut self, path: Option<String>, info: PathInfoJson) {
+        let Some(path) = path.or(info.path) else {
+            return;
+        };
+        if get_store_path(Path::new(&path)).is_none() {
+            return;
+        }
+        let time = info.registration_time.unwrap_or(0);
+        self.0
+            .push((Id::try_from(time).unwrap_or(Id::MAX), PathBuf::from(path)));
+    }
+}
+
+impl<'de> Deserialize<'de> for Listing {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ListingVisitor;
+        impl<'de> Visitor<'de> for ListingVisitor {
+            type Value = Listing;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a list or map of path infos")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Listing, A::Error> {
+                let mut listing = Listing(Vec::new());
+                while let Some(info) = seq.next_element()? {
+                    listing.push(None, info);
+                }
+                Ok(listing)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Listing, A::Error> {
+                let mut listing = Listing(Vec::new());
+                // invalid paths are null
+                while let Some((path, info)) = map.next_entry::<String, Option<PathInfoJson>>()? {
+                    if let Some(info) = info {
+                        listing.push(Some(path), info);
+                    }
+                }
+                Ok(listing)
+            }
+        }
+        deserializer.deserialize_any(ListingVisitor)
+    }
+}
+
+#[test]
+fn test_listing() {
+    let old: Listing = serde_json::from_str(
+        r#"[{"path":"/nix/store/aaaa-foo","narHash":"sha256:aa","registrationTime":1700000000},
+            {"path":"/nix/store/bbbb-bar","registrationTime":1600000000}]"#,
+    )
+    .unwrap();
+    let new: Listing = serde_json::from_str(
+        r#"{"/nix/store/aaaa-foo":{"narHash":"sha256:aa","registrationTime":1700000000},
+            "/nix/store/bbbb-bar":{"registrationTime":1600000000},
+            "/nix/store/cccc-invalid":null}"#,
+    )
+    .unwrap();
+    for listing in [old, new] {
+        assert_eq!(
+            listing.0,
+            vec![
+                (1700000000, PathBuf::from("/nix/store/aaaa-foo")),
+                (1600000000, PathBuf::from("/nix/store/bbbb-bar"))
+            ]
+        );
+    }
+}
@@ -0,0 +1,796 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Read only access to the nix database `/nix/var/nix/db/db.sqlite`.
+//!
+//! Unlike `nix-store --query`, this does not go through the nix daemon, and therefore works
+//! even when the daemon does not trust the user running this program.
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use once_cell::sync::{Lazy, OnceCell};
+use sqlx::sqlite::{Sqlite, SqliteConnectOptions, SqliteConnection};
+use sqlx::{ConnectOptions, Connection, QueryBuilder, Row};
+use tempfile::TempDir;
+
+use crate::db::Id;
+use crate::log::ResultExt;
+use crate::store::get_store_path;
+
+/// Location of the nix database, relative to the root of the filesystem of the store
+const NIX_DB: &str = "nix/var/nix/db/db.sqlite";
+
+/// Opens the nix database read only.
+///
+/// As this lies to sqlite about the database being immutable, do not keep the connection open
+/// for long.
+pub async fn open() -> anyhow::Result<SqliteConnection> {
+    open_at(Path::new("/")).await
+}
+
+/// Opens the nix database of a store whose filesystem is mounted at `root`, read only.
+///
+/// See [open].
+pub async fn open_at(root: &Path) -> anyhow::Result<SqliteConnection> {
+    let path = root.join(NIX_DB);
+    match ACCESS.get().copied().unwrap_or_default() {
+        NixDbAccess::Immutable => open_immutable(&path).await,
+        NixDbAccess::ReadOnly => open_read_only(&path).await,
+        NixDbAccess::Snapshot => open_snapshot(root).await,
+    }
+}
+
+/// How the nix database is opened, as specified by `--nix-db-access`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NixDbAccess {
+    /// Pretend the database is never modified, which works without write access to it, but may
+    /// read inconsistent data while nix writes to it
+    #[default]
+    Immutable,
+    /// Open the database read only with the usual locking, which requires read access to its
+    /// `-wal` and `-shm` files, which nix creates
+    ReadOnly,
+    /// Copy the database to a private snapshot with a read only connection, like `read-only`,
+    /// and read the snapshot, which is reused for a few minutes
+    Snapshot,
+}
+
+/// The access method given with `--nix-db-access`
+static ACCESS: OnceCell<NixDbAccess> = OnceCell::new();
+
+/// Opens the nix database with `access` from now on, instead of [NixDbAccess::Immutable].
+pub fn set_access(access: NixDbAccess) {
+    if ACCESS.set(access).is_err() {
+        tracing::warn!("nix db access set twice");
+    }
+}
+
+/// Opens the sqlite database at `path` read only, promising sqlite that it is not modified.
+async fn open_immutable(path: &Path) -> anyhow::Result<SqliteConnection> {
+    // note: this is a hack. One cannot open a sqlite db read only with WAL if the underlying
+    // file is not writable. So we promise sqlite that the db will not be modified with
+    // immutable=1, but it's false.
+    SqliteConnectOptions::new()
+        .filename(path)
+        .immutable(true)
+        .read_only(true)
+        .connect()
+        .await
+        .with_context(|| format!("opening nix db {}", path.display()))
+}
+
+/// Opens the sqlite database at `path` read only, honoring the locks of writers.
+async fn open_read_only(path: &Path) -> anyhow::Result<SqliteConnection> {
+    SqliteConnectOptions::new()
+        .filename(path)
+        .read_only(true)
+        .connect()
+        .await
+        .with_context(|| format!("opening nix db {} read only", path.display()))
+}
+
+/// How long a snapshot of the nix db is read before taking a new one.
+///
+/// Copying the nix db takes a while on large stores, so store paths registered in the meantime
+/// are only seen that much later.
+const SNAPSHOT_LIFETIME: Duration = Duration::from_secs(300);
+
+/// A copy of the nix db
+struct Snapshot {
+    /// when it was taken
+    at: Instant,
+    /// the modification time of the nix db when it was taken, see [last_modified], unless too
+    /// recent to tell apart from later writes
+    modified: Option<SystemTime>,
+    /// contains the copy, as `db.sqlite`
+    dir: TempDir,
+}
+
+/// The latest snapshot of the nix db of each store by root
+static SNAPSHOTS: Lazy<tokio::sync::Mutex<HashMap<PathBuf, Snapshot>>> =
+    Lazy::new(Default::default);
+
+/// The last time the sqlite database at `path` or its write ahead log was modified, if known
+async fn last_modified(path: &Path) -> Option<SystemTime> {
+    let mut wal = path.as_os_str().to_owned();
+    wal.push("-wal");
+    let mut result = None;
+    for file in [path.as_os_str(), wal.as_os_str()] {
+        if let Ok(modified) = tokio::fs::metadata(file).await.and_then(|m| m.modified()) {
+            result = result.max(Some(modified));
+        }
+    }
+    result
+}
+
+/// Opens a recent snapshot of the nix database of the store mounted at `root`, taking one if
+/// needed.
+///
+/// A snapshot older than [SNAPSHOT_LIFETIME] is still reused if the nix db was not modified
+/// since.
+async fn open_snapshot(root: &Path) -> anyhow::Result<SqliteConnection> {
+    let path = root.join(NIX_DB);
+    let mut snapshots = SNAPSHOTS.lock().await;
+    let modified = last_modified(&path).await;
+    // modification times are coarse, a write in the same tick would go unnoticed
+    let stable = modified.filter(|m| m.elapsed().is_ok_and(|e| e > Duration::from_secs(1)));
+    if let Some(snapshot) = snapshots.get_mut(root) {
+        if snapshot.at.elapsed() >= SNAPSHOT_LIFETIME
+            && modified.is_some()
+            && modified == snapshot.modified
+        {
+            snapshot.at = Instant::now();
+        }
+        if snapshot.at.elapsed() < SNAPSHOT_LIFETIME {
+            return open_immutable(&snapshot.dir.path().join("db.sqlite")).await;
+        }
+    }
+    let dir = tempfile::Builder::new()
+        .prefix("nixdb-snapshot")
+        .tempdir_in(crate::db::cache_dir()?)
+        .context("creating directory for nix db snapshot")?;
+    let snapshot = dir.path().join("db.sqlite");
+    take_snapshot(&path, &snapshot).await?;
+    // connections to the previous snapshot can still read it once it is deleted
+    snapshots.insert(
+        root.to_path_buf(),
+        Snapshot {
+            at: Instant::now(),
+            modified: stable,
+            dir,
+        },
+    );
+    // nothing writes to the snapshot
+    open_immutable(&snapshot).await
+}
+
+/// Copies the sqlite database at `path` to `snapshot`, consistently.
+async fn take_snapshot(path: &Path, snapshot: &Path) -> anyhow::Result<()> {
+    tracing::debug!("taking snapshot of {}", path.display());
+    let mut db = open_read_only(path).await?;
+    let result = sqlx::query("vacuum into $1;")
+        .bind(path_str(snapshot)?)
+        .execute(&mut db)
+        .await
+        .with_context(|| {
+            format!(
+                "copying nix db {} to {}",
+                path.display(),
+                snapshot.display()
+            )
+        });
+    db.close().await.context("closing nix db").or_warn();
+    result?;
+    Ok(())
+}
+
+/// Converts a path to utf8 for use in queries.
+fn path_str(path: &Path) -> anyhow::Result<&str> {
+    match path.to_str() {
+        Some(s) => Ok(s),
+        None => anyhow::bail!("{} is not utf8", path.display()),
+    }
+}
+
+/// What the nix db knows about the derivation of a store path
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathInfo {
+    /// a deriver of the store path, preferably existing, as returned by [get_deriver]
+    pub deriver: Option<PathBuf>,
+    /// the outputs of `deriver`, if it is a valid path with known outputs
+    pub deriver_outputs: Option<Vec<PathBuf>>,
+    /// the hash of the nar serialisation of the store path, like `sha256:0123abcd...`
+    pub nar_hash: Option<String>,
+}
+
+/// Appends ` in (values...)` to a query
+fn push_in<'args>(query: &mut QueryBuilder<'args, Sqlite>, values: &'args [String]) {
+    query.push(" in (");
+    let mut separated = query.separated(", ");
+    for value in values {
+        separated.push_bind(value.as_str());
+    }
+    query.push(")");
+}
+
+/// Starts a query ending with `in (values...)`
+fn query_in<'args>(prefix: &str, values: &'args [String]) -> QueryBuilder<'args, Sqlite> {
+    let mut query = QueryBuilder::new(prefix);
+    push_in(&mut query, values);
+    query.push(";");
+    query
+}
+
+/// Obtains the deriver of these store paths and the outputs of these derivers, with a constant
+/// number of queries.
+///
+/// Paths which are not valid in the nix db are absent from the result.
+///
+/// `root` is where the filesystem of the store is mounted, `/` for the local store.
+pub async fn get_path_infos(
+    root: &Path,
+    paths: &[PathBuf],
+) -> anyhow::Result<HashMap<PathBuf, PathInfo>> {
+    let paths = paths
+        .iter()
+        .map(|path| path_str(path).map(|s| s.to_owned()))
+        .collect::<anyhow::Result<Vec<String>>>()?;
+    let mut db = open_at(root).await?;
+    let result = get_path_infos_in(&mut db, &paths).await;
+    db.close().await.context("closing nix db").or_warn();
+    Ok(result?
+        .into_iter()
+        .map(|(path, info)| (PathBuf::from(path), info))
+        .collect())
+}
+
+async fn get_path_infos_in(
+    db: &mut SqliteConnection,
+    paths: &[String],
+) -> anyhow::Result<HashMap<String, PathInfo>> {
+    let mut result = HashMap::new();
+    if paths.is_empty() {
+        return Ok(result);
+    }
+    // original derivers
+    let rows = query_in(
+        "select path, deriver, hash from ValidPaths where path",
+        paths,
+    )
+    .build()
+    .fetch_all(&mut *db)
+    .await
+    .context("reading derivers in nix db")?;
+    for row in rows {
+        let path: String = row.try_get("path").context("parsing path in nix db")?;
+        let deriver: Option<&str> = row
+            .try_get("deriver")
+            .context("parsing deriver in nix db")?;
+        let nar_hash: Option<String> = row.try_get("hash").context("parsing hash in nix db")?;
+        result.insert(
+            path,
+            PathInfo {
+                deriver: deriver.map(PathBuf::from),
+                deriver_outputs: None,
+                nar_hash,
+            },
+        );
+    }
+    // valid derivers are preferred if they exist, as they may differ from the original deriver
+    // when the path was substituted
+    let rows = query_in(
+        "select DerivationOutputs.path as output, ValidPaths.path as drv from DerivationOutputs
+            join ValidPaths on DerivationOutputs.drv = ValidPaths.id
+            where DerivationOutputs.path",
+        paths,
+    )
+    .build()
+    .fetch_all(&mut *db)
+    .await
+    .context("reading valid derivers in nix db")?;
+    for row in rows {
+        let output: &str = row.try_get("output").context("parsing output in nix db")?;
+        let drv: &str = row.try_get("drv").context("parsing deriver in nix db")?;
+        if let Some(info) = result.get_mut(output) {
+            let drv = PathBuf::from(drv);
+            if drv.exists() {
+                info.deriver = Some(drv);
+            }
+        }
+    }
+    // outputs of derivers
+    let derivers: Vec<String> = result
+        .values()
+        .filter_map(|info| info.deriver.as_ref().and_then(|drv| drv.to_str()))
+        .map(|drv| drv.to_owned())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    if derivers.is_empty() {
+        return Ok(result);
+    }
+    let rows = query_in(
+        "select ValidPaths.path as drv, DerivationOutputs.path as output from DerivationOutputs
+            join ValidPaths on DerivationOutputs.drv = ValidPaths.id
+            where ValidPaths.path",
+        &derivers,
+    )
+    .build()
+    .fetch_all(&mut *db)
+    .await
+    .context("reading derivation outputs in nix db")?;
+    let mut outputs: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    for row in rows {
+        let drv: &str = row.try_get("drv").context("parsing deriver in nix db")?;
+        let output: &str = row.try_get("output").context("parsing output in nix db")?;
+        outputs
+            .entry(PathBuf::from(drv))
+            .or_default()
+            .push(PathBuf::from(output));
+    }
+    for info in result.values_mut() {
+        info.deriver_outputs = info
+            .deriver
+            .as_ref()
+            .and_then(|drv| outputs.get(drv))
+            .cloned();
+    }
+    Ok(result)
+}
+
+/// Obtains a deriver for this store path, preferably existing.
+///
+/// Corresponds to `nix-store --query --valid-derivers` falling back to
+/// `nix-store --query --deriver`.
+pub async fn get_deriver(storepath: &Path) -> anyhow::Result<Option<PathBuf>> {
+    let path = path_str(storepath)?;
+    let mut db = open().await?;
+    let result = get_deriver_in(&mut db, path).await;
+    db.close().await.context("closing nix db").or_warn();
+    result
+}
+
+async fn get_deriver_in(db: &mut SqliteConnection, path: &str) -> anyhow::Result<Option<PathBuf>> {
+    match get_path_infos_in(db, &[path.to_owned()])
+        .await?
+        .remove(path)
+    {
+        None => anyhow::bail!("{} is not a valid path in nix db", path),
+        Some(info) => Ok(info.deriver),
+    }
+}
+
+/// Obtains the list of outputs of this derivation
+///
+/// Corresponds to `nix-store --query --outputs`. The derivation must be a valid path.
+pub async fn get_outputs(drvpath: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let path = path_str(drvpath)?;
+    let mut db = open().await?;
+    let result = get_outputs_in(&mut db, path).await;
+    db.close().await.context("closing nix db").or_warn();
+    result
+}
+
+async fn get_outputs_in(db: &mut SqliteConnection, path: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let id = sqlx::query("select id from ValidPaths where path = $1;")
+        .bind(path)
+        .fetch_optional(&mut *db)
+        .await
+        .context("reading derivation in nix db")?;
+    let id: i64 = match id {
+        None => anyhow::bail!("{} is not a valid path in nix db", path),
+        Some(row) => row.try_get("id").context("parsing id in nix db")?,
+    };
+    let rows = sqlx::query("select path from DerivationOutputs where drv = $1;")
+        .bind(id)
+        .fetch_all(&mut *db)
+        .await
+        .context("reading derivation outputs in nix db")?;
+    let mut result = Vec::with_capacity(rows.len());
+    for row in rows {
+        let output: &str = row.try_get("path").context("parsing output in nix db")?;
+        result.push(PathBuf::from(output));
+    }
+    Ok(result)
+}
+
+/// Obtains the store paths this store path refers to.
+///
+/// Corresponds to `nix-store --query --references`. The store path must be valid.
+pub async fn get_references(storepath: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let path = path_str(storepath)?;
+    let mut db = open().await?;
+    let result = get_references_in(&mut db, path).await;
+    db.close().await.context("closing nix db").or_warn();
+    result
+}
+
+async fn get_references_in(db: &mut SqliteConnection, path: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let rows = sqlx::query(
+        "select reference.path as path from ValidPaths referrer
+        join Refs on Refs.referrer = referrer.id
+        join ValidPaths reference on Refs.reference = reference.id
+        where referrer.path = $1;",
+    )
+    .bind(path)
+    .fetch_all(&mut *db)
+    .await
+    .context("reading references in nix db")?;
+    let mut result = Vec::with_capacity(rows.len());
+    for row in rows {
+        let reference: &str = row.try_get("path").context("parsing reference in nix db")?;
+        result.push(PathBuf::from(reference));
+    }
+    Ok(result)
+}
+
+/// Obtains the store paths in the closure of these store paths whose id in the nix db is at
+/// least `from_id`, in increasing id order.
+///
+/// Invalid roots are ignored.
+pub async fn get_closure(roots: &[PathBuf], from_id: u32) -> anyhow::Result<Vec<PathBuf>> {
+    let roots = roots
+        .iter()
+        .map(|path| path_str(path).map(|s| s.to_owned()))
+        .collect::<anyhow::Result<Vec<String>>>()?;
+    let mut db = open().await?;
+    let result = get_closure_in(&mut db, &roots, from_id).await;
+    db.close().await.context("closing nix db").or_warn();
+    result
+}
+
+async fn get_closure_in(
+    db: &mut SqliteConnection,
+    roots: &[String],
+    from_id: u32,
+) -> anyhow::Result<Vec<PathBuf>> {
+    if roots.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut query = QueryBuilder::new(
+        "with recursive closure(id) as (
+            select id from ValidPaths where path",
+    );
+    push_in(&mut query, roots);
+    query.push(
+        "
+            union
+            select Refs.reference from Refs join closure on Refs.referrer = closure.id
+        )
+        select ValidPaths.path as path from ValidPaths join closure on ValidPaths.id = closure.id
+        where ValidPaths.id >= ",
+    );
+    query.push_bind(from_id);
+    query.push(" order by ValidPaths.id;");
+    let rows = query
+        .build()
+        .fetch_all(&mut *db)
+        .await
+        .context("reading closure in nix db")?;
+    let mut result = Vec::with_capacity(rows.len());
+    for row in rows {
+        let path: &str = row.try_get("path").context("parsing path in nix db")?;
+        result.push(PathBuf::from(path));
+    }
+    Ok(result)
+}
+
+/// Checks that the nix db of the store mounted at `root` has the tables and columns this module
+/// reads, which could change with a new version of nix.
+pub async fn check_schema(root: &Path) -> anyhow::Result<()> {
+    let mut db = open_at(root).await?;
+    let result = check_schema_in(&mut db).await;
+    db.close().await.context("closing nix db").or_warn();
+    result
+}
+
+/// Whether the nix db of the store mounted at `root` cannot be read by this version, because it
+/// does not exist or has an unexpected schema, according to this error of [check_schema].
+///
+/// Other errors, like a locked database, may go away by themselves.
+pub fn is_schema_mismatch(root: &Path, error: &anyhow::Error) -> bool {
+    if !root.join(NIX_DB).exists() {
+        return true;
+    }
+    error
+        .chain()
+        .any(|cause| match cause.downcast_ref::<sqlx::Error>() {
+            Some(sqlx::Error::Database(e)) => {
+                e.message().starts_with("no such table")
+                    || e.message().starts_with("no such column")
+            }
+            _ => false,
+        })
+}
+
+async fn check_schema_in(db: &mut SqliteConnection) -> anyhow::Result<()> {
+    sqlx::query(
+        "select ValidPaths.id, ValidPaths.path, ValidPaths.hash, ValidPaths.deriver,
+            DerivationOutputs.drv, DerivationOutputs.path, Refs.referrer, Refs.reference
+            from ValidPaths, DerivationOutputs, Refs limit 0;",
+    )
+    .fetch_all(&mut *db)
+    .await
+    .context("checking the schema of nix db")?;
+    Ok(())
+}
+
+/// Reads at most `limit` store paths of id greater or equal to `from_id` in the nix db of the
+/// store mounted at `root`.
+///
+/// Returns the id you should call this function with for the "next" paths.
+pub async fn get_new_store_path_batch(
+    root: &Path,
+    from_id: Id,
+    limit: usize,
+) -> anyhow::Result<(Vec<PathBuf>, Id)> {
+    let mut db = open_at(root).await?;
+    let result = get_new_store_path_batch_in(&mut db, from_id, limit).await;
+    // As we lie about the database being immutable let's not keep the connection open
+    db.close().await.context("closing nix db").or_warn();
+    result
+}
+
+async fn get_new_store_path_batch_in(
+    db: &mut SqliteConnection,
+    from_id: Id,
+    limit: usize,
+) -> anyhow::Result<(Vec<PathBuf>, Id)> {
+    let rows =
+        sqlx::query("select path, id from ValidPaths where id >= $1 order by id asc limit $2")
+            .bind(from_id)
+            .bind(limit as u32)
+            .fetch_all(&mut *db)
+            .await
+            .context("reading nix db")?;
+    let mut paths = Vec::new();
+    let mut max_id = 0;
+    for row in rows {
+        let path: &str = row.try_get("path").context("parsing path in nix db")?;
+        let path = match get_store_path(Path::new(path)) {
+            Some(path) => path,
+            None => anyhow::bail!(
+                "read corrupted stuff from nix db: {}, concurrent write?",
+                path
+            ),
+        };
+        paths.push(PathBuf::from(path));
+        let id: Id = row.try_get("id").context("parsing id in nix db")?;
+        max_id = id.max(max_id);
+    }
+    if (max_id == 0) ^ paths.is_empty() {
+        anyhow::bail!("read paths with id == 0...");
+    }
+    Ok((paths, max_id + 1))
+}
+
+/// Creates a database with the relevant subset of the schema of the nix database
+#[cfg(test)]
+async fn test_db() -> SqliteConnection {
+    let mut db = SqliteConnection::connect(":memory:").await.unwrap();
+    sqlx::query(
+        "create table ValidPaths (id integer primary key autoincrement not null,
+            path text unique not null, hash text not null, deriver text);
+        create table DerivationOutputs (drv integer not null, id text not null,
+            path text not null, primary key (drv, id));
+        create table Refs (referrer integer not null, reference integer not null,
+            primary key (referrer, reference));
+        insert into ValidPaths (id, path, hash, deriver) values
+            (1, '/nix/store/aaaa-foo', 'sha256:aa', '/nix/store/dddd-foo.drv'),
+            (2, '/nix/store/bbbb-foo-debug', 'sha256:bb', null),
+            (3, '/nix/store/dddd-foo.drv', 'sha256:dd', null),
+            (4, '/nix/store/eeee-lib', 'sha256:ee', null),
+            (5, '/nix/store/ffff-unrelated', 'sha256:ff', null);
+        insert into Refs values (1, 1), (1, 4), (3, 1), (3, 2);
+        insert into DerivationOutputs values
+            (3, 'out', '/nix/store/aaaa-foo'),
+            (3, 'debug', '/nix/store/bbbb-foo-debug');",
+    )
+    .execute(&mut db)
+    .await
+    .unwrap();
+    db
+}
+
+#[tokio::test]
+async fn test_get_deriver() {
+    let mut db = test_db().await;
+    // the deriver does not exist on disk, so we get the original deriver
+    assert_eq!(
+        get_deriver_in(&mut db, "/nix/store/aaaa-foo")
+            .await
+            .unwrap(),
+        Some(PathBuf::from("/nix/store/dddd-foo.drv"))
+    );
+    assert_eq!(
+        get_deriver_in(&mut db, "/nix/store/bbbb-foo-debug")
+            .await
+            .unwrap(),
+        None
+    );
+    assert!(get_deriver_in(&mut db, "/nix/store/cccc-bar")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_get_outputs() {
+    let mut db = test_db().await;
+    let mut outputs = get_outputs_in(&mut db, "/nix/store/dddd-foo.drv")
+        .await
+        .unwrap();
+    outputs.sort();
+    assert_eq!(
+        outputs,
+        vec![
+            PathBuf::from("/nix/store/aaaa-foo"),
+            PathBuf::from("/nix/store/bbbb-foo-debug")
+        ]
+    );
+    assert!(get_outputs_in(&mut db, "/nix/store/eeee-bar.drv")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_get_path_infos() {
+    let mut db = test_db().await;
+    let infos = get_path_infos_in(
+        &mut db,
+        &[
+            "/nix/store/aaaa-foo".to_owned(),
+            "/nix/store/bbbb-foo-debug".to_owned(),
+            "/nix/store/cccc-bar".to_owned(),
+        ],
+    )
+    .await
+    .unwrap();
+    assert_eq!(infos.len(), 2);
+    let mut foo = infos["/nix/store/aaaa-foo"].clone();
+    foo.deriver_outputs.as_mut().unwrap().sort();
+    assert_eq!(
+        foo,
+        PathInfo {
+            deriver: Some(PathBuf::from("/nix/store/dddd-foo.drv")),
+            deriver_outputs: Some(vec![
+                PathBuf::from("/nix/store/aaaa-foo"),
+                PathBuf::from("/nix/store/bbbb-foo-debug")
+            ]),
+            nar_hash: Some("sha256:aa".to_owned()),
+        }
+    );
+    assert_eq!(
+        infos["/nix/store/bbbb-foo-debug"],
+        PathInfo {
+            nar_hash: Some("sha256:bb".to_owned()),
+            ..PathInfo::default()
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_get_closure() {
+    let mut db = test_db().await;
+    let roots = |roots: &[&str]| roots.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        get_closure_in(
+            &mut db,
+            &roots(&["/nix/store/aaaa-foo", "/nix/store/cccc-bar"]),
+            0
+        )
+        .await
+        .unwrap(),
+        vec![
+            PathBuf::from("/nix/store/aaaa-foo"),
+            PathBuf::from("/nix/store/eeee-lib")
+        ]
+    );
+    assert_eq!(
+        get_closure_in(&mut db, &roots(&["/nix/store/dddd-foo.drv"]), 2)
+            .await
+            .unwrap(),
+        vec![
+            PathBuf::from("/nix/store/bbbb-foo-debug"),
+            PathBuf::from("/nix/store/dddd-foo.drv"),
+            PathBuf::from("/nix/store/eeee-lib")
+        ]
+    );
+    assert!(get_closure_in(&mut db, &[], 0).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_get_references() {
+    let mut db = test_db().await;
+    let mut references = get_references_in(&mut db, "/nix/store/aaaa-foo")
+        .await
+        .unwrap();
+    references.sort();
+    assert_eq!(
+        references,
+        vec![
+            PathBuf::from("/nix/store/aaaa-foo"),
+            PathBuf::from("/nix/store/eeee-lib")
+        ]
+    );
+    assert!(get_references_in(&mut db, "/nix/store/cccc-bar")
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_check_schema() {
+    let mut db = test_db().await;
+    check_schema_in(&mut db).await.unwrap();
+    sqlx::query("alter table ValidPaths rename to StorePaths;")
+        .execute(&mut db)
+        .await
+        .unwrap();
+    let error = check_schema_in(&mut db).await.unwrap_err();
+    assert!(is_schema_mismatch(Path::new("/nonexistent"), &error));
+    let root = tempfile::TempDir::new().unwrap();
+    let nixdb = root.path().join(NIX_DB);
+    std::fs::create_dir_all(nixdb.parent().unwrap()).unwrap();
+    std::fs::write(&nixdb, b"").unwrap();
+    assert!(is_schema_mismatch(root.path(), &error));
+    let locked = anyhow::Error::new(std::io::Error::other("database is locked"));
+    assert!(!is_schema_mismatch(root.path(), &locked));
+}
+
+#[tokio::test]
+async fn test_get_new_store_path_batch() {
+    let mut db = test_db().await;
+    let (paths, next) = get_new_store_path_batch_in(&mut db, 2, 2).await.unwrap();
+    assert_eq!(
+        paths,
+        vec![
+            PathBuf::from("/nix/store/bbbb-foo-debug"),
+            PathBuf::from("/nix/store/dddd-foo.drv")
+        ]
+    );
+    assert_eq!(next, 4);
+    let (paths, _) = get_new_store_path_batch_in(&mut db, 6, 2).await.unwrap();
+    assert!(paths.is_empty());
+}
+
+#[tokio::test]
+async fn test_snapshot() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("db.sqlite");
+    let mut writer = SqliteConnectOptions::new()
+        .filename(&path)
+        .create_if_missing(true)
+        .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
+        .connect()
+        .await
+        .unwrap();
+    sqlx::query(
+        "create table ValidPaths (id integer primary key autoincrement not null,
+            path text unique not null, hash text not null, deriver text);
+        insert into ValidPaths (id, path, hash) values (1, '/nix/store/aaaa-foo', 'sha256:aa');",
+    )
+    .execute(&mut writer)
+    .await
+    .unwrap();
+    let snapshot = dir.path().join("snapshot.sqlite");
+    take_snapshot(&path, &snapshot).await.unwrap();
+    // written after the snapshot, while the writer keeps its connection open
+    sqlx::query(
+        "insert into ValidPaths (id, path, hash) values (2, '/nix/store/bbbb-bar', 'sha256:bb');",
+    )
+    .execute(&mut writer)
+    .await
+    .unwrap();
+    let mut reader = open_read_only(&path).await.unwrap();
+    let (paths, _) = get_new_store_path_batch_in(&mut reader, 0, 10)
+        .await
+        .unwrap();
+    assert_eq!(paths.len(), 2);
+    let mut copy = open_immutable(&snapshot).await.unwrap();
+    let (paths, _) = get_new_store_path_batch_in(&mut copy, 0, 10).await.unwrap();
+    assert_eq!(paths, vec![PathBuf::from("/nix/store/aaaa-foo")]);
+}
@@ -0,0 +1,108 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Notifications to systemd, for services of `Type=notify` with `WatchdogSec=`.
+//!
+//! The server tells systemd when it is ready, pings the watchdog as long as the tokio runtime
+//! runs tasks, and describes the progress of indexation in the status shown by
+//! `systemctl status`. Without `$NOTIFY_SOCKET`, this does nothing.
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+use anyhow::Context;
+
+use crate::log::ResultExt;
+
+/// Sends `state`, newline separated assignments like `READY=1`, to the socket of the service
+/// manager, if any.
+fn notify(state: &str) -> anyhow::Result<()> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => notify_socket(&socket, state),
+        None => Ok(()),
+    }
+}
+
+/// Sends `state` to the datagram socket `socket`, a path or an abstract socket starting with
+/// `@`.
+fn notify_socket(socket: &OsStr, state: &str) -> anyhow::Result<()> {
+    let address = match socket.as_bytes() {
+        [b'@', name @ ..] => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)
+        }
+        _ => SocketAddr::from_pathname(socket),
+    }
+    .with_context(|| format!("invalid NOTIFY_SOCKET {:?}", socket))?;
+    let client = UnixDatagram::unbound().context("creating socket to notify systemd")?;
+    client
+        .send_to_addr(state.as_bytes(), &address)
+        .with_context(|| format!("notifying systemd on {:?}", socket))?;
+    Ok(())
+}
+
+/// Tells systemd that the server accepts connections, along with a status.
+pub fn ready(status: &str) {
+    notify(&format!("READY=1\nSTATUS={}", status)).or_warn();
+}
+
+/// Updates the status shown by `systemctl status`.
+pub fn status(status: &str) {
+    notify(&format!("STATUS={}", status)).or_warn();
+}
+
+/// The interval at which systemd expects watchdog pings, if it does, from `$WATCHDOG_USEC` and
+/// `$WATCHDOG_PID`.
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            // meant for another process
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec))
+}
+
+/// Starts a task pinging the watchdog of systemd, if enabled, twice per interval.
+///
+/// The task only runs while the tokio runtime makes progress, so systemd restarts the server
+/// when it hangs.
+pub fn start_watchdog() {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    tracing::debug!("pinging systemd watchdog every {:?}", interval / 2);
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval / 2);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            notify("WATCHDOG=1").or_warn();
+        }
+    });
+}
+
+#[test]
+fn test_notify_socket() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("notify");
+    let server = UnixDatagram::bind(&path).unwrap();
+    notify_socket(path.as_os_str(), "READY=1\nSTATUS=ok").unwrap();
+    let mut buf = [0u8; 64];
+    let n = server.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"READY=1\nSTATUS=ok");
+    let name = format!("@nixseparatedebuginfod-test-{}", std::process::id());
+    let abstract_server = {
+        use std::os::linux::net::SocketAddrExt;
+        let address = SocketAddr::from_abstract_name(&name.as_bytes()[1..]).unwrap();
+        UnixDatagram::bind_addr(&address).unwrap()
+    };
+    notify_socket(OsStr::new(&name), "WATCHDOG=1").unwrap();
+    let n = abstract_server.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"WATCHDOG=1");
+    assert!(notify_socket(dir.path().join("missing").as_os_str(), "READY=1").is_err());
+}
@@ -0,0 +1,274 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Per client limits, configured by `--max-requests-per-minute` and `--max-mb-per-hour`, so that
+//! a runaway client of a shared server cannot saturate its bandwidth.
+//!
+//! Clients are identified by their IP address. Requests over a limit are answered with
+//! `429 Too Many Requests` and a `Retry-After` header. Downloaded bytes are counted in the cache
+//! db, so that restarting the server does not reset them.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::body::{Body, HttpBody};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use futures_util::StreamExt;
+use http::header::{CONTENT_LENGTH, RETRY_AFTER};
+use http::{HeaderValue, StatusCode};
+
+use crate::db::Cache;
+use crate::log::ResultExt;
+
+/// How many clients are tracked before forgetting those which did not make requests recently
+const MAX_TRACKED_CLIENTS: usize = 10000;
+
+/// Limits of what each client may request
+pub struct ClientQuotas {
+    cache: Cache,
+    max_requests_per_minute: Option<u64>,
+    max_bytes_per_hour: Option<u64>,
+    /// start of the current one minute window of each client, and how many requests it made
+    /// in it
+    requests: Mutex<HashMap<IpAddr, (Instant, u64)>>,
+}
+
+impl ClientQuotas {
+    /// Creates quotas, or `None` if there are no limits.
+    pub fn new(
+        cache: Cache,
+        max_requests_per_minute: Option<u64>,
+        max_mb_per_hour: Option<u64>,
+    ) -> Option<Self> {
+        if max_requests_per_minute.is_none() && max_mb_per_hour.is_none() {
+            return None;
+        }
+        Some(Self {
+            cache,
+            max_requests_per_minute,
+            max_bytes_per_hour: max_mb_per_hour.map(|mb| mb.saturating_mul(1024 * 1024)),
+            requests: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Counts a request of this client, and returns how long it should wait if it made too many.
+    fn count_request(&self, client: IpAddr, now: Instant) -> Option<Duration> {
+        let max = self.max_requests_per_minute?;
+        let minute = Duration::from_secs(60);
+        let mut requests = self.requests.lock().unwrap();
+        if requests.len() >= MAX_TRACKED_CLIENTS {
+            requests.retain(|_, (start, _)| now.duration_since(*start) < minute);
+        }
+        let (start, count) = requests.entry(client).or_insert((now, 0));
+        if now.duration_since(*start) >= minute {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= max {
+            return Some(minute - now.duration_since(*start));
+        }
+        *count += 1;
+        None
+    }
+
+    /// How many more bytes this client may download in the current hour, if limited
+    async fn remaining_bytes(&self, client: IpAddr) -> Option<u64> {
+        let max = self.max_bytes_per_hour?;
+        let used = match self.cache.get_client_bytes(&client.to_string()).await {
+            Ok(used) => used,
+            Err(e) => {
+                tracing::warn!("{:#}", e);
+                0
+            }
+        };
+        Some(max.saturating_sub(used))
+    }
+}
+
+/// Time until the next hour, when byte quotas are reset
+fn until_next_hour() -> Duration {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    Duration::from_secs(3600 - now % 3600)
+}
+
+/// Answers `429 Too Many Requests`
+fn too_many_requests(retry_after: Duration, message: String) -> Response {
+    tracing::info!("Responding error 429: {}", message);
+    let retry_after = HeaderValue::from(retry_after.as_secs().max(1));
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(RETRY_AFTER, retry_after)],
+        message,
+    )
+        .into_response()
+}
+
+/// Counts the bytes of a response body as it is sent, and adds them to the downloads of the
+/// client once it is dropped.
+struct Accounting {
+    cache: Cache,
+    client: IpAddr,
+    bytes: u64,
+}
+
+impl Accounting {
+    fn count(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+}
+
+impl Drop for Accounting {
+    fn drop(&mut self) {
+        if self.bytes == 0 {
+            return;
+        }
+        let cache = self.cache.clone();
+        let client = self.client.to_string();
+        let bytes = self.bytes;
+        tokio::spawn(async move { cache.add_client_bytes(&client, bytes).await.or_warn() });
+    }
+}
+
+/// Middleware enforcing [ClientQuotas]
+pub async fn enforce_quotas(
+    State(quotas): State<Arc<ClientQuotas>>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if matches!(request.uri().path(), "/healthz" | "/readyz") {
+        // probes of orchestrators are not client requests
+        return next.run(request).await;
+    }
+    let client = address.ip();
+    if let Some(retry_after) = quotas.count_request(client, Instant::now()) {
+        return too_many_requests(
+            retry_after,
+            format!(
+                "more than {} requests per minute",
+                quotas.max_requests_per_minute.unwrap_or(0)
+            ),
+        );
+    }
+    let remaining = quotas.remaining_bytes(client).await;
+    if remaining == Some(0) {
+        return too_many_requests(
+            until_next_hour(),
+            "download quota for this hour exceeded".to_owned(),
+        );
+    }
+    let response = next.run(request).await;
+    if quotas.max_bytes_per_hour.is_none() {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let length = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok())
+        .or_else(|| body.size_hint().exact());
+    if let (Some(length), Some(remaining)) = (length, remaining) {
+        if length > remaining {
+            return too_many_requests(
+                until_next_hour(),
+                format!(
+                    "response of {} bytes exceeds the {} bytes left in the download quota for this hour",
+                    length, remaining
+                ),
+            );
+        }
+    }
+    if let Some(length) = length {
+        // the stream counting bytes below has no known length anymore
+        parts
+            .headers
+            .entry(CONTENT_LENGTH)
+            .or_insert(HeaderValue::from(length));
+    }
+    let mut accounting = Accounting {
+        cache: quotas.cache.clone(),
+        client,
+        bytes: 0,
+    };
+    let body = body.into_data_stream().inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            accounting.count(chunk.len());
+        }
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+#[tokio::test]
+async fn test_count_request() {
+    let quotas = ClientQuotas {
+        cache: Cache::open_in_memory().await.unwrap(),
+        max_requests_per_minute: Some(2),
+        max_bytes_per_hour: None,
+        requests: Mutex::new(HashMap::new()),
+    };
+    let a: IpAddr = "192.0.2.1".parse().unwrap();
+    let b: IpAddr = "192.0.2.2".parse().unwrap();
+    let start = Instant::now();
+    assert_eq!(quotas.count_request(a, start), None);
+    assert_eq!(quotas.count_request(a, start), None);
+    assert_eq!(
+        quotas.count_request(a, start + Duration::from_secs(20)),
+        Some(Duration::from_secs(40))
+    );
+    assert_eq!(quotas.count_request(b, start), None);
+    assert_eq!(
+        quotas.count_request(a, start + Duration::from_secs(60)),
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_enforce_quotas() {
+    use tower::ServiceExt;
+    let cache = Cache::open_in_memory().await.unwrap();
+    let quotas = Arc::new(ClientQuotas::new(cache.clone(), None, Some(1)).unwrap());
+    let app = axum::Router::new()
+        .route("/", axum::routing::get(|| async { vec![0u8; 600 * 1024] }))
+        .layer(axum::middleware::from_fn_with_state(quotas, enforce_quotas));
+    let address: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+    let get = || {
+        let mut request = Request::new(Body::empty());
+        request.extensions_mut().insert(ConnectInfo(address));
+        app.clone().oneshot(request)
+    };
+    let response = get().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response_length = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .map(|length| length.to_str().unwrap().to_owned());
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(body.len(), 600 * 1024);
+    // the bytes are counted in a separate task
+    for _ in 0..100 {
+        if cache.get_client_bytes("192.0.2.1").await.unwrap() > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(
+        cache.get_client_bytes("192.0.2.1").await.unwrap(),
+        600 * 1024
+    );
+    assert_eq!(response_length.as_deref(), Some("614400"));
+    // the second response would exceed the quota
+    let response = get().await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers().contains_key(RETRY_AFTER));
+}
@@ -0,0 +1,1681 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Finds the files corresponding to a buildid, fetching them if necessary.
+//!
+//! This is what the [crate::server] serves, and what the `find` subcommand prints.
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::os::unix::prelude::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use futures_util::StreamExt;
+use tempfile::TempDir;
+use tokio::sync::OnceCell;
+
+use crate::config::NixConfig;
+use crate::db::{decode_path, encode_path, Cache, Entry};
+use crate::index::index_single_store_path_to_cache;
+use crate::log::ResultExt;
+use crate::store::{
+    archive_members, file_created_by_patch, get_file_for_source_with, get_store_path, is_patch,
+    is_read_only, normalize, realise, source_files, RealiseOptions, SourceLocation,
+};
+use crate::substituter::{
+    fetch_nar_size, index_lookup_keys, url_priority, warm_debuginfo_lookup, Credentials,
+    FileSubstituter, HttpClient, HttpSubstituter, Substituter, DEFAULT_PRIORITY,
+};
+use crate::upstream::Upstream;
+use crate::Options;
+
+/// Finds the debuginfo, executable and source of buildids, trying harder and harder.
+///
+/// Cloning this structure returns a structure referring to the same cache.
+#[derive(Clone)]
+pub struct Resolver {
+    cache: Cache,
+    substituters: Arc<Substituters>,
+    /// where to store debuginfo fetched from substituters, if not in the store
+    private_debuginfo: Option<PathBuf>,
+    /// limits on realising source store paths
+    source_quota: Arc<SourceQuota>,
+    /// debuginfo lookups in progress
+    debuginfo_requests: Arc<Coalescer<Option<PathBuf>>>,
+    /// central cache to ask before trying harder
+    upstream: Option<Arc<Upstream>>,
+    /// local directories where sources not found otherwise may be
+    source_maps: Arc<Vec<SourceMap>>,
+    /// local checkouts where sources not found otherwise may be, at the same relative path
+    extra_source_dirs: Arc<Vec<PathBuf>>,
+    /// debug outputs already reindexed because a split dwarf file was missing from them
+    split_dwarf_reindexed: Arc<Mutex<HashSet<String>>>,
+}
+
+/// How many debug outputs reindexed for split dwarf files are remembered
+const MAX_SPLIT_DWARF_REINDEXED: usize = 1000;
+
+impl Resolver {
+    /// Creates a [`Resolver`] looking in this cache, then in these substituters.
+    pub fn new(
+        cache: Cache,
+        substituters: Vec<Box<dyn Substituter>>,
+        private_debuginfo: Option<PathBuf>,
+        source_quota: SourceQuota,
+    ) -> Self {
+        Self {
+            cache,
+            substituters: Arc::new(Substituters::new(substituters)),
+            private_debuginfo,
+            source_quota: Arc::new(source_quota),
+            debuginfo_requests: Arc::new(Coalescer::default()),
+            upstream: None,
+            source_maps: Arc::new(Vec::new()),
+            extra_source_dirs: Arc::new(Vec::new()),
+            split_dwarf_reindexed: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Asks `upstream` about buildids missing from the cache before trying harder.
+    pub fn with_upstream(self, upstream: Upstream) -> Self {
+        Self {
+            upstream: Some(Arc::new(upstream)),
+            ..self
+        }
+    }
+
+    /// Looks for source files not found otherwise in these local directories.
+    pub fn with_source_maps(self, source_maps: Vec<SourceMap>) -> Self {
+        Self {
+            source_maps: Arc::new(source_maps),
+            ..self
+        }
+    }
+
+    /// Looks for source files not found otherwise in these local checkouts.
+    pub fn with_extra_source_dirs(self, extra_source_dirs: Vec<PathBuf>) -> Self {
+        Self {
+            extra_source_dirs: Arc::new(extra_source_dirs),
+            ..self
+        }
+    }
+
+    /// Creates a [`Resolver`] looking in this cache, then in the substituters of nix.conf, as
+    /// configured by the command line options.
+    pub async fn from_options(cache: Cache, args: &Options) -> anyhow::Result<Self> {
+        let http = HttpClient::new(
+            args.http_proxy.as_deref(),
+            args.max_download_rate.map(|rate| rate * 1000),
+        )?;
+        let substituters = if args.read_only {
+            Vec::new()
+        } else if args.from_cache.is_empty() {
+            get_substituters(&http, &args.substituter, !args.no_default_substituters).await
+        } else {
+            substituters_from_urls(args.from_cache.iter().map(String::as_str), &http).await
+        };
+        // without a local store, debuginfo cannot be added to the store
+        let private_debuginfo = if args.private_debuginfo || !args.from_cache.is_empty() {
+            let dir = crate::db::cache_dir()?.join("debuginfo");
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("creating directory {}", dir.display()))?;
+            Some(dir)
+        } else {
+            None
+        };
+        let source_quota = SourceQuota::new(
+            args.max_source_size.map(|size| size * MB),
+            args.source_quota.map(|size| size * MB),
+        );
+        let resolver = Resolver::new(cache, substituters, private_debuginfo, source_quota)
+            .with_source_maps(args.source_map.clone())
+            .with_extra_source_dirs(args.extra_source_dir.clone());
+        // without delaying startup
+        resolver.substituters.refresh_if_stale();
+        Ok(match &args.upstream {
+            None => resolver,
+            Some(spec) => resolver.with_upstream(Upstream::open(spec).await?),
+        })
+    }
+
+    /// Copies what the upstream cache knows about this buildid to the local cache.
+    ///
+    /// Returns whether upstream knew anything. Errors are only logged, as the local cache can
+    /// still try harder.
+    async fn replicate(&self, buildid: &str) -> bool {
+        let Some(upstream) = &self.upstream else {
+            return false;
+        };
+        match upstream.get_entry(buildid).await {
+            Ok(Some(entry)) => {
+                tracing::debug!("found {} upstream", buildid);
+                match self.cache.register_missing(&[entry]).await {
+                    Ok(()) => true,
+                    Err(e) => {
+                        tracing::warn!("copying {} from upstream: {:#}", buildid, e);
+                        false
+                    }
+                }
+            }
+            Ok(None) => false,
+            Err(e) => {
+                tracing::warn!("asking upstream about {}: {:#}", buildid, e);
+                false
+            }
+        }
+    }
+
+    /// Looks for the debuginfo of this buildid, trying harder and harder.
+    ///
+    /// Returns the path of the debuginfo, which exists.
+    ///
+    /// Concurrent lookups of the same buildid, as when several threads of gdb load the same
+    /// library, share a single lookup.
+    pub async fn debuginfo(&self, buildid: &str) -> anyhow::Result<Option<PathBuf>> {
+        self.debuginfo_requests
+            .run(buildid, self.resolve_debuginfo(buildid))
+            .await
+    }
+
+    /// Implementation of [Resolver::debuginfo], without sharing
+    async fn resolve_debuginfo(&self, buildid: &str) -> anyhow::Result<Option<PathBuf>> {
+        if let Some(path) = crate::bundle::debuginfo(buildid) {
+            return Ok(Some(path));
+        }
+        let res = and_realise(self.cache.get_debuginfo(buildid).await, "debuginfo").await;
+        let res = match res {
+            Ok(None) if self.replicate(buildid).await => {
+                and_realise(self.cache.get_debuginfo(buildid).await, "debuginfo").await
+            }
+            res => res,
+        };
+        let res = match res {
+            Ok(None) => {
+                // try again harder
+                tracing::debug!("{} was not in cache, reindexing online", buildid);
+                // substituters may still have the debuginfo
+                maybe_reindex_by_build_id(&self.cache, buildid)
+                    .await
+                    .or_warn();
+                and_realise(self.cache.get_debuginfo(buildid).await, "debuginfo").await
+            }
+            res => res,
+        };
+        let res = match res {
+            Ok(None) => {
+                // try again harder
+                tracing::debug!(
+                    "online reindexation failed for {}, using hydra API",
+                    buildid
+                );
+                match maybe_fetch_debuginfo_from_substituter_index(
+                    &self.cache,
+                    &self.substituters.sorted(),
+                    self.private_debuginfo.as_deref(),
+                    buildid,
+                )
+                .await
+                {
+                    Ok(()) => {
+                        and_realise(self.cache.get_debuginfo(buildid).await, "debuginfo").await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            res => res,
+        };
+        if let Ok(None) = res {
+            unavailable_if_unrealised(self.cache.get_debuginfo(buildid).await, "debuginfo")?;
+        }
+        res
+    }
+
+    /// Looks up in substituters where the debuginfo of the buildids in these store paths is,
+    /// for those whose debuginfo is not known locally, without downloading it.
+    ///
+    /// This saves the roundtrips to the debuginfo index of substituters when the debuginfo is
+    /// first requested.
+    pub async fn warm_debuginfo_lookups(&self, storepaths: &[PathBuf]) {
+        let mut buildids = Vec::new();
+        for storepath in storepaths {
+            let storepath = encode_path(storepath);
+            match self
+                .cache
+                .get_entries_in(&storepath, MAX_WARMED_PER_PATH)
+                .await
+            {
+                Ok(entries) => buildids.extend(
+                    entries
+                        .into_iter()
+                        .filter(|entry| entry.debuginfo.is_none())
+                        .map(|entry| entry.buildid),
+                ),
+                Err(e) => tracing::warn!("listing buildids in {}: {:#}", storepath, e),
+            }
+        }
+        tracing::info!(
+            "Looking up the debuginfo of {} buildids in substituters",
+            buildids.len()
+        );
+        let found = futures_util::stream::iter(buildids)
+            .map(|buildid| async move {
+                for substituter in self.substituters.sorted() {
+                    match warm_debuginfo_lookup(substituter, &self.cache, &buildid).await {
+                        Ok(true) => return true,
+                        Ok(false) => (),
+                        Err(e) => tracing::debug!(
+                            "looking up debuginfo of {} in {}: {:#}",
+                            buildid,
+                            substituter.url(),
+                            e
+                        ),
+                    }
+                }
+                false
+            })
+            .buffer_unordered(WARM_CONCURRENCY)
+            .filter(|found| std::future::ready(*found))
+            .count()
+            .await;
+        tracing::info!("Substituters have the debuginfo of {} of them", found);
+    }
+
+    /// Looks for the split dwarf file (`.dwo` or `.dwp`) `name` of this buildid, in the debug
+    /// output containing its debuginfo.
+    ///
+    /// `name` is the `DW_AT_dwo_name` of a skeleton unit, or the file name of the executable
+    /// followed by `.dwp`. Files are looked up by file name, and among files with the same file
+    /// name, the one whose path ends with `name` is preferred.
+    ///
+    /// Returns the path of the split dwarf file, which exists.
+    pub async fn split_dwarf(&self, buildid: &str, name: &Path) -> anyhow::Result<Option<PathBuf>> {
+        let file_name = match name.file_name().and_then(|name| name.to_str()) {
+            None => return Ok(None),
+            Some(file_name) => file_name,
+        };
+        let debuginfo = match self.debuginfo(buildid).await? {
+            None => return Ok(None),
+            Some(debuginfo) => debuginfo,
+        };
+        let output = match get_store_path(&debuginfo).and_then(|output| output.to_str()) {
+            // debuginfo fetched with --private-debuginfo has no debug output
+            None => return Ok(None),
+            Some(output) => output.to_owned(),
+        };
+        let mut candidates = self.cache.get_split_dwarf(&output, file_name).await?;
+        if candidates.is_empty() && !is_read_only() && self.first_split_dwarf_reindex(&output) {
+            // the debug output may have been realised after the last indexation, but once it
+            // is indexed, further misses are final
+            index_single_store_path_to_cache(&self.cache, Path::new(&output), false)
+                .await
+                .with_context(|| format!("indexing {}", output))?;
+            candidates = self.cache.get_split_dwarf(&output, file_name).await?;
+        }
+        let relative = name.strip_prefix("/").unwrap_or(name);
+        let best = candidates
+            .iter()
+            .find(|candidate| Path::new(candidate).ends_with(relative))
+            .or(candidates.first());
+        Ok(best.map(PathBuf::from))
+    }
+
+    /// Whether this debug output was not reindexed for a missing split dwarf file yet, and
+    /// remembers that it is.
+    fn first_split_dwarf_reindex(&self, output: &str) -> bool {
+        let mut reindexed = self.split_dwarf_reindexed.lock().unwrap();
+        if reindexed.len() >= MAX_SPLIT_DWARF_REINDEXED {
+            reindexed.clear();
+        }
+        reindexed.insert(output.to_owned())
+    }
+
+    /// Looks for the executable of this buildid.
+    ///
+    /// If the executable cannot be realised, it may be fetched from the nar of its store path in
+    /// substituters, in which case it only exists as long as the returned temporary directory.
+    pub async fn executable(
+        &self,
+        buildid: &str,
+    ) -> anyhow::Result<Option<(Option<TempDir>, PathBuf)>> {
+        let mut res = and_realise(self.cache.get_executable(buildid).await, "executable").await?;
+        if res.is_none() && self.replicate(buildid).await {
+            res = and_realise(self.cache.get_executable(buildid).await, "executable").await?;
+        }
+        if let Some(exe) = res {
+            return Ok(Some((None, exe)));
+        }
+        match self.cache.get_executable(buildid).await? {
+            Some(exe) => {
+                // the executable is known but cannot be realised
+                tracing::debug!(
+                    "{} cannot be realised, using substituter nars",
+                    exe.display()
+                );
+                match maybe_fetch_from_substituter_nar(&self.substituters.sorted(), exe.as_ref())
+                    .await?
+                {
+                    Some((tempdir, path)) => Ok(Some((Some(tempdir), path))),
+                    None => Err(anyhow::Error::new(Unavailable(format!(
+                        "executable {} could not be realised nor fetched from substituters",
+                        exe.display()
+                    )))),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Looks for the source file `request` of this buildid.
+    ///
+    /// May download the source if required and allowed by the [SourceQuota].
+    pub async fn source(
+        &self,
+        buildid: &str,
+        request: &Path,
+    ) -> anyhow::Result<Option<SourceLocation>> {
+        // size of the source store paths realised for this request
+        let mut used = 0;
+        let source = self.source_path(buildid, &mut used).await?;
+        let prefix = match self.cache.get_source_prefix(buildid).await {
+            Ok(prefix) => prefix.as_deref().map(decode_path),
+            Err(e) => {
+                tracing::warn!("{:#}", e);
+                None
+            }
+        };
+        let file = match &source {
+            None => {
+                tracing::debug!("no source found for buildid {}", buildid);
+                None
+            }
+            Some(source) => {
+                tracing::debug!(
+                    "found source store path for buildid {} at {}",
+                    buildid,
+                    source.display()
+                );
+                self.find_in_source(source.clone(), request, prefix.as_deref())
+                    .await
+                    .context("looking in source")?
+            }
+        };
+        if file.is_some() {
+            return Ok(file);
+        }
+        // the other srcs and the patches of the derivation
+        let roots = match &source {
+            None => Vec::new(),
+            Some(source) => self
+                .cache
+                .get_source_roots(&encode_path(source))
+                .await
+                .with_context(|| {
+                    format!("getting other sources of {} from cache", source.display())
+                })?,
+        };
+        for root in roots {
+            let root = self
+                .and_realise_source(Ok(Some(decode_path(&root))), "extra source", &mut used)
+                .await
+                .with_context(|| format!("getting other sources of {} from cache", buildid))?;
+            let Some(root) = root else {
+                continue;
+            };
+            let file = self
+                .find_in_source(root.clone(), request, prefix.as_deref())
+                .await
+                .with_context(|| format!("looking in {}", root.display()));
+            match file {
+                Ok(Some(file)) => return Ok(Some(file)),
+                Ok(None) => (),
+                Err(e) => tracing::info!("{:#}", e),
+            }
+        }
+        // generated files are not in the source, but may be in a capture of the build directory
+        let build_source = self.cache.get_build_source(buildid).await;
+        let build_source = self
+            .and_realise_source(build_source, "build directory", &mut used)
+            .await
+            .with_context(|| format!("getting build directory of {} from cache", buildid))?;
+        let file = match build_source {
+            None => None,
+            Some(build_source) => {
+                tracing::debug!(
+                    "found build directory for buildid {} at {}",
+                    buildid,
+                    build_source.display()
+                );
+                self.find_in_source(build_source, request, None)
+                    .await
+                    .context("looking in build directory")?
+            }
+        };
+        if file.is_some() {
+            return Ok(file);
+        }
+        // locally patched packages have their source in a checkout
+        if let Some(file) = self
+            .find_in_extra_source_dirs(request, prefix.as_deref())
+            .await
+        {
+            return Ok(Some(file));
+        }
+        // binaries built in nix develop have their source in a working tree
+        let file = self.find_in_source_maps(request).await;
+        if file.is_none() {
+            // the file may be in the source that could not be realised
+            unavailable_if_unrealised(self.cache.get_source(buildid).await, "source")?;
+            unavailable_if_unrealised(
+                self.cache.get_build_source(buildid).await,
+                "build directory",
+            )?;
+        }
+        Ok(file)
+    }
+
+    /// Looks for the source store path of this buildid, and realises it if allowed by the
+    /// [SourceQuota].
+    ///
+    /// `used` is the size of the source store paths already realised for the same request.
+    async fn source_path(&self, buildid: &str, used: &mut u64) -> anyhow::Result<Option<PathBuf>> {
+        let source = self.cache.get_source(buildid).await;
+        let source = match self.and_realise_source(source, "source", used).await {
+            Ok(None) if self.replicate(buildid).await => {
+                let source = self.cache.get_source(buildid).await;
+                self.and_realise_source(source, "source", used).await
+            }
+            source => source,
+        };
+        let source = match source {
+            Ok(None) => {
+                // try again harder
+                match maybe_reindex_by_build_id(&self.cache, buildid).await {
+                    Ok(()) => {
+                        let source = self.cache.get_source(buildid).await;
+                        self.and_realise_source(source, "source", used).await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            source => source,
+        };
+        source.with_context(|| format!("getting source of {} from cache", buildid))
+    }
+
+    /// Lists the files of the source store path of this buildid, as served at
+    /// `/buildid/BUILDID/source-index`.
+    ///
+    /// May download the source if allowed by the [SourceQuota].
+    pub async fn source_index(&self, buildid: &str) -> anyhow::Result<Option<SourceIndex>> {
+        let Some(source) = self.source_path(buildid, &mut 0).await? else {
+            unavailable_if_unrealised(self.cache.get_source(buildid).await, "source")?;
+            return Ok(None);
+        };
+        let prefix = self.cache.get_source_prefix(buildid).await?;
+        let cache = self.cache.clone();
+        let runtime = tokio::runtime::Handle::current();
+        let path = source.clone();
+        let files = tokio::task::spawn_blocking(move || {
+            source_files(&path, &mut |archive| {
+                cached_archive_members(&cache, &runtime, archive)
+            })
+        })
+        .await??;
+        Ok(Some(SourceIndex {
+            source: encode_path(&source),
+            prefix,
+            files: files.iter().map(|file| encode_path(file)).collect(),
+        }))
+    }
+
+    /// Looks for the file matching `request` in the existing source path `source`, which was
+    /// unpacked to `prefix` during the build if known.
+    ///
+    /// Listing the files of a large source archive takes a while, so the list is kept in the
+    /// cache for next time.
+    async fn find_in_source(
+        &self,
+        source: PathBuf,
+        request: &Path,
+        prefix: Option<&Path>,
+    ) -> anyhow::Result<Option<SourceLocation>> {
+        let request = request.to_path_buf();
+        let prefix = prefix.map(Path::to_path_buf);
+        let cache = self.cache.clone();
+        let runtime = tokio::runtime::Handle::current();
+        tokio::task::spawn_blocking(move || {
+            let mut list_archive =
+                |archive: &Path| cached_archive_members(&cache, &runtime, archive);
+            get_file_for_source_with(&source, &request, prefix.as_deref(), &mut list_archive)
+        })
+        .await?
+    }
+
+    /// Looks for the source file `request` in the directories given by `--source-map`.
+    async fn find_in_source_maps(&self, request: &Path) -> Option<SourceLocation> {
+        // requests to the server have no leading slash
+        let request = normalize(&Path::new("/").join(request));
+        for map in self.source_maps.iter() {
+            let Some(candidate) = map.apply(&request) else {
+                continue;
+            };
+            if tokio::fs::metadata(&candidate)
+                .await
+                .is_ok_and(|metadata| metadata.is_file())
+            {
+                tracing::debug!(
+                    "found {} at {} with --source-map",
+                    request.display(),
+                    candidate.display()
+                );
+                return Some(SourceLocation::File(candidate));
+            }
+        }
+        None
+    }
+
+    /// Looks for the source file `request` in the directories given by `--extra-source-dir`.
+    ///
+    /// If the source was unpacked to `prefix` during the build, the file is looked for at the
+    /// same path relative to it. Otherwise, the longest end of `request` of at least
+    /// [MIN_EXTRA_SOURCE_COMPONENTS] components which exists in a directory is chosen, like
+    /// `src/main.c` for `/build/foo-1.0/src/main.c`, so that a file of another package is not
+    /// served just because it has the same name.
+    async fn find_in_extra_source_dirs(
+        &self,
+        request: &Path,
+        prefix: Option<&Path>,
+    ) -> Option<SourceLocation> {
+        if self.extra_source_dirs.is_empty() {
+            return None;
+        }
+        // requests to the server have no leading slash
+        let request = normalize(&Path::new("/").join(request));
+        let relative: Vec<PathBuf> =
+            match prefix.and_then(|prefix| request.strip_prefix(prefix).ok()) {
+                Some(relative) => vec![relative.to_path_buf()],
+                None => {
+                    let components: Vec<_> = request.iter().skip(1).collect();
+                    let starts = components
+                        .len()
+                        .saturating_sub(MIN_EXTRA_SOURCE_COMPONENTS - 1);
+                    (0..starts)
+                        .map(|start| components[start..].iter().collect())
+                        .collect()
+                }
+            };
+        for relative in &relative {
+            for dir in self.extra_source_dirs.iter() {
+                let candidate = dir.join(relative);
+                if tokio::fs::metadata(&candidate)
+                    .await
+                    .is_ok_and(|metadata| metadata.is_file())
+                {
+                    tracing::debug!(
+                        "found {} at {} with --extra-source-dir",
+                        request.display(),
+                        candidate.display()
+                    );
+                    return Some(SourceLocation::File(candidate));
+                }
+            }
+        }
+        None
+    }
+
+    /// Like [and_realise], but fails with [SourceTooLarge] instead of realising a source store
+    /// path that would exceed the [SourceQuota].
+    ///
+    /// `used` is the size of the source store paths already realised for the same request.
+    async fn and_realise_source(
+        &self,
+        result: anyhow::Result<Option<PathBuf>>,
+        tag: &str,
+        used: &mut u64,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        let charged = match &result {
+            Ok(Some(path)) => self.check_source_quota(path, used).await?,
+            _ => 0,
+        };
+        let realised = and_realise(result, tag).await;
+        if !matches!(realised, Ok(Some(_))) {
+            self.source_quota.refund(charged, used);
+        }
+        realised
+    }
+
+    /// Realises this source store path, unless it would exceed the [SourceQuota].
+    ///
+    /// `used` is the size of the source store paths already realised for the same request.
+    pub async fn realise_source(&self, path: &Path, used: &mut u64) -> anyhow::Result<()> {
+        let charged = self.check_source_quota(path, used).await?;
+        let realised = realise(path).await;
+        if realised.is_err() {
+            self.source_quota.refund(charged, used);
+        }
+        realised
+    }
+
+    /// Checks that realising the store path of this source file does not exceed the
+    /// [SourceQuota], and counts it as realised. Returns the size counted, to be refunded if
+    /// realising fails.
+    ///
+    /// `used` is the size of the source store paths already realised for the same request, and
+    /// is increased accordingly. Store paths already in the store and store paths whose size is
+    /// not known by substituters are always allowed.
+    async fn check_source_quota(&self, path: &Path, used: &mut u64) -> anyhow::Result<u64> {
+        let quota = &self.source_quota;
+        if quota.per_request.is_none() && quota.total.is_none() {
+            return Ok(0);
+        }
+        let storepath = match get_store_path(path) {
+            Some(storepath) if !storepath.exists() => storepath,
+            _ => return Ok(0),
+        };
+        let size = match self.nar_size(storepath).await {
+            Some(size) => size,
+            None => return Ok(0),
+        };
+        quota.charge(storepath, size, used)?;
+        Ok(size)
+    }
+
+    /// The size of the nar of this store path according to the first substituter that knows it
+    pub async fn nar_size(&self, storepath: &Path) -> Option<u64> {
+        for substituter in self.substituters.sorted() {
+            match fetch_nar_size(substituter, storepath).await {
+                Ok(Some(size)) => return Some(size),
+                Ok(None) => (),
+                Err(e) => tracing::info!(
+                    "cannot get size of {} from substituter {}: {:#}",
+                    storepath.display(),
+                    substituter.url(),
+                    e
+                ),
+            }
+        }
+        None
+    }
+}
+
+/// Number of concurrent lookups in [Resolver::warm_debuginfo_lookups]
+const WARM_CONCURRENCY: usize = 8;
+
+/// Maximum number of buildids of a single store path looked up by
+/// [Resolver::warm_debuginfo_lookups]
+const MAX_WARMED_PER_PATH: u32 = 1000;
+
+/// Runs a single lookup at a time for each key, and shares its result with the concurrent
+/// lookups of the same key.
+pub struct Coalescer<T> {
+    /// shared result of the lookup in progress for each key
+    in_flight: std::sync::Mutex<HashMap<String, Arc<SharedResult<T>>>>,
+}
+
+/// The result of a lookup, once it is over
+type SharedResult<T> = OnceCell<Result<T, Arc<anyhow::Error>>>;
+
+impl<T> Default for Coalescer<T> {
+    fn default() -> Self {
+        Self {
+            in_flight: Default::default(),
+        }
+    }
+}
+
+impl<T: Clone> Coalescer<T> {
+    /// Returns the result of `lookup`, or of the lookup of the same `key` in progress.
+    ///
+    /// If the lookup in progress is cancelled, one of the waiting lookups takes over.
+    pub async fn run(
+        &self,
+        key: &str,
+        lookup: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let cell = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.to_owned())
+            .or_insert_with(|| Arc::new(OnceCell::new()))
+            .clone();
+        let result = cell
+            .get_or_init(|| async { lookup.await.map_err(Arc::new) })
+            .await
+            .clone();
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(key)
+            .is_some_and(|current| Arc::ptr_eq(current, &cell))
+        {
+            in_flight.remove(key);
+        }
+        result.map_err(|e| anyhow::Error::new(SharedError(e)))
+    }
+}
+
+/// An error returned to all the lookups sharing it in a [Coalescer]
+#[derive(Debug)]
+pub struct SharedError(Arc<anyhow::Error>);
+
+impl From<anyhow::Error> for SharedError {
+    fn from(error: anyhow::Error) -> Self {
+        Self(Arc::new(error))
+    }
+}
+
+impl SharedError {
+    /// The error of the shared lookup
+    pub fn inner(&self) -> &anyhow::Error {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for SharedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for SharedError {}
+
+#[tokio::test]
+async fn test_coalescer() {
+    let coalescer = Coalescer::default();
+    let lookups = std::sync::atomic::AtomicUsize::new(0);
+    let lookup = |result: anyhow::Result<u32>| async {
+        lookups.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        result
+    };
+    let (a, b, c) = tokio::join!(
+        coalescer.run("a", lookup(Ok(1))),
+        coalescer.run("a", lookup(Ok(2))),
+        coalescer.run("b", lookup(Ok(3))),
+    );
+    assert_eq!((a.unwrap(), b.unwrap(), c.unwrap()), (1, 1, 3));
+    assert_eq!(lookups.load(std::sync::atomic::Ordering::SeqCst), 2);
+    assert!(coalescer.in_flight.lock().unwrap().is_empty());
+
+    let (a, b) = tokio::join!(
+        coalescer.run("a", lookup(Err(anyhow::anyhow!("oops")))),
+        coalescer.run("a", lookup(Ok(2))),
+    );
+    assert_eq!(a.unwrap_err().to_string(), "oops");
+    assert_eq!(b.unwrap_err().to_string(), "oops");
+    // the previous lookup is over
+    assert_eq!(coalescer.run("a", lookup(Ok(4))).await.unwrap(), 4);
+}
+
+#[tokio::test]
+async fn test_debuginfo_of_older_generation() {
+    // the executable of a generation rolled back from, which still runs, but whose store path
+    // and debug output were garbage collected since it was indexed
+    let exe = std::env::current_exe().unwrap();
+    let buildid = crate::store::get_buildid(&exe).unwrap().unwrap();
+    let dir = TempDir::new().unwrap();
+    let gone = dir.path().join("gone");
+    let old_exe = gone.join("aaaa-hello-1.0/bin/hello");
+    let old_debuginfo = gone.join(format!(
+        "bbbb-hello-1.0-debug/lib/debug/.build-id/{}/{}.debug",
+        &buildid[..2],
+        &buildid[2..]
+    ));
+    let cache = Cache::open_in_memory().await.unwrap();
+    cache
+        .register(&[crate::db::Entry {
+            buildid: buildid.clone(),
+            executable: Some(encode_path(&old_exe)),
+            debuginfo: Some(encode_path(&old_debuginfo)),
+            source: None,
+            build_source: None,
+            architecture: None,
+        }])
+        .await
+        .unwrap();
+    // its debuginfo is still in the debuginfo index of a substituter
+    let binary_cache = dir.path().join("binary-cache");
+    std::fs::create_dir_all(binary_cache.join("debuginfo")).unwrap();
+    std::fs::copy(&exe, binary_cache.join("debuginfo").join(&buildid)).unwrap();
+    let substituter = crate::substituter::FileSubstituter::from_url(&format!(
+        "file://{}",
+        binary_cache.display()
+    ))
+    .await
+    .unwrap()
+    .unwrap();
+    let private = dir.path().join("private");
+    std::fs::create_dir(&private).unwrap();
+    let resolver = Resolver::new(
+        cache.clone(),
+        vec![Box::new(substituter)],
+        Some(private.clone()),
+        SourceQuota::default(),
+    );
+    let debuginfo = resolver.debuginfo(&buildid).await.unwrap().unwrap();
+    assert!(debuginfo.starts_with(&private));
+    assert_eq!(
+        crate::store::get_buildid(&debuginfo).unwrap(),
+        Some(buildid.clone())
+    );
+    // what is known about the older generation was not forgotten
+    assert_eq!(cache.get_executable(&buildid).await.unwrap(), Some(old_exe));
+}
+
+/// Limits on the size of the source store paths realised to answer requests, as nar sizes in
+/// bytes.
+#[derive(Debug, Default)]
+pub struct SourceQuota {
+    /// maximum size of the source store paths realised for a single request
+    per_request: Option<u64>,
+    /// maximum size of the source store paths realised since startup
+    total: Option<u64>,
+    /// size of the source store paths realised since startup
+    used: std::sync::Mutex<u64>,
+}
+
+impl SourceQuota {
+    /// Creates a quota with these limits in bytes.
+    pub fn new(per_request: Option<u64>, total: Option<u64>) -> Self {
+        Self {
+            per_request,
+            total,
+            used: std::sync::Mutex::new(0),
+        }
+    }
+
+    /// Counts `size` bytes for `storepath` against the quota, unless this would exceed it.
+    ///
+    /// `used` is the size already counted for the same request.
+    fn charge(&self, storepath: &Path, size: u64, used: &mut u64) -> Result<(), SourceTooLarge> {
+        let too_large = |limit, option| SourceTooLarge {
+            storepath: storepath.to_path_buf(),
+            size,
+            limit,
+            option,
+        };
+        if let Some(limit) = self.per_request {
+            if *used + size > limit {
+                return Err(too_large(limit, "--max-source-size"));
+            }
+        }
+        let mut total_used = self.used.lock().unwrap();
+        if let Some(limit) = self.total {
+            if *total_used + size > limit {
+                return Err(too_large(limit, "--source-quota"));
+            }
+        }
+        *total_used += size;
+        *used += size;
+        Ok(())
+    }
+
+    /// Gives back `size` bytes counted by [SourceQuota::charge] for a store path which could
+    /// not be realised.
+    fn refund(&self, size: u64, used: &mut u64) {
+        let mut total_used = self.used.lock().unwrap();
+        *total_used = total_used.saturating_sub(size);
+        *used = used.saturating_sub(size);
+    }
+}
+
+/// The error when realising a source store path would exceed the [SourceQuota]
+#[derive(Debug)]
+pub struct SourceTooLarge {
+    /// the source store path
+    pub storepath: PathBuf,
+    /// its nar size in bytes
+    pub size: u64,
+    /// the limit it would exceed, in bytes
+    pub limit: u64,
+    /// the command line option setting this limit
+    pub option: &'static str,
+}
+
+impl std::fmt::Display for SourceTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "not downloading source {} of {} MB, as it would exceed the limit of {} MB set by {}",
+            self.storepath.display(),
+            self.size / MB,
+            self.limit / MB,
+            self.option
+        )
+    }
+}
+
+impl std::error::Error for SourceTooLarge {}
+
+/// The error when a file is known but cannot be fetched right now, because realising it failed
+/// or substituters could not be reached. Retrying later may succeed.
+#[derive(Debug)]
+pub struct Unavailable(pub String);
+
+impl std::fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+/// Source files under `from` are looked for under `to`, as specified by `--source-map FROM=TO`.
+///
+/// This is for binaries built outside nix builds, like in `nix develop`, whose source is in a
+/// working tree instead of the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMap {
+    /// prefix of the requested source files
+    pub from: PathBuf,
+    /// the local directory containing them
+    pub to: PathBuf,
+}
+
+impl SourceMap {
+    /// Where the normalized absolute source file `request` is looked for, if it is under `from`.
+    fn apply(&self, request: &Path) -> Option<PathBuf> {
+        let relative = request.strip_prefix(&self.from).ok()?;
+        Some(self.to.join(relative))
+    }
+}
+
+impl FromStr for SourceMap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (from, to) = match s.split_once('=') {
+            Some(x) => x,
+            None => anyhow::bail!("expected FROM=TO, got {:?}", s),
+        };
+        let from = normalize(Path::new(from));
+        let to = PathBuf::from(to);
+        for path in [&from, &to] {
+            anyhow::ensure!(
+                path.is_absolute(),
+                "source map path {} should be absolute",
+                path.display()
+            );
+        }
+        Ok(SourceMap { from, to })
+    }
+}
+
+#[test]
+fn test_source_map() {
+    let map: SourceMap = "/build/source/=/home/me/project".parse().unwrap();
+    assert_eq!(
+        map,
+        SourceMap {
+            from: PathBuf::from("/build/source"),
+            to: PathBuf::from("/home/me/project"),
+        }
+    );
+    assert_eq!(
+        map.apply(Path::new("/build/source/src/main.c")),
+        Some(PathBuf::from("/home/me/project/src/main.c"))
+    );
+    assert_eq!(map.apply(Path::new("/build/sourcefoo/main.c")), None);
+    assert_eq!(map.apply(Path::new("/build/other/main.c")), None);
+    assert!("/build/source".parse::<SourceMap>().is_err());
+    assert!("build=/home/me/project".parse::<SourceMap>().is_err());
+    assert!("/build=project".parse::<SourceMap>().is_err());
+}
+
+#[tokio::test]
+async fn test_find_in_source_maps() {
+    let dir = tempfile::TempDir::new().unwrap();
+    std::fs::create_dir(dir.path().join("src")).unwrap();
+    std::fs::write(dir.path().join("src/main.c"), "int main() {}").unwrap();
+    let map = format!("/build/source={}", dir.path().display());
+    let resolver = Resolver::new(
+        Cache::open_in_memory().await.unwrap(),
+        vec![],
+        None,
+        SourceQuota::default(),
+    )
+    .with_source_maps(vec![map.parse().unwrap()]);
+    assert_eq!(
+        resolver
+            .find_in_source_maps(Path::new("build/source/lib/../src/main.c"))
+            .await,
+        Some(SourceLocation::File(dir.path().join("src/main.c")))
+    );
+    assert_eq!(
+        resolver
+            .find_in_source_maps(Path::new("build/source/src"))
+            .await,
+        None
+    );
+    assert_eq!(
+        resolver
+            .find_in_source_maps(Path::new("build/source/../../etc/passwd"))
+            .await,
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_find_in_extra_source_dirs() {
+    let dir = tempfile::TempDir::new().unwrap();
+    std::fs::create_dir_all(dir.path().join("src/io")).unwrap();
+    std::fs::write(dir.path().join("src/io/main.c"), "int main() {}").unwrap();
+    std::fs::write(dir.path().join("main.c"), "int main() {}").unwrap();
+    let resolver = Resolver::new(
+        Cache::open_in_memory().await.unwrap(),
+        vec![],
+        None,
+        SourceQuota::default(),
+    )
+    .with_extra_source_dirs(vec![dir.path().to_path_buf()]);
+    let find = |request: &'static str, prefix: Option<&'static str>| {
+        resolver.find_in_extra_source_dirs(Path::new(request), prefix.map(Path::new))
+    };
+    let file = |name: &str| Some(SourceLocation::File(dir.path().join(name)));
+    assert_eq!(
+        find("build/foo-1.0/src/io/main.c", None).await,
+        file("src/io/main.c")
+    );
+    // the file name alone is not enough
+    assert_eq!(find("build/foo-1.0/lib/main.c", None).await, None);
+    assert_eq!(find("main.c", None).await, None);
+    assert_eq!(
+        find("build/source/src/io/main.c", Some("/build/source")).await,
+        file("src/io/main.c")
+    );
+    assert_eq!(
+        find("build/source/lib/main.c", Some("/build/source")).await,
+        None
+    );
+    assert_eq!(find("build/foo-1.0/src/io", None).await, None);
+    assert_eq!(find("build/../../etc/passwd", None).await, None);
+}
+
+/// How many components of the end of the path of a source file must be found in a directory
+/// of `--extra-source-dir`, when the directory the source was unpacked to is not known
+const MIN_EXTRA_SOURCE_COMPONENTS: usize = 2;
+
+/// Lists the files of a source archive like [archive_members], but keeps the list in the cache
+/// for next time, as listing a large source archive takes a while.
+///
+/// Must be called outside the tokio `runtime`, which is used to query the cache.
+fn cached_archive_members(
+    cache: &Cache,
+    runtime: &tokio::runtime::Handle,
+    archive: &Path,
+) -> anyhow::Result<Vec<String>> {
+    let Some(key) = archive.to_str() else {
+        return archive_members(archive);
+    };
+    match runtime.block_on(cache.get_archive_members(key)) {
+        Ok(Some(members)) => return Ok(members),
+        Ok(None) => (),
+        Err(e) => tracing::warn!("{:#}", e),
+    }
+    let members = archive_members(archive)?;
+    runtime
+        .block_on(cache.register_archive_members(key, &members))
+        .or_warn();
+    Ok(members)
+}
+
+/// The files of the source of a buildid, as served at `/buildid/BUILDID/source-index`.
+///
+/// Paths are encoded with [encode_path].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct SourceIndex {
+    /// store path of the source
+    pub source: String,
+    /// where the source was unpacked during the build, like `/build/source`, if known
+    pub prefix: Option<String>,
+    /// files of the source: relative to it for a directory, or the members of an archive
+    pub files: Vec<String>,
+}
+
+/// Unit of `--max-source-size` and `--source-quota`
+pub const MB: u64 = 1_000_000;
+
+#[test]
+fn test_source_quota() {
+    let path = Path::new("/nix/store/aaaa-source");
+    let quota = SourceQuota::new(Some(10 * MB), Some(25 * MB));
+    let mut used = 0;
+    quota.charge(path, 6 * MB, &mut used).unwrap();
+    let err = quota.charge(path, 6 * MB, &mut used).unwrap_err();
+    assert_eq!(err.option, "--max-source-size");
+    assert_eq!(used, 6 * MB);
+    let mut used = 0;
+    quota.charge(path, 10 * MB, &mut used).unwrap();
+    let mut used = 0;
+    let err = quota.charge(path, 10 * MB, &mut used).unwrap_err();
+    assert_eq!(err.option, "--source-quota");
+    assert_eq!(used, 0);
+    quota.charge(path, 9 * MB, &mut used).unwrap();
+    // failed downloads do not use up the quota
+    quota.refund(9 * MB, &mut used);
+    assert_eq!(used, 0);
+    quota.charge(path, 9 * MB, &mut used).unwrap();
+    let err = quota.charge(path, 9 * MB, &mut 0).unwrap_err();
+    assert_eq!(err.option, "--source-quota");
+
+    let unlimited = SourceQuota::default();
+    let mut used = 0;
+    unlimited.charge(path, 1000 * MB, &mut used).unwrap();
+}
+
+/// Reindex harder.
+///
+/// If the .drv file is not in the store, automatic indexation will find the executable but not
+/// the debuginfo and source. We can attempt to download this drv file during a second
+/// indexation attempt.
+async fn maybe_reindex_by_build_id(cache: &Cache, buildid: &str) -> anyhow::Result<()> {
+    if is_read_only() {
+        return Ok(());
+    }
+    let exe = match cache
+        .get_executable(buildid)
+        .await
+        .with_context(|| format!("getting executable of {} from cache", buildid))?
+    {
+        Some(exe) => exe,
+        None => return Ok(()),
+    };
+    if tokio::fs::symlink_metadata(&exe).await.is_err() {
+        // like the executable of a generation rolled back from and garbage collected, which
+        // may still run. Reindexing it would only forget it.
+        tracing::debug!("not reindexing {}, which does not exist", exe.display());
+        return Ok(());
+    }
+    tracing::debug!("reindexing {}", exe.display());
+    let storepath = match get_store_path(exe.as_path()) {
+        Some(storepath) => storepath,
+        None => anyhow::bail!(
+            "executable {} for buildid {} is not a store path",
+            exe.display(),
+            buildid
+        ),
+    };
+    index_single_store_path_to_cache(cache, storepath, true)
+        .await
+        .with_context(|| format!("indexing {} online", exe.display()))?;
+    Ok(())
+}
+
+/// Fails with [Unavailable] if `known`, the file of type `tag` recorded in the cache, does not
+/// exist, because it is being realised or realising it failed in a way that retrying may fix.
+///
+/// Files which substituters do not have, like garbage collected store paths, are simply not
+/// served, so that clients do not retry forever.
+fn unavailable_if_unrealised(
+    known: anyhow::Result<Option<PathBuf>>,
+    tag: &str,
+) -> anyhow::Result<()> {
+    match known? {
+        // missing files are simply not served
+        Some(_) if is_read_only() => Ok(()),
+        Some(path)
+            if !path.exists()
+                && (crate::store::is_being_realised(&path)
+                    || crate::store::realise_failed_transiently(&path)) =>
+        {
+            let mut message = format!("{} {} could not be realised", tag, path.display());
+            if let Some(failure) = crate::store::realise_failure(&path) {
+                message.push_str(": ");
+                message.push_str(&failure);
+            }
+            Err(anyhow::Error::new(Unavailable(message)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Forgets what `cache` knows about this buildid, including lookups of its debuginfo in
+/// substituters, so that it is resolved again when it is next requested, for example after it
+/// was associated with the wrong files.
+///
+/// Returns the forgotten entry, if the buildid was known.
+pub async fn invalidate(cache: &Cache, buildid: &str) -> anyhow::Result<Option<Entry>> {
+    cache
+        .forget_buildid(buildid, &index_lookup_keys(buildid))
+        .await
+        .with_context(|| format!("invalidating {}", buildid))
+}
+
+/// Ensures that the contained path exists, and if this is not the case
+/// replace it by `Ok(None)`
+///
+/// The tag is the kind of file this should be, to be used in error messages
+pub async fn and_realise<T: AsRef<Path>>(
+    result: anyhow::Result<Option<T>>,
+    tag: &str,
+) -> anyhow::Result<Option<T>> {
+    match result {
+        Ok(Some(p)) => {
+            let res = realise(p.as_ref())
+                .await
+                .with_context(|| format!("realising {} of type {}", p.as_ref().display(), tag));
+
+            if res.is_err() {
+                res.or_warn();
+                Ok(None)
+            } else {
+                Ok(Some(p))
+            }
+        }
+        other => other,
+    }
+}
+
+/// attempts to fetch a file of a store path that cannot be realised from the nar of this store
+/// path in substituters.
+///
+/// The file is not added to the store and is only available as long as the returned temporary
+/// directory exists.
+async fn maybe_fetch_from_substituter_nar(
+    substituters: &[&dyn Substituter],
+    file: &Path,
+) -> anyhow::Result<Option<(TempDir, PathBuf)>> {
+    if crate::filter::is_blocked_path(file) {
+        return Ok(None);
+    }
+    for substituter in substituters.iter() {
+        match crate::substituter::fetch_store_path_member(*substituter, file).await {
+            Err(e) => tracing::info!(
+                "cannot fetch {} from substituter {}: {:#}",
+                file.display(),
+                substituter.url(),
+                e
+            ),
+            Ok(None) => (),
+            Ok(Some(result)) => return Ok(Some(result)),
+        }
+    }
+    Ok(None)
+}
+
+/// attempts to fetch debuginfo from substituters via the same API as dwarffs
+///
+/// Nothing is fetched when store paths are blocked: the index of a substituter points to a nar
+/// without telling which store path it is the nar of, so it may be the nar of a blocked store
+/// path.
+async fn maybe_fetch_debuginfo_from_substituter_index(
+    cache: &Cache,
+    substituters: &[&dyn Substituter],
+    private_dir: Option<&Path>,
+    buildid: &str,
+) -> anyhow::Result<()> {
+    if crate::filter::is_blocked_buildid(buildid) {
+        return Ok(());
+    }
+    if crate::filter::blocks_store_paths() {
+        tracing::debug!(
+            "not looking up {} in the index of substituters, as store paths are blocked",
+            buildid
+        );
+        return Ok(());
+    }
+    // whether a substituter could tell whether it has the debuginfo
+    let mut answered = false;
+    let mut last_error = None;
+    for substituter in substituters.iter() {
+        match crate::substituter::fetch_debuginfo(*substituter, cache, private_dir, buildid).await {
+            Err(e) => {
+                tracing::info!(
+                    "cannot fetch buildid {} from substituter {}: {:#}",
+                    buildid,
+                    substituter.url(),
+                    e
+                );
+                last_error = Some(e);
+            }
+            Ok(None) => answered = true,
+            Ok(Some(path)) => {
+                answered = true;
+                tracing::info!(
+                    "fetched {} from substituter {}, now indexing it",
+                    path.display(),
+                    substituter.url()
+                );
+                index_single_store_path_to_cache(cache, &path, false)
+                    .await
+                    .with_context(|| format!("indexing {}", path.display()))
+                    .or_warn();
+                if let Ok(Some(_)) =
+                    and_realise(cache.get_debuginfo(buildid).await, "debuginfo").await
+                {
+                    break;
+                }
+            }
+        }
+    }
+    match last_error {
+        Some(e) if !answered => Err(e.context(Unavailable(format!(
+            "no substituter could be asked for the debuginfo of {}",
+            buildid
+        )))),
+        _ => Ok(()),
+    }
+}
+
+/// Minimum length of a prefix of a buildid for it to be expanded to a full buildid
+const MIN_BUILDID_PREFIX_LEN: usize = 8;
+
+/// Replaces a shortened buildid by the only full buildid in the cache it is a prefix of, if any.
+pub async fn expand_buildid(cache: &Cache, buildid: String) -> String {
+    if buildid.len() < MIN_BUILDID_PREFIX_LEN {
+        return buildid;
+    }
+    match cache.expand_buildid_prefix(&buildid).await {
+        Ok(Some(full)) => {
+            if full != buildid {
+                tracing::debug!("expanded buildid {} to {}", buildid, full);
+            }
+            full
+        }
+        Ok(None) => buildid,
+        Err(e) => {
+            tracing::warn!("expanding buildid {}: {:#}", buildid, e);
+            buildid
+        }
+    }
+}
+
+/// Extracts a file inside an archive to the directory `dir`, unless it was already extracted, and
+/// returns where it was extracted.
+///
+/// `archive` may also be a patch creating `member`, see [is_patch].
+///
+/// Extracted files are deleted when they are not used for some time.
+pub async fn extract_archive_member(
+    cache: &Cache,
+    dir: &Path,
+    archive: &Path,
+    member: &Path,
+) -> anyhow::Result<PathBuf> {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(archive.as_os_str().as_bytes());
+    hasher.update(b"\0");
+    hasher.update(member.as_os_str().as_bytes());
+    let target = dir.join(base16::encode_lower(&hasher.finalize()));
+    if !target.exists() {
+        let member_path = member
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("non utf8 archive name"))?;
+        let temppath = tempfile::NamedTempFile::new_in(dir)
+            .context("temppath")?
+            .into_temp_path();
+        if is_patch(archive) {
+            let contents = file_created_by_patch(archive, member)?;
+            tokio::fs::write(&temppath, contents)
+                .await
+                .context("writing temppath")?;
+            temppath
+                .persist(&target)
+                .with_context(|| format!("moving extracted file to {}", target.display()))?;
+            return register_extracted(cache, target).await;
+        }
+        let archive_file = tokio::fs::File::open(&archive)
+            .await
+            .with_context(|| format!("opening source archive {}", archive.display()))?;
+        let out = tokio::fs::File::create(&temppath)
+            .await
+            .context("opening temppath")?;
+        crate::sandbox::uncompress_archive_file(archive_file, out, member_path)
+            .await
+            .with_context(|| {
+                format!("expanding {} from {}", member.display(), archive.display())
+            })?;
+        temppath
+            .persist(&target)
+            .with_context(|| format!("moving extracted file to {}", target.display()))?;
+    }
+    register_extracted(cache, target).await
+}
+
+/// Registers a file extracted by [extract_archive_member] for deletion when unused, and returns
+/// its path.
+async fn register_extracted(cache: &Cache, target: PathBuf) -> anyhow::Result<PathBuf> {
+    let target_str = target
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("non utf8 cache directory"))?;
+    cache
+        .register_private_path(target_str)
+        .await
+        .context("registering extracted source file")?;
+    cache
+        .remove_expired_private_paths()
+        .await
+        .context("expiring extracted source files")
+        .or_warn();
+    crate::dedup::deduplicate_in_cache(target.clone());
+    Ok(target)
+}
+
+/// Creates the substituters `extra`, followed by those configured in nix.conf unless
+/// `from_nix_conf` is false, which support the same API as `dwarffs`.
+///
+/// Http substituters fetch with `http`, authenticated with the credentials of nix.conf.
+pub async fn get_substituters(
+    http: &HttpClient,
+    extra: &[String],
+    from_nix_conf: bool,
+) -> Vec<Box<dyn Substituter>> {
+    let (config, known) = if crate::store::is_store_mounted_readonly() {
+        // there is no nix to ask, only the substituters of the command line are used
+        (NixConfig::default(), true)
+    } else {
+        match crate::config::get_nix_config().await {
+            Ok(config) => (config, true),
+            Err(e) => {
+                tracing::warn!("could not determine the list of substituters: {e:#}");
+                (NixConfig::default(), false)
+            }
+        }
+    };
+    let mut urls: Vec<&str> = extra.iter().map(String::as_str).collect();
+    if from_nix_conf {
+        let mut seen: HashSet<&str> = urls.iter().copied().collect();
+        for key in &["substituters", "trusted-substituters"] {
+            let several = config.get(*key).map(|s| s.as_str()).unwrap_or("");
+            for word in several.split(' ') {
+                if !word.is_empty() && seen.insert(word) {
+                    urls.push(word);
+                }
+            }
+        }
+    }
+    tracing::debug!("using substituters {urls:?}");
+    let credentials = Credentials::from_config(&config)
+        .await
+        .with_default_login_for(urls.iter().copied());
+    let http = http.with_credentials(credentials);
+    // otherwise nix knows better than us which substituters to use
+    if known || !from_nix_conf {
+        crate::store::set_realise_options(RealiseOptions {
+            substituters: urls.iter().map(|url| url.to_string()).collect(),
+            netrc_file: config.get("netrc-file").cloned(),
+        });
+    }
+    substituters_from_urls(urls, &http).await
+}
+
+/// Creates substituters for these urls, skipping those which are not supported, in the order of
+/// the urls.
+async fn substituters_from_urls<'a>(
+    urls: impl IntoIterator<Item = &'a str>,
+    http: &HttpClient,
+) -> Vec<Box<dyn Substituter>> {
+    let mut substituters: Vec<Box<dyn Substituter>> = vec![];
+    for url in urls {
+        match FileSubstituter::from_url(url).await {
+            Ok(Some(s)) => {
+                tracing::debug!("using substituter {} for hydra API", s.url());
+                substituters.push(Box::new(s));
+                continue;
+            }
+            Err(e) => tracing::warn!("substituter url {url} has a problem: {e:#}"),
+            Ok(None) => tracing::debug!("substituter {url} is not supported by file:// backend"),
+        }
+        match HttpSubstituter::from_url(url, http).await {
+            Ok(Some(s)) => {
+                tracing::debug!("using substituter {} for hydra API", s.url());
+                substituters.push(Box::new(s));
+            }
+            Err(e) => tracing::warn!("substituter url {url} has a problem: {e:#}"),
+            Ok(None) => tracing::debug!("substituter {url} is not supported by https:// backend"),
+        }
+    }
+    substituters
+}
+
+/// How long the priorities of substituters are used before fetching them again
+const PRIORITY_TTL: Duration = Duration::from_secs(3600);
+
+/// How long to wait for the `nix-cache-info` of a substituter before using
+/// [DEFAULT_PRIORITY] instead
+const PRIORITY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Substituters, sorted by priority like in nix, and then in the order of their urls.
+///
+/// Priorities set in `nix-cache-info` are fetched in the background, and refreshed after
+/// [PRIORITY_TTL]. In the meantime, only the priorities set in urls are taken into account.
+pub struct Substituters {
+    /// in the order of their urls
+    all: Vec<Box<dyn Substituter>>,
+    order: Mutex<Order>,
+}
+
+/// The order of [Substituters]
+struct Order {
+    /// indices in [Substituters::all], sorted by priority
+    sorted: Arc<[usize]>,
+    /// when the priorities of `nix-cache-info` were fetched, if they were
+    fetched: Option<Instant>,
+    /// whether they are being fetched
+    refreshing: bool,
+}
+
+impl Substituters {
+    /// Sorts these substituters by the priorities set in their urls.
+    pub fn new(all: Vec<Box<dyn Substituter>>) -> Self {
+        let priorities: Vec<u64> = all
+            .iter()
+            .map(|s| url_priority(s.url()).unwrap_or(DEFAULT_PRIORITY))
+            .collect();
+        Self {
+            order: Mutex::new(Order {
+                sorted: sort_by_priority(&priorities),
+                fetched: None,
+                refreshing: false,
+            }),
+            all,
+        }
+    }
+
+    /// The substituters, by priority.
+    ///
+    /// Starts fetching their priorities in the background if they are unknown or too old.
+    pub fn sorted(self: &Arc<Self>) -> Vec<&dyn Substituter> {
+        self.refresh_if_stale();
+        let order = self.order.lock().unwrap().sorted.clone();
+        order.iter().map(|&i| self.all[i].as_ref()).collect()
+    }
+
+    /// Starts fetching the priorities of the substituters in the background if they are
+    /// unknown or too old.
+    pub fn refresh_if_stale(self: &Arc<Self>) {
+        let mut order = self.order.lock().unwrap();
+        let stale = order.fetched.is_none_or(|at| at.elapsed() > PRIORITY_TTL);
+        if stale && !order.refreshing && !self.all.is_empty() {
+            order.refreshing = true;
+            let this = self.clone();
+            tokio::spawn(async move { this.refresh().await });
+        }
+    }
+
+    /// Fetches the priorities of the substituters and sorts them accordingly.
+    async fn refresh(&self) {
+        let priorities = futures_util::future::join_all(self.all.iter().map(|s| async move {
+            match tokio::time::timeout(PRIORITY_TIMEOUT, s.priority()).await {
+                Ok(priority) => priority,
+                Err(_) => {
+                    tracing::debug!("timeout fetching the priority of substituter {}", s.url());
+                    url_priority(s.url()).unwrap_or(DEFAULT_PRIORITY)
+                }
+            }
+        }))
+        .await;
+        for (priority, substituter) in priorities.iter().zip(&self.all) {
+            tracing::debug!(
+                "substituter {} has priority {}",
+                substituter.url(),
+                priority
+            );
+        }
+        *self.order.lock().unwrap() = Order {
+            sorted: sort_by_priority(&priorities),
+            fetched: Some(Instant::now()),
+            refreshing: false,
+        };
+    }
+}
+
+/// Indices of these priorities, from the lowest priority to the highest
+fn sort_by_priority(priorities: &[u64]) -> Arc<[usize]> {
+    let mut order: Vec<usize> = (0..priorities.len()).collect();
+    // stable, so that substituters of the same priority stay in order
+    order.sort_by_key(|&i| priorities[i]);
+    order.into()
+}
+
+#[tokio::test]
+async fn test_substituters_priority() {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    std::fs::write(
+        dirs[2].path().join("nix-cache-info"),
+        "StoreDir: /nix/store\nPriority: 10\n",
+    )
+    .unwrap();
+    let urls = [
+        format!("file://{}?priority=60", dirs[0].path().display()),
+        format!("file://{}", dirs[1].path().display()),
+        format!("file://{}", dirs[2].path().display()),
+    ];
+    let http = HttpClient::new(None, None).unwrap();
+    let substituters = Arc::new(Substituters::new(
+        substituters_from_urls(urls.iter().map(String::as_str), &http).await,
+    ));
+    let urls_of = |substituters: &Arc<Substituters>| -> Vec<String> {
+        substituters
+            .sorted()
+            .iter()
+            .map(|s| s.url().to_owned())
+            .collect()
+    };
+    let in_order = |indices: [usize; 3]| indices.map(|i| urls[i].clone()).to_vec();
+    // before nix-cache-info is fetched in the background
+    assert_eq!(urls_of(&substituters), in_order([1, 2, 0]));
+    let fetched = in_order([2, 1, 0]);
+    for _ in 0..100 {
+        if urls_of(&substituters) == fetched {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(urls_of(&substituters), fetched);
+    // fresh priorities are not fetched again
+    std::fs::write(
+        dirs[1].path().join("nix-cache-info"),
+        "StoreDir: /nix/store\nPriority: 5\n",
+    )
+    .unwrap();
+    assert_eq!(urls_of(&substituters), fetched);
+    substituters.refresh().await;
+    assert_eq!(urls_of(&substituters), in_order([1, 2, 0]));
+}
@@ -0,0 +1,356 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Decompression of untrusted archives in a sandboxed worker process, with `--sandbox`.
+//!
+//! Any local process can make the server decompress source archives and nars with libarchive,
+//! so a bug in libarchive could be exploited to take over the server. With `--sandbox`, this is
+//! done by a `nixseparatedebuginfod decompress` subprocess which reads the archive from its
+//! stdin and writes the result to its stdout, after forbidding itself any access to the
+//! filesystem and network with [landlock](https://docs.kernel.org/userspace-api/landlock.html).
+//!
+//! Decompression of zstd and gzip nars and parsing of nars are implemented in Rust and are not
+//! sandboxed.
+
+use std::fs::File;
+use std::io::{BufReader, Seek, Write};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::{ExitCode, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Context;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Whether decompression happens in a worker process, see [enable]
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Decompress archives in sandboxed worker processes from now on.
+///
+/// Should be called on startup. Warns if the kernel does not support landlock, in which case
+/// workers are only isolated from the memory of the server.
+pub fn enable() {
+    match landlock_abi() {
+        Ok(abi) => tracing::debug!("landlock ABI version {}", abi),
+        Err(e) => tracing::warn!(
+            "sandboxed decompression cannot restrict filesystem access: {:#}",
+            e
+        ),
+    }
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Options of the hidden `decompress` subcommand, the sandboxed worker.
+///
+/// Without options, decompresses raw compressed data, like a compressed nar.
+#[derive(clap::Args, Debug)]
+pub struct WorkerOptions {
+    /// Write the names of the files in the archive, separated by nul bytes
+    #[arg(long, conflicts_with = "member")]
+    list: bool,
+    /// Write this file of the archive
+    #[arg(long)]
+    member: Option<String>,
+}
+
+/// Runs the worker: decompresses its stdin to its stdout as specified by `options`, without
+/// access to anything else.
+///
+/// Landlock only restricts the calling thread, so this must be called before other threads are
+/// started.
+pub fn worker(options: &WorkerOptions) -> anyhow::Result<ExitCode> {
+    // libarchive needs to seek in archives, which stdin is
+    let input = File::from(
+        std::io::stdin()
+            .as_fd()
+            .try_clone_to_owned()
+            .context("duplicating stdin")?,
+    );
+    if let Err(e) = restrict_self() {
+        // the server already warned about it
+        eprintln!("not sandboxed: {:#}", e);
+    }
+    let mut output = std::io::BufWriter::new(std::io::stdout().lock());
+    match (&options.member, options.list) {
+        (Some(member), _) => {
+            compress_tools::uncompress_archive_file(input, &mut output, member)
+                .with_context(|| format!("expanding {}", member))?;
+        }
+        (None, true) => {
+            for name in compress_tools::list_archive_files(input).context("listing archive")? {
+                output
+                    .write_all(name.as_bytes())
+                    .and_then(|()| output.write_all(b"\0"))
+                    .context("writing archive members")?;
+            }
+        }
+        (None, false) => {
+            compress_tools::uncompress_data(input, &mut output).context("decompressing")?;
+        }
+    }
+    output.flush().context("writing output")?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Sets up a command running the worker with these arguments
+fn worker_command(args: &[&str]) -> anyhow::Result<std::process::Command> {
+    let exe = std::env::current_exe().context("locating the current executable")?;
+    let mut cmd = std::process::Command::new(exe);
+    cmd.arg("decompress").args(args);
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    Ok(cmd)
+}
+
+/// Fails with the error message of the worker if it failed.
+fn check_worker(output: &std::process::Output) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        output.status.success(),
+        "sandboxed decompression failed ({}): {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(())
+}
+
+/// Lists the files in an archive, like [compress_tools::list_archive_files].
+pub fn list_archive_files(archive: File) -> anyhow::Result<Vec<String>> {
+    if !is_enabled() {
+        return Ok(compress_tools::list_archive_files(archive)?);
+    }
+    let mut cmd = worker_command(&["--list"])?;
+    cmd.stdin(archive);
+    tracing::debug!("Running {:?}", &cmd);
+    let output = cmd.output().with_context(|| format!("running {:?}", cmd))?;
+    check_worker(&output)?;
+    let names = String::from_utf8(output.stdout).context("archive members are not utf8")?;
+    Ok(names.split_terminator('\0').map(str::to_owned).collect())
+}
+
+/// Writes the file `member` of an archive to `out`, like
+/// [compress_tools::tokio_support::uncompress_archive_file].
+pub async fn uncompress_archive_file(
+    archive: tokio::fs::File,
+    mut out: impl AsyncWrite + Unpin,
+    member: &str,
+) -> anyhow::Result<()> {
+    if !is_enabled() {
+        compress_tools::tokio_support::uncompress_archive_file(archive, out, member).await?;
+        return Ok(());
+    }
+    let member_arg = format!("--member={}", member);
+    let mut cmd = tokio::process::Command::from(worker_command(&[&member_arg])?);
+    cmd.stdin(archive.into_std().await);
+    // do not leave the worker running if the request is cancelled
+    cmd.kill_on_drop(true);
+    tracing::debug!("Running {:?}", &cmd);
+    let mut child = cmd.spawn().with_context(|| format!("running {:?}", cmd))?;
+    let mut stdout = child.stdout.take().context("stdout of worker")?;
+    // the worker only writes to stderr when it exits, so it cannot be blocked on it
+    tokio::io::copy(&mut stdout, &mut out)
+        .await
+        .with_context(|| format!("copying output of {:?}", cmd))?;
+    out.flush().await.context("writing expanded file")?;
+    let output = child
+        .wait_with_output()
+        .await
+        .with_context(|| format!("waiting for {:?}", cmd))?;
+    check_worker(&output)
+}
+
+/// Decompresses raw compressed data to `out`, like [compress_tools::uncompress_data].
+///
+/// `input` must not have been consumed, only buffered.
+pub fn uncompress_data(input: BufReader<File>, out: &mut impl Write) -> anyhow::Result<()> {
+    if !is_enabled() {
+        compress_tools::uncompress_data(input, out)?;
+        return Ok(());
+    }
+    let mut file = input.into_inner();
+    file.rewind().context("rewinding compressed file")?;
+    let mut cmd = worker_command(&[])?;
+    cmd.stdin(file);
+    tracing::debug!("Running {:?}", &cmd);
+    let mut child = cmd.spawn().with_context(|| format!("running {:?}", cmd))?;
+    let mut stdout = child.stdout.take().context("stdout of worker")?;
+    // the worker only writes to stderr when it exits, so it cannot be blocked on it
+    let copied = std::io::copy(&mut stdout, out);
+    // if copying failed, let the worker fail instead of blocking on a full pipe
+    drop(stdout);
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("waiting for {:?}", cmd))?;
+    check_worker(&output)?;
+    copied.with_context(|| format!("copying output of {:?}", cmd))?;
+    Ok(())
+}
+
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1;
+/// All filesystem accesses of landlock ABI 1: execute, write, read, read dir, remove dir,
+/// remove file, make char, dir, regular file, socket, fifo, block device and symlink
+const LANDLOCK_ACCESS_FS_V1: u64 = (1 << 13) - 1;
+const LANDLOCK_ACCESS_FS_REFER: u64 = 1 << 13;
+const LANDLOCK_ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+/// Executing, and reading files and directories
+pub const LANDLOCK_ACCESS_FS_READ: u64 = 1 | (1 << 2) | (1 << 3);
+/// The accesses which apply to files rather than directories: execute, write, read, truncate
+const LANDLOCK_ACCESS_FS_FILE: u64 = 1 | (1 << 1) | (1 << 2) | LANDLOCK_ACCESS_FS_TRUNCATE;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+/// Binding and connecting TCP sockets
+const LANDLOCK_ACCESS_NET_ALL: u64 = (1 << 2) - 1;
+
+/// `struct landlock_ruleset_attr`. `handled_access_net` only exists from ABI 4.
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+    handled_access_net: u64,
+}
+
+/// `struct landlock_path_beneath_attr`
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// The landlock ABI version supported by the kernel
+fn landlock_abi() -> anyhow::Result<i64> {
+    // SAFETY: the kernel does not dereference the null attribute when asked for the version
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0usize,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 0 {
+        return Err(std::io::Error::last_os_error()).context("landlock is not supported");
+    }
+    Ok(abi)
+}
+
+/// Forbids the current thread, and the processes and threads it starts, to access any file
+/// or TCP socket it has not opened yet, and to gain privileges.
+fn restrict_self() -> anyhow::Result<()> {
+    landlock(&[], true)
+}
+
+/// Forbids the current thread, and the processes and threads it starts, to access files it has
+/// not opened yet, except with the landlock access rights of `allowed` beneath each of its
+/// paths, and to gain privileges. Also forbids TCP sockets if `restrict_net`.
+///
+/// Paths of `allowed` which do not exist are ignored. Pass `u64::MAX` to allow everything.
+pub fn landlock(allowed: &[(PathBuf, u64)], restrict_net: bool) -> anyhow::Result<()> {
+    // SAFETY: prctl with integer arguments
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("setting no_new_privs");
+    }
+    let abi = landlock_abi()?;
+    let mut handled_access_fs = LANDLOCK_ACCESS_FS_V1;
+    if abi >= 2 {
+        handled_access_fs |= LANDLOCK_ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        handled_access_fs |= LANDLOCK_ACCESS_FS_TRUNCATE;
+    }
+    let attr = RulesetAttr {
+        handled_access_fs,
+        handled_access_net: if restrict_net {
+            LANDLOCK_ACCESS_NET_ALL
+        } else {
+            0
+        },
+    };
+    // older kernels reject the field they do not know
+    let size = if abi >= 4 {
+        std::mem::size_of::<RulesetAttr>()
+    } else {
+        std::mem::size_of::<u64>()
+    };
+    // SAFETY: attr is valid for size bytes
+    let ruleset = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const RulesetAttr,
+            size,
+            0,
+        )
+    };
+    if ruleset < 0 {
+        return Err(std::io::Error::last_os_error()).context("creating landlock ruleset");
+    }
+    // SAFETY: the kernel just returned this file descriptor, which we own
+    let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as i32) };
+    for (path, access) in allowed {
+        let parent = match std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+            .open(path)
+        {
+            Ok(parent) => parent,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                return Err(e).with_context(|| format!("opening {} for landlock", path.display()))
+            }
+        };
+        let mut allowed_access = access & handled_access_fs;
+        if !parent.metadata().is_ok_and(|metadata| metadata.is_dir()) {
+            allowed_access &= LANDLOCK_ACCESS_FS_FILE;
+        }
+        let attr = PathBeneathAttr {
+            allowed_access,
+            parent_fd: parent.as_raw_fd(),
+        };
+        // SAFETY: attr is a valid rule for a file descriptor which outlives the call
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0,
+            )
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("allowing access to {}", path.display()));
+        }
+    }
+    // SAFETY: restricting self with a valid ruleset file descriptor
+    let result = unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error()).context("enforcing landlock ruleset");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_restrict_self() {
+    use std::io::Read;
+    if let Err(e) = landlock_abi() {
+        eprintln!("skipping: {:#}", e);
+        return;
+    }
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("file");
+    std::fs::write(&path, "content").unwrap();
+    let other = dir.path().join("other");
+    let opened = File::open(&path).unwrap();
+    // landlock only applies to the calling thread
+    std::thread::spawn(move || {
+        restrict_self().unwrap();
+        assert!(File::open(&path).is_err());
+        assert!(std::fs::write(&other, "").is_err());
+        let mut content = String::new();
+        BufReader::new(opened).read_to_string(&mut content).unwrap();
+        assert_eq!(content, "content");
+    })
+    .join()
+    .unwrap();
+}
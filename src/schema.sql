@@ -6,7 +6,9 @@ create table if not exists builds (
   buildid text unique not null,
   executable text,
   debuginfo text,
-  source text
+  source text,
+  buildsource text,
+  architecture text
   );
 
 create index if not exists bybuildid on builds(buildid);
@@ -15,4 +17,74 @@ create table if not exists version (version int not null);
 
 create table if not exists gc (timestamp int not null);
 
-create table if not exists id (next int not null);
+create table if not exists id (next int not null, source text);
+
+create table if not exists misses (
+  buildid text unique not null,
+  missed int not null,
+  found int
+  );
+
+create table if not exists substituterlookups (
+  substituter text not null,
+  path text not null,
+  target text,
+  timestamp int not null,
+  unique(substituter, path)
+  );
+
+create table if not exists narhashes (
+  storepath text unique not null,
+  narhash text not null
+  );
+
+create table if not exists privatepaths (
+  path text unique not null,
+  timestamp int not null
+  );
+
+create table if not exists splitdwarf (
+  output text not null,
+  name text not null,
+  path text unique not null
+  );
+
+create index if not exists splitdwarfbyname on splitdwarf(output, name);
+
+create table if not exists sourceroots (
+  source text not null,
+  path text not null,
+  unique(source, path)
+  );
+
+create table if not exists archivemembers (
+  archive text unique not null,
+  members blob not null
+  );
+
+create table if not exists sourceprefixes (
+  buildid text unique not null,
+  prefix text not null
+  );
+
+create table if not exists clientdownloads (
+  client text not null,
+  hour integer not null,
+  bytes integer not null,
+  unique(client, hour)
+  );
+
+create table if not exists channelindices (
+  url text unique not null,
+  etag text not null
+  );
+
+create table if not exists events (
+  event text unique not null,
+  timestamp int not null
+  );
+
+create table if not exists eagerrealised (
+  path text unique not null,
+  size int not null
+  );
@@ -9,31 +9,79 @@
 
 use anyhow::Context;
 use axum::body::Body;
-use axum::extract::{Path, State};
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
-use axum::{routing::get, Router};
-use http::header::{HeaderMap, CONTENT_LENGTH};
-use std::collections::HashSet;
-use std::os::unix::prelude::MetadataExt;
+use axum::error_handling::HandleErrorLayer;
+use axum::extract::{ConnectInfo, MatchedPath, Path, Query, Request, State};
+use axum::http::{StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::MethodRouter;
+use axum::routing::{delete, get, post};
+use axum::{BoxError, Router};
+use futures_util::{StreamExt, TryStreamExt};
+use http::header::{
+    HeaderMap, HeaderValue, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, RANGE, RETRY_AFTER, X_CONTENT_TYPE_OPTIONS,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::PathBuf;
 use std::process::ExitCode;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Semaphore;
 use tokio_util::io::ReaderStream;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::ServiceBuilder;
 
-use crate::db::Cache;
-use crate::index::{index_single_store_path_to_cache, StoreWatcher};
+use crate::client::Prefetched;
+use crate::clients::{apply_client_policy, ClientPolicy};
+use crate::coredump::buildids_in_core_file;
+use crate::db::{decode_path, encode_path, Cache, Entry, Events, Id, Metadata, SourcePrefix};
+use crate::eager::EagerRealiser;
+use crate::fallback::{fall_back, Fallbacks};
+use crate::filter::IndexFilter;
+use crate::gdbindex::GdbIndexer;
+use crate::html;
+use crate::index::{gc_roots, relocate, StoreWatcher};
 use crate::log::ResultExt;
-use crate::store::{demangle, get_file_for_source, get_store_path, realise, SourceLocation};
-use crate::substituter::{FileSubstituter, HttpSubstituter, Substituter};
+use crate::metrics::Metrics;
+use crate::quota::{enforce_quotas, ClientQuotas};
+use crate::resolve::{
+    and_realise, expand_buildid, extract_archive_member, invalidate, Resolver, SharedError,
+    SourceQuota, SourceTooLarge, Unavailable, MB,
+};
+use crate::store::{
+    demangle, file_created_by_patch, get_buildid, get_deriver, get_elf_info, get_store_path,
+    is_patch, normalize, SourceLocation,
+};
 use crate::Options;
 
 #[derive(Clone)]
 struct ServerState {
     cache: Cache,
-    watcher: StoreWatcher,
-    substituters: Arc<Vec<Box<dyn Substituter>>>,
+    /// `None` with `--from-cache`, when there is no local store to index
+    watcher: Option<StoreWatcher>,
+    resolver: Resolver,
+    metrics: Arc<Metrics>,
+    /// where to store source files extracted from archives, if possible
+    extracted_sources: Option<PathBuf>,
+    /// generates missing `.gdb_index` sections, with `--generate-gdb-index`
+    gdb_indexer: Option<GdbIndexer>,
+    /// how long to wait for indexation to complete before serving the cache
+    indexing_timeout: Duration,
+    /// what to answer when a file is not found before indexation completes
+    while_indexing: WhileIndexing,
+    /// whether to check the nar hash of store paths before serving files in them
+    verify: bool,
+    /// store paths whose nar hash was already checked
+    verified: Arc<std::sync::Mutex<HashSet<PathBuf>>>,
+    /// whether to serve MiniDebugInfo as debuginfo, with `--serve-minidebuginfo`
+    serve_minidebuginfo: bool,
+    /// derivers of store paths already queried for [get_metadata]
+    derivers: Arc<std::sync::Mutex<HashMap<PathBuf, Option<PathBuf>>>>,
+    /// when the server started
+    started: Instant,
 }
 
 /// The only status code in the client code of debuginfod in elfutils that prevents
@@ -42,260 +90,598 @@ struct ServerState {
 /// 503 Not Available also works, but only for the section request
 const NON_CACHING_ERROR_STATUS: StatusCode = StatusCode::NOT_ACCEPTABLE;
 
+/// How long clients should wait before retrying after an overloaded or timed out request
+pub const RETRY_AFTER_SECS: u16 = 10;
+
+/// What to answer when a file is not found while indexation is not complete
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WhileIndexing {
+    /// Answer with an error that the `debuginfod` client of `elfutils` does not cache
+    NonCachingError,
+    /// Wait for indexation to complete before answering
+    Wait,
+    /// Answer 503 Service Unavailable with a Retry-After header
+    Unavailable,
+}
+
+/// The status code to answer when a file is not found.
+///
+/// `ready` should be true if indexation is currently complete.
+fn not_found_status(ready: bool, while_indexing: WhileIndexing) -> StatusCode {
+    match (ready, while_indexing) {
+        (true, _) => StatusCode::NOT_FOUND,
+        (false, WhileIndexing::Unavailable) => StatusCode::SERVICE_UNAVAILABLE,
+        (false, _) => NON_CACHING_ERROR_STATUS,
+    }
+}
+
+/// Logs an error and converts it to a response, telling the client when to retry if relevant.
+fn error_response((code, error): (StatusCode, String)) -> Response {
+    tracing::info!("Responding error {}: {}", code, error);
+    if code == StatusCode::SERVICE_UNAVAILABLE {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+        (code, headers, error).into_response()
+    } else {
+        (code, error).into_response()
+    }
+}
+
+/// The status code and message to answer for this error.
+///
+/// Refusing to download a source larger than the quota is `406 Not Acceptable`, failing to
+/// realise a known file or to reach substituters is `503 Service Unavailable` so that clients
+/// retry later, failures of the cache db or of the server itself are
+/// `500 Internal Server Error`, and other errors are `404 Not Found`.
+fn error_status(error: anyhow::Error) -> (StatusCode, String) {
+    let message = format!("{:#}", error);
+    let error = error
+        .downcast_ref::<SharedError>()
+        .map_or(&error, SharedError::inner);
+    let internal = error
+        .chain()
+        .any(|cause| cause.is::<sqlx::Error>() || cause.is::<tokio::task::JoinError>());
+    let status = if error.downcast_ref::<SourceTooLarge>().is_some() {
+        StatusCode::NOT_ACCEPTABLE
+    } else if error.downcast_ref::<Unavailable>().is_some() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else if internal {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        StatusCode::NOT_FOUND
+    };
+    (status, message)
+}
+
+#[test]
+fn test_error_status() {
+    let status = |error: anyhow::Error| error_status(error).0;
+    assert_eq!(
+        status(anyhow::anyhow!("ambiguous source file")),
+        StatusCode::NOT_FOUND
+    );
+    let unavailable = || anyhow::Error::new(Unavailable("substituter is down".to_owned()));
+    assert_eq!(
+        status(unavailable().context("looking up debuginfo")),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    let shared = anyhow::Error::new(SharedError::from(unavailable()));
+    assert_eq!(status(shared), StatusCode::SERVICE_UNAVAILABLE);
+    let internal = anyhow::Error::new(sqlx::Error::PoolClosed).context("reading from cache db");
+    assert_eq!(status(internal), StatusCode::INTERNAL_SERVER_ERROR);
+    let too_large = SourceTooLarge {
+        storepath: PathBuf::from("/nix/store/aaaa-source"),
+        size: 0,
+        limit: 0,
+        option: "--max-source-size",
+    };
+    assert_eq!(
+        status(anyhow::Error::new(too_large)),
+        StatusCode::NOT_ACCEPTABLE
+    );
+}
+
 /// Serve the content of this file, or an appropriate error.
 ///
 /// Attempts to substitute the file if necessary.
 ///
-/// `ready` should be true if indexation is currently complete. If it is false,
-/// error codes are tuned to prevent the client from caching the answer.
+/// `not_found` is the status code to answer if the file is not found, see [not_found_status].
+///
+/// `content_type` is the `Content-Type` of the file, see [ELF_CONTENT_TYPE] and
+/// [source_content_type].
+///
+/// `request_headers` are the headers of the request, to serve only the requested `Range` of the
+/// file, if any.
 async fn unwrap_file<T: AsRef<std::path::Path>>(
     path: anyhow::Result<Option<T>>,
-    ready: bool,
-) -> impl IntoResponse {
+    not_found: StatusCode,
+    content_type: &'static str,
+    request_headers: &HeaderMap,
+) -> Response {
     let response = match path {
         Ok(Some(p)) => {
-            match tokio::fs::File::open(p.as_ref()).await {
-                Err(e) => Err((StatusCode::NOT_FOUND, format!("{:#}", e))),
-                Ok(file) => {
-                    let mut headers = HeaderMap::new();
-                    if let Ok(metadata) = p.as_ref().metadata() {
-                        if let Ok(value) = metadata.size().to_string().parse() {
-                            headers.insert(CONTENT_LENGTH, value);
-                        }
-                    }
-                    tracing::info!("returning {}", p.as_ref().display());
-                    // convert the `AsyncRead` into a `Stream`
-                    let stream = ReaderStream::new(file);
-                    // convert the `Stream` into an `axum::body::HttpBody`
-                    let body = Body::from_stream(stream);
-                    Ok((headers, body))
-                }
+            let response = file_response(p.as_ref(), content_type, request_headers).await;
+            if response.is_ok() {
+                tracing::info!("returning {}", p.as_ref().display());
             }
+            response
         }
-        Ok(None) => Err((
-            if ready {
-                StatusCode::NOT_FOUND
-            } else {
-                NON_CACHING_ERROR_STATUS
-            },
-            "not found in cache".to_string(),
-        )),
-        Err(e) => Err((StatusCode::NOT_FOUND, format!("{:#}", e))),
-    };
-    if let Err((code, error)) = &response {
-        tracing::info!("Responding error {}: {}", code, error);
+        Ok(None) => Err((not_found, "not found in cache".to_string())),
+        Err(e) => Err(error_status(e)),
     };
-    response
+    match response {
+        Ok(response) => response,
+        Err(error) => error_response(error),
+    }
 }
 
-/// Start indexation, and wait for it to complete until timeout.
+/// Parses the value of a `Range` header for a file of this size.
 ///
-/// Returns whether indexation is complete.
-async fn start_indexation_and_wait(watcher: StoreWatcher, timeout: Duration) -> bool {
-    match watcher.maybe_index_new_paths().await {
-        Err(e) => {
-            tracing::warn!("cannot start registration of new store path: {:#}", e);
-            false
-        }
-        Ok(None) => true,
-        Ok(Some(handle)) => {
-            tokio::select! {
-                _ = tokio::time::sleep(timeout) => false,
-                _ = handle => true,
-            }
+/// Only single byte ranges are supported. Returns None if the header should be ignored and the
+/// whole file served, `Some(Err(()))` if the range cannot be satisfied, and otherwise the
+/// inclusive bounds of the range.
+fn parse_range(value: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        // the last `end` bytes
+        let len: u64 = end.parse().ok()?;
+        if len == 0 || size == 0 {
+            return Some(Err(()));
         }
+        return Some(Ok((size - len.min(size), size - 1)));
+    }
+    let start: u64 = start.parse().ok()?;
+    let end: u64 = if end.is_empty() {
+        u64::MAX
+    } else {
+        end.parse().ok()?
+    };
+    if end < start {
+        return None;
     }
+    if start >= size {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end.min(size - 1))))
+}
+
+#[test]
+fn test_parse_range() {
+    assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok((0, 99))));
+    assert_eq!(parse_range("bytes=900-", 1000), Some(Ok((900, 999))));
+    assert_eq!(parse_range("bytes=900-2000", 1000), Some(Ok((900, 999))));
+    assert_eq!(parse_range("bytes=-100", 1000), Some(Ok((900, 999))));
+    assert_eq!(parse_range("bytes=-2000", 1000), Some(Ok((0, 999))));
+    assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+    assert_eq!(parse_range("bytes=-0", 1000), Some(Err(())));
+    assert_eq!(parse_range("bytes=0-", 0), Some(Err(())));
+    assert_eq!(parse_range("bytes=5-3", 1000), None);
+    assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+    assert_eq!(parse_range("lines=0-1", 1000), None);
 }
 
-/// Reindex harder.
+/// `Content-Type` of debuginfo and executables
+const ELF_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// `Content-Type` of a source file, inferred from its extension.
 ///
-/// If the .drv file is not in the store, automatic indexation will find the executable but not
-/// the debuginfo and source. We can attempt to download this drv file during a second
-/// indexation attempt.
-async fn maybe_reindex_by_build_id(cache: &Cache, buildid: &str) -> anyhow::Result<()> {
-    let exe = match cache
-        .get_executable(buildid)
-        .await
-        .with_context(|| format!("getting executable of {} from cache", buildid))?
-    {
-        Some(exe) => exe,
-        None => return Ok(()),
-    };
-    tracing::debug!("reindexing {}", &exe);
-    let exe = PathBuf::from(exe);
-    let storepath = match get_store_path(exe.as_path()) {
-        Some(storepath) => storepath,
-        None => anyhow::bail!(
-            "executable {} for buildid {} is not a store path",
-            exe.display(),
-            buildid
-        ),
+/// Source files are mostly text, so unknown extensions are served as `text/plain`. Markup like
+/// html is served as `text/plain` too, so that browsers display it instead of rendering it.
+fn source_content_type(path: &std::path::Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("ico") => "image/vnd.microsoft.icon",
+        Some("pdf") => "application/pdf",
+        Some("gz" | "tgz") => "application/gzip",
+        Some("xz") => "application/x-xz",
+        Some("bz2") => "application/x-bzip2",
+        Some("zst") => "application/zstd",
+        Some("zip") => "application/zip",
+        Some("o" | "a" | "so" | "bin" | "exe" | "dll" | "class" | "pyc" | "wasm") => {
+            "application/octet-stream"
+        }
+        _ => "text/plain",
+    }
+}
+
+#[test]
+fn test_source_content_type() {
+    use std::path::Path;
+    assert_eq!(source_content_type(Path::new("src/main.c")), "text/plain");
+    assert_eq!(source_content_type(Path::new("Makefile")), "text/plain");
+    assert_eq!(
+        source_content_type(Path::new("doc/index.html")),
+        "text/plain"
+    );
+    assert_eq!(
+        source_content_type(Path::new("icons/logo.PNG")),
+        "image/png"
+    );
+    assert_eq!(
+        source_content_type(Path::new("lib/libfoo.so")),
+        "application/octet-stream"
+    );
+}
+
+/// Serves this file, or the part requested by the `Range` header of `request_headers`, with this
+/// `Content-Type`.
+async fn file_response(
+    path: &std::path::Path,
+    content_type: &'static str,
+    request_headers: &HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let error = |e: std::io::Error| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("opening {}: {:#}", path.display(), e),
+        )
     };
-    index_single_store_path_to_cache(cache, storepath, true)
-        .await
-        .with_context(|| format!("indexing {} online", exe.display()))?;
-    Ok(())
+    let mut file = tokio::fs::File::open(path).await.map_err(error)?;
+    let size = file.metadata().await.map_err(error)?.len();
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    let range = request_headers
+        .get(RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_range(value, size));
+    match range {
+        None => {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(size));
+            // convert the `AsyncRead` into a `Stream`
+            let stream = ReaderStream::new(file);
+            // convert the `Stream` into an `axum::body::HttpBody`
+            let body = Body::from_stream(stream);
+            Ok((headers, body).into_response())
+        }
+        Some(Err(())) => {
+            if let Ok(value) = format!("bytes */{size}").parse() {
+                headers.insert(CONTENT_RANGE, value);
+            }
+            Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response())
+        }
+        Some(Ok((start, end))) => {
+            file.seek(std::io::SeekFrom::Start(start))
+                .await
+                .map_err(error)?;
+            let len = end - start + 1;
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+            if let Ok(value) = format!("bytes {start}-{end}/{size}").parse() {
+                headers.insert(CONTENT_RANGE, value);
+            }
+            let stream = ReaderStream::new(file.take(len));
+            let body = Body::from_stream(stream);
+            Ok((StatusCode::PARTIAL_CONTENT, headers, body).into_response())
+        }
+    }
 }
 
-/// Ensures that the contained path exists, and if this is not the case
-/// replace it by `Ok(None)`
+/// Start indexation, and wait for it to complete until timeout, or forever if `timeout` is
+/// None.
 ///
-/// The tag is the kind of file this should be, to be used in error messages
-async fn and_realise<T: AsRef<std::path::Path>>(
-    result: anyhow::Result<Option<T>>,
-    tag: &str,
-) -> anyhow::Result<Option<T>> {
-    match result {
-        Ok(Some(p)) => {
-            let res = realise(p.as_ref())
-                .await
-                .with_context(|| format!("realising {} of type {}", p.as_ref().display(), tag));
+/// Returns whether indexation is complete.
+async fn start_indexation_and_wait(watcher: StoreWatcher, timeout: Option<Duration>) -> bool {
+    match timeout {
+        None => watcher.wait_for_cycle().await,
+        Some(timeout) => tokio::time::timeout(timeout, watcher.wait_for_cycle())
+            .await
+            .unwrap_or(false),
+    }
+}
 
-            if res.is_err() {
-                res.or_warn();
-                Ok(None)
-            } else {
-                Ok(Some(p))
+impl ServerState {
+    /// A state serving `cache`, without a store watcher nor substituters
+    #[cfg(test)]
+    fn for_test(cache: Cache) -> Self {
+        Self {
+            resolver: Resolver::new(cache.clone(), vec![], None, SourceQuota::new(None, None)),
+            cache,
+            watcher: None,
+            metrics: Arc::new(Metrics::default()),
+            extracted_sources: None,
+            gdb_indexer: None,
+            indexing_timeout: Duration::ZERO,
+            while_indexing: WhileIndexing::NonCachingError,
+            verify: false,
+            verified: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            serve_minidebuginfo: false,
+            derivers: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            started: Instant::now(),
+        }
+    }
+
+    /// The deriver of this store path, queried at most once per store path.
+    async fn deriver(&self, storepath: PathBuf) -> anyhow::Result<Option<PathBuf>> {
+        if let Some(deriver) = self.derivers.lock().unwrap().get(&storepath) {
+            return Ok(deriver.clone());
+        }
+        let storepath2 = storepath.clone();
+        let deriver = tokio::task::spawn_blocking(move || get_deriver(&storepath2))
+            .await
+            .context("joining deriver query")??;
+        let mut derivers = self.derivers.lock().unwrap();
+        if derivers.len() >= MAX_DERIVERS {
+            derivers.clear();
+        }
+        derivers.insert(storepath, deriver.clone());
+        Ok(deriver)
+    }
+
+    /// Start indexation, and wait for it as configured by `--indexing-timeout` and
+    /// `--while-indexing`.
+    ///
+    /// Returns whether indexation is complete.
+    async fn wait_for_indexation(&self) -> bool {
+        let Some(watcher) = &self.watcher else {
+            return true;
+        };
+        let timeout = match self.while_indexing {
+            WhileIndexing::Wait => None,
+            _ => Some(self.indexing_timeout),
+        };
+        start_indexation_and_wait(watcher.clone(), timeout).await
+    }
+
+    /// When `--verify` is set, checks that the store path containing this file has the nar hash
+    /// recorded at indexation time.
+    ///
+    /// Store paths without a recorded hash are not checked. Successful checks are remembered
+    /// until the server restarts.
+    async fn verify(&self, path: &std::path::Path) -> anyhow::Result<()> {
+        if !self.verify {
+            return Ok(());
+        }
+        let storepath = match get_store_path(path) {
+            None => return Ok(()),
+            Some(storepath) => storepath.to_path_buf(),
+        };
+        if self.verified.lock().unwrap().contains(&storepath) {
+            return Ok(());
+        }
+        let expected = match storepath.to_str() {
+            None => None,
+            Some(s) => self.cache.get_nar_hash(s).await?,
+        };
+        let expected = match expected {
+            None => {
+                tracing::debug!("no nar hash recorded for {}", storepath.display());
+                return Ok(());
             }
+            Some(expected) => expected,
+        };
+        let actual = crate::store::get_nar_hash(&storepath)
+            .await
+            .with_context(|| format!("computing nar hash of {}", storepath.display()))?;
+        if actual != expected {
+            anyhow::bail!(
+                "{} has nar hash {} instead of {}, refusing to serve it",
+                storepath.display(),
+                actual,
+                expected
+            );
+        }
+        tracing::debug!("verified nar hash of {}", storepath.display());
+        self.verified.lock().unwrap().insert(storepath);
+        Ok(())
+    }
+
+    /// Turns a file found by a lookup into an error if it fails [ServerState::verify].
+    async fn verified<P: AsRef<std::path::Path>>(
+        &self,
+        res: anyhow::Result<Option<P>>,
+    ) -> anyhow::Result<Option<P>> {
+        if let Ok(Some(path)) = &res {
+            self.verify(path.as_ref()).await?;
         }
-        other => other,
+        res
     }
 }
 
-/// attempts to fetch debuginfo from substituters via the same API as dwarffs
-async fn maybe_fetch_debuginfo_from_substituter_index(
+/// Remembers that `buildid` could not be served, if this answer is final.
+///
+/// Only for debuginfo and executables: a missing source file or section does not mean that the
+/// buildid is unknown.
+async fn maybe_record_miss<T>(
     cache: &Cache,
-    substituters: &[Box<dyn Substituter>],
     buildid: &str,
-) -> anyhow::Result<()> {
-    for substituter in substituters.iter() {
-        match crate::substituter::fetch_debuginfo(substituter.as_ref(), buildid).await {
-            Err(e) => tracing::info!(
-                "cannot fetch buildid {} from substituter {}: {:#}",
-                buildid,
-                substituter.url(),
-                e
-            ),
-            Ok(None) => (),
-            Ok(Some(path)) => {
-                tracing::info!(
-                    "fetched {} from substituter {}, now indexing it",
-                    path.display(),
-                    substituter.url()
-                );
-                index_single_store_path_to_cache(cache, &path, false)
-                    .await
-                    .with_context(|| format!("indexing {}", path.display()))
-                    .or_warn();
-                if let Ok(Some(_)) =
-                    and_realise(cache.get_debuginfo(buildid).await, "debuginfo").await
-                {
-                    break;
-                }
-            }
-        }
+    result: &anyhow::Result<Option<T>>,
+    ready: bool,
+) {
+    if ready && matches!(result, Ok(None)) {
+        cache
+            .record_miss(buildid)
+            .await
+            .with_context(|| format!("recording miss of {}", buildid))
+            .or_warn();
+    }
+}
+
+/// Checks that a buildid sent by a client is well formed, and lowercases it.
+///
+/// In case of error, returns the response to send to the client.
+fn parse_buildid(buildid: &str) -> Result<String, (StatusCode, String)> {
+    if buildid.is_empty()
+        || buildid.len() & 1 != 0
+        || !buildid.bytes().all(|c| c.is_ascii_hexdigit())
+    {
+        let message = format!("invalid buildid {buildid:?}: expected an even number of hex digits");
+        tracing::info!("Responding error {}: {}", StatusCode::BAD_REQUEST, message);
+        return Err((StatusCode::BAD_REQUEST, message));
     }
-    Ok(())
+    Ok(buildid.to_ascii_lowercase())
 }
 
-/// How long to wait for indexation to complete before serving the cache
-const INDEXING_TIMEOUT: Duration = Duration::from_secs(1);
+#[test]
+fn test_parse_buildid() {
+    assert_eq!(
+        parse_buildid("483BD7F7229BDB06462222E1E353E4F37E15C293").unwrap(),
+        "483bd7f7229bdb06462222e1e353e4f37e15c293"
+    );
+    assert_eq!(parse_buildid("48aB").unwrap(), "48ab");
+    assert_eq!(parse_buildid("").unwrap_err().0, StatusCode::BAD_REQUEST);
+    assert_eq!(parse_buildid("483").unwrap_err().0, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        parse_buildid("48zz").unwrap_err().0,
+        StatusCode::BAD_REQUEST
+    );
+}
 
 #[axum_macros::debug_handler]
 async fn get_debuginfo(
     Path(buildid): Path<String>,
     State(state): State<ServerState>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    let ready = start_indexation_and_wait(state.watcher, INDEXING_TIMEOUT).await;
-    let res = and_realise(state.cache.get_debuginfo(&buildid).await, "debuginfo").await;
-    let res = match res {
-        Ok(None) => {
-            // try again harder
-            tracing::debug!("{} was not in cache, reindexing online", buildid);
-            match maybe_reindex_by_build_id(&state.cache, &buildid).await {
-                Ok(()) => and_realise(state.cache.get_debuginfo(&buildid).await, "debuginfo").await,
-                Err(e) => Err(e),
-            }
-        }
-        res => res,
+    let buildid = match parse_buildid(&buildid) {
+        Ok(buildid) => buildid,
+        Err(response) => return response.into_response(),
     };
+    let ready = state.wait_for_indexation().await;
+    let buildid = expand_buildid(&state.cache, buildid).await;
+    let res = state.resolver.debuginfo(&buildid).await;
+    let res = state.verified(res).await;
+    // keeps the extracted MiniDebugInfo alive until it is opened
+    let mut _tempdir = None;
     let res = match res {
-        Ok(None) => {
-            // try again harder
-            tracing::debug!(
-                "online reindexation failed for {}, using hydra API",
-                buildid
-            );
-            match maybe_fetch_debuginfo_from_substituter_index(
-                &state.cache,
-                state.substituters.as_ref(),
-                &buildid,
-            )
-            .await
-            {
-                Ok(()) => and_realise(state.cache.get_debuginfo(&buildid).await, "debuginfo").await,
-                Err(e) => Err(e),
+        Ok(None) if state.serve_minidebuginfo => match minidebuginfo(&state, &buildid).await {
+            Ok(Some((tempdir, path))) => {
+                _tempdir = Some(tempdir);
+                Ok(Some(path))
             }
-        }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                tracing::warn!("extracting MiniDebugInfo of {}: {:#}", buildid, e);
+                Ok(None)
+            }
+        },
         res => res,
     };
-    unwrap_file(res, ready).await
+    maybe_record_miss(&state.cache, &buildid, &res, ready).await;
+    let mut response = unwrap_file(
+        res,
+        not_found_status(ready, state.while_indexing),
+        ELF_CONTENT_TYPE,
+        &headers,
+    )
+    .await;
+    if _tempdir.is_some() {
+        // the debug output may be found later
+        response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    }
+    response
+}
+
+/// Extracts the MiniDebugInfo of the executable of this buildid, for executables without debug
+/// output, with `--serve-minidebuginfo`.
+///
+/// The executable is not realised for this: it must already be in the store.
+///
+/// The returned file only exists as long as the returned temporary directory.
+async fn minidebuginfo(
+    state: &ServerState,
+    buildid: &str,
+) -> anyhow::Result<Option<(tempfile::TempDir, PathBuf)>> {
+    let Some(executable) = state.cache.get_executable(buildid).await? else {
+        return Ok(None);
+    };
+    if !tokio::fs::try_exists(&executable).await.unwrap_or(false) {
+        return Ok(None);
+    }
+    state.verify(&executable).await?;
+    let tempdir = tempfile::TempDir::new_in(crate::db::temp_dir()?)
+        .context("creating temporary directory")?;
+    let target = tempdir.path().join("minidebuginfo");
+    let target_clone = target.clone();
+    let found = tokio::task::spawn_blocking(move || {
+        crate::minidebuginfo::extract(&executable, &target_clone)
+    })
+    .await
+    .context("joining MiniDebugInfo extraction")??;
+    if !found {
+        return Ok(None);
+    }
+    tracing::info!("serving MiniDebugInfo of {} as debuginfo", buildid);
+    Ok(Some((tempdir, target)))
 }
 
 #[axum_macros::debug_handler]
-async fn get_executable(
-    Path(buildid): Path<String>,
+async fn get_split_dwarf(
+    Path((buildid, name)): Path<(String, String)>,
     State(state): State<ServerState>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    let ready = start_indexation_and_wait(state.watcher, INDEXING_TIMEOUT).await;
-    let res = and_realise(state.cache.get_executable(&buildid).await, "executable").await;
-    unwrap_file(res, ready).await
+    let buildid = match parse_buildid(&buildid) {
+        Ok(buildid) => buildid,
+        Err(response) => return response.into_response(),
+    };
+    let ready = state.wait_for_indexation().await;
+    let buildid = expand_buildid(&state.cache, buildid).await;
+    let res = state
+        .resolver
+        .split_dwarf(&buildid, std::path::Path::new(&name))
+        .await;
+    let res = state.verified(res).await;
+    unwrap_file(
+        res,
+        not_found_status(ready, state.while_indexing),
+        ELF_CONTENT_TYPE,
+        &headers,
+    )
+    .await
 }
 
-/// queries the cache for a source file `request` corresponding to `buildid`.
-///
-/// may download the source if required, and returns where the requested file is on disk.
-async fn fetch_and_get_source(
-    buildid: String,
-    request: PathBuf,
-    cache: Cache,
-) -> anyhow::Result<Option<SourceLocation>> {
-    let source = cache.get_source(&buildid).await;
-    let source = match and_realise(source, "source").await {
-        Ok(None) => {
-            // try again harder
-            match maybe_reindex_by_build_id(&cache, &buildid).await {
-                Ok(()) => and_realise(cache.get_source(&buildid).await, "source").await,
-                Err(e) => Err(e),
-            }
-        }
-        source => source,
+#[axum_macros::debug_handler]
+async fn get_executable(
+    Path(buildid): Path<String>,
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let buildid = match parse_buildid(&buildid) {
+        Ok(buildid) => buildid,
+        Err(response) => return response.into_response(),
     };
-    let source = source.with_context(|| format!("getting source of {} from cache", &buildid))?;
-    let source = match source {
-        None => {
-            tracing::debug!("no source found for buildid {}", &buildid);
-            return Ok(None);
+    let ready = state.wait_for_indexation().await;
+    let buildid = expand_buildid(&state.cache, buildid).await;
+    // keeps the file downloaded from a substituter alive until it is opened
+    let mut _tempdir = None;
+    let res = match state.resolver.executable(&buildid).await {
+        Ok(Some((tempdir, path))) => {
+            _tempdir = tempdir;
+            Ok(Some(path))
         }
-        Some(x) => PathBuf::from(x),
+        Ok(None) => Ok(None),
+        Err(e) => Err(e),
     };
-    tracing::debug!(
-        "found source store path for buildid {} at {}",
-        &buildid,
-        source.display()
-    );
-    let file =
-        tokio::task::spawn_blocking(move || get_file_for_source(source.as_ref(), request.as_ref()))
-            .await?
-            .context("looking in source")?;
-    Ok(file)
+    let res = state.verified(res).await;
+    maybe_record_miss(&state.cache, &buildid, &res, ready).await;
+    unwrap_file(
+        res,
+        not_found_status(ready, state.while_indexing),
+        ELF_CONTENT_TYPE,
+        &headers,
+    )
+    .await
 }
 
 /// reads a file inside an archive into an http response
 async fn uncompress_archive_file_to_http_body(
     archive: &std::path::Path,
     member: &std::path::Path,
-) -> anyhow::Result<impl IntoResponse> {
+) -> anyhow::Result<Body> {
+    if is_patch(archive) {
+        let contents = file_created_by_patch(archive, member)?;
+        return Ok(Body::from(contents));
+    }
     let archive_file = tokio::fs::File::open(&archive)
         .await
         .with_context(|| format!("opening source archive {}", archive.display()))?;
@@ -308,12 +694,8 @@ async fn uncompress_archive_file_to_http_body(
     let archive = archive.to_path_buf();
     let member = member.to_path_buf();
     let decompressor_future = async move {
-        if let Err(e) = compress_tools::tokio_support::uncompress_archive_file(
-            archive_file,
-            asyncwriter,
-            &member_path,
-        )
-        .await
+        if let Err(e) =
+            crate::sandbox::uncompress_archive_file(archive_file, asyncwriter, &member_path).await
         {
             tracing::error!(
                 "expanding {} from {}: {:#}",
@@ -327,153 +709,1994 @@ async fn uncompress_archive_file_to_http_body(
     Ok(Body::from_stream(streamreader))
 }
 
+/// Serves a file inside an archive, extracting it to disk first if possible so that later and
+/// partial requests do not decompress the archive again.
+async fn archive_member_response(
+    state: &ServerState,
+    headers: &HeaderMap,
+    archive: &std::path::Path,
+    member: &std::path::Path,
+) -> Result<Response, (StatusCode, String)> {
+    let extracted = match &state.extracted_sources {
+        None => Err(anyhow::anyhow!("no directory to extract source files")),
+        Some(dir) => extract_archive_member(&state.cache, dir, archive, member).await,
+    };
+    match extracted {
+        Ok(extracted) => {
+            let response = file_response(&extracted, source_content_type(member), headers).await;
+            if response.is_ok() {
+                tracing::info!(
+                    "returning {} from {} extracted in {}",
+                    member.display(),
+                    archive.display(),
+                    extracted.display()
+                );
+            }
+            response
+        }
+        Err(e) => {
+            tracing::debug!("streaming archive member instead of caching it: {:#}", e);
+            match uncompress_archive_file_to_http_body(archive, member).await {
+                Ok(r) => {
+                    tracing::info!("returning {} from {}", member.display(), archive.display());
+                    let content_type = HeaderValue::from_static(source_content_type(member));
+                    Ok(([(CONTENT_TYPE, content_type)], r).into_response())
+                }
+                Err(e) => Err(error_status(e)),
+            }
+        }
+    }
+}
+
 #[axum_macros::debug_handler]
 async fn get_source(
     Path((buildid, request)): Path<(String, String)>,
     State(state): State<ServerState>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
     // when gdb attempts to show the source of a function that comes
     // from a header in another library, the request is store path made
     // relative to /
     // in this case, let's fetch it
-    if request.starts_with("nix/store") {
-        let absolute = PathBuf::from("/").join(request);
-        let demangled = demangle(absolute);
-        let error = realise(&demangled)
+    if let Some(demangled) = parse_store_source_request(&request) {
+        // the whole store path, as the file may be missing from it
+        let storepath = get_store_path(&demangled).unwrap_or(&demangled);
+        let error = state
+            .resolver
+            .realise_source(storepath, &mut 0)
             .await
-            .with_context(|| format!("downloading source {}", demangled.display()));
-        return unwrap_file(error.map(|()| Some(demangled)), true)
-            .await
-            .into_response();
+            .map_err(|e| match e.downcast_ref::<SourceTooLarge>() {
+                Some(_) => e,
+                None => e.context(Unavailable(format!(
+                    "downloading source {}",
+                    demangled.display()
+                ))),
+            });
+        let content_type = source_content_type(&demangled);
+        let res = state.verified(error.map(|()| Some(demangled))).await;
+        return unwrap_file(res, StatusCode::NOT_FOUND, content_type, &headers).await;
     }
     // as a fallback, have a look at the source of the buildid
-    let ready = start_indexation_and_wait(state.watcher, INDEXING_TIMEOUT).await;
+    let buildid = match parse_buildid(&buildid) {
+        Ok(buildid) => buildid,
+        Err(response) => return response.into_response(),
+    };
+    let ready = state.wait_for_indexation().await;
+    let buildid = expand_buildid(&state.cache, buildid).await;
     let request = PathBuf::from(request);
-    let sourcefile = fetch_and_get_source(buildid.to_owned(), request, state.cache).await;
+    let sourcefile = state.resolver.source(&buildid, &request).await;
+    let sourcefile = match sourcefile {
+        Ok(Some(location)) => {
+            let path = match &location {
+                SourceLocation::File(path) => path,
+                SourceLocation::Archive { archive, .. } => archive,
+            };
+            state.verify(path).await.map(|()| Some(location))
+        }
+        res => res,
+    };
     let response = match sourcefile {
-        Ok(Some(SourceLocation::File(path))) => match tokio::fs::File::open(&path).await {
-            Err(e) => Err((
-                StatusCode::NOT_FOUND,
-                format!("opening {}: {:#}", path.display(), e),
-            )),
-            Ok(file) => {
-                let mut headers = HeaderMap::new();
-                if let Ok(metadata) = path.metadata() {
-                    if let Ok(value) = metadata.size().to_string().parse() {
-                        headers.insert(CONTENT_LENGTH, value);
-                    }
-                }
+        Ok(Some(SourceLocation::File(path))) => {
+            let response = file_response(&path, source_content_type(&path), &headers).await;
+            if response.is_ok() {
                 tracing::info!("returning {}", path.display());
-                // convert the `AsyncRead` into a `Stream`
-                let stream = ReaderStream::new(file);
-                // convert the `Stream` into an `axum::body::HttpBody`
-                let body = Body::from_stream(stream);
-                Ok((headers, body).into_response())
             }
-        },
+            response
+        }
         Ok(Some(SourceLocation::Archive {
             ref archive,
             ref member,
-        })) => match uncompress_archive_file_to_http_body(archive, member).await {
-            Ok(r) => {
-                tracing::info!("returning {} from {}", member.display(), archive.display());
-                Ok(r.into_response())
-            }
-            Err(e) => Err((StatusCode::NOT_FOUND, format!("{:#}", e))),
-        },
+        })) => archive_member_response(&state, &headers, archive, member).await,
         Ok(None) => Err((
-            if ready {
-                StatusCode::NOT_FOUND
-            } else {
-                NON_CACHING_ERROR_STATUS
-            },
+            not_found_status(ready, state.while_indexing),
             "not found in cache".to_string(),
         )),
-        Err(e) => Err((StatusCode::NOT_FOUND, format!("{:#}", e))),
-    };
-    if let Err((code, error)) = &response {
-        tracing::info!("Responding error {}: {}", code, error);
+        Err(e) => Err(error_status(e)),
     };
-    response.into_response()
+    match response {
+        Ok(response) => response,
+        Err(error) => error_response(error),
+    }
 }
 
-async fn get_section(Path(_param): Path<(String, String)>) -> impl IntoResponse {
-    StatusCode::NOT_IMPLEMENTED
+/// The file of the store requested by a request `nix/store/xxx-foo/include/foo.h` to
+/// `/buildid/BUILDID/source/`, or `/nix/store/...` when gdb requests it with two slashes,
+/// normalized and with the hash part lowercased as by [demangle].
+///
+/// Returns `None` if the request is not for a file of a store path, for example because `..`
+/// leads out of it.
+fn parse_store_source_request(request: &str) -> Option<PathBuf> {
+    let relative = request.trim_start_matches('/');
+    if !relative.starts_with("nix/store/") {
+        return None;
+    }
+    let path = demangle(normalize(&PathBuf::from("/").join(relative)));
+    get_store_path(&path)?;
+    Some(path)
 }
 
-async fn get_substituters() -> anyhow::Result<Vec<Box<dyn Substituter>>> {
-    let config = crate::config::get_nix_config()
-        .await
-        .context("determining the list of substituters")?;
-    let mut urls = HashSet::new();
-    for key in &["substituters", "trusted-substituters"] {
-        let several = config.get(*key).map(|s| s.as_str()).unwrap_or("");
-        for word in several.split(' ') {
-            if !word.is_empty() {
-                urls.insert(word);
-            }
-        }
+#[test]
+fn test_parse_store_source_request() {
+    let glibc = "/nix/store/jw65xnml1fgf4bfgzgiszck3lfjwxg6l-glibc-2.37/include/stdio.h";
+    assert_eq!(
+        parse_store_source_request(&glibc[1..]).as_deref(),
+        Some(std::path::Path::new(glibc))
+    );
+    assert_eq!(
+        parse_store_source_request(
+            "/nix/store/JW65XNML1FGF4BFGZGISZCK3LFJWXG6L-glibc-2.37/include/bits/../stdio.h"
+        )
+        .as_deref(),
+        Some(std::path::Path::new(glibc))
+    );
+    assert_eq!(
+        parse_store_source_request(
+            "nix/store/jw65xnml1fgf4bfgzgiszck3lfjwxg6l-glibc-2.37/../../../etc/passwd"
+        ),
+        None
+    );
+    assert_eq!(parse_store_source_request("nix/store/"), None);
+    assert_eq!(parse_store_source_request("build/source/main.c"), None);
+}
+
+/// What can be requested about a file at `/path/FILE/WHAT`
+const PATH_ENDPOINTS: &[&str] = &["debuginfo", "executable", "metadata", "status"];
+
+/// Splits a request `nix/store/xxx-foo/bin/foo/debuginfo` to `/path/` into the absolute path
+/// of the file and the endpoint.
+fn parse_path_request(request: &str) -> Option<(PathBuf, &str)> {
+    let (file, what) = request.rsplit_once('/')?;
+    if file.is_empty() || !PATH_ENDPOINTS.contains(&what) {
+        return None;
     }
-    tracing::debug!("found substituters {urls:?} in nix.conf");
-    let mut substituters: Vec<Box<dyn Substituter>> = vec![];
-    for url in urls.iter() {
-        match FileSubstituter::from_url(url).await {
-            Ok(Some(s)) => {
-                tracing::debug!("using substituter {} for hydra API", s.url());
-                substituters.push(Box::new(s));
-                continue;
-            }
-            Err(e) => tracing::warn!("substituter url {url} has a problem: {e:#}"),
-            Ok(None) => tracing::debug!("substituter {url} is not supported by file:// backend"),
+    Some((PathBuf::from("/").join(file), what))
+}
+
+#[test]
+fn test_parse_path_request() {
+    assert_eq!(
+        parse_path_request("nix/store/aaaa-hello/bin/hello/debuginfo"),
+        Some((
+            PathBuf::from("/nix/store/aaaa-hello/bin/hello"),
+            "debuginfo"
+        ))
+    );
+    assert_eq!(
+        parse_path_request("run/current-system/sw/bin/ls/metadata"),
+        Some((PathBuf::from("/run/current-system/sw/bin/ls"), "metadata"))
+    );
+    assert_eq!(parse_path_request("nix/store/aaaa-hello/bin/hello"), None);
+    assert_eq!(parse_path_request("debuginfo"), None);
+    assert_eq!(parse_path_request("/debuginfo"), None);
+}
+
+/// Computes the buildid of a file on the server, which must resolve to a file in the store.
+///
+/// For a store given with `--store`, the file is looked up in the filesystem of this store.
+async fn buildid_of_file(
+    state: &ServerState,
+    file: PathBuf,
+) -> Result<String, (StatusCode, String)> {
+    let root = match &state.watcher {
+        Some(watcher) => watcher.root().to_path_buf(),
+        None => PathBuf::from("/"),
+    };
+    let located = relocate(&root, &file);
+    let store = relocate(&root, std::path::Path::new("/nix/store"));
+    tokio::task::spawn_blocking(move || {
+        let not_found = |e: anyhow::Error| (StatusCode::NOT_FOUND, format!("{:#}", e));
+        let canonical = std::fs::canonicalize(&located)
+            .with_context(|| format!("resolving {}", file.display()))
+            .map_err(not_found)?;
+        // do not disclose anything about other files of the server
+        if !canonical.starts_with(&store) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("{} is not in the store", file.display()),
+            ));
         }
-        match HttpSubstituter::from_url(url).await {
-            Ok(Some(s)) => {
-                tracing::debug!("using substituter {} for hydra API", s.url());
-                substituters.push(Box::new(s));
-            }
-            Err(e) => tracing::warn!("substituter url {url} has a problem: {e:#}"),
-            Ok(None) => tracing::debug!("substituter {url} is not supported by https:// backend"),
+        match get_buildid(&canonical).map_err(not_found)? {
+            Some(buildid) => Ok(buildid),
+            None => Err((
+                StatusCode::NOT_FOUND,
+                format!("{} has no buildid", file.display()),
+            )),
         }
+    })
+    .await
+    .unwrap_or_else(|e| Err((StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))))
+}
+
+/// Serves `/path/FILE/WHAT` like `/buildid/BUILDID/WHAT`, where `BUILDID` is the buildid of
+/// `FILE`, a file on the server, for scripts which have the path of a binary but no way to
+/// read its buildid.
+async fn get_by_path(
+    Path(request): Path<String>,
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+) -> Response {
+    let Some((file, what)) = parse_path_request(&request) else {
+        return error_response((
+            StatusCode::NOT_FOUND,
+            format!("expected /path/FILE/{}", PATH_ENDPOINTS.join("|")),
+        ));
+    };
+    let buildid = match buildid_of_file(&state, file).await {
+        Ok(buildid) => buildid,
+        Err(error) => return error_response(error),
+    };
+    let buildid = Path(buildid);
+    match what {
+        "debuginfo" => get_debuginfo(buildid, State(state), headers)
+            .await
+            .into_response(),
+        "executable" => get_executable(buildid, State(state), headers)
+            .await
+            .into_response(),
+        "metadata" => get_metadata(buildid, State(state)).await.into_response(),
+        _ => get_status(buildid, State(state)).await.into_response(),
     }
-    Ok(substituters)
 }
 
-/// If option `-i` is specified, index and exit. Otherwise starts indexation and runs the
-/// debuginfod server.
-pub async fn run_server(args: Options) -> anyhow::Result<ExitCode> {
-    let cache = Cache::open().await.context("opening global cache")?;
-    let watcher = StoreWatcher::new(cache.clone());
-    if args.index_only {
-        match watcher.maybe_index_new_paths().await? {
-            None => (),
-            Some(handle) => handle.await?,
-        };
-        Ok(ExitCode::SUCCESS)
-    } else {
-        watcher.watch_store();
-        let substituters = match get_substituters().await {
-            Ok(l) => l,
-            Err(e) => {
-                tracing::warn!("could not determine the list of substituters: {e:#}");
-                vec![]
-            }
-        };
-        let state = ServerState {
-            watcher,
-            cache,
-            substituters: Arc::new(substituters),
-        };
-        let app = Router::new()
-            .route("/buildid/:buildid/section/:section", get(get_section))
-            .route("/buildid/:buildid/source/*path", get(get_source))
-            .route("/buildid/:buildid/executable", get(get_executable))
-            .route("/buildid/:buildid/debuginfo", get(get_debuginfo))
-            .layer(tower_http::trace::TraceLayer::new_for_http())
-            .with_state(state);
-        let listener = tokio::net::TcpListener::bind(&args.listen_address)
+/// Lists buildids which were requested but not found, and whether they were found since.
+///
+/// Clients which cache negative answers can poll this to invalidate their cache.
+async fn get_missing(State(state): State<ServerState>) -> impl IntoResponse {
+    match state.cache.get_misses().await {
+        Ok(misses) => Ok(axum::Json(misses)),
+        Err(e) => {
+            tracing::warn!("listing misses: {:#}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))
+        }
+    }
+}
+
+/// How many buildids are prefetched in parallel
+const N_PREFETCH: usize = 8;
+
+/// Maximum size of the body of a request to `/prefetch`, which can be a core dump
+const MAX_PREFETCH_REQUEST_SIZE: usize = 1 << 30;
+
+/// Maximum number of buildids prefetched by one request
+const MAX_PREFETCH_BUILDIDS: usize = 1000;
+
+/// Reads the body of a prefetch request: either a core dump, or whitespace separated buildids.
+///
+/// In case of error, returns the response to send to the client.
+async fn read_prefetch_request(body: Body) -> Result<Vec<String>, (StatusCode, String)> {
+    let internal_error = |e: anyhow::Error| {
+        tracing::warn!("reading prefetch request: {:#}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+    };
+    let temppath = crate::db::temp_dir()
+        .and_then(|dir| {
+            tempfile::NamedTempFile::new_in(dir).context("creating temporary file for request body")
+        })
+        .map_err(internal_error)?
+        .into_temp_path();
+    let file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .truncate(true)
+        .open(&temppath)
+        .await
+        .context("opening temporary file for request body")
+        .map_err(internal_error)?;
+    let mut writer = tokio::io::BufWriter::new(file);
+    let mut stream = body.into_data_stream();
+    let mut size = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| (StatusCode::BAD_REQUEST, format!("{:#}", e)))?;
+        size += chunk.len();
+        if size > MAX_PREFETCH_REQUEST_SIZE {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("request body larger than {MAX_PREFETCH_REQUEST_SIZE} bytes"),
+            ));
+        }
+        writer
+            .write_all(&chunk)
             .await
-            .with_context(|| format!("opening listen socket on {}", &args.listen_address))?;
-        axum::serve::serve(listener, app.into_make_service()).await?;
-        Ok(ExitCode::SUCCESS)
+            .context("writing request body to disk")
+            .map_err(internal_error)?;
     }
+    writer
+        .flush()
+        .await
+        .context("writing request body to disk")
+        .map_err(internal_error)?;
+    let mut file = writer.into_inner();
+    let mut magic = [0u8; 4];
+    file.rewind()
+        .await
+        .context("rewinding request body")
+        .map_err(internal_error)?;
+    let is_elf = file.read_exact(&mut magic).await.is_ok() && &magic == b"\x7fELF";
+    drop(file);
+    let buildids = if is_elf {
+        tokio::task::spawn_blocking(move || buildids_in_core_file(temppath.as_ref()))
+            .await
+            .context("joining core dump reader")
+            .map_err(internal_error)?
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("{:#}", e)))?
+    } else {
+        let content = tokio::fs::read(&temppath)
+            .await
+            .context("reading request body")
+            .map_err(internal_error)?;
+        let text = String::from_utf8(content)
+            .map_err(|_| (StatusCode::BAD_REQUEST, "body is not utf8".to_owned()))?;
+        text.split_whitespace()
+            .map(parse_buildid)
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    if buildids.len() > MAX_PREFETCH_BUILDIDS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("at most {MAX_PREFETCH_BUILDIDS} buildids per request"),
+        ));
+    }
+    Ok(buildids)
+}
+
+#[tokio::test]
+async fn test_read_prefetch_request() {
+    let buildids = read_prefetch_request(Body::from("abcd\nEF01 "))
+        .await
+        .unwrap();
+    assert_eq!(buildids, vec!["abcd".to_owned(), "ef01".to_owned()]);
+    let many: Vec<String> = (0..=MAX_PREFETCH_BUILDIDS)
+        .map(|i| format!("{:04x}", i))
+        .collect();
+    let error = read_prefetch_request(Body::from(many.join(" ")))
+        .await
+        .unwrap_err();
+    assert_eq!(error.0, StatusCode::BAD_REQUEST);
+    assert!(read_prefetch_request(Body::from("xyz")).await.is_err());
+}
+
+/// Fetches the executable and debuginfo of this buildid
+async fn prefetch_one(state: ServerState, buildid: String) -> Prefetched {
+    let buildid = expand_buildid(&state.cache, buildid).await;
+    let debuginfo = state.resolver.debuginfo(&buildid).await;
+    let executable = and_realise(state.cache.get_executable(&buildid).await, "executable").await;
+    Prefetched {
+        debuginfo: matches!(debuginfo, Ok(Some(_))),
+        executable: matches!(executable, Ok(Some(_))),
+        buildid,
+    }
+}
+
+/// Fetches in advance the executables and debuginfo of a list of buildids, or of the buildids
+/// mapped in a core dump.
+///
+/// The body is either a core dump or a whitespace separated list of buildids.
+async fn post_prefetch(State(state): State<ServerState>, body: Body) -> impl IntoResponse {
+    let buildids = match read_prefetch_request(body).await {
+        Ok(buildids) => buildids,
+        Err((code, error)) => {
+            tracing::info!("Responding error {}: {}", code, error);
+            return (code, error).into_response();
+        }
+    };
+    tracing::info!("prefetching {} buildids", buildids.len());
+    state.wait_for_indexation().await;
+    let results: Vec<Prefetched> = futures_util::stream::iter(buildids)
+        .map(|buildid| prefetch_one(state.clone(), buildid))
+        .buffer_unordered(N_PREFETCH)
+        .collect()
+        .await;
+    axum::Json(results).into_response()
+}
+
+/// Maximum size of the body of a request to `/index`
+const MAX_INDEX_REQUEST_SIZE: usize = 1_000_000;
+
+/// Parses the body of a request to `/index`: whitespace separated store paths, like
+/// `$OUT_PATHS` in a nix post-build hook.
+fn parse_index_request(body: &[u8]) -> Result<Vec<PathBuf>, String> {
+    let body = std::str::from_utf8(body).map_err(|e| format!("invalid utf8: {}", e))?;
+    let mut paths = Vec::new();
+    for word in body.split_whitespace() {
+        let path = PathBuf::from(word);
+        if get_store_path(&path) != Some(path.as_path()) {
+            return Err(format!("{} is not a store path", word));
+        }
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+#[test]
+fn test_parse_index_request() {
+    assert_eq!(
+        parse_index_request(
+            b"/nix/store/aaaa-hello /nix/store/bbbb-hello-debug\n/nix/store/aaaa-hello\n"
+        ),
+        Ok(vec![
+            PathBuf::from("/nix/store/aaaa-hello"),
+            PathBuf::from("/nix/store/bbbb-hello-debug")
+        ])
+    );
+    assert!(parse_index_request(b"/nix/store/aaaa-hello/bin/hello").is_err());
+    assert!(parse_index_request(b"/etc").is_err());
+    assert_eq!(parse_index_request(b""), Ok(vec![]));
+}
+
+/// Indexes the store paths in the body immediately, for example from a nix post-build hook.
+async fn post_index(State(state): State<ServerState>, body: Body) -> impl IntoResponse {
+    let watcher = match &state.watcher {
+        Some(watcher) => watcher,
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                "this server does not index a store".to_owned(),
+            )
+        }
+    };
+    let body = match axum::body::to_bytes(body, MAX_INDEX_REQUEST_SIZE).await {
+        Ok(body) => body,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("{:#}", e)),
+    };
+    let paths = match parse_index_request(&body) {
+        Ok(paths) => paths,
+        Err(e) => return (StatusCode::BAD_REQUEST, e),
+    };
+    let n = paths.len();
+    match watcher.index_now(paths).await {
+        Ok(()) => (StatusCode::OK, format!("indexed {} store paths\n", n)),
+        Err(e) => {
+            tracing::warn!("indexing store paths on request: {:#}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+        }
+    }
+}
+
+/// Body of a request to `/register`, in json
+#[derive(Debug, Deserialize)]
+struct RegisterRequest {
+    /// a local executable, built outside the store
+    executable: PathBuf,
+    /// the directory containing its source files
+    source: Option<PathBuf>,
+}
+
+/// What [register_local] reads from the filesystem about a [RegisterRequest]
+struct LocalExecutable {
+    /// canonical path of the executable
+    executable: PathBuf,
+    /// canonical path of the source directory
+    source: Option<PathBuf>,
+    info: Option<crate::store::ElfInfo>,
+    has_debuginfo: bool,
+}
+
+/// Checks the paths of this request and reads the executable, synchronously.
+fn read_local_executable(request: &RegisterRequest) -> Result<LocalExecutable, String> {
+    let executable = std::fs::canonicalize(&request.executable)
+        .map_err(|e| format!("{}: {}", request.executable.display(), e))?;
+    if !executable.is_file() {
+        return Err(format!("{} is not a file", executable.display()));
+    }
+    let source = match &request.source {
+        None => None,
+        Some(source) => {
+            let source = std::fs::canonicalize(source)
+                .map_err(|e| format!("{}: {}", source.display(), e))?;
+            if !source.is_dir() {
+                return Err(format!("{} is not a directory", source.display()));
+            }
+            Some(source)
+        }
+    };
+    let info = get_elf_info(&executable).map_err(|e| format!("{:#}", e))?;
+    let has_debuginfo = matches!(
+        crate::dwarf::compilation_directory(&executable),
+        Ok(Some(_))
+    );
+    Ok(LocalExecutable {
+        executable,
+        source,
+        info,
+        has_debuginfo,
+    })
+}
+
+/// Registers the buildid of a local executable, and the directory of its source, in the cache.
+///
+/// Fails with `409 Conflict` if the cache already knows other files for this buildid, so that
+/// registration cannot replace what indexation found.
+///
+/// Returns the buildid.
+async fn register_local(
+    cache: &Cache,
+    request: RegisterRequest,
+) -> Result<String, (StatusCode, String)> {
+    let bad_request = |message: String| (StatusCode::BAD_REQUEST, message);
+    let internal_error = |e: anyhow::Error| {
+        tracing::warn!("registering local executable: {:#}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+    };
+    let LocalExecutable {
+        executable,
+        source,
+        info,
+        has_debuginfo,
+    } = tokio::task::spawn_blocking(move || read_local_executable(&request))
+        .await
+        .context("reading executable")
+        .map_err(internal_error)?
+        .map_err(bad_request)?;
+    let Some(info) = info else {
+        return Err(bad_request(format!(
+            "{} has no buildid",
+            executable.display()
+        )));
+    };
+    let executable = encode_path(&executable);
+    let source = source.as_deref().map(encode_path);
+    let entry = Entry {
+        buildid: info.buildid.clone(),
+        executable: Some(executable.clone()),
+        // binaries built in nix develop are usually not stripped
+        debuginfo: has_debuginfo.then_some(executable),
+        source: source.clone(),
+        build_source: None,
+        architecture: info.architecture,
+    };
+    let known = cache
+        .get_entry(&entry.buildid)
+        .await
+        .context("reading cache")
+        .map_err(internal_error)?;
+    if let Some(known) = known {
+        let conflicts = |known: &Option<String>, new: &Option<String>| {
+            known.is_some() && new.is_some() && known != new
+        };
+        if conflicts(&known.executable, &entry.executable)
+            || conflicts(&known.debuginfo, &entry.debuginfo)
+            || conflicts(&known.source, &entry.source)
+        {
+            return Err((
+                StatusCode::CONFLICT,
+                format!(
+                    "buildid {} is already known with other files, see /buildid/{}/status",
+                    entry.buildid, entry.buildid
+                ),
+            ));
+        }
+    }
+    cache
+        .register_missing(&[entry])
+        .await
+        .context("registering local executable")
+        .map_err(internal_error)?;
+    if let Some(source) = source {
+        // binaries built in the source directory refer to source files by their absolute path
+        let prefix = SourcePrefix {
+            buildid: info.buildid.clone(),
+            prefix: source,
+        };
+        cache
+            .register_source_prefixes(&[prefix])
+            .await
+            .context("registering source directory")
+            .map_err(internal_error)?;
+    }
+    Ok(info.buildid)
+}
+
+/// Fails with 403 Forbidden unless the client at `address` runs on this machine, for endpoints
+/// which would let remote clients read arbitrary files or change what is served.
+fn require_local_client(
+    address: &std::net::SocketAddr,
+    action: &str,
+) -> Result<(), (StatusCode, String)> {
+    if address.ip().to_canonical().is_loopback() {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::FORBIDDEN,
+            format!("only local clients may {}", action),
+        ))
+    }
+}
+
+#[tokio::test]
+async fn test_register_remote_client() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let state = ServerState::for_test(cache.clone());
+    let request = || RegisterRequest {
+        executable: std::env::current_exe().unwrap(),
+        source: Some(PathBuf::from("/")),
+    };
+    let remote = post_register(
+        State(state.clone()),
+        ConnectInfo("192.0.2.1:1949".parse().unwrap()),
+        axum::Json(request()),
+    )
+    .await;
+    assert_eq!(remote.status(), StatusCode::FORBIDDEN);
+    assert_eq!(cache.count_buildids().await.unwrap(), 0);
+    let local = post_register(
+        State(state),
+        ConnectInfo("127.0.0.1:1949".parse().unwrap()),
+        axum::Json(request()),
+    )
+    .await;
+    assert_eq!(local.status(), StatusCode::OK);
+}
+
+#[test]
+fn test_require_local_client() {
+    for local in ["127.0.0.1:1949", "[::1]:1949", "[::ffff:127.0.0.1]:1949"] {
+        assert!(require_local_client(&local.parse().unwrap(), "test").is_ok());
+    }
+    let (code, _) = require_local_client(&"192.0.2.1:1949".parse().unwrap(), "test").unwrap_err();
+    assert_eq!(code, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_register_local() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let exe = std::env::current_exe().unwrap();
+    let source = tempfile::TempDir::new().unwrap();
+    let request = RegisterRequest {
+        executable: exe.clone(),
+        source: Some(source.path().to_path_buf()),
+    };
+    let buildid = register_local(&cache, request).await.unwrap();
+    assert_eq!(Some(buildid.clone()), get_buildid(&exe).unwrap());
+    let entry = cache.get_entry(&buildid).await.unwrap().unwrap();
+    let exe = std::fs::canonicalize(exe).unwrap();
+    assert_eq!(entry.executable.as_deref(), exe.to_str());
+    let source = std::fs::canonicalize(source.path()).unwrap();
+    assert_eq!(entry.source.as_deref(), source.to_str());
+    assert_eq!(
+        cache.get_source_prefix(&buildid).await.unwrap().as_deref(),
+        source.to_str()
+    );
+
+    let not_elf = RegisterRequest {
+        executable: source.join("missing"),
+        source: None,
+    };
+    let (code, _) = register_local(&cache, not_elf).await.unwrap_err();
+    assert_eq!(code, StatusCode::BAD_REQUEST);
+
+    // registering again is harmless, but other files cannot replace known ones
+    let again = RegisterRequest {
+        executable: exe.clone(),
+        source: Some(source.clone()),
+    };
+    assert_eq!(register_local(&cache, again).await.unwrap(), buildid);
+    let other_source = tempfile::TempDir::new().unwrap();
+    let conflicting = RegisterRequest {
+        executable: exe,
+        source: Some(other_source.path().to_path_buf()),
+    };
+    let (code, _) = register_local(&cache, conflicting).await.unwrap_err();
+    assert_eq!(code, StatusCode::CONFLICT);
+}
+
+/// Registers a local executable and its source directory, with `--allow-register`, so that
+/// binaries built in `nix develop` can be debugged too.
+async fn post_register(
+    State(state): State<ServerState>,
+    ConnectInfo(address): ConnectInfo<std::net::SocketAddr>,
+    axum::Json(request): axum::Json<RegisterRequest>,
+) -> Response {
+    if let Err(response) = require_local_client(&address, "register executables") {
+        return error_response(response);
+    }
+    tracing::info!("registering {:?}", &request);
+    match register_local(&state.cache, request).await {
+        Ok(buildid) => axum::Json(serde_json::json!({ "buildid": buildid })).into_response(),
+        Err(response) => error_response(response),
+    }
+}
+
+/// Reads the content of this section in the first of these elf files where it is present with
+/// data, that is not `NOBITS` as it is in separate debuginfo for the sections of the executable.
+///
+/// Files whose architecture is not `architecture`, when known, cannot have been indexed for this
+/// buildid and are skipped.
+fn read_section(
+    candidates: &[PathBuf],
+    name: &str,
+    architecture: Option<&str>,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    use object::{Object, ObjectSection};
+    for path in candidates {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("opening {} to read section {}", path.display(), name))?;
+        let reader = object::read::ReadCache::new(file);
+        let object = match object::read::File::parse(&reader) {
+            Ok(object) => object,
+            Err(e) => {
+                tracing::warn!("cannot parse {} as elf: {}", path.display(), e);
+                continue;
+            }
+        };
+        let actual = crate::store::architecture_name(&object);
+        if let (Some(expected), Some(actual)) = (architecture, actual.as_deref()) {
+            if expected != actual {
+                tracing::warn!(
+                    "{} has architecture {} instead of {}, ignoring it",
+                    path.display(),
+                    actual,
+                    expected
+                );
+                continue;
+            }
+        }
+        let section = match object.section_by_name(name) {
+            Some(section) if section.file_range().is_some() => section,
+            _ => continue,
+        };
+        let data = section
+            .uncompressed_data()
+            .with_context(|| format!("reading section {} of {}", name, path.display()))?;
+        tracing::info!("returning section {} of {}", name, path.display());
+        return Ok(Some(data.into_owned()));
+    }
+    Ok(None)
+}
+
+/// Serves `/buildid/BUILDID/tree/PATH` for `--browse-sources`: the file `PATH` of the source
+/// store path of this buildid, or a listing if it is a directory.
+#[axum_macros::debug_handler]
+async fn get_source_tree(
+    Path((buildid, path)): Path<(String, String)>,
+    State(state): State<ServerState>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    let is_dir_request = uri.path().ends_with('/');
+    match source_tree_response(&state, buildid, &path, is_dir_request, &headers).await {
+        Ok(response) => response,
+        Err(error) => error_response(error),
+    }
+}
+
+/// Serves `/buildid/BUILDID/tree/`, the root of the source store path of this buildid.
+async fn get_source_tree_root(
+    Path(buildid): Path<String>,
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+) -> Response {
+    match source_tree_response(&state, buildid, "", true, &headers).await {
+        Ok(response) => response,
+        Err(error) => error_response(error),
+    }
+}
+
+/// Implementation of [get_source_tree].
+///
+/// `is_dir_request` is whether the url ends with a slash, as required for the relative links of
+/// directory listings.
+async fn source_tree_response(
+    state: &ServerState,
+    buildid: String,
+    relative: &str,
+    is_dir_request: bool,
+    headers: &HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let buildid = parse_buildid(&buildid)?;
+    let ready = state.wait_for_indexation().await;
+    let buildid = expand_buildid(&state.cache, buildid).await;
+    let source = match state.cache.get_source(&buildid).await {
+        Ok(Some(source)) => source,
+        Ok(None) => {
+            return Err((
+                not_found_status(ready, state.while_indexing),
+                "no source known for this buildid".to_owned(),
+            ))
+        }
+        Err(e) => return Err(error_status(e)),
+    };
+    state
+        .resolver
+        .realise_source(&source, &mut 0)
+        .await
+        .map_err(|e| match e.downcast_ref::<SourceTooLarge>() {
+            Some(_) => error_status(e),
+            None => error_status(e.context(Unavailable(format!(
+                "downloading source {}",
+                source.display()
+            )))),
+        })?;
+    state.verify(&source).await.map_err(error_status)?;
+    if !source.is_dir() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("source {} is not a directory", source.display()),
+        ));
+    }
+    let mut path = source.clone();
+    for component in std::path::Path::new(relative).components() {
+        match component {
+            std::path::Component::Normal(name) => path.push(name),
+            std::path::Component::CurDir => (),
+            _ => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid path {}", relative),
+                ))
+            }
+        }
+    }
+    let not_found =
+        |e: std::io::Error| (StatusCode::NOT_FOUND, format!("{}: {}", path.display(), e));
+    // symlinks must not lead out of the source
+    let root = tokio::fs::canonicalize(&source).await.map_err(not_found)?;
+    let canonical = tokio::fs::canonicalize(&path).await.map_err(not_found)?;
+    if !canonical.starts_with(&root) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("{} is not in {}", path.display(), source.display()),
+        ));
+    }
+    if !canonical.is_dir() {
+        let response = file_response(&path, source_content_type(&path), headers).await?;
+        tracing::info!("returning {}", path.display());
+        return Ok(response);
+    }
+    if !is_dir_request {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let location = format!("{}/", html::percent_encode(&name));
+        return Ok(axum::response::Redirect::permanent(&location).into_response());
+    }
+    let mut entries = Vec::new();
+    let mut dir = tokio::fs::read_dir(&canonical).await.map_err(not_found)?;
+    while let Some(entry) = dir.next_entry().await.map_err(not_found)? {
+        let is_dir = tokio::fs::metadata(entry.path())
+            .await
+            .is_ok_and(|metadata| metadata.is_dir());
+        entries.push((entry.file_name().to_string_lossy().into_owned(), is_dir));
+    }
+    entries.sort();
+    let page = html::directory_page(&path.display().to_string(), &entries);
+    Ok(axum::response::Html(page).into_response())
+}
+
+#[axum_macros::debug_handler]
+async fn get_section(
+    Path((buildid, section)): Path<(String, String)>,
+    State(state): State<ServerState>,
+) -> impl IntoResponse {
+    let buildid = match parse_buildid(&buildid) {
+        Ok(buildid) => buildid,
+        Err(response) => return response.into_response(),
+    };
+    let ready = state.wait_for_indexation().await;
+    let buildid = expand_buildid(&state.cache, buildid).await;
+    let debuginfo = state
+        .verified(state.resolver.debuginfo(&buildid).await)
+        .await;
+    let executable = state
+        .verified(and_realise(state.cache.get_executable(&buildid).await, "executable").await)
+        .await;
+    let mut candidates = Vec::new();
+    let mut error = None;
+    for res in [debuginfo, executable] {
+        match res {
+            Ok(Some(path)) => candidates.push(path),
+            Ok(None) => (),
+            Err(e) => error = Some(e),
+        }
+    }
+    let res = match error {
+        Some(e) if candidates.is_empty() => Err(e),
+        _ => Ok(candidates.first().cloned()),
+    };
+    match res {
+        Err(e) => return error_response(error_status(e)),
+        Ok(None) => {
+            return error_response((
+                not_found_status(ready, state.while_indexing),
+                "not found in cache".to_string(),
+            ))
+        }
+        Ok(Some(_)) => (),
+    }
+    let architecture = match state.cache.get_entry(&buildid).await {
+        Ok(entry) => entry.and_then(|entry| entry.architecture),
+        Err(e) => {
+            tracing::warn!("reading architecture of {}: {:#}", buildid, e);
+            None
+        }
+    };
+    let name = section.clone();
+    // the debuginfo if any, else the executable
+    let debuginfo = candidates[0].clone();
+    let data = tokio::task::spawn_blocking(move || {
+        read_section(&candidates, &name, architecture.as_deref())
+    })
+    .await
+    .context("joining section reader");
+    match data {
+        Ok(Ok(Some(data))) => data.into_response(),
+        Ok(Ok(None)) => match &state.gdb_indexer {
+            Some(indexer) if section == crate::gdbindex::SECTION => {
+                gdb_index_response(indexer, &buildid, &debuginfo).await
+            }
+            _ => error_response((
+                StatusCode::NOT_FOUND,
+                format!("no section {} with data for {}", section, buildid),
+            )),
+        },
+        Ok(Err(e)) | Err(e) => error_response(error_status(e)),
+    }
+}
+
+/// Serves the `.gdb_index` section generated for this debuginfo file.
+async fn gdb_index_response(
+    indexer: &GdbIndexer,
+    buildid: &str,
+    debuginfo: &std::path::Path,
+) -> Response {
+    let index = match indexer.index(buildid, debuginfo).await {
+        Ok(index) => index,
+        Err(e) => {
+            return error_response((
+                StatusCode::NOT_FOUND,
+                format!("generating gdb index for {}: {:#}", buildid, e),
+            ))
+        }
+    };
+    match tokio::fs::read(&index).await {
+        Ok(data) => data.into_response(),
+        Err(e) => error_response((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("reading {}: {:#}", index.display(), e),
+        )),
+    }
+}
+
+#[test]
+fn test_read_section() {
+    let exe = std::env::current_exe().unwrap();
+    let architecture = {
+        let data = std::fs::read(&exe).unwrap();
+        let object = object::read::File::parse(data.as_slice()).unwrap();
+        crate::store::architecture_name(&object)
+    };
+    assert!(architecture.is_some());
+    let candidates = vec![exe];
+    let text = read_section(&candidates, ".text", architecture.as_deref())
+        .unwrap()
+        .unwrap();
+    assert!(!text.is_empty());
+    assert_eq!(
+        read_section(&candidates, ".nonexistent", None).unwrap(),
+        None
+    );
+    assert_eq!(
+        read_section(&candidates, ".text", Some("pdp11-le")).unwrap(),
+        None
+    );
+}
+
+/// Returns what the cache knows about this buildid as json, without fetching anything.
+async fn get_status(
+    Path(buildid): Path<String>,
+    State(state): State<ServerState>,
+) -> impl IntoResponse {
+    let buildid = match parse_buildid(&buildid) {
+        Ok(buildid) => buildid,
+        Err(response) => return response.into_response(),
+    };
+    let buildid = expand_buildid(&state.cache, buildid).await;
+    match state.cache.get_entry(&buildid).await {
+        Ok(Some(entry)) => axum::Json(entry).into_response(),
+        Ok(None) => error_response((StatusCode::NOT_FOUND, "not found in cache".to_string())),
+        Err(e) => error_response((StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))),
+    }
+}
+
+/// Forgets what is known about this buildid, with `--allow-invalidate`, so that it is looked up
+/// again on the next request, and indexes its store path again.
+///
+/// Only local clients may do this.
+async fn delete_buildid(
+    Path(buildid): Path<String>,
+    State(state): State<ServerState>,
+    ConnectInfo(address): ConnectInfo<std::net::SocketAddr>,
+) -> impl IntoResponse {
+    if let Err(response) = require_local_client(&address, "invalidate buildids") {
+        return error_response(response);
+    }
+    let buildid = match parse_buildid(&buildid) {
+        Ok(buildid) => buildid,
+        Err(response) => return response.into_response(),
+    };
+    tracing::info!("invalidating {}", buildid);
+    match invalidate(&state.cache, &buildid).await {
+        Ok(Some(entry)) => {
+            if let (Some(watcher), Some(storepath)) =
+                (&state.watcher, Metadata::store_path_of(&entry))
+            {
+                let watcher = watcher.clone();
+                tokio::spawn(async move { watcher.index_now(vec![storepath]).await.or_warn() });
+            }
+            format!("forgot buildid {}\n", buildid).into_response()
+        }
+        Ok(None) => error_response((StatusCode::NOT_FOUND, "not found in cache".to_string())),
+        Err(e) => error_response((StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))),
+    }
+}
+
+/// Returns what is known about this buildid as json: what [get_status] returns, the deriver of
+/// its store path and the package name and version.
+async fn get_metadata(
+    Path(buildid): Path<String>,
+    State(state): State<ServerState>,
+) -> impl IntoResponse {
+    let buildid = match parse_buildid(&buildid) {
+        Ok(buildid) => buildid,
+        Err(response) => return response.into_response(),
+    };
+    match metadata(&state, buildid).await {
+        Ok(Some(metadata)) => axum::Json(metadata).into_response(),
+        Ok(None) => error_response((StatusCode::NOT_FOUND, "not found in cache".to_string())),
+        Err(e) => error_response((StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))),
+    }
+}
+
+/// Serves `/buildid/BUILDID/source-index`, the list of the files of the source of this buildid,
+/// so that clients can choose the right path to request.
+async fn get_source_index(
+    Path(buildid): Path<String>,
+    State(state): State<ServerState>,
+) -> impl IntoResponse {
+    let buildid = match parse_buildid(&buildid) {
+        Ok(buildid) => buildid,
+        Err(response) => return response.into_response(),
+    };
+    let ready = state.wait_for_indexation().await;
+    let buildid = expand_buildid(&state.cache, buildid).await;
+    match state.resolver.source_index(&buildid).await {
+        Ok(Some(index)) => axum::Json(index).into_response(),
+        Ok(None) => error_response((
+            not_found_status(ready, state.while_indexing),
+            "no source known for this buildid".to_owned(),
+        )),
+        Err(e) => error_response(error_status(e)),
+    }
+}
+
+/// What [get_metadata] returns about this buildid, or `None` if the cache does not know it.
+async fn metadata(state: &ServerState, buildid: String) -> anyhow::Result<Option<Metadata>> {
+    let buildid = expand_buildid(&state.cache, buildid).await;
+    let Some(entry) = state.cache.get_entry(&buildid).await? else {
+        return Ok(None);
+    };
+    let deriver = match Metadata::store_path_of(&entry) {
+        None => None,
+        Some(storepath) => match state.deriver(storepath).await {
+            Ok(deriver) => deriver,
+            Err(e) => {
+                tracing::info!("no deriver for buildid {}: {:#}", buildid, e);
+                None
+            }
+        },
+    };
+    Ok(Some(Metadata::new(entry, deriver)))
+}
+
+/// Remember the derivers of at most this many store paths
+const MAX_DERIVERS: usize = 10_000;
+
+/// Maximum number of buildids in a request to `/packages`
+const MAX_PACKAGES_BUILDIDS: usize = 1000;
+
+/// Reads the buildids of the query string of a request to `/packages`, like
+/// `buildid=ab12&buildid=cd34`, without duplicates.
+fn parse_packages_query(query: Option<&str>) -> Result<Vec<String>, (StatusCode, String)> {
+    let mut buildids = Vec::new();
+    for pair in query
+        .unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+    {
+        match pair.split_once('=') {
+            Some(("buildid", buildid)) => buildids.push(parse_buildid(buildid)?),
+            _ => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("unexpected parameter {pair:?}, expected buildid=BUILDID"),
+                ))
+            }
+        }
+    }
+    if buildids.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "expected at least one buildid=BUILDID parameter".to_owned(),
+        ));
+    }
+    buildids.sort_unstable();
+    buildids.dedup();
+    if buildids.len() > MAX_PACKAGES_BUILDIDS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("at most {MAX_PACKAGES_BUILDIDS} buildids per request"),
+        ));
+    }
+    Ok(buildids)
+}
+
+#[test]
+fn test_parse_packages_query() {
+    assert_eq!(
+        parse_packages_query(Some("buildid=cd34&buildid=AB12&buildid=ab12")).unwrap(),
+        ["ab12", "cd34"]
+    );
+    for bad in [None, Some(""), Some("buildid=xyz"), Some("id=ab12")] {
+        assert_eq!(
+            parse_packages_query(bad).unwrap_err().0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
+
+/// How many buildids of a request to `/packages` are looked up in parallel
+const N_PACKAGES: usize = 8;
+
+/// Returns a json object mapping each buildid of the query string to what [get_metadata]
+/// returns about it, notably its package name and version, or `null` if it is unknown.
+///
+/// Nothing is fetched nor realised, but finding the deriver of each store path not seen yet
+/// runs a subprocess, so this endpoint has the same concurrency limit as the others.
+async fn get_packages(State(state): State<ServerState>, uri: Uri) -> impl IntoResponse {
+    let buildids = match parse_packages_query(uri.query()) {
+        Ok(buildids) => buildids,
+        Err(error) => return error_response(error),
+    };
+    let results: anyhow::Result<BTreeMap<String, Option<Metadata>>> =
+        futures_util::stream::iter(buildids)
+            .map(|buildid| {
+                let state = &state;
+                async move {
+                    let metadata = metadata(state, buildid.clone()).await?;
+                    Ok((buildid, metadata))
+                }
+            })
+            .buffer_unordered(N_PACKAGES)
+            .try_collect()
+            .await;
+    match results {
+        Ok(results) => axum::Json(results).into_response(),
+        Err(e) => error_response((StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))),
+    }
+}
+
+/// Query string of the index page
+#[derive(Debug, Deserialize)]
+struct IndexQuery {
+    /// what to look up: a buildid, a file or a store path
+    q: Option<String>,
+}
+
+/// Serves an html page with the status of the server and a form to look up what the cache knows
+/// about a buildid, a file or a store path, without fetching anything.
+async fn get_index(
+    State(state): State<ServerState>,
+    Query(query): Query<IndexQuery>,
+) -> impl IntoResponse {
+    let status = html::Status {
+        indexing: state
+            .watcher
+            .as_ref()
+            .is_some_and(StoreWatcher::is_indexing),
+        buildids: state.cache.count_buildids().await.ok(),
+        misses: state
+            .cache
+            .get_misses()
+            .await
+            .ok()
+            .map(|misses| misses.len()),
+        last_indexation: state
+            .cache
+            .get_events()
+            .await
+            .ok()
+            .and_then(|events| events.index_end)
+            .map(|end| crate::db::now() - end),
+    };
+    let query = query.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+    let result = match query {
+        None => Ok(vec![]),
+        Some(query) => lookup(&state.cache, query).await,
+    };
+    let code = match &result {
+        Err(e) if e.is::<OutsideStore>() => StatusCode::FORBIDDEN,
+        _ => StatusCode::OK,
+    };
+    (
+        code,
+        axum::response::Html(html::index_page(&status, query, &result)),
+    )
+}
+
+/// The error when looking up a file of the server which is not in the store
+#[derive(Debug)]
+struct OutsideStore(PathBuf);
+
+impl std::fmt::Display for OutsideStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is not in the store", self.0.display())
+    }
+}
+
+impl std::error::Error for OutsideStore {}
+
+/// Maximum number of buildids shown when looking up a store path on the index page
+const MAX_LOOKUP_RESULTS: u32 = 100;
+
+/// Finds the entries of the cache about `query`, which is a buildid, an elf file, or a path
+/// containing elf files.
+///
+/// Fails with [OutsideStore] for paths which are not in the store once links are followed, so
+/// as not to disclose anything about other files of the server.
+async fn lookup(cache: &Cache, query: &str) -> anyhow::Result<Vec<Entry>> {
+    if let Ok(buildid) = parse_buildid(query) {
+        let buildid = expand_buildid(cache, buildid).await;
+        return Ok(cache.get_entry(&buildid).await?.into_iter().collect());
+    }
+    let path = PathBuf::from(query);
+    if !path.is_absolute() {
+        anyhow::bail!("expected a buildid or an absolute path");
+    }
+    let path = tokio::fs::canonicalize(&path)
+        .await
+        .unwrap_or_else(|_| demangle(path));
+    if get_store_path(&path).is_none() {
+        return Err(OutsideStore(path).into());
+    }
+    let path_clone = path.clone();
+    let info = tokio::task::spawn_blocking(move || match path_clone.is_file() {
+        true => get_elf_info(&path_clone),
+        false => Ok(None),
+    })
+    .await??;
+    if let Some(info) = info {
+        // show the buildid of the file even if nothing is known about it
+        let entry = cache.get_entry(&info.buildid).await?;
+        return Ok(vec![entry.unwrap_or(Entry {
+            buildid: info.buildid,
+            executable: None,
+            debuginfo: None,
+            source: None,
+            build_source: None,
+            architecture: info.architecture,
+        })]);
+    }
+    cache
+        .get_entries_in(&encode_path(&path), MAX_LOOKUP_RESULTS)
+        .await
+}
+
+#[tokio::test]
+async fn test_lookup_outside_store() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let exe = std::env::current_exe().unwrap();
+    let error = lookup(&cache, exe.to_str().unwrap()).await.unwrap_err();
+    assert!(error.is::<OutsideStore>());
+    // links to files outside the store are followed
+    let dir = tempfile::TempDir::new().unwrap();
+    let link = dir.path().join("link");
+    std::os::unix::fs::symlink(&exe, &link).unwrap();
+    let error = lookup(&cache, link.to_str().unwrap()).await.unwrap_err();
+    assert!(error.is::<OutsideStore>());
+    let response = get_index(
+        State(ServerState::for_test(cache)),
+        Query(IndexQuery {
+            q: Some(exe.to_str().unwrap().to_owned()),
+        }),
+    )
+    .await
+    .into_response();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+/// The kind of request for a route, as used in metrics labels
+fn request_type(route: &str) -> &str {
+    match route.strip_prefix("/buildid/:buildid/") {
+        Some(rest) => rest.split('/').next().unwrap_or(rest),
+        None => route,
+    }
+}
+
+#[test]
+fn test_request_type() {
+    assert_eq!(request_type("/buildid/:buildid/debuginfo"), "debuginfo");
+    assert_eq!(request_type("/buildid/:buildid/source/*path"), "source");
+    assert_eq!(request_type("/metrics"), "/metrics");
+}
+
+/// Counts requests and responses for `/metrics`
+async fn count_requests(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+    let kind = request_type(&route);
+    metrics.inc("http_requests_total", &[("type", kind)]);
+    let start = Instant::now();
+    let response = next.run(request).await;
+    metrics.add(
+        "http_responses_duration_milliseconds_sum",
+        &[("type", kind)],
+        start.elapsed().as_millis() as u64,
+    );
+    metrics.inc(
+        "http_responses_total",
+        &[("type", kind), ("code", response.status().as_str())],
+    );
+    response
+}
+
+async fn get_metrics(State(state): State<ServerState>) -> impl IntoResponse {
+    (
+        [(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+        )],
+        state.metrics.render(),
+    )
+}
+
+/// Liveness probe: answers as long as the server is running.
+async fn get_healthz() -> impl IntoResponse {
+    "ok\n"
+}
+
+/// What `/status` says about the server
+#[derive(Debug, Serialize)]
+struct ServerStatus {
+    /// version of the server
+    version: &'static str,
+    /// seconds since the server started
+    uptime: u64,
+    /// whether indexation of new store paths is in progress
+    indexing: bool,
+    /// whether the store paths present on startup were indexed
+    ready: bool,
+    /// last indexation start and end, and creation of the cache, as unix timestamps
+    #[serde(flatten)]
+    events: Events,
+}
+
+/// Describes the server in json: its uptime, and when indexation last started and completed,
+/// so that operators can tell whether indexation silently stopped.
+async fn get_server_status(State(state): State<ServerState>) -> Response {
+    let events = match state.cache.get_events().await {
+        Ok(events) => events,
+        Err(e) => return error_response((StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))),
+    };
+    let status = ServerStatus {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime: state.started.elapsed().as_secs(),
+        indexing: state
+            .watcher
+            .as_ref()
+            .is_some_and(StoreWatcher::is_indexing),
+        ready: state.watcher.as_ref().is_none_or(StoreWatcher::is_ready),
+        events,
+    };
+    axum::Json(status).into_response()
+}
+
+/// Readiness probe: whether the store paths present on startup were indexed, so that a buildid
+/// which is not found is really unknown.
+///
+/// Answers `503 Service Unavailable` with a `Retry-After` header during initial indexation.
+async fn get_readyz(State(state): State<ServerState>) -> Response {
+    let ready = match &state.watcher {
+        // nothing to index with --from-cache
+        None => true,
+        Some(watcher) => watcher.is_ready(),
+    };
+    if ready {
+        "ready\n".into_response()
+    } else {
+        error_response((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "initial indexation is in progress".to_owned(),
+        ))
+    }
+}
+
+/// Summary of the requests, semaphores and indexation in progress, with `--debug-tasks`.
+async fn get_debug_tasks(State(state): State<ServerState>) -> impl IntoResponse {
+    let indexation = match &state.watcher {
+        None => String::new(),
+        Some(watcher) => format!(
+            "indexation: {}\n",
+            if watcher.is_indexing() {
+                "running"
+            } else {
+                "idle"
+            }
+        ),
+    };
+    crate::diagnostics::report(&indexation)
+}
+
+/// Description of the API served at `/webapi`
+const WEBAPI: &str = "This is nixseparatedebuginfod, a debuginfod server for nix store paths.
+
+Endpoints:
+/                                html page to look up what is known about a buildid or file
+/buildid/BUILDID/debuginfo       separate debug symbols of this buildid
+/buildid/BUILDID/dwo/NAME        split dwarf file NAME (.dwo, or executable name.dwp) of this buildid
+/buildid/BUILDID/executable      stripped executable of this buildid
+/buildid/BUILDID/source/PATH     source file PATH of this buildid
+/buildid/BUILDID/source-index    files of the source of this buildid, and where it was unpacked
+                                 during the build, in json
+/buildid/BUILDID/section/NAME    content of the elf section NAME of this buildid, or a generated
+                                 .gdb_index section with --generate-gdb-index
+/buildid/BUILDID/status          what is known about this buildid, in json
+/buildid/BUILDID (DELETE)        forget what is known about this buildid, with --allow-invalidate
+/buildid/BUILDID/metadata        same with the deriver, package name and version, in json
+/packages?buildid=ID&buildid=ID  metadata of several buildids, in a json object by buildid
+/buildid/BUILDID/tree/PATH       file or directory listing PATH of the source store path of this
+                                 buildid, with --browse-sources
+/path/FILE/WHAT                  same as /buildid/BUILDID/WHAT for the buildid of FILE, a file in
+                                 the store of the server, for WHAT among debuginfo, executable,
+                                 metadata and status
+/missing                         buildids that were requested but not found
+/prefetch (POST)                 fetch in advance the buildids in the body, or in a core dump
+/index (POST)                    index now the store paths in the body, like $OUT_PATHS
+/register (POST)                 serve the local executable and source directory in the json
+                                 body {\"executable\": PATH, \"source\": DIR}, with
+                                 --allow-register
+/metrics                         metrics in prometheus format
+/healthz                         liveness probe, always ok while the server runs
+/status                          uptime, and when indexation last started and completed, in json
+/readyz                          readiness probe, 503 until initial indexation is complete
+/debug/tasks                     requests and indexation in progress, with --debug-tasks
+/store/NAME/...                  the endpoints above for the store NAME given with --store
+
+With --fallback, files not found are requested from other debuginfod servers.
+
+See https://www.mankier.com/8/debuginfod#Webapi
+";
+
+async fn get_webapi() -> impl IntoResponse {
+    WEBAPI
+}
+
+/// Prevents browsers from guessing a `Content-Type` other than the one we send, for example
+/// rendering a source file as html.
+async fn nosniff(mut response: Response) -> Response {
+    response
+        .headers_mut()
+        .insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    response
+}
+
+/// Name of the header telling how fresh the index is, see [add_index_lag]
+const X_NIX_INDEX_LAG: &str = "x-nix-index-lag";
+
+/// The value of the `X-Nix-Index-Lag` header: `SECONDS; last-id=ID` where `SECONDS` is how long
+/// ago the last indexation cycle completed and `ID` the id of the last store path in the nix db
+/// it indexed, or `never` before the first cycle completes.
+fn index_lag(last_cycle: Option<(Duration, Id)>) -> HeaderValue {
+    match last_cycle {
+        None => HeaderValue::from_static("never"),
+        Some((elapsed, id)) => {
+            HeaderValue::try_from(format!("{}; last-id={}", elapsed.as_secs(), id))
+                .expect("header value is ascii")
+        }
+    }
+}
+
+#[test]
+fn test_index_lag() {
+    assert_eq!(index_lag(None), "never");
+    assert_eq!(
+        index_lag(Some((Duration::from_millis(61500), 1234))),
+        "61; last-id=1234"
+    );
+}
+
+/// Tells with the `X-Nix-Index-Lag` header of each response how fresh the index of the store is,
+/// so that clients getting a 404 can tell whether it is worth asking for indexation.
+async fn add_index_lag(State(state): State<ServerState>, request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    if let Some(watcher) = &state.watcher {
+        response
+            .headers_mut()
+            .insert(X_NIX_INDEX_LAG, index_lag(watcher.last_cycle()));
+    }
+    response
+}
+
+/// Whether a request to these routes, relative to the store, is about a buildid or store path
+/// blocked with `--block`.
+async fn is_blocked(state: &ServerState, uri_path: &str) -> anyhow::Result<bool> {
+    if let Some(request) = uri_path.strip_prefix("/path/") {
+        return Ok(crate::filter::is_blocked_path(
+            &std::path::Path::new("/").join(request),
+        ));
+    }
+    let Some(buildid) = uri_path
+        .strip_prefix("/buildid/")
+        .and_then(|rest| rest.split('/').next())
+    else {
+        return Ok(false);
+    };
+    let Ok(buildid) = parse_buildid(buildid) else {
+        return Ok(false);
+    };
+    let buildid = expand_buildid(&state.cache, buildid).await;
+    if crate::filter::is_blocked_buildid(&buildid) {
+        return Ok(true);
+    }
+    let Some(entry) = state.cache.get_entry(&buildid).await? else {
+        return Ok(false);
+    };
+    Ok([
+        entry.executable,
+        entry.debuginfo,
+        entry.source,
+        entry.build_source,
+    ]
+    .iter()
+    .flatten()
+    .any(|path| crate::filter::is_blocked_path(&decode_path(path))))
+}
+
+/// Answers a 404 which clients can cache to requests about buildids and store paths blocked
+/// with `--block`, without fetching anything.
+async fn reject_blocked(
+    State(state): State<ServerState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match is_blocked(&state, request.uri().path()).await {
+        Ok(false) => next.run(request).await,
+        Ok(true) => {
+            let mut response =
+                error_response((StatusCode::NOT_FOUND, "blocked by --block".to_owned()));
+            response
+                .headers_mut()
+                .insert(CACHE_CONTROL, HeaderValue::from_static("max-age=86400"));
+            response
+        }
+        Err(e) => error_response(error_status(e.context("checking --block"))),
+    }
+}
+
+/// Turns errors of the concurrency limit and timeout middlewares into 503 responses
+async fn handle_overload(error: BoxError) -> impl IntoResponse {
+    let message = if error.is::<tower::timeout::error::Elapsed>() {
+        "request timed out".to_string()
+    } else if error.is::<tower::load_shed::error::Overloaded>() {
+        "too many concurrent requests".to_string()
+    } else {
+        format!("unhandled internal error: {:#}", error)
+    };
+    error_response((StatusCode::SERVICE_UNAVAILABLE, message))
+}
+
+/// Waits for indexation, and then looks up in substituters where the debuginfo of the
+/// executables reachable from profiles and gc roots is, for `--warm-debuginfo-lookups`.
+async fn warm_debuginfo_lookups(watcher: StoreWatcher, resolver: Resolver) {
+    start_indexation_and_wait(watcher, None).await;
+    let roots = match tokio::task::spawn_blocking(gc_roots).await {
+        Ok(roots) => roots,
+        Err(e) => {
+            tracing::warn!("listing gc roots: {:#}", e);
+            return;
+        }
+    };
+    let paths = match crate::nixdb::get_closure(&roots, 0).await {
+        Ok(paths) => paths,
+        Err(e) => {
+            tracing::warn!("reading closure of gc roots in nix db: {:#}", e);
+            return;
+        }
+    };
+    resolver.warm_debuginfo_lookups(&paths).await;
+}
+
+/// Logs when the cache was created and indexation last ran, to notice if it had stopped before
+/// a restart.
+fn log_events(events: &Events) {
+    let ago = |timestamp: Option<i64>| {
+        timestamp.map_or_else(
+            || "never".to_owned(),
+            |timestamp| format!("{} seconds ago", crate::db::now() - timestamp),
+        )
+    };
+    tracing::info!(
+        "cache created {}, indexation last started {} and last completed {}",
+        ago(events.created),
+        ago(events.index_start),
+        ago(events.index_end)
+    );
+}
+
+/// If option `-i` is specified, index and exit. Otherwise starts indexation and runs the
+/// debuginfod server.
+pub async fn run_server(args: Options) -> anyhow::Result<ExitCode> {
+    let cache = Cache::open().await.context("opening global cache")?;
+    match cache.get_events().await {
+        Ok(events) => log_events(&events),
+        Err(e) => tracing::warn!("{:#}", e),
+    }
+    let filter = IndexFilter::from_options(&args);
+    let watcher = if args.from_cache.is_empty() && !args.read_only {
+        Some(StoreWatcher::new(cache.clone(), filter.clone()))
+    } else {
+        None
+    };
+    if args.index_only {
+        let Some(watcher) = watcher else {
+            anyhow::bail!(
+                "--index-only requires a local nix store, not --from-cache nor --read-only"
+            );
+        };
+        watcher.index_cycle().await?;
+        Ok(ExitCode::SUCCESS)
+    } else {
+        // before substituters create temporary files there
+        crate::db::clean_temp_dir()
+            .await
+            .context("cleaning temporary directory")
+            .or_warn();
+        let resolver = Resolver::from_options(cache.clone(), &args).await?;
+        let watcher =
+            match (watcher, args.eager_debuginfo_mb) {
+                (Some(watcher), Some(mb)) => Some(watcher.with_eager_realiser(
+                    EagerRealiser::start(resolver.clone(), cache.clone(), mb * MB),
+                )),
+                (watcher, _) => watcher,
+            };
+        if let Some(watcher) = &watcher {
+            watcher.watch_store();
+        }
+        if !args.channel_index.is_empty() {
+            let http = crate::channel::http_client(&args).await?;
+            crate::channel::watch_channel_indices(cache.clone(), http, args.channel_index.clone());
+        }
+        let extracted_sources = crate::db::cache_dir()
+            .map(|dir| dir.join("sources"))
+            .and_then(|dir| {
+                std::fs::create_dir_all(&dir)
+                    .with_context(|| format!("creating directory {}", dir.display()))?;
+                Ok(dir)
+            });
+        let extracted_sources = match extracted_sources {
+            Ok(dir) => Some(dir),
+            Err(e) => {
+                tracing::warn!("cannot cache source files extracted from archives: {:#}", e);
+                None
+            }
+        };
+        let gdb_indexer = if args.generate_gdb_index {
+            let dir = crate::db::cache_dir()
+                .map(|dir| dir.join("gdb-index"))
+                .and_then(|dir| {
+                    std::fs::create_dir_all(&dir)
+                        .with_context(|| format!("creating directory {}", dir.display()))?;
+                    Ok(dir)
+                });
+            match dir {
+                Ok(dir) => Some(GdbIndexer::new(cache.clone(), dir)),
+                Err(e) => {
+                    tracing::warn!("cannot generate gdb indices: {:#}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        if args.warm_debuginfo_lookups {
+            match &watcher {
+                Some(watcher) => {
+                    tokio::spawn(warm_debuginfo_lookups(watcher.clone(), resolver.clone()));
+                }
+                None => tracing::warn!("--warm-debuginfo-lookups requires a local nix store"),
+            }
+        }
+        let metrics = Arc::new(Metrics::default());
+        let state = ServerState {
+            watcher,
+            cache,
+            resolver,
+            metrics: metrics.clone(),
+            extracted_sources,
+            gdb_indexer,
+            indexing_timeout: Duration::from_secs(args.indexing_timeout),
+            while_indexing: args.while_indexing,
+            verify: args.verify,
+            verified: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            serve_minidebuginfo: args.serve_minidebuginfo,
+            derivers: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            started: Instant::now(),
+        };
+        let mut app = routes(state.clone(), &args, "");
+        if !args.fallback.is_empty() {
+            let http = crate::channel::http_client(&args).await?;
+            let fallbacks = Fallbacks::new(http, &args.fallback)?;
+            app = app.layer(axum::middleware::from_fn_with_state(
+                Arc::new(fallbacks),
+                fall_back,
+            ));
+        }
+        let mut names = HashSet::new();
+        for store in &args.store {
+            anyhow::ensure!(
+                names.insert(store.name.clone()),
+                "store {} specified twice",
+                store.name
+            );
+            let cache = Cache::open_store(&store.name)
+                .await
+                .with_context(|| format!("opening cache of store {}", store.name))?;
+            let watcher = StoreWatcher::new_foreign(cache.clone(), filter.clone(), store);
+            watcher.watch_store();
+            // files of other stores cannot be realised or added to the local store
+            let resolver = Resolver::new(cache.clone(), vec![], None, SourceQuota::new(None, None));
+            let state = ServerState {
+                watcher: Some(watcher),
+                cache,
+                resolver,
+                verify: false,
+                verified: Arc::new(std::sync::Mutex::new(HashSet::new())),
+                derivers: Arc::new(std::sync::Mutex::new(HashMap::new())),
+                ..state.clone()
+            };
+            let prefix = format!("/store/{}", store.name);
+            app = app.nest(&prefix, routes(state, &args, &prefix));
+        }
+        let policy = ClientPolicy::from_options(&args);
+        if policy.log || !policy.timeouts.is_empty() {
+            app = app.layer(axum::middleware::from_fn_with_state(
+                Arc::new(policy),
+                apply_client_policy,
+            ));
+        }
+        let quotas = ClientQuotas::new(
+            state.cache.clone(),
+            args.max_requests_per_minute,
+            args.max_mb_per_hour,
+        );
+        if let Some(quotas) = quotas {
+            app = app.layer(axum::middleware::from_fn_with_state(
+                Arc::new(quotas),
+                enforce_quotas,
+            ));
+        }
+        if args.keep_alive_after > 0 {
+            app = app.layer(axum::middleware::from_fn_with_state(
+                Duration::from_secs(args.keep_alive_after),
+                crate::keepalive::keep_alive,
+            ));
+        }
+        if args.debug_tasks {
+            crate::diagnostics::enable();
+            app = app.layer(axum::middleware::from_fn(
+                crate::diagnostics::track_requests,
+            ));
+        }
+        let app = app
+            .layer(axum::middleware::map_response(nosniff))
+            .layer(axum::middleware::from_fn_with_state(
+                metrics,
+                count_requests,
+            ))
+            .layer(tower_http::trace::TraceLayer::new_for_http());
+        let app = ServiceBuilder::new()
+            .map_request(move |request| route_virtual_host(&names, request))
+            .service(app);
+        let listener = tokio::net::TcpListener::bind(&args.listen_address)
+            .await
+            .with_context(|| format!("opening listen socket on {}", &args.listen_address))?;
+        crate::notify::ready(&format!("listening on {}", &args.listen_address));
+        crate::notify::start_watchdog();
+        axum::serve::serve(
+            listener,
+            axum::ServiceExt::into_make_service_with_connect_info::<std::net::SocketAddr>(app),
+        )
+        .await?;
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+/// The routes of the server for one store, served under `prefix`
+fn routes(state: ServerState, args: &Options, prefix: &str) -> Router {
+    // each endpoint gets its own concurrency limit
+    let limit = |path: &str, route: MethodRouter<ServerState>| {
+        let semaphore = Arc::new(Semaphore::new(args.max_concurrent_requests));
+        crate::diagnostics::register_semaphore(
+            format!("requests to {prefix}{path}"),
+            &semaphore,
+            args.max_concurrent_requests,
+        );
+        route.layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(handle_overload))
+                .load_shed()
+                .layer(GlobalConcurrencyLimitLayer::with_semaphore(semaphore))
+                .timeout(ClientPolicy::from_options(args).max_timeout()),
+        )
+    };
+    let router = Router::new()
+        .route("/", get(get_index))
+        .route(
+            "/buildid/:buildid/section/:section",
+            limit("/buildid/:buildid/section/:section", get(get_section)),
+        )
+        .route("/buildid/:buildid/status", get(get_status))
+        .route(
+            "/buildid/:buildid/metadata",
+            limit("/buildid/:buildid/metadata", get(get_metadata)),
+        )
+        .route(
+            "/buildid/:buildid/source/*path",
+            limit("/buildid/:buildid/source/*path", get(get_source)),
+        )
+        .route(
+            "/buildid/:buildid/source-index",
+            limit("/buildid/:buildid/source-index", get(get_source_index)),
+        )
+        .route(
+            "/buildid/:buildid/executable",
+            limit("/buildid/:buildid/executable", get(get_executable)),
+        )
+        .route(
+            "/buildid/:buildid/debuginfo",
+            limit("/buildid/:buildid/debuginfo", get(get_debuginfo)),
+        )
+        .route(
+            "/buildid/:buildid/dwo/*name",
+            limit("/buildid/:buildid/dwo/*name", get(get_split_dwarf)),
+        )
+        .route("/path/*request", limit("/path/*request", get(get_by_path)))
+        .route("/packages", limit("/packages", get(get_packages)))
+        .route("/missing", get(get_missing))
+        .route("/prefetch", limit("/prefetch", post(post_prefetch)))
+        .route("/index", limit("/index", post(post_index)))
+        .route("/metrics", get(get_metrics))
+        .route("/healthz", get(get_healthz))
+        .route("/status", get(get_server_status))
+        .route("/readyz", get(get_readyz))
+        .route("/webapi", get(get_webapi));
+    let router = if args.debug_tasks {
+        router.route("/debug/tasks", get(get_debug_tasks))
+    } else {
+        router
+    };
+    let router = if args.allow_register {
+        router.route("/register", post(post_register))
+    } else {
+        router
+    };
+    let router = if args.allow_invalidate {
+        router.route("/buildid/:buildid", delete(delete_buildid))
+    } else {
+        router
+    };
+    let router = if args.browse_sources {
+        router
+            .route(
+                "/buildid/:buildid/tree",
+                get(|| async { axum::response::Redirect::permanent("tree/") }),
+            )
+            .route(
+                "/buildid/:buildid/tree/",
+                limit("/buildid/:buildid/tree/", get(get_source_tree_root)),
+            )
+            .route(
+                "/buildid/:buildid/tree/*path",
+                limit("/buildid/:buildid/tree/*path", get(get_source_tree)),
+            )
+    } else {
+        router
+    };
+    let router = if args.block.is_empty() {
+        router
+    } else {
+        router.layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            reject_blocked,
+        ))
+    };
+    router
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            add_index_lag,
+        ))
+        .with_state(state)
+}
+
+/// Serves requests for virtual host `NAME.*` as requests for `/store/NAME`, when `NAME` is one of
+/// the stores specified with `--store`.
+fn route_virtual_host<B>(names: &HashSet<String>, mut request: Request<B>) -> Request<B> {
+    let name = match request
+        .headers()
+        .get(http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.split(['.', ':']).next())
+    {
+        Some(name) if names.contains(name) => name.to_owned(),
+        _ => return request,
+    };
+    if request.uri().path().starts_with("/store/") {
+        return request;
+    }
+    let path_and_query = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    let rest = path_and_query.strip_prefix('/').unwrap_or(path_and_query);
+    let uri = if rest.is_empty() || rest.starts_with('?') {
+        format!("/store/{name}{rest}")
+    } else {
+        format!("/store/{name}/{rest}")
+    };
+    match uri.parse() {
+        Ok(uri) => *request.uri_mut() = uri,
+        Err(e) => tracing::warn!("cannot route {} to store {}: {}", uri, name, e),
+    }
+    request
+}
+
+#[test]
+fn test_route_virtual_host() {
+    let names: HashSet<String> = ["alice".to_owned()].into_iter().collect();
+    let route = |host: &str, uri: &str| {
+        let request = Request::builder()
+            .uri(uri)
+            .header(http::header::HOST, host)
+            .body(())
+            .unwrap();
+        route_virtual_host(&names, request).uri().to_string()
+    };
+    assert_eq!(
+        route("alice.example.org:1949", "/buildid/ab/debuginfo"),
+        "/store/alice/buildid/ab/debuginfo"
+    );
+    assert_eq!(route("alice", "/?q=ab"), "/store/alice?q=ab");
+    assert_eq!(
+        route("bob.example.org", "/buildid/ab/debuginfo"),
+        "/buildid/ab/debuginfo"
+    );
+    assert_eq!(
+        route("alice", "/store/alice/metrics"),
+        "/store/alice/metrics"
+    );
 }
@@ -0,0 +1,158 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! The TOML configuration file given with `--config`.
+//!
+//! Its keys are the long names of the command line options, and apply unless the same option is
+//! given on the command line:
+//!
+//! ```toml
+//! listen-address = "[::]:1949"
+//! max-concurrent-requests = 32
+//! substituter = [ "https://cache.nixos.org" ]
+//! index-deny = [ "path:-texlive-" ]
+//! generate-gdb-index = true
+//! ```
+//!
+//! Flags take booleans, options which can be repeated take arrays, and `verbose` and `quiet`
+//! take the number of times they would be repeated.
+
+use std::ffi::OsString;
+
+use anyhow::Context;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, Parser};
+use serde_json::Value;
+
+use crate::Options;
+
+/// Parses the command line and the configuration file it specifies, if any.
+///
+/// Exits on an invalid command line, like [Parser::parse].
+pub fn parse_options() -> anyhow::Result<Options> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let options = Options::parse_from(&args);
+    let Some(path) = &options.config else {
+        return Ok(options);
+    };
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading configuration file {}", path.display()))?;
+    let matches = Options::command().get_matches_from(&args);
+    let from_file = config_args(&text, &matches)
+        .with_context(|| format!("in configuration file {}", path.display()))?;
+    let mut merged = args;
+    // after the name of the program, and before the subcommand, if any
+    merged.splice(1..1, from_file.into_iter().map(OsString::from));
+    Options::try_parse_from(&merged)
+        .with_context(|| format!("invalid option in configuration file {}", path.display()))
+}
+
+/// Turns the configuration file `text` into command line arguments, skipping the options
+/// already given in `matches`.
+fn config_args(text: &str, matches: &ArgMatches) -> anyhow::Result<Vec<String>> {
+    let table: serde_json::Map<String, Value> =
+        basic_toml::from_str(text).context("parsing TOML")?;
+    let command = Options::command();
+    let mut args = Vec::new();
+    for (key, value) in &table {
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key) && arg.get_id() != "config")
+            .with_context(|| format!("unknown option {}", key))?;
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let invalid = || format!("invalid value for {}: {}", key, value);
+        match (arg.get_action(), value) {
+            (ArgAction::SetTrue, Value::Bool(true)) => args.push(format!("--{}", key)),
+            (ArgAction::SetTrue, Value::Bool(false)) => (),
+            (ArgAction::Count, Value::Number(n)) => {
+                let n = n.as_u64().with_context(invalid)?;
+                args.extend((0..n).map(|_| format!("--{}", key)));
+            }
+            (ArgAction::Append, Value::Array(values)) => {
+                for value in values {
+                    args.push(option_arg(key, value).with_context(invalid)?);
+                }
+            }
+            (ArgAction::Set | ArgAction::Append, value) => {
+                args.push(option_arg(key, value).with_context(invalid)?)
+            }
+            _ => anyhow::bail!(invalid()),
+        }
+    }
+    Ok(args)
+}
+
+/// The argument setting option `key` to `value`, if it is a string or number
+fn option_arg(key: &str, value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(format!("--{}={}", key, s)),
+        Value::Number(n) => Some(format!("--{}={}", key, n)),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_config_args() {
+    let text = r#"
+        listen-address = "0.0.0.0:1949"
+        max-concurrent-requests = 32
+        index-deny = [ "path:-texlive-", "package:nix" ]
+        generate-gdb-index = true
+        verify = false
+        verbose = 2
+        substituter = [ "https://cache.nixos.org" ]
+        cache-dir = "/var/cache/debuginfod"
+    "#;
+    let command_line = [
+        "nixseparatedebuginfod",
+        "--max-concurrent-requests",
+        "8",
+        "--substituter",
+        "https://example.org",
+        "find",
+        "debuginfo",
+        "abcd",
+    ];
+    let matches = Options::command().get_matches_from(command_line);
+    let mut merged: Vec<String> = command_line.iter().map(|s| s.to_string()).collect();
+    merged.splice(1..1, config_args(text, &matches).unwrap());
+    let options = Options::try_parse_from(&merged).unwrap();
+    assert_eq!(options.listen_address.to_string(), "0.0.0.0:1949");
+    // the command line takes precedence
+    assert_eq!(options.max_concurrent_requests, 8);
+    assert_eq!(options.substituter, vec!["https://example.org".to_owned()]);
+    assert_eq!(options.index_deny.len(), 2);
+    assert!(options.generate_gdb_index);
+    assert!(!options.verify);
+    assert_eq!(options.verbose, 2);
+    assert_eq!(
+        options.cache_dir.as_deref(),
+        Some(std::path::Path::new("/var/cache/debuginfod"))
+    );
+    assert!(matches!(options.command, Some(crate::Command::Find(_))));
+
+    assert!(config_args("no-such-option = 1", &matches).is_err());
+    assert!(config_args("verify = \"yes\"", &matches).is_err());
+    assert!(config_args("config = \"/etc/other.toml\"", &matches).is_err());
+    assert!(config_args("listen-address = [", &matches).is_err());
+}
+
+#[test]
+fn test_read_only_conflicts() {
+    let command_line = ["nixseparatedebuginfod", "--read-only"];
+    let matches = Options::command().get_matches_from(command_line);
+    let parse = |text: &str| {
+        let mut merged: Vec<String> = command_line.iter().map(|s| s.to_string()).collect();
+        merged.splice(1..1, config_args(text, &matches).unwrap());
+        Options::try_parse_from(&merged)
+    };
+    assert!(parse("generate-gdb-index = true").unwrap().read_only);
+    assert!(parse("substituter = [ \"https://cache.nixos.org\" ]").is_err());
+    assert!(parse("read-nix-db = true").is_err());
+    assert!(parse("store-mounted-readonly = true").is_err());
+    assert!(parse("allow-register = true").is_err());
+    assert!(parse("allow-invalidate = true").is_err());
+}
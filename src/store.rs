@@ -4,16 +4,25 @@
 
 //! Lower level utilities to query the store.
 
-use crate::db::Entry;
+use crate::db::{encode_path, Entry, Indexed, SourcePrefix, SourceRoots, SplitDwarf};
+use crate::drv::Derivation;
+use crate::filter::{package_name, IndexFilter};
 use crate::log::ResultExt;
+use crate::nixdb::PathInfo;
 use anyhow::Context;
+use object::elf;
+use object::read::elf::{FileHeader, ProgramHeader, SectionHeader};
 use object::read::Object;
+use object::Endianness;
 use once_cell::unsync::Lazy;
 use std::{
+    collections::{HashMap, HashSet},
     ffi::{OsStr, OsString},
+    os::unix::fs::MetadataExt,
     os::unix::prelude::{OsStrExt, OsStringExt},
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     sync::atomic::{AtomicBool, Ordering},
+    sync::{Arc, Mutex},
 };
 use tokio::sync::mpsc::Sender;
 
@@ -22,8 +31,268 @@ use tokio::sync::mpsc::Sender;
 /// Set by [detect_nix].
 static NIX_STORE_QUERY_VALID_DERIVERS_SUPPORTED: AtomicBool = AtomicBool::new(false);
 
+/// Whether to read derivers and outputs from the nix db instead of running `nix-store --query`
+///
+/// Set by [read_nix_db].
+static READ_NIX_DB: AtomicBool = AtomicBool::new(false);
+
+/// Whether nix must not be used at all, with `--read-only`.
+///
+/// Set by [read_only].
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Whether there is no nix to run, with `--store-mounted-readonly`.
+///
+/// Set by [store_mounted_readonly].
+static STORE_MOUNTED_READONLY: AtomicBool = AtomicBool::new(false);
+
+/// Why the last `nix-store --realise` of each store path failed, see [realise_failure]
+static REALISE_FAILURES: once_cell::sync::Lazy<Mutex<HashMap<PathBuf, RealiseFailure>>> =
+    once_cell::sync::Lazy::new(Default::default);
+
+/// How many times each store path is being realised right now, see [is_being_realised]
+static REALISING: once_cell::sync::Lazy<Mutex<HashMap<PathBuf, usize>>> =
+    once_cell::sync::Lazy::new(Default::default);
+
+/// How many failures of `nix-store --realise` are remembered
+const MAX_REALISE_FAILURES: usize = 1000;
+
+/// The substituters and netrc file of `nix-store --realise`, see [set_realise_options]
+static REALISE_OPTIONS: once_cell::sync::OnceCell<RealiseOptions> =
+    once_cell::sync::OnceCell::new();
+
+/// The network configuration of `nix-store --realise`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RealiseOptions {
+    /// urls of the only substituters to use
+    pub substituters: Vec<String>,
+    /// the `netrc-file` nix setting
+    pub netrc_file: Option<String>,
+}
+
+impl RealiseOptions {
+    /// Arguments of nix commands applying these options
+    fn args(&self) -> Vec<String> {
+        let mut args = vec![
+            "--option".to_owned(),
+            "substituters".to_owned(),
+            self.substituters.join(" "),
+        ];
+        if let Some(netrc_file) = &self.netrc_file {
+            args.extend([
+                "--option".to_owned(),
+                "netrc-file".to_owned(),
+                netrc_file.clone(),
+            ]);
+        }
+        args
+    }
+}
+
+#[test]
+fn test_realise_options() {
+    let options = RealiseOptions {
+        substituters: vec![
+            "https://cache.example.com".to_owned(),
+            "https://cache.nixos.org".to_owned(),
+        ],
+        netrc_file: Some("/etc/nix/netrc".to_owned()),
+    };
+    assert_eq!(
+        options.args(),
+        [
+            "--option",
+            "substituters",
+            "https://cache.example.com https://cache.nixos.org",
+            "--option",
+            "netrc-file",
+            "/etc/nix/netrc"
+        ]
+    );
+}
+
+/// Makes `nix-store --realise` fetch from these substituters only, with this netrc file, instead
+/// of following nix.conf, so that the options of this program control all downloads.
+///
+/// Untrusted users can only select substituters which nix.conf lists in `substituters` or
+/// `trusted-substituters`.
+pub fn set_realise_options(options: RealiseOptions) {
+    if REALISE_OPTIONS.set(options).is_err() {
+        tracing::warn!("substituters of nix-store --realise set twice");
+    }
+}
+
+/// The arguments of `nix-store --realise` set by [set_realise_options]
+fn realise_args() -> Vec<String> {
+    REALISE_OPTIONS
+        .get()
+        .map(RealiseOptions::args)
+        .unwrap_or_default()
+}
+
+/// Read derivers and outputs directly from the nix db instead of asking nix-daemon, which
+/// refuses some queries from untrusted users.
+///
+/// Should be called on startup, before [detect_nix].
+pub fn read_nix_db() {
+    READ_NIX_DB.store(true, Ordering::SeqCst);
+}
+
+/// Never realise store paths nor ask nix about them, so that only files already in the store are
+/// served, with `--read-only`.
+///
+/// Should be called on startup.
+pub fn read_only() {
+    READ_ONLY.store(true, Ordering::SeqCst);
+}
+
+/// Whether [read_only] was called
+pub fn is_read_only() -> bool {
+    READ_ONLY.load(Ordering::SeqCst)
+}
+
+/// Never run nix, because `/nix/store` and the nix db are bind-mounted read-only in a container
+/// without nix binaries, with `--store-mounted-readonly`. Derivers and outputs are read from the
+/// nix db as with [read_nix_db], derivations are parsed directly, and store paths are never
+/// realised.
+///
+/// Should be called on startup, before [detect_nix].
+pub fn store_mounted_readonly() {
+    STORE_MOUNTED_READONLY.store(true, Ordering::SeqCst);
+    read_nix_db();
+}
+
+/// Whether [store_mounted_readonly] was called
+pub fn is_store_mounted_readonly() -> bool {
+    STORE_MOUNTED_READONLY.load(Ordering::SeqCst)
+}
+
+/// Runs a query of the [crate::nixdb] module from synchronous code.
+///
+/// Must be called from a thread of the tokio runtime which may block, like in
+/// [tokio::task::spawn_blocking].
+fn block_on_nix_db<T>(
+    query: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    tokio::runtime::Handle::try_current()
+        .context("querying nix db outside of tokio runtime")?
+        .block_on(query)
+}
+
 const NIX_STORE: &str = "/nix/store";
 
+/// Why `nix-store --realise` failed
+#[derive(Debug, Clone)]
+struct RealiseFailure {
+    message: String,
+    /// whether retrying later may succeed, see [is_transient_failure]
+    transient: bool,
+}
+
+/// Whether this error of `nix-store --realise` looks like retrying later may succeed, like
+/// network errors, as opposed to the store path not being available from any substituter.
+fn is_transient_failure(error: &str) -> bool {
+    let error = error.to_ascii_lowercase();
+    let permanent = [
+        "don't know how to build",
+        "http error 404",
+        "http error 410",
+    ];
+    let transient = [
+        "unable to download",
+        "timeout",
+        "timed out",
+        "resolve host",
+        "connect",
+        "http error 5",
+        "http error 429",
+        "temporar",
+        "no space left",
+    ];
+    !permanent.iter().any(|pattern| error.contains(pattern))
+        && transient.iter().any(|pattern| error.contains(pattern))
+}
+
+#[test]
+fn test_is_transient_failure() {
+    assert!(is_transient_failure(
+        "error: unable to download 'https://cache.nixos.org/nar/x.nar.xz': Couldn't resolve host name (6)"
+    ));
+    assert!(is_transient_failure(
+        "error: unable to download 'https://cache.nixos.org/x.narinfo': HTTP error 503"
+    ));
+    assert!(!is_transient_failure(
+        "error: unable to download 'https://cache.nixos.org/nar/x.nar.xz': HTTP error 404"
+    ));
+    assert!(!is_transient_failure(
+        "error: don't know how to build these paths:\n  /nix/store/xxxx-foo-debug"
+    ));
+}
+
+/// Counts a realise of this path in [REALISING] while it lives
+struct Realising<'a>(&'a Path);
+
+impl<'a> Realising<'a> {
+    fn new(path: &'a Path) -> Self {
+        *REALISING
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_default() += 1;
+        Realising(path)
+    }
+}
+
+impl Drop for Realising<'_> {
+    fn drop(&mut self) {
+        let mut realising = REALISING.lock().unwrap();
+        if let Some(count) = realising.get_mut(self.0) {
+            *count -= 1;
+            if *count == 0 {
+                realising.remove(self.0);
+            }
+        }
+    }
+}
+
+/// Whether [realise] is currently running for this path
+pub fn is_being_realised(path: &Path) -> bool {
+    REALISING.lock().unwrap().contains_key(path)
+}
+
+/// Fails if `path`, which does not exist, cannot be realised, for example because `read_only`,
+/// which is [is_read_only] outside tests, forbids it.
+fn check_realisable(path: &Path, read_only: bool) -> anyhow::Result<()> {
+    if get_store_path(path).is_none() {
+        anyhow::bail!("{} does not exist and is not in the store", path.display());
+    }
+    if crate::filter::is_blocked_path(path) {
+        anyhow::bail!("{} is blocked by --block", path.display());
+    }
+    if read_only {
+        anyhow::bail!(
+            "{} is not in the store, and --read-only forbids realising it",
+            path.display()
+        );
+    }
+    if is_store_mounted_readonly() {
+        anyhow::bail!(
+            "{} is not in the store, and cannot be realised without nix with --store-mounted-readonly",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_check_realisable() {
+    let path = Path::new("/nix/store/9krlzvny65gdc8s7kpb6lkx8cd02c25b-hello-2.12.1");
+    check_realisable(path, false).unwrap();
+    let error = check_realisable(path, true).unwrap_err();
+    assert!(format!("{error:#}").contains("--read-only"));
+    assert!(check_realisable(Path::new("/etc/shadow"), false).is_err());
+}
+
 /// attempts have this store path exist in the store
 ///
 /// if the path already exists, do nothing
@@ -34,14 +303,96 @@ pub async fn realise(path: &Path) -> anyhow::Result<()> {
     if metadata(path).await.is_ok() {
         return Ok(());
     };
+    check_realisable(path, is_read_only())?;
     let mut command = Command::new("nix-store");
-    command.arg("--realise").arg(path);
+    command.arg("--realise").arg(path).args(realise_args());
     tracing::info!("Running {:?}", &command);
-    let _ = command.status().await;
-    if metadata(path).await.is_ok() {
+    crate::keepalive::realise_started();
+    let realising = Realising::new(path);
+    let output = crate::subprocess::output(&mut command)
+        .await
+        .with_context(|| format!("running {:?}", command));
+    drop(realising);
+    let output = output?;
+    let checked = crate::subprocess::check(&command, &output);
+    let realised = metadata(path).await.is_ok();
+    let mut failures = REALISE_FAILURES.lock().unwrap();
+    if realised {
+        failures.remove(path);
         return Ok(());
     };
-    anyhow::bail!("nix-store --realise {} failed", path.display());
+    let error = match checked {
+        Err(e) => e,
+        Ok(()) => anyhow::anyhow!("nix-store --realise {} did not realise it", path.display()),
+    };
+    if failures.len() >= MAX_REALISE_FAILURES {
+        failures.clear();
+    }
+    let stderr = error
+        .downcast_ref::<crate::subprocess::CommandFailed>()
+        .map_or("", |failed| failed.stderr.as_str());
+    let failure = RealiseFailure {
+        transient: is_transient_failure(stderr),
+        message: format!("{:#}", error),
+    };
+    failures.insert(path.to_path_buf(), failure);
+    Err(error)
+}
+
+/// Why the last attempt to [realise] `path` failed, if it failed.
+///
+/// The stderr of `nix-store --realise` is only logged, not included.
+pub fn realise_failure(path: &Path) -> Option<String> {
+    let failures = REALISE_FAILURES.lock().unwrap();
+    failures.get(path).map(|failure| failure.message.clone())
+}
+
+/// Whether the last attempt to [realise] `path` failed in a way that retrying later may fix,
+/// see [is_transient_failure].
+pub fn realise_failed_transiently(path: &Path) -> bool {
+    let failures = REALISE_FAILURES.lock().unwrap();
+    failures.get(path).is_some_and(|failure| failure.transient)
+}
+
+/// Computes the hash of the nar serialisation of this store path, in the format of the nix db:
+/// `sha256:` followed by the hash in base16.
+pub async fn get_nar_hash(storepath: &Path) -> anyhow::Result<String> {
+    use sha2::Digest;
+    use tokio::io::AsyncReadExt;
+    let mut cmd = tokio::process::Command::new("nix-store");
+    cmd.arg("--dump").arg(storepath);
+    cmd.stdout(std::process::Stdio::piped());
+    cmd.stderr(std::process::Stdio::piped());
+    tracing::debug!("Running {:?}", &cmd);
+    let mut child = cmd.spawn().with_context(|| format!("running {:?}", cmd))?;
+    let mut stdout = child.stdout.take().context("stdout of nix-store --dump")?;
+    let stderr = child.stderr.take().context("stderr of nix-store --dump")?;
+    let hash = async {
+        let mut hasher = sha2::Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let n = stdout.read(&mut buffer).await?;
+            if n == 0 {
+                return Ok(hasher.finalize());
+            }
+            hasher.update(&buffer[..n]);
+        }
+    };
+    // concurrently, so that nix-store does not block writing to stderr
+    let (hash, stderr): (std::io::Result<_>, _) =
+        tokio::join!(hash, crate::subprocess::stderr_tail(stderr));
+    let status = child
+        .wait()
+        .await
+        .with_context(|| format!("waiting for {:?}", cmd))?;
+    let output = std::process::Output {
+        status,
+        stdout: Vec::new(),
+        stderr: stderr.with_context(|| format!("reading stderr of {:?}", cmd))?,
+    };
+    crate::subprocess::check(&cmd, &output)?;
+    let hash = hash.with_context(|| format!("reading output of {:?}", cmd))?;
+    Ok(format!("sha256:{}", base16::encode_lower(&hash)))
 }
 
 /// downloads a .drv file if necessary
@@ -54,6 +405,18 @@ fn download_drv(path: &Path) -> anyhow::Result<()> {
     if metadata(path).is_ok() {
         return Ok(());
     };
+    if is_read_only() {
+        anyhow::bail!(
+            "{} is not in the store, and --read-only forbids realising it",
+            path.display()
+        );
+    }
+    if is_store_mounted_readonly() {
+        anyhow::bail!(
+            "{} is not in the store, and cannot be realised without nix with --store-mounted-readonly",
+            path.display()
+        );
+    }
     let mut command = Command::new("nix-store");
     command.arg("--realise");
     // nix-store --realise foo.drv downloads the drv and its default output
@@ -61,17 +424,44 @@ fn download_drv(path: &Path) -> anyhow::Result<()> {
     // as the narinfo does not give the list of outputs, nix has to download the drv first, and
     // then fails to download the output
     command.arg(path.with_extension("drv!outputdoesn0tex1st"));
+    command.args(realise_args());
     tracing::info!("Running {:?}", &command);
-    let _ = command.status();
+    let output = command
+        .output()
+        .with_context(|| format!("running {:?}", command))?;
+    // this is expected to fail, see above
+    let checked = crate::subprocess::check(&command, &output);
     if metadata(path).is_ok() {
         return Ok(());
     };
-    anyhow::bail!("nix-store --realise {} failed", path.display());
+    checked?;
+    anyhow::bail!("nix-store --realise {} did not realise it", path.display());
 }
 
 /// Walks a store path and attempts to register everything that has a buildid in it.
 /// If offline is false, may try to download the .drv file from cache.
-pub fn index_store_path(storepath: &Path, sendto: Sender<Entry>, offline: bool) {
+///
+/// `info` is what the nix db knows about the deriver of this path, if it was read in advance.
+/// Otherwise it is queried with `nix-store`.
+///
+/// Store paths not allowed by `filter` are skipped.
+///
+/// Files with several hard links already in `parsed` are not parsed again.
+///
+/// `pause` is called every [FILES_BETWEEN_PAUSES] files, so that indexation of a huge store path
+/// can let others progress.
+///
+/// Returns false if the store path was deleted during indexation, for example by the garbage
+/// collector. What was sent about it is then retracted with [Indexed::Deleted].
+pub fn index_store_path(
+    storepath: &Path,
+    sendto: Sender<Indexed>,
+    offline: bool,
+    info: Option<PathInfo>,
+    filter: &IndexFilter,
+    parsed: &ParsedInodes,
+    pause: &mut dyn FnMut(),
+) -> bool {
     let span = tracing::info_span!("indexing", storepath=%storepath.display()).entered();
     if storepath
         .file_name()
@@ -79,18 +469,73 @@ pub fn index_store_path(storepath: &Path, sendto: Sender<Entry>, offline: bool)
         .as_bytes()
         .ends_with(b".drv")
     {
-        return;
+        return true;
     }
-    if !storepath.is_dir() {
-        return;
+    let vanished = storepath.is_dir()
+        && index_store_directory(storepath, &sendto, offline, info, filter, parsed, pause);
+    let deleted = vanished
+        || matches!(storepath.symlink_metadata(), Err(e) if e.kind() == std::io::ErrorKind::NotFound);
+    if deleted {
+        tracing::warn!("{} was deleted during indexation", storepath.display());
+        sendto
+            .blocking_send(Indexed::Deleted(encode_path(storepath)))
+            .context("sending deleted store path failed")
+            .or_warn();
+    }
+    drop(span);
+    !deleted
+}
+
+/// Indexes a store path which is a directory, like [index_store_path].
+///
+/// Returns true if some of its files disappeared while it was indexed.
+fn index_store_directory(
+    storepath: &Path,
+    sendto: &Sender<Indexed>,
+    offline: bool,
+    info: Option<PathInfo>,
+    filter: &IndexFilter,
+    parsed: &ParsedInodes,
+    pause: &mut dyn FnMut(),
+) -> bool {
+    // store paths are immutable, so a file which disappears is being garbage collected
+    let vanished = std::cell::Cell::new(false);
+    let known_outputs = info
+        .as_ref()
+        .and_then(|info| info.deriver_outputs.as_deref());
+    let deriver = Lazy::new(|| {
+        match info
+            .as_ref()
+            .map(|info| Ok(info.deriver.clone()))
+            .unwrap_or_else(|| get_deriver(storepath))
+        {
+            Err(e) => {
+                tracing::warn!("no deriver for {}: {:#}", storepath.display(), e);
+                None
+            }
+            Ok(deriver) => deriver,
+        }
+    });
+    if !filter.allows(storepath, || deriver.as_deref()) {
+        tracing::debug!("skipping {} as configured", storepath.display());
+        return false;
     }
-    let deriver_source = Lazy::new(|| match get_deriver(storepath) {
+    // debug outputs and source, when nix knows no deriver
+    let guessed = Lazy::new(|| match get_references(storepath) {
         Err(e) => {
-            tracing::warn!("no deriver for {}: {:#}", storepath.display(), e);
-            (None, None)
+            tracing::info!(
+                "cannot guess debug outputs and source of {} from its references: {:#}",
+                storepath.display(),
+                e
+            );
+            (Vec::new(), None)
         }
-        Ok(None) => (None, None),
-        Ok(Some(deriver)) => {
+        Ok(references) => guess_from_references(storepath, &references),
+    });
+    let deriver_source = Lazy::new(|| match &*deriver {
+        None => (None, Some(guessed.1.clone()), None),
+        Some(deriver) => {
+            let deriver = deriver.clone();
             if !offline && !deriver.is_file() {
                 download_drv(deriver.as_ref())
                     .with_context(|| {
@@ -102,38 +547,65 @@ pub fn index_store_path(storepath: &Path, sendto: Sender<Entry>, offline: bool)
                     })
                     .or_warn();
             }
-            if deriver.is_file() {
-                let source = match get_source(deriver.as_path()) {
-                    Err(e) => {
-                        tracing::info!(
-                            "no source for {} (deriver of {}): {:#}",
-                            deriver.display(),
-                            storepath.display(),
-                            e
-                        );
-                        None
-                    }
-                    Ok(s) => Some(s),
-                };
-                (Some(deriver), source)
-            } else {
-                (None, None)
+            if !deriver.is_file() {
+                // the references of a store path with a deriver are not worth a query: its
+                // debug outputs and source are not among them
+                return (None, None, None);
             }
+            // read once, instead of asking nix-store for each binding
+            let deriver = match Derivation::read(&deriver) {
+                Err(e) => {
+                    tracing::warn!("deriver of {}: {:#}", storepath.display(), e);
+                    return (None, None, None);
+                }
+                Ok(deriver) => deriver,
+            };
+            let source = match get_source(&deriver) {
+                Err(e) => {
+                    tracing::info!(
+                        "no source for {} (deriver of {}): {:#}",
+                        deriver.path.display(),
+                        storepath.display(),
+                        e
+                    );
+                    None
+                }
+                Ok(s) => Some(s),
+            };
+            let build_source = match get_build_source(&deriver, known_outputs) {
+                Err(e) => {
+                    tracing::info!(
+                        "no build directory for {} (deriver of {}): {:#}",
+                        deriver.path.display(),
+                        storepath.display(),
+                        e
+                    );
+                    None
+                }
+                Ok(s) => s,
+            };
+            (Some(deriver), source, build_source)
         }
     });
     let storepath_os: &OsStr = storepath.as_ref();
     if storepath_os.as_bytes().ends_with(b"-debug") {
+        for file in find_split_dwarf(storepath) {
+            sendto
+                .blocking_send(Indexed::SplitDwarf(file))
+                .context("sending split dwarf file failed")
+                .or_warn();
+        }
         let mut root = storepath.to_owned();
         root.push("lib");
         root.push("debug");
         root.push(".build-id");
         if !root.is_dir() {
-            return;
+            return false;
         };
         let readroot = match std::fs::read_dir(&root) {
             Err(e) => {
                 tracing::warn!("could not list {}: {:#}", root.display(), e);
-                return;
+                return is_not_found(&e);
             }
             Ok(r) => r,
         };
@@ -157,6 +629,7 @@ pub fn index_store_path(storepath: &Path, sendto: Sender<Entry>, offline: bool)
             let read_mid = match std::fs::read_dir(&mid_path) {
                 Err(e) => {
                     tracing::warn!("could not list {}: {:#}", mid_path.display(), e);
+                    vanished.set(vanished.get() || is_not_found(&e));
                     continue;
                 }
                 Ok(r) => r,
@@ -185,185 +658,786 @@ pub fn index_store_path(storepath: &Path, sendto: Sender<Entry>, offline: bool)
                     &mid_name,
                     &end_name[..(end_name.len() - ".debug".len())]
                 );
-                let (_, source) = &*deriver_source;
+                let (_, source, build_source) = &*deriver_source;
                 let entry = Entry {
-                    debuginfo: end.path().to_str().map(|s| s.to_owned()),
+                    debuginfo: Some(encode_path(&end.path())),
                     executable: None,
-                    source: source.as_ref().and_then(|path| {
-                        path.as_ref()
-                            .and_then(|path| path.to_str())
-                            .map(|s| s.to_owned())
-                    }),
+                    source: source
+                        .as_ref()
+                        .and_then(|path| path.as_deref().map(encode_path)),
+                    build_source: build_source.as_deref().map(encode_path),
+                    // not worth opening every debug file for, the executable has it
+                    architecture: None,
                     buildid,
                 };
                 sendto
-                    .blocking_send(entry)
+                    .blocking_send(Indexed::Build(entry))
                     .context("sending entry failed")
                     .or_warn();
             }
         }
     } else {
-        let debug_output = Lazy::new(|| {
-            let (deriver, _) = &*deriver_source;
-            match deriver {
-                None => None,
-                Some(deriver) => match get_debug_output(deriver.as_path()) {
-                    Ok(None) => None,
+        let debug_outputs = Lazy::new(|| {
+            let (usable_deriver, _, _) = &*deriver_source;
+            match usable_deriver {
+                None if deriver.is_none() => guessed.0.clone(),
+                None => Vec::new(),
+                Some(deriver) => match get_debug_outputs(deriver, known_outputs) {
                     Err(e) => {
                         tracing::warn!(
                             "could not determine if the deriver {} of {} has a debug output: {:#}",
+                            deriver.path.display(),
                             storepath.display(),
-                            deriver.display(),
                             e
                         );
-                        None
+                        Vec::new()
                     }
-                    Ok(Some(d)) => Some(d),
+                    Ok(d) => d,
                 },
             }
         });
-        for file in walkdir::WalkDir::new(storepath) {
-            let file = match file {
-                Err(_) => continue,
-                Ok(file) => file,
-            };
-            if !file.file_type().is_file() {
-                continue;
-            };
-            let path = file.path();
-            let buildid = match get_buildid(path) {
+        let register = |path: &Path, info: anyhow::Result<Option<ElfInfo>>| {
+            let ElfInfo {
+                buildid,
+                architecture,
+            } = match info {
                 Err(e) => {
                     tracing::info!("cannot get buildid of {}: {:#}", path.display(), e);
-                    continue;
+                    if e.chain()
+                        .any(|e| e.downcast_ref::<std::io::Error>().is_some_and(is_not_found))
+                    {
+                        vanished.set(true);
+                    }
+                    return;
                 }
-                Ok(Some(buildid)) => buildid,
-                Ok(None) => continue,
+                Ok(Some(info)) => info,
+                Ok(None) => return,
             };
-            let debuginfo = match &*debug_output {
-                None => None,
-                Some(storepath) => {
-                    let theoretical = debuginfo_path_for(&buildid, storepath.as_path());
-                    if storepath.is_dir() {
-                        // the store path is available, check the prediction
-                        if !theoretical.is_file() {
-                            tracing::warn!(
-                                "{} has buildid {}, and {} exists but not {}",
-                                path.display(),
-                                buildid,
-                                storepath.display(),
-                                theoretical.display()
-                            );
-                            None
-                        } else {
-                            Some(theoretical)
-                        }
-                    } else {
-                        Some(theoretical)
-                    }
+            let debuginfo = if debug_outputs.is_empty() {
+                None
+            } else {
+                let debuginfo = find_debuginfo_in_outputs(&buildid, debug_outputs.as_slice());
+                if debuginfo.is_none() {
+                    tracing::warn!(
+                        "{} has buildid {}, but none of the debug outputs {:?} contain it",
+                        path.display(),
+                        buildid,
+                        &*debug_outputs,
+                    );
                 }
+                debuginfo
             };
-            let (_, source) = &*deriver_source;
+            // the debug info is usually only in the debuginfo file
+            if let Some(prefix) = source_prefix_of(debuginfo.as_deref().unwrap_or(path)) {
+                let prefix = SourcePrefix {
+                    buildid: buildid.clone(),
+                    prefix,
+                };
+                sendto
+                    .blocking_send(Indexed::SourcePrefix(prefix))
+                    .context("sending source prefix failed")
+                    .or_warn();
+            }
+            let (_, source, build_source) = &*deriver_source;
             let entry = Entry {
                 buildid,
-                source: source.as_ref().and_then(|path| {
-                    path.as_ref()
-                        .and_then(|path| path.to_str())
-                        .map(|s| s.to_owned())
-                }),
-                executable: path.to_str().map(|s| s.to_owned()),
-                debuginfo: debuginfo.and_then(|path| path.to_str().map(|s| s.to_owned())),
+                source: source
+                    .as_ref()
+                    .and_then(|path| path.as_deref().map(encode_path)),
+                build_source: build_source.as_deref().map(encode_path),
+                executable: Some(encode_path(path)),
+                debuginfo: debuginfo.as_deref().map(encode_path),
+                architecture,
             };
             sendto
-                .blocking_send(entry)
+                .blocking_send(Indexed::Build(entry))
                 .context("sending entry failed")
                 .or_warn();
+        };
+        // files which may have a buildid, parsed in parallel by batches
+        let mut candidates = Vec::new();
+        for (i, file) in walk_store_path(storepath).enumerate() {
+            if i >= MAX_FILES_PER_STORE_PATH {
+                tracing::warn!(
+                    "{} has more than {} files, not indexing the rest",
+                    storepath.display(),
+                    MAX_FILES_PER_STORE_PATH
+                );
+                break;
+            }
+            if i % FILES_BETWEEN_PAUSES == FILES_BETWEEN_PAUSES - 1 {
+                for (path, info) in parsed.get_elf_infos(std::mem::take(&mut candidates)) {
+                    register(&path, info);
+                }
+                pause();
+            }
+            let file = match file {
+                Err(_) => continue,
+                Ok(file) => file,
+            };
+            if !file.file_type().is_file() || !may_be_executable(&file) {
+                continue;
+            };
+            let inode = match file.metadata() {
+                Ok(metadata) if metadata.nlink() > 1 => Some((metadata.dev(), metadata.ino())),
+                _ => None,
+            };
+            candidates.push((file.into_path(), inode));
         }
+        for (path, info) in parsed.get_elf_infos(candidates) {
+            register(&path, info);
+        }
+    }
+    if let Some((deriver, Some(source), _)) = Lazy::get(&deriver_source) {
+        send_extra_sources(deriver.as_ref(), source.as_deref(), sendto);
     }
-    drop(span)
+    vanished.get()
 }
 
-/// Return the path where separate debuginfo is to be found in a debug output for a buildid
-fn debuginfo_path_for(buildid: &str, debug_output: &Path) -> PathBuf {
-    let mut res = debug_output.to_path_buf();
-    res.push("lib");
-    res.push("debug");
-    res.push(".build-id");
-    res.push(&buildid[..2]);
-    res.push(format!("{}.debug", &buildid[2..]));
-    res
+fn is_not_found(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::NotFound
 }
 
-/// Obtains the original deriver of a store path.
-///
-/// Corresponds to `nix-store --query --deriver`
-///
-/// The store path must exist.
-fn get_original_deriver(storepath: &Path) -> anyhow::Result<Option<PathBuf>> {
-    let mut cmd = std::process::Command::new("nix-store");
-    cmd.arg("--query").arg("--deriver").arg(storepath);
-    tracing::debug!("Running {:?}", &cmd);
-    let out = cmd.output().with_context(|| format!("running {:?}", cmd))?;
-    if !out.status.success() {
-        anyhow::bail!("{:?} failed: {}", cmd, String::from_utf8_lossy(&out.stderr));
-    }
-    let n = out.stdout.len();
-    if n <= 1 || out.stdout[n - 1] != b'\n' {
-        anyhow::bail!(
-            "{:?} returned weird output: {}",
-            cmd,
-            String::from_utf8_lossy(&out.stderr)
-        );
-    }
-    let path = PathBuf::from(OsString::from_vec(out.stdout[..n - 1].to_owned()));
-    if path.as_path() == Path::new("unknown-deriver") {
-        return Ok(None);
+#[test]
+fn test_index_deleted_store_path() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let storepath = dir.path().join("aaaa-deleted");
+    let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+    let complete = index_store_path(
+        &storepath,
+        tx,
+        true,
+        None,
+        &IndexFilter::default(),
+        &ParsedInodes::default(),
+        &mut || (),
+    );
+    assert!(!complete);
+    match rx.try_recv() {
+        Ok(Indexed::Deleted(path)) => assert_eq!(Path::new(&path), storepath),
+        other => panic!("unexpected {:?}", other),
     }
-    if !path.is_absolute() {
-        // nix returns `unknown-deriver` when it does not know
-        anyhow::bail!("no deriver: {}", path.display());
-    };
-    Ok(Some(path))
+    // a store path which is a file is not deleted
+    std::fs::write(&storepath, "").unwrap();
+    let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+    assert!(index_store_path(
+        &storepath,
+        tx,
+        true,
+        None,
+        &IndexFilter::default(),
+        &ParsedInodes::default(),
+        &mut || (),
+    ));
+    assert!(rx.try_recv().is_err());
 }
 
-/// Obtains a set of local derivers for a store path.
-///
-/// Corresponds to `nix-store --query --valid-derivers`
-///
-/// The store path must exist.
+/// Number of threads parsing files, shared by all store paths indexed at the same time
+const MAX_PARSING_THREADS: usize = 4;
+
+/// Threads parsing the files of store paths with many libraries, shared by all indexation
+/// workers so that the number of threads does not grow with the number of workers.
+static PARSING_POOL: once_cell::sync::Lazy<Option<rayon::ThreadPool>> =
+    once_cell::sync::Lazy::new(|| {
+        let threads = std::thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(MAX_PARSING_THREADS);
+        match rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("elf-parser-{i}"))
+            .build()
+        {
+            Ok(pool) => Some(pool),
+            Err(e) => {
+                tracing::warn!("cannot start elf parsing threads: {:#}", e);
+                None
+            }
+        }
+    });
+
+/// Calls [get_elf_info] on all these files with a few threads, for store paths with many
+/// libraries like `llvm` or `qt`.
 ///
-/// Fails if nix version is < 2.18
-fn get_valid_derivers(storepath: &Path) -> anyhow::Result<Vec<PathBuf>> {
-    let mut cmd = std::process::Command::new("nix-store");
-    cmd.arg("--query").arg("--valid-derivers").arg(storepath);
-    tracing::debug!("Running {:?}", &cmd);
-    let out = cmd.output().with_context(|| format!("running {:?}", cmd))?;
-    if !out.status.success() {
-        anyhow::bail!("{:?} failed: {}", cmd, String::from_utf8_lossy(&out.stderr));
+/// The result is in the same order as `paths`.
+fn get_elf_infos(paths: Vec<PathBuf>) -> Vec<(PathBuf, anyhow::Result<Option<ElfInfo>>)> {
+    use rayon::prelude::*;
+    let parse = |path: PathBuf| {
+        let info = get_elf_info(&path);
+        (path, info)
+    };
+    match &*PARSING_POOL {
+        Some(pool) if paths.len() > 1 => {
+            pool.install(|| paths.into_par_iter().map(parse).collect())
+        }
+        _ => paths.into_iter().map(parse).collect(),
     }
-    let mut result = Vec::new();
-    for line in out.stdout.split(|&c| c == b'\n') {
-        if !line.is_empty() {
-            let path = PathBuf::from(OsString::from_vec(line.to_owned()));
-            if !path.is_absolute() {
-                // nix returns `unknown-deriver` when it does not know
-                anyhow::bail!(
-                    "incorrect deriver {} for {}",
-                    String::from_utf8_lossy(line),
-                    path.display()
-                );
-            };
-            result.push(path)
+}
+
+#[test]
+fn test_get_elf_infos() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let exe = std::env::current_exe().unwrap();
+    let buildid = get_buildid(&exe).unwrap();
+    let mut paths = Vec::new();
+    for i in 0..20 {
+        if i % 3 == 0 {
+            paths.push(exe.clone());
+        } else {
+            let path = dir.path().join(i.to_string());
+            std::fs::write(&path, vec![0u8; 100]).unwrap();
+            paths.push(path);
+        }
+    }
+    paths.push(dir.path().join("missing"));
+    let infos = get_elf_infos(paths.clone());
+    assert_eq!(infos.len(), paths.len());
+    for (i, (path, info)) in infos.into_iter().enumerate() {
+        assert_eq!(path, paths[i]);
+        if i == 20 {
+            assert!(info.is_err());
+        } else if i % 3 == 0 {
+            assert_eq!(info.unwrap().map(|info| info.buildid), buildid);
+        } else {
+            assert_eq!(info.unwrap(), None);
         }
     }
-    Ok(result)
 }
 
-/// Attempts to obtain any deriver for this store path, preferably existing.
-///
-/// Corresponds to `nix-store --query --deriver` or `nix-store --query --valid-derivers.
+/// What was found in the files with several hard links parsed during a batch of indexation, by
+/// `(device, inode)`.
+///
+/// With `auto-optimise-store`, identical files of different store paths are hard links to the
+/// same file in `/nix/store/.links`, so a library present in many store paths is only parsed
+/// once per batch. Cloning this structure returns a structure referring to the same files.
+#[derive(Clone, Default)]
+pub struct ParsedInodes(Arc<Mutex<HashMap<Inode, Option<ElfInfo>>>>);
+
+/// `(device, inode)` of a file
+type Inode = (u64, u64);
+
+impl ParsedInodes {
+    /// Like [get_elf_infos], for files with their `(device, inode)` if they have several hard
+    /// links.
+    ///
+    /// Files whose inode was already parsed are not parsed again. The result is in the same
+    /// order as `files`.
+    fn get_elf_infos(
+        &self,
+        files: Vec<(PathBuf, Option<Inode>)>,
+    ) -> Vec<(PathBuf, anyhow::Result<Option<ElfInfo>>)> {
+        let mut known = Vec::with_capacity(files.len());
+        let mut unknown = Vec::new();
+        let mut inodes = Vec::new();
+        {
+            let parsed = self.0.lock().unwrap();
+            for (i, (path, inode)) in files.into_iter().enumerate() {
+                match inode.and_then(|inode| parsed.get(&inode)) {
+                    Some(info) => known.push((i, path, Ok(info.clone()))),
+                    None => {
+                        unknown.push(path);
+                        inodes.push((i, inode));
+                    }
+                }
+            }
+        }
+        let infos = get_elf_infos(unknown);
+        let mut parsed = self.0.lock().unwrap();
+        for ((path, info), (i, inode)) in infos.into_iter().zip(inodes) {
+            // errors are not remembered, as they may be specific to this path
+            if let (Some(inode), Ok(info)) = (inode, &info) {
+                parsed.insert(inode, info.clone());
+            }
+            known.push((i, path, info));
+        }
+        drop(parsed);
+        known.sort_unstable_by_key(|&(i, _, _)| i);
+        known
+            .into_iter()
+            .map(|(_, path, info)| (path, info))
+            .collect()
+    }
+}
+
+#[test]
+fn test_parsed_inodes() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let exe = std::env::current_exe().unwrap();
+    let buildid = get_buildid(&exe).unwrap();
+    let original = dir.path().join("original");
+    std::fs::copy(&exe, &original).unwrap();
+    let link = dir.path().join("link");
+    std::fs::hard_link(&original, &link).unwrap();
+    let inode = |path: &Path| {
+        let metadata = std::fs::metadata(path).unwrap();
+        Some((metadata.dev(), metadata.ino()))
+    };
+    let parsed = ParsedInodes::default();
+    let infos = parsed.get_elf_infos(vec![(original.clone(), inode(&original))]);
+    assert_eq!(
+        infos[0]
+            .1
+            .as_ref()
+            .unwrap()
+            .as_ref()
+            .map(|info| &info.buildid),
+        buildid.as_ref()
+    );
+    // store files are immutable, so changing the content shows that the link is not parsed again
+    std::fs::write(&original, "replaced").unwrap();
+    let infos = parsed.get_elf_infos(vec![
+        (dir.path().join("missing"), None),
+        (link.clone(), inode(&link)),
+    ]);
+    assert!(infos[0].1.is_err());
+    assert_eq!(infos[1].0, link);
+    assert_eq!(
+        infos[1]
+            .1
+            .as_ref()
+            .unwrap()
+            .as_ref()
+            .map(|info| &info.buildid),
+        buildid.as_ref()
+    );
+}
+
+/// Walks the files of a store path, following symlinks to directories of the same store path,
+/// like the symlink farms of `buildFHSEnv` or unpacked appimages.
+///
+/// Symlinks to other store paths are not followed: these are indexed on their own. Each file
+/// is visited only once, even if several symlinks lead to it or they form a cycle.
+fn walk_store_path(storepath: &Path) -> impl Iterator<Item = walkdir::Result<walkdir::DirEntry>> {
+    // the store path itself may be a symlink to another store path
+    let root = match (storepath.parent(), storepath.file_name()) {
+        (Some(parent), Some(name)) => std::fs::canonicalize(parent)
+            .map_or_else(|_| storepath.to_path_buf(), |parent| parent.join(name)),
+        _ => storepath.to_path_buf(),
+    };
+    let mut visited = HashSet::new();
+    walkdir::WalkDir::new(storepath)
+        .follow_links(true)
+        .into_iter()
+        .filter_entry(move |file| {
+            if file.path_is_symlink() {
+                match std::fs::canonicalize(file.path()) {
+                    Ok(target) if target.starts_with(&root) => (),
+                    _ => return false,
+                }
+            }
+            match file.metadata() {
+                Ok(metadata) => visited.insert((metadata.dev(), metadata.ino())),
+                // let the caller handle the error
+                Err(_) => true,
+            }
+        })
+}
+
+#[test]
+fn test_walk_store_path() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let storepath = dir.path().join("aaaa-fhs");
+    let other = dir.path().join("bbbb-other");
+    std::fs::create_dir_all(storepath.join("opt/app/bin")).unwrap();
+    std::fs::create_dir_all(&other).unwrap();
+    std::fs::write(storepath.join("opt/app/bin/app"), "app").unwrap();
+    std::fs::write(other.join("lib.so"), "lib").unwrap();
+    std::fs::create_dir(storepath.join("usr")).unwrap();
+    std::os::unix::fs::symlink("../opt/app/bin", storepath.join("usr/bin")).unwrap();
+    std::os::unix::fs::symlink("../..", storepath.join("opt/app/loop")).unwrap();
+    std::os::unix::fs::symlink(&other, storepath.join("usr/lib")).unwrap();
+    // the cycle is reported as an error
+    let files: Vec<PathBuf> = walk_store_path(&storepath)
+        .filter_map(Result::ok)
+        .filter(|file| file.file_type().is_file())
+        .map(|file| file.into_path())
+        .collect();
+    assert_eq!(files.len(), 1, "{:?}", files);
+    assert!(files[0].ends_with("bin/app"));
+
+    // a store path which is a symlink to another store path is not followed
+    let link = dir.path().join("cccc-link");
+    std::os::unix::fs::symlink(&other, &link).unwrap();
+    assert!(walk_store_path(&link)
+        .map(|file| file.unwrap())
+        .all(|file| !file.file_type().is_file()));
+}
+
+/// Files of a store path examined by [index_store_path] between two pauses
+pub const FILES_BETWEEN_PAUSES: usize = 1000;
+
+/// Maximum number of files examined by [index_store_path] in a single store path, so that
+/// huge store paths like `texlive` do not take forever to index
+const MAX_FILES_PER_STORE_PATH: usize = 200_000;
+
+/// Files smaller than this cannot be executables with a buildid: an elf header alone is 64
+/// bytes.
+const MIN_EXECUTABLE_SIZE: u64 = 64;
+
+/// Extensions of files which are never executables or libraries, and are common in huge store
+/// paths like `texlive` or `linux-firmware`
+const NON_EXECUTABLE_EXTENSIONS: &[&str] = &[
+    "afm", "bib", "bst", "c", "cc", "cls", "cpp", "css", "dtx", "enc", "gif", "gz", "h", "hpp",
+    "htm", "html", "jpeg", "jpg", "js", "json", "lua", "map", "md", "mf", "mo", "otf", "pdf",
+    "pfb", "pl", "pm", "png", "po", "py", "pyc", "rs", "sty", "svg", "tex", "tfm", "ttf", "txt",
+    "vf", "woff", "woff2", "xml", "xz", "zst",
+];
+
+/// Whether this file is worth opening to look for a buildid, judging from its name and size.
+fn may_be_executable(file: &walkdir::DirEntry) -> bool {
+    let extension = file.path().extension().and_then(|e| e.to_str());
+    if extension.is_some_and(|e| NON_EXECUTABLE_EXTENSIONS.contains(&e)) {
+        return false;
+    }
+    match file.metadata() {
+        Ok(metadata) => metadata.len() >= MIN_EXECUTABLE_SIZE,
+        // let opening the file report the error
+        Err(_) => true,
+    }
+}
+
+#[test]
+fn test_may_be_executable() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let big = vec![0u8; MIN_EXECUTABLE_SIZE as usize];
+    for (name, content) in [
+        ("libfoo.so.1", &big[..]),
+        ("hello", &big[..]),
+        ("font.tfm", &big[..]),
+        ("README.txt", &big[..]),
+        ("tiny", &b"#!/bin/sh"[..]),
+    ] {
+        std::fs::write(dir.path().join(name), content).unwrap();
+    }
+    let mut examined: Vec<String> = walkdir::WalkDir::new(dir.path())
+        .into_iter()
+        .map(|file| file.unwrap())
+        .filter(|file| file.file_type().is_file() && may_be_executable(file))
+        .map(|file| file.file_name().to_string_lossy().into_owned())
+        .collect();
+    examined.sort();
+    assert_eq!(examined, vec!["hello", "libfoo.so.1"]);
+}
+
+/// Explains in human readable sentences why the debuginfo and source of the elf file `path`
+/// with this buildid can or cannot be found.
+///
+/// Follows the same steps as [index_store_path], and may download the deriver like it.
+pub fn diagnose(path: &Path, buildid: &str) -> Vec<String> {
+    let mut findings = Vec::new();
+    let storepath = match get_store_path(path) {
+        None => {
+            findings.push(format!(
+                "{} is not in the nix store, so nothing is known about how it was built",
+                path.display()
+            ));
+            return findings;
+        }
+        Some(storepath) => storepath,
+    };
+    let deriver = match get_deriver(storepath) {
+        Err(e) => {
+            findings.push(format!(
+                "cannot determine the deriver of {}: {:#}",
+                storepath.display(),
+                e
+            ));
+            return findings;
+        }
+        Ok(None) => {
+            findings.push(format!(
+                "{} has no known deriver (it was probably built by a tool that does not register \
+                derivers, or copied from another machine without its deriver)",
+                storepath.display()
+            ));
+            let (debug_outputs, source) = match get_references(storepath) {
+                Ok(references) => guess_from_references(storepath, &references),
+                Err(_) => (Vec::new(), None),
+            };
+            findings.push(match debug_outputs.as_slice() {
+                [] => "no debug output found among its references".to_owned(),
+                outputs => format!("guessed debug outputs from its references: {:?}", outputs),
+            });
+            findings.push(match source {
+                None => "no source found among its references".to_owned(),
+                Some(source) => format!("guessed source from its references: {}", source.display()),
+            });
+            return findings;
+        }
+        Ok(Some(deriver)) => deriver,
+    };
+    findings.push(format!(
+        "{} was built by {}",
+        storepath.display(),
+        deriver.display()
+    ));
+    if !deriver.is_file() {
+        if let Err(e) = download_drv(&deriver) {
+            findings.push(format!(
+                "the deriver is not in the store and could not be downloaded from substituters: {:#}",
+                e
+            ));
+            return findings;
+        }
+        findings.push("downloaded the deriver from a substituter".to_owned());
+    }
+    let drv = match Derivation::read(&deriver) {
+        Err(e) => {
+            findings.push(format!("cannot read the deriver: {:#}", e));
+            return findings;
+        }
+        Ok(drv) => drv,
+    };
+    match get_debug_outputs(&drv, None) {
+        Err(e) => findings.push(format!(
+            "cannot list the outputs of {}: {:#}",
+            deriver.display(),
+            e
+        )),
+        Ok(outputs) if outputs.is_empty() => findings.push(format!(
+            "{} has no debug output: the package was not built with `separateDebugInfo = true`, \
+            so separate debug symbols do not exist",
+            deriver.display()
+        )),
+        Ok(outputs) => {
+            for output in outputs {
+                let debuginfo = debuginfo_path_for(buildid, &output);
+                findings.push(if !output.exists() {
+                    format!(
+                        "debug output {} is not in the store, it is fetched from substituters \
+                        on request",
+                        output.display()
+                    )
+                } else if debuginfo.exists() {
+                    format!("debug symbols are in {}", debuginfo.display())
+                } else {
+                    format!(
+                        "debug output {} does not contain debug symbols for buildid {}",
+                        output.display(),
+                        buildid
+                    )
+                });
+            }
+        }
+    }
+    match get_source(&drv) {
+        Err(e) => findings.push(format!(
+            "cannot determine the source of {}: {:#}",
+            deriver.display(),
+            e
+        )),
+        Ok(None) => findings.push(format!(
+            "{} has no `src` or `srcs` attribute, so source files cannot be found",
+            deriver.display()
+        )),
+        Ok(Some(source)) if source.exists() => {
+            findings.push(format!("source is {}", source.display()))
+        }
+        Ok(Some(source)) => findings.push(format!(
+            "source {} is not in the store, it is fetched from substituters on request",
+            source.display()
+        )),
+    }
+    findings
+}
+
+#[test]
+fn test_diagnose_outside_store() {
+    let exe = std::env::current_exe().unwrap();
+    let findings = diagnose(&exe, "0123456789abcdef");
+    assert_eq!(findings.len(), 1);
+    assert!(findings[0].contains("not in the nix store"));
+}
+
+/// Lists the split dwarf files (`.dwo` and `.dwp`) in this debug output.
+fn find_split_dwarf(debug_output: &Path) -> Vec<SplitDwarf> {
+    let output = match debug_output.to_str() {
+        Some(output) => output,
+        None => return Vec::new(),
+    };
+    let mut result = Vec::new();
+    for file in walkdir::WalkDir::new(debug_output) {
+        let file = match file {
+            Err(_) => continue,
+            Ok(file) => file,
+        };
+        if !file.file_type().is_file() {
+            continue;
+        }
+        let (name, path) = match (file.file_name().to_str(), file.path().to_str()) {
+            (Some(name), Some(path)) => (name, path),
+            _ => continue,
+        };
+        if name.ends_with(".dwo") || name.ends_with(".dwp") {
+            result.push(SplitDwarf {
+                output: output.to_owned(),
+                name: name.to_owned(),
+                path: path.to_owned(),
+            });
+        }
+    }
+    result
+}
+
+#[test]
+fn test_find_split_dwarf() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("aaaa-foo-debug");
+    std::fs::create_dir_all(output.join("lib/debug/dwo")).unwrap();
+    std::fs::write(output.join("lib/debug/dwo/main.dwo"), "").unwrap();
+    std::fs::write(output.join("lib/debug/foo.dwp"), "").unwrap();
+    std::fs::write(output.join("lib/debug/foo.debug"), "").unwrap();
+    let mut found = find_split_dwarf(&output);
+    found.sort_by(|a, b| a.name.cmp(&b.name));
+    let names: Vec<_> = found.iter().map(|file| file.name.as_str()).collect();
+    assert_eq!(names, vec!["foo.dwp", "main.dwo"]);
+    assert_eq!(
+        found[1].path,
+        output.join("lib/debug/dwo/main.dwo").to_str().unwrap()
+    );
+    assert_eq!(found[1].output, output.to_str().unwrap());
+}
+
+/// Return the path where separate debuginfo is to be found in a debug output for a buildid
+fn debuginfo_path_for(buildid: &str, debug_output: &Path) -> PathBuf {
+    let mut res = debug_output.to_path_buf();
+    res.push("lib");
+    res.push("debug");
+    res.push(".build-id");
+    res.push(&buildid[..2]);
+    res.push(format!("{}.debug", &buildid[2..]));
+    res
+}
+
+/// Return the path where separate debuginfo for this buildid is among these debug outputs.
+///
+/// Debug outputs which are present in the store are checked for the actual file. If none contains
+/// it, the prediction is made in the first debug output which is not in the store, if any.
+fn find_debuginfo_in_outputs(buildid: &str, debug_outputs: &[PathBuf]) -> Option<PathBuf> {
+    let mut unavailable = None;
+    for debug_output in debug_outputs {
+        let theoretical = debuginfo_path_for(buildid, debug_output.as_path());
+        if debug_output.is_dir() {
+            // the store path is available, check the prediction
+            if theoretical.is_file() {
+                return Some(theoretical);
+            }
+        } else if unavailable.is_none() {
+            unavailable = Some(theoretical);
+        }
+    }
+    unavailable
+}
+
+#[test]
+fn test_find_debuginfo_in_outputs_second() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let debug = dir.path().join("foo-debug");
+    let lib_debug = dir.path().join("foo-lib-debug");
+    let expected = debuginfo_path_for("abcdef", &lib_debug);
+    std::fs::create_dir_all(&debug).unwrap();
+    std::fs::create_dir_all(expected.parent().unwrap()).unwrap();
+    std::fs::write(&expected, "debuginfo").unwrap();
+    assert_eq!(
+        find_debuginfo_in_outputs("abcdef", &[debug, lib_debug]),
+        Some(expected)
+    );
+}
+
+#[test]
+fn test_find_debuginfo_in_outputs_unavailable() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let debug = dir.path().join("foo-debug");
+    let lib_debug = dir.path().join("foo-lib-debug");
+    std::fs::create_dir_all(&debug).unwrap();
+    assert_eq!(
+        find_debuginfo_in_outputs("abcdef", &[debug, lib_debug.clone()]),
+        Some(debuginfo_path_for("abcdef", &lib_debug))
+    );
+}
+
+#[test]
+fn test_find_debuginfo_in_outputs_missing() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let debug = dir.path().join("foo-debug");
+    std::fs::create_dir_all(&debug).unwrap();
+    assert_eq!(find_debuginfo_in_outputs("abcdef", &[debug]), None);
+}
+
+/// Obtains the original deriver of a store path.
+///
+/// Corresponds to `nix-store --query --deriver`
 ///
 /// The store path must exist.
-fn get_deriver(storepath: &Path) -> anyhow::Result<Option<PathBuf>> {
+fn get_original_deriver(storepath: &Path) -> anyhow::Result<Option<PathBuf>> {
+    let mut cmd = std::process::Command::new("nix-store");
+    cmd.arg("--query").arg("--deriver").arg(storepath);
+    tracing::debug!("Running {:?}", &cmd);
+    let out = cmd.output().with_context(|| format!("running {:?}", cmd))?;
+    crate::subprocess::check(&cmd, &out)?;
+    let n = out.stdout.len();
+    if n <= 1 || out.stdout[n - 1] != b'\n' {
+        anyhow::bail!(
+            "{:?} returned weird output: {}",
+            cmd,
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+    let path = PathBuf::from(OsString::from_vec(out.stdout[..n - 1].to_owned()));
+    if path.as_path() == Path::new("unknown-deriver") {
+        return Ok(None);
+    }
+    if !path.is_absolute() {
+        // nix returns `unknown-deriver` when it does not know
+        anyhow::bail!("no deriver: {}", path.display());
+    };
+    Ok(Some(path))
+}
+
+/// Obtains a set of local derivers for a store path.
+///
+/// Corresponds to `nix-store --query --valid-derivers`
+///
+/// The store path must exist.
+///
+/// Fails if nix version is < 2.18
+fn get_valid_derivers(storepath: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut cmd = std::process::Command::new("nix-store");
+    cmd.arg("--query").arg("--valid-derivers").arg(storepath);
+    tracing::debug!("Running {:?}", &cmd);
+    let out = cmd.output().with_context(|| format!("running {:?}", cmd))?;
+    crate::subprocess::check(&cmd, &out)?;
+    let mut result = Vec::new();
+    for line in out.stdout.split(|&c| c == b'\n') {
+        if !line.is_empty() {
+            let path = PathBuf::from(OsString::from_vec(line.to_owned()));
+            if !path.is_absolute() {
+                // nix returns `unknown-deriver` when it does not know
+                anyhow::bail!(
+                    "incorrect deriver {} for {}",
+                    String::from_utf8_lossy(line),
+                    path.display()
+                );
+            };
+            result.push(path)
+        }
+    }
+    Ok(result)
+}
+
+/// Attempts to obtain any deriver for this store path, preferably existing.
+///
+/// Corresponds to `nix-store --query --deriver` or `nix-store --query --valid-derivers.
+///
+/// The store path must exist. Paths outside the store, like debuginfo fetched from substituters
+/// with `--private-debuginfo`, have no deriver.
+pub fn get_deriver(storepath: &Path) -> anyhow::Result<Option<PathBuf>> {
+    if get_store_path(storepath).is_none() || is_read_only() {
+        return Ok(None);
+    }
+    if READ_NIX_DB.load(Ordering::SeqCst) {
+        return block_on_nix_db(crate::nixdb::get_deriver(storepath))
+            .with_context(|| format!("getting deriver for {} in nix db", storepath.display()));
+    }
     if NIX_STORE_QUERY_VALID_DERIVERS_SUPPORTED.load(Ordering::SeqCst) {
         for path in get_valid_derivers(storepath)
             .with_context(|| format!("getting valid deriver for {}", storepath.display()))?
@@ -382,6 +1456,126 @@ fn get_deriver(storepath: &Path) -> anyhow::Result<Option<PathBuf>> {
         .with_context(|| format!("getting original deriver for {}", storepath.display()))
 }
 
+/// Obtains the store paths this store path refers to.
+///
+/// Corresponds to `nix-store --query --references`
+///
+/// The store path must exist.
+fn get_references(storepath: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if get_store_path(storepath).is_none() {
+        return Ok(vec![]);
+    }
+    if READ_NIX_DB.load(Ordering::SeqCst) {
+        return block_on_nix_db(crate::nixdb::get_references(storepath))
+            .with_context(|| format!("getting references of {} in nix db", storepath.display()));
+    }
+    let mut cmd = std::process::Command::new("nix-store");
+    cmd.arg("--query").arg("--references").arg(storepath);
+    tracing::debug!("Running {:?}", &cmd);
+    let out = cmd.output().with_context(|| format!("running {:?}", cmd))?;
+    crate::subprocess::check(&cmd, &out)?;
+    Ok(out
+        .stdout
+        .split(|&elt| elt == b'\n')
+        .filter(|reference| !reference.is_empty())
+        .map(|reference| PathBuf::from(OsString::from_vec(reference.to_owned())))
+        .collect())
+}
+
+/// Guesses the debug outputs and the source of a store path whose deriver is not known, for
+/// example because it was built locally by a tool that does not register derivers, from the
+/// store paths it refers to.
+///
+/// Debug outputs are references ending with `-debug` with the same package name as
+/// `storepath`. The source is a reference named like a source (`source` like flake inputs,
+/// ending with `-source` or `-src`, or an archive), preferably with the same package name, and
+/// only if there is no ambiguity.
+fn guess_from_references(
+    storepath: &Path,
+    references: &[PathBuf],
+) -> (Vec<PathBuf>, Option<PathBuf>) {
+    let package = package_name(storepath);
+    let same_package = |path: &Path| package.is_some() && package_name(path) == package;
+    let mut debug_outputs = Vec::new();
+    let mut sources = Vec::new();
+    for reference in references {
+        if reference == storepath {
+            continue;
+        }
+        let name = match reference.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => continue,
+        };
+        if name.ends_with("-debug") {
+            if same_package(reference) {
+                debug_outputs.push(reference.clone());
+            }
+        } else if name.ends_with("-source")
+            || name.ends_with("-src")
+            || [
+                ".tar", ".tar.gz", ".tgz", ".tar.xz", ".tar.bz2", ".tar.zst", ".zip",
+            ]
+            .iter()
+            .any(|extension| name.ends_with(extension))
+        {
+            sources.push(reference.clone());
+        }
+    }
+    let source = match sources.as_slice() {
+        [only] => Some(only.clone()),
+        _ => {
+            let mut matching = sources.iter().filter(|source| same_package(source));
+            match (matching.next(), matching.next()) {
+                (Some(source), None) => Some(source.clone()),
+                _ => None,
+            }
+        }
+    };
+    (debug_outputs, source)
+}
+
+#[test]
+fn test_guess_from_references() {
+    let storepath = Path::new("/nix/store/aaaa-foo-1.0");
+    let references = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+    assert_eq!(
+        guess_from_references(
+            storepath,
+            &references(&[
+                "/nix/store/aaaa-foo-1.0",
+                "/nix/store/bbbb-foo-1.0-debug",
+                "/nix/store/cccc-glibc-2.39-debug",
+                "/nix/store/dddd-glibc-2.39",
+                "/nix/store/eeee-source",
+            ])
+        ),
+        (
+            vec![PathBuf::from("/nix/store/bbbb-foo-1.0-debug")],
+            Some(PathBuf::from("/nix/store/eeee-source"))
+        )
+    );
+    assert_eq!(
+        guess_from_references(
+            storepath,
+            &references(&[
+                "/nix/store/ffff-bar-2.0.tar.gz",
+                "/nix/store/gggg-foo-1.0.tar.xz",
+            ])
+        ),
+        (
+            vec![],
+            Some(PathBuf::from("/nix/store/gggg-foo-1.0.tar.xz"))
+        )
+    );
+    assert_eq!(
+        guess_from_references(
+            storepath,
+            &references(&["/nix/store/ffff-bar-src", "/nix/store/hhhh-source"])
+        ),
+        (vec![], None)
+    );
+}
+
 /// Checks that nix is installed.
 ///
 /// Also stores in global state whether some features only available in recent nix
@@ -405,6 +1599,16 @@ pub fn detect_nix() -> anyhow::Result<()> {
         Some(test_path) => test_path,
         None => anyhow::bail!("/nix/store is empty, did you really install nix?"),
     };
+    if READ_NIX_DB.load(Ordering::SeqCst) {
+        block_on_nix_db(crate::nixdb::get_deriver(&test_path)).with_context(|| {
+            format!(
+                "checking access to nix db by getting deriver of {}",
+                test_path.display()
+            )
+        })?;
+        tracing::info!("reading derivers from the nix db");
+        return Ok(());
+    }
     if get_valid_derivers(&test_path).is_ok() {
         NIX_STORE_QUERY_VALID_DERIVERS_SUPPORTED.store(true, Ordering::SeqCst);
         tracing::info!("detected nix >= 2.18");
@@ -420,111 +1624,611 @@ pub fn detect_nix() -> anyhow::Result<()> {
     Ok(())
 }
 
-/// Obtains the debug output corresponding to this derivation
+/// Obtains the list of outputs of this derivation
 ///
 /// The derivation must exist.
-fn get_debug_output(drvpath: &Path) -> anyhow::Result<Option<PathBuf>> {
+fn get_outputs(drvpath: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if READ_NIX_DB.load(Ordering::SeqCst) {
+        return block_on_nix_db(crate::nixdb::get_outputs(drvpath))
+            .with_context(|| format!("getting outputs of {} in nix db", drvpath.display()));
+    }
     let mut cmd = std::process::Command::new("nix-store");
     cmd.arg("--query").arg("--outputs").arg(drvpath);
     tracing::debug!("Running {:?}", &cmd);
     let out = cmd.output().with_context(|| format!("running {:?}", cmd))?;
-    if !out.status.success() {
-        anyhow::bail!("{:?} failed: {}", cmd, String::from_utf8_lossy(&out.stderr));
+    crate::subprocess::check(&cmd, &out)?;
+    Ok(out
+        .stdout
+        .split(|&elt| elt == b'\n')
+        .filter(|output| !output.is_empty())
+        .map(|output| PathBuf::from(OsString::from_vec(output.to_owned())))
+        .collect())
+}
+
+/// Obtains the list of outputs of this derivation, unless they are already `known` or written
+/// in the derivation
+fn get_outputs_unless_known(
+    drv: &Derivation,
+    known: Option<&[PathBuf]>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    match known.or(drv.outputs.as_deref()) {
+        Some(outputs) => Ok(outputs.to_vec()),
+        None => get_outputs(&drv.path),
     }
-    for output in out.stdout.split(|&elt| elt == b'\n') {
-        if output.ends_with(b"-debug") {
-            return Ok(Some(PathBuf::from(OsString::from_vec(output.to_owned()))));
+}
+
+/// Obtains the debug outputs corresponding to this derivation
+///
+/// Some derivations have several, like `debug` and `lib-debug`.
+///
+/// `known_outputs` are the outputs of the derivation, if known.
+fn get_debug_outputs(
+    drv: &Derivation,
+    known_outputs: Option<&[PathBuf]>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    Ok(get_outputs_unless_known(drv, known_outputs)?
+        .into_iter()
+        .filter(|output| output.as_os_str().as_bytes().ends_with(b"-debug"))
+        .collect())
+}
+
+/// Obtains the store path stored in the environment binding `name` of this derivation
+///
+/// Returns `Ok(None)` if there is no such binding.
+fn get_path_binding(drv: &Derivation, name: &str) -> anyhow::Result<Option<PathBuf>> {
+    let path = match drv.binding(name) {
+        None => return Ok(None),
+        Some(value) => PathBuf::from(value),
+    };
+    if !path.is_absolute() {
+        anyhow::bail!("weird {}: {}", name, path.display());
+    };
+    Ok(Some(path))
+}
+
+/// Obtains the store paths stored space separated in the environment binding `name` of this
+/// derivation, like `srcs` or `patches`.
+///
+/// Words which are not store paths are ignored.
+fn get_paths_binding(drv: &Derivation, name: &str) -> Vec<PathBuf> {
+    let Some(value) = drv.binding(name) else {
+        return Vec::new();
+    };
+    value
+        .as_bytes()
+        .split(|c| c.is_ascii_whitespace())
+        .map(|word| Path::new(OsStr::from_bytes(word)))
+        .filter(|path| get_store_path(path).is_some())
+        .map(Path::to_path_buf)
+        .collect()
+}
+
+/// Obtains the source store path corresponding to this derivation
+///
+/// Source is understood as `src = `, or else the first of `srcs = `. See [get_extra_sources]
+/// for the others.
+fn get_source(drv: &Derivation) -> anyhow::Result<Option<PathBuf>> {
+    match get_path_binding(drv, "src")? {
+        Some(source) => Ok(Some(source)),
+        None => Ok(get_paths_binding(drv, "srcs").into_iter().next()),
+    }
+}
+
+/// Obtains the store paths where source files of this derivation can be found, other than
+/// `source`, as returned by [get_source]: the other elements of `srcs`, and `patches`, which
+/// may create files.
+fn get_extra_sources(drv: &Derivation, source: &Path) -> Vec<PathBuf> {
+    let mut result = get_paths_binding(drv, "srcs");
+    result.extend(get_paths_binding(drv, "patches"));
+    result.retain(|path| path != source);
+    result.sort_unstable();
+    result.dedup();
+    result
+}
+
+/// Records the [get_extra_sources] of the deriver of an indexed store path, if it has a source.
+fn send_extra_sources(
+    deriver: Option<&Derivation>,
+    source: Option<&Path>,
+    sendto: &Sender<Indexed>,
+) {
+    let (Some(deriver), Some(source)) = (deriver, source) else {
+        return;
+    };
+    let roots = get_extra_sources(deriver, source);
+    let (Some(source), false) = (source.to_str(), roots.is_empty()) else {
+        return;
+    };
+    let roots = SourceRoots {
+        source: source.to_owned(),
+        roots: roots
+            .iter()
+            .filter_map(|root| root.to_str())
+            .map(str::to_owned)
+            .collect(),
+    };
+    sendto
+        .blocking_send(Indexed::SourceRoots(roots))
+        .context("sending extra sources failed")
+        .or_warn();
+}
+
+/// Whether this source file is a patch, as in the `patches` of a derivation
+pub fn is_patch(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|extension| extension.to_str()),
+        Some("patch" | "diff")
+    )
+}
+
+/// The file name in a `---` or `+++` line of a unified diff, without timestamp
+fn patch_file_name(line: &[u8]) -> &[u8] {
+    let end = line
+        .iter()
+        .position(|&c| c == b'\t' || c == b'\r')
+        .unwrap_or(line.len());
+    &line[..end]
+}
+
+/// The number of lines of the new file in a hunk header like `@@ -0,0 +1,12 @@`
+fn hunk_new_len(line: &[u8]) -> Option<usize> {
+    let line = std::str::from_utf8(line.strip_prefix(b"@@ -")?).ok()?;
+    let (_, new) = line.split_once(" +")?;
+    let (range, _) = new.split_once(' ')?;
+    match range.split_once(',') {
+        None => Some(1),
+        Some((_, len)) => len.parse().ok(),
+    }
+}
+
+/// Files created by this unified diff, with their contents.
+///
+/// Files modified by the patch are not returned, as their contents depend on the source the
+/// patch applies to.
+pub fn files_created_by_patch(patch: &[u8]) -> Vec<(PathBuf, Vec<u8>)> {
+    let lines: Vec<&[u8]> = patch.split(|&c| c == b'\n').collect();
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i + 1 < lines.len() {
+        let (Some(old), Some(new)) = (
+            lines[i].strip_prefix(b"--- "),
+            lines[i + 1].strip_prefix(b"+++ "),
+        ) else {
+            i += 1;
+            continue;
+        };
+        i += 2;
+        if patch_file_name(old) != b"/dev/null" {
+            continue;
+        }
+        let name = patch_file_name(new);
+        let name = name.strip_prefix(b"b/").unwrap_or(name);
+        let mut contents = Vec::new();
+        while let Some(len) = lines.get(i).and_then(|line| hunk_new_len(line)) {
+            i += 1;
+            for _ in 0..len {
+                match lines.get(i).and_then(|line| line.split_first()) {
+                    Some((b'+' | b' ', line)) => {
+                        contents.extend_from_slice(line);
+                        contents.push(b'\n');
+                        i += 1;
+                    }
+                    _ => break,
+                }
+            }
+            if lines.get(i).is_some_and(|line| line.starts_with(b"\\")) {
+                // \ No newline at end of file
+                contents.pop();
+                i += 1;
+            }
+        }
+        result.push((PathBuf::from(OsStr::from_bytes(name)), contents));
+    }
+    result
+}
+
+/// The contents of `member`, a file created by this patch file.
+pub fn file_created_by_patch(patch: &Path, member: &Path) -> anyhow::Result<Vec<u8>> {
+    let contents =
+        std::fs::read(patch).with_context(|| format!("reading patch {}", patch.display()))?;
+    files_created_by_patch(&contents)
+        .into_iter()
+        .find(|(name, _)| name == member)
+        .map(|(_, contents)| contents)
+        .with_context(|| format!("{} does not create {}", patch.display(), member.display()))
+}
+
+#[test]
+fn test_files_created_by_patch() {
+    let patch = b"From: someone
+Subject: add a header and fix main
+
+diff --git a/src/main.c b/src/main.c
+--- a/src/main.c
++++ b/src/main.c
+@@ -1,2 +1,2 @@
+-int main() { return 1; }
++int main() { return 0; }
+diff --git a/src/new.h b/src/new.h
+new file mode 100644
+--- /dev/null
++++ b/src/new.h
+@@ -0,0 +1,3 @@
++#pragma once
++
++int f();
+--- /dev/null\t2024-01-01 00:00:00
++++ foo-1.0/empty.c\t2024-01-01 00:00:00
+@@ -0,0 +1 @@
++int g;
+\\ No newline at end of file
+";
+    assert_eq!(
+        files_created_by_patch(patch),
+        vec![
+            (
+                PathBuf::from("src/new.h"),
+                b"#pragma once\n\nint f();\n".to_vec()
+            ),
+            (PathBuf::from("foo-1.0/empty.c"), b"int g;".to_vec()),
+        ]
+    );
+}
+
+/// Obtains the store path where the build directory of this derivation was captured, if any.
+///
+/// Generated files (`config.h`, bison or protobuf output...) are not in `src`, but can be
+/// found there. This is either the store path in the `NIX_DEBUG_INFO_SOURCES` environment
+/// binding, or an output named `build`. For Rust packages, which have neither, this is the
+/// vendored dependencies in `cargoDeps` instead.
+///
+/// `known_outputs` are the outputs of the derivation, if known.
+fn get_build_source(
+    drv: &Derivation,
+    known_outputs: Option<&[PathBuf]>,
+) -> anyhow::Result<Option<PathBuf>> {
+    if let Some(path) = get_path_binding(drv, "NIX_DEBUG_INFO_SOURCES")? {
+        return Ok(Some(path));
+    }
+    for output in get_outputs_unless_known(drv, known_outputs)? {
+        if output.as_os_str().as_bytes().ends_with(b"-build") {
+            return Ok(Some(output));
         }
     }
-    Ok(None)
+    get_path_binding(drv, "cargoDeps")
+}
+
+/// Where a source file might be
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceLocation {
+    /// Inside an archive, or created by a patch
+    Archive {
+        /// path of the archive, or of the patch if [is_patch]
+        archive: PathBuf,
+        /// path of the file in the archive
+        member: PathBuf,
+    },
+    /// A file directly in the store
+    File(PathBuf),
+}
+
+impl SourceLocation {
+    /// Get the path against which we match the requested file name
+    fn member_path(&self) -> &Path {
+        match self {
+            SourceLocation::Archive { member, .. } => member.as_path(),
+            SourceLocation::File(path) => path.as_path(),
+        }
+    }
+}
+
+/// Return the build id of this file.
+///
+/// Elf files have a build id note. For Mach-O files, this is their UUID, and for PE files their
+/// CodeView signature, see [object_buildid].
+///
+/// If the file is not an executable returns Ok(None).
+/// Errors are only for errors returned from the fs.
+pub fn get_buildid(path: &Path) -> anyhow::Result<Option<String>> {
+    Ok(get_elf_info(path)?.map(|info| info.buildid))
+}
+
+/// What identifies an elf, Mach-O or PE file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfInfo {
+    /// the buildid, in base16
+    pub buildid: String,
+    /// the architecture, as returned by [architecture_name]
+    pub architecture: Option<String>,
+}
+
+/// Return the build id and architecture of this file.
+///
+/// Same conventions as [get_buildid].
+pub fn get_elf_info(path: &Path) -> anyhow::Result<Option<ElfInfo>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("opening {} to get its buildid", path.display()))?;
+    let reader = object::read::ReadCache::new(file);
+    let info = match object::FileKind::parse(&reader) {
+        Ok(object::FileKind::Elf32) => elf_info::<elf::FileHeader32<Endianness>, _>(&reader),
+        Ok(object::FileKind::Elf64) => elf_info::<elf::FileHeader64<Endianness>, _>(&reader),
+        _ => {
+            let object = match object::read::File::parse(&reader) {
+                Err(_) => {
+                    // object::read::Error is opaque, so no way to distinguish "this is not elf"
+                    // and a real error
+                    return Ok(None);
+                }
+                Ok(o) => o,
+            };
+            object_buildid(&object).map(|buildid| {
+                buildid.map(|buildid| ElfInfo {
+                    buildid,
+                    architecture: architecture_name(&object),
+                })
+            })
+        }
+    };
+    info.with_context(|| format!("parsing {} for buildid", path.display()))
+}
+
+/// The [ElfInfo] of an elf file, reading only its headers and notes.
+///
+/// Parsing the whole file with [object::read::File::parse] also reads its symbol tables, which
+/// are hundreds of MB in large debuginfo files, and [object::read::ReadCache] keeps everything
+/// it read in memory.
+///
+/// The buildid is the content of the build id note, or else the Go build ID, as the Go linker
+/// only writes a build id note when linking externally. Debuggers only request the former, so
+/// the latter is only useful to tools which know the Go build ID. See [decode_go_buildid]. Notes are
+/// looked for in sections, and in segments for files without section headers.
+fn elf_info<'data, Elf: FileHeader<Endian = Endianness>, R: object::ReadRef<'data>>(
+    data: R,
+) -> object::Result<Option<ElfInfo>> {
+    let header = Elf::parse(data)?;
+    let endian = header.endian()?;
+    let sections = header.sections(endian, data)?;
+    let mut notes = Notes::default();
+    for section in sections.iter() {
+        if let Some(section_notes) = section.notes(endian, data)? {
+            if notes.read(section_notes, endian)? {
+                break;
+            }
+        }
+    }
+    if notes.buildid.is_none() {
+        // the segments of separate debuginfo files point to sections which were removed
+        for segment in header.program_headers(endian, data).unwrap_or(&[]) {
+            if let Ok(Some(segment_notes)) = segment.notes(endian, data) {
+                if notes.read(segment_notes, endian).unwrap_or(false) {
+                    break;
+                }
+            }
+        }
+    }
+    let Some(buildid) = notes
+        .buildid
+        .or(notes.go_buildid)
+        .filter(|id| !id.is_empty())
+    else {
+        return Ok(None);
+    };
+    Ok(Some(ElfInfo {
+        buildid: base16::encode_lower(buildid),
+        architecture: elf_header_architecture(header),
+    }))
+}
+
+/// The notes of an elf file which can serve as buildid
+#[derive(Default)]
+struct Notes<'data> {
+    buildid: Option<&'data [u8]>,
+    go_buildid: Option<&'data [u8]>,
+}
+
+impl<'data> Notes<'data> {
+    /// Reads these notes.
+    ///
+    /// Returns whether the build id note was found, after which other notes do not matter.
+    fn read<Elf: FileHeader>(
+        &mut self,
+        mut notes: object::read::elf::NoteIterator<'data, Elf>,
+        endian: Elf::Endian,
+    ) -> object::Result<bool> {
+        while let Some(note) = notes.next()? {
+            match (note.name(), note.n_type(endian)) {
+                (elf::ELF_NOTE_GNU, elf::NT_GNU_BUILD_ID) => {
+                    self.buildid = Some(note.desc());
+                    return Ok(true);
+                }
+                (ELF_NOTE_GO, GO_BUILDID_NOTE_TYPE) => self.go_buildid = Some(note.desc()),
+                _ => (),
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// The owner of the note containing the Go build ID
+const ELF_NOTE_GO: &[u8] = b"Go";
+
+/// The type of the note of owner `Go` containing the Go build ID, in section
+/// `.note.go.buildid`
+const GO_BUILDID_NOTE_TYPE: u32 = 4;
+
+/// The architecture of the elf file with this header, as named by [architecture_name].
+///
+/// [object] only maps `e_machine` to an [object::Architecture] for a parsed file, so a copy of
+/// the header alone, without program headers nor sections, is parsed.
+fn elf_header_architecture<Elf: FileHeader<Endian = Endianness>>(header: &Elf) -> Option<String> {
+    let mut copy = object::bytes_of(header).to_vec();
+    // e_phoff and e_shoff, then e_phnum, e_shentsize, e_shnum and e_shstrndx
+    let (offsets, counts) = if header.is_class_64() {
+        (32..48, 56..64)
+    } else {
+        (28..36, 44..52)
+    };
+    copy.get_mut(offsets)?.fill(0);
+    copy.get_mut(counts)?.fill(0);
+    let file = object::read::elf::ElfFile::<Elf, &[u8]>::parse(&copy[..]).ok()?;
+    architecture_name(&file)
+}
+
+#[test]
+fn test_elf_info() {
+    let exe = std::env::current_exe().unwrap();
+    let data = std::fs::read(&exe).unwrap();
+    let object = object::read::File::parse(&*data).unwrap();
+    let expected = ElfInfo {
+        buildid: base16::encode_lower(object.build_id().unwrap().unwrap()),
+        architecture: architecture_name(&object),
+    };
+    assert!(expected.architecture.is_some());
+    assert_eq!(get_elf_info(&exe).unwrap(), Some(expected));
+
+    // notes in segments only
+    let note = make_note(elf::ELF_NOTE_GNU, elf::NT_GNU_BUILD_ID, b"\xab\xcd");
+    let mut file = make_elf64(elf::ET_EXEC, &[(elf::PT_NOTE, 120, note.len() as u64)], &[]);
+    file.extend_from_slice(&note);
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("exe");
+    std::fs::write(&path, &file).unwrap();
+    assert_eq!(
+        get_elf_info(&path).unwrap(),
+        Some(ElfInfo {
+            buildid: "abcd".to_owned(),
+            architecture: Some("x86_64-le".to_owned()),
+        })
+    );
+}
+
+#[test]
+fn test_go_buildid() {
+    let go_id = b"abcDEF-_12/xyz";
+    let go_note = make_note(ELF_NOTE_GO, GO_BUILDID_NOTE_TYPE, go_id);
+    let gnu_note = make_note(elf::ELF_NOTE_GNU, elf::NT_GNU_BUILD_ID, b"\xab\xcd");
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("exe");
+    let buildid = |sections: &[(&str, u32, &[u8])]| {
+        std::fs::write(&path, make_elf64(elf::ET_EXEC, &[], sections)).unwrap();
+        get_buildid(&path).unwrap()
+    };
+    let go_buildid = buildid(&[(".note.go.buildid", elf::SHT_NOTE, &go_note)]).unwrap();
+    assert_eq!(
+        decode_go_buildid(&go_buildid).as_deref(),
+        Some("abcDEF-_12/xyz")
+    );
+    // the GNU build id note wins
+    assert_eq!(
+        buildid(&[
+            (".note.go.buildid", elf::SHT_NOTE, &go_note),
+            (".note.gnu.build-id", elf::SHT_NOTE, &gnu_note),
+        ]),
+        Some("abcd".to_owned())
+    );
+    assert_eq!(buildid(&[(".text", elf::SHT_PROGBITS, b"code")]), None);
+}
+
+/// The buildid of this object file, in base16.
+///
+/// - for Mach-O, the `LC_UUID` load command, as used by `lldb`
+/// - for PE, the GUID and age of the CodeView debug directory, see [codeview_buildid]
+/// - otherwise, the build id note
+///
+/// Elf files are parsed by [elf_info] instead.
+fn object_buildid<'data, R: object::ReadRef<'data>>(
+    object: &object::read::File<'data, R>,
+) -> object::Result<Option<String>> {
+    Ok(match object.format() {
+        object::BinaryFormat::MachO => object.mach_uuid()?.map(|uuid| base16::encode_lower(&uuid)),
+        object::BinaryFormat::Pe => object
+            .pdb_info()?
+            .map(|info| codeview_buildid(info.guid(), info.age())),
+        _ => object.build_id()?.map(base16::encode_lower),
+    })
+}
+
+/// The Go build ID whose base16 encoding is this buildid, if it is one.
+///
+/// Go build IDs are base64 strings separated by `/`, so they cannot be confused with the
+/// random bytes of other kinds of buildids.
+pub fn decode_go_buildid(buildid: &str) -> Option<String> {
+    let mut bytes = vec![0; buildid.len() / 2];
+    base16::decode_slice(buildid, &mut bytes).ok()?;
+    let valid = bytes.contains(&b'/')
+        && bytes
+            .iter()
+            .all(|&c| c.is_ascii_alphanumeric() || b"/-_=".contains(&c));
+    if valid {
+        String::from_utf8(bytes).ok()
+    } else {
+        None
+    }
 }
 
-/// Obtains the source store path corresponding to this derivation
-///
-/// The derivation must exist.
-///
-/// Source is understood as `src = `, multiple sources or patches are not supported.
-fn get_source(drvpath: &Path) -> anyhow::Result<Option<PathBuf>> {
-    let mut cmd = std::process::Command::new("nix-store");
-    cmd.arg("--query").arg("--binding").arg("src").arg(drvpath);
-    tracing::debug!("Running {:?}", &cmd);
-    let out = cmd.output().with_context(|| format!("running {:?}", cmd))?;
-    if !out.status.success() {
-        if out
-            .stderr
-            .as_slice()
-            .ends_with(b"has no environment binding named 'src'\n")
-        {
-            return Ok(None);
-        } else {
-            anyhow::bail!("{:?} failed: {}", cmd, String::from_utf8_lossy(&out.stderr));
-        }
-    }
-    let n = out.stdout.len();
-    if n <= 1 || out.stdout[n - 1] != b'\n' {
-        anyhow::bail!(
-            "{:?} returned weird output: {}",
-            cmd,
-            String::from_utf8_lossy(&out.stderr)
-        );
-    }
-    let path = PathBuf::from(OsString::from_vec(out.stdout[..n - 1].to_owned()));
-    if !path.is_absolute() {
-        anyhow::bail!("weird source: {}", path.display());
-    };
-    Ok(Some(path))
+#[test]
+fn test_decode_go_buildid() {
+    let id = b"abcDEF-_12/xyz";
+    let buildid = base16::encode_lower(id);
+    assert_eq!(
+        decode_go_buildid(&buildid).as_deref(),
+        Some("abcDEF-_12/xyz")
+    );
+    assert_eq!(
+        decode_go_buildid("483bd7f7229bdb06462222e1e353e4f37e15c293"),
+        None
+    );
+    assert_eq!(decode_go_buildid("4"), None);
 }
 
-/// Where a source file might be
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum SourceLocation {
-    /// Inside an archive
-    Archive {
-        /// path of the archive
-        archive: PathBuf,
-        /// path of the file in the archive
-        member: PathBuf,
-    },
-    /// A file directly in the store
-    File(PathBuf),
+/// The buildid of a PE file with this CodeView GUID and age.
+///
+/// Like the keys of Microsoft symbol servers, this is the GUID in its usual textual order,
+/// followed by the age, except that the age has a fixed width of 8 hex digits so that buildids
+/// have an even length.
+fn codeview_buildid(guid: [u8; 16], age: u32) -> String {
+    // the first three fields of the GUID are little endian
+    let data1 = u32::from_le_bytes([guid[0], guid[1], guid[2], guid[3]]);
+    let data2 = u16::from_le_bytes([guid[4], guid[5]]);
+    let data3 = u16::from_le_bytes([guid[6], guid[7]]);
+    format!(
+        "{:08x}{:04x}{:04x}{}{:08x}",
+        data1,
+        data2,
+        data3,
+        base16::encode_lower(&guid[8..]),
+        age
+    )
 }
 
-impl SourceLocation {
-    /// Get the path against which we match the requested file name
-    fn member_path(&self) -> &Path {
-        match self {
-            SourceLocation::Archive { member, .. } => member.as_path(),
-            SourceLocation::File(path) => path.as_path(),
-        }
-    }
+#[test]
+fn test_codeview_buildid() {
+    let guid = [
+        0x33, 0x22, 0x11, 0x00, 0x55, 0x44, 0x77, 0x66, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee,
+        0xff,
+    ];
+    assert_eq!(
+        codeview_buildid(guid, 2),
+        "00112233445566778899aabbccddeeff00000002"
+    );
 }
 
-/// Return the build id of this file.
+/// A name for the machine architecture of this object file, like `x86_64-le` or
+/// `powerpc64-be`: the architecture followed by the endianness.
 ///
-/// If the file is not an executable returns Ok(None).
-/// Errors are only for errors returned from the fs.
-pub fn get_buildid(path: &Path) -> anyhow::Result<Option<String>> {
-    let file = std::fs::File::open(path)
-        .with_context(|| format!("opening {} to get its buildid", path.display()))?;
-    let reader = object::read::ReadCache::new(file);
-    let object = match object::read::File::parse(&reader) {
-        Err(_) => {
-            // object::read::Error is opaque, so no way to distinguish "this is not elf" and a real
-            // error
-            return Ok(None);
-        }
-        Ok(o) => o,
+/// Returns `None` if the architecture is not known.
+pub fn architecture_name<'data>(object: &impl Object<'data>) -> Option<String> {
+    format_architecture(object.architecture(), object.is_little_endian())
+}
+
+/// See [architecture_name]
+fn format_architecture(architecture: object::Architecture, little_endian: bool) -> Option<String> {
+    let architecture = match architecture {
+        object::Architecture::Unknown => return None,
+        architecture => format!("{:?}", architecture).to_ascii_lowercase(),
     };
-    match object
-        .build_id()
-        .with_context(|| format!("parsing {} for buildid", path.display()))?
-    {
-        None => Ok(None),
-        Some(data) => {
-            let buildid = base16::encode_lower(&data);
-            Ok(Some(buildid))
-        }
-    }
+    let endianness = if little_endian { "le" } else { "be" };
+    Some(format!("{}-{}", architecture, endianness))
 }
 
 /// To remove references, gcc is patched to replace the hash part
@@ -576,10 +2280,132 @@ fn test_demangle_non_storepath() {
     );
 }
 
+/// Where the source of this executable or debuginfo file was unpacked during its build, see
+/// [crate::dwarf::source_prefix].
+fn source_prefix_of(path: &Path) -> Option<String> {
+    match crate::dwarf::compilation_directory(path) {
+        Ok(comp_dir) => comp_dir.as_deref().and_then(crate::dwarf::source_prefix),
+        Err(e) => {
+            tracing::debug!(
+                "cannot read compilation directory of {}: {:#}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Strips the cargo registry directory from the path of a source file of a Rust dependency, for
+/// example `/build/.cargo/registry/src/index.crates.io-6f17d22bba15001f/serde-1.0.197/src/lib.rs`
+/// becomes `serde-1.0.197/src/lib.rs`.
+///
+/// Returns None if the path is not in a cargo registry.
+fn strip_cargo_registry(request: &Path) -> Option<&Path> {
+    let mut components = request.components();
+    while let Some(component) = components.next() {
+        let name = component.as_os_str().as_bytes();
+        if name.starts_with(b"index.crates.io-") || name.starts_with(b"github.com-") {
+            let rest = components.as_path();
+            return (!rest.as_os_str().is_empty()).then_some(rest);
+        }
+    }
+    None
+}
+
+/// Whether a directory of a candidate source file matches a directory of the requested path.
+///
+/// Vendored Rust crates are in directories named either `NAME-VERSION` like in the cargo
+/// registry, or just `NAME`.
+fn same_component(candidate: &OsStr, target: &OsStr) -> bool {
+    if candidate == target {
+        return true;
+    }
+    let target = target.as_bytes();
+    match target
+        .windows(2)
+        .position(|w| w[0] == b'-' && w[1].is_ascii_digit())
+    {
+        Some(i) => candidate.as_bytes() == &target[..i],
+        None => false,
+    }
+}
+
+/// Normalizes `.`, `..` and repeated slashes in a path logically, without looking at the
+/// filesystem.
+///
+/// gdb requests paths like `/build/glibc-2.39/io/../sysdeps/unix/sysv/linux/openat64.c`, and
+/// archives have members like `./glibc-2.39/io/openat64.c`.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => match result.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    result.pop();
+                }
+                // `/..` is `/`
+                Some(Component::RootDir) => (),
+                _ => result.push(".."),
+            },
+            other => result.push(other),
+        }
+    }
+    result
+}
+
+#[test]
+fn test_normalize() {
+    let n = |s: &str| normalize(Path::new(s));
+    assert_eq!(
+        n("/build/glibc-2.37/io/../sysdeps/unix/sysv/linux/openat64.c"),
+        Path::new("/build/glibc-2.37/sysdeps/unix/sysv/linux/openat64.c")
+    );
+    assert_eq!(n("//build//src/./main.c"), Path::new("/build/src/main.c"));
+    assert_eq!(n("./glibc/io/openat64.c"), Path::new("glibc/io/openat64.c"));
+    assert_eq!(n("../../a/b/../c"), Path::new("../../a/c"));
+    assert_eq!(n("/../a"), Path::new("/a"));
+}
+
+/// How many directories of `candidate`, from the end, match those of `target`, not counting
+/// the file name.
+///
+/// `candidate` is normalized, and `target` must be.
+fn matching_len(candidate: &Path, target: &[&OsStr]) -> usize {
+    let candidate = normalize(candidate);
+    let total_len = candidate.iter().count();
+    candidate
+        .iter()
+        .rev()
+        .zip(target.iter().rev())
+        .skip(1)
+        .position(|(c, t)| !same_component(c, t))
+        .unwrap_or(total_len - 1)
+}
+
 /// Attempts to find a file that matches the request in an existing source path.
+///
+/// Files of Rust dependencies in the cargo registry are looked up in the directory of their
+/// crate, for example in vendored dependencies.
 pub fn get_file_for_source(
     source: &Path,
     request: &Path,
+) -> anyhow::Result<Option<SourceLocation>> {
+    get_file_for_source_with(source, request, None, &mut archive_members)
+}
+
+/// Like [get_file_for_source], but lists the files of source archives with `list_archive`
+/// instead of [archive_members], for example to cache the result.
+///
+/// `prefix` is where the source was unpacked during the build, like `/build/source`, if known
+/// from the debug info. Then the file at the same path relative to the root of the source is
+/// preferred to guessing from the end of the requested path.
+pub fn get_file_for_source_with(
+    source: &Path,
+    request: &Path,
+    prefix: Option<&Path>,
+    list_archive: &mut dyn FnMut(&Path) -> anyhow::Result<Vec<String>>,
 ) -> anyhow::Result<Option<SourceLocation>> {
     tracing::info!(
         "request path {:?} in source {:?}",
@@ -587,8 +2413,9 @@ pub fn get_file_for_source(
         source.display()
     );
 
+    let cargo_dependency = strip_cargo_registry(request);
+    let request = normalize(cargo_dependency.unwrap_or(request));
     let target: Vec<&OsStr> = request.iter().collect();
-    // invariant: we only keep candidates which have same path as target for components i..
     let mut candidates: Vec<_> = Vec::new();
     let source_type = source
         .metadata()
@@ -603,16 +2430,39 @@ pub fn get_file_for_source(
                 Ok(f) => {
                     if Some(&f.file_name()) == target.last() {
                         candidates.push(SourceLocation::File(f.path().to_path_buf()));
+                    } else if cargo_dependency.is_some_and(|dependency| {
+                        f.file_type().is_file() && is_crate_of(f.path(), dependency)
+                    }) {
+                        // a vendored crate which was not unpacked
+                        for member in list_archive(f.path())? {
+                            if Path::new(&member).file_name().as_ref() == target.last() {
+                                candidates.push(SourceLocation::Archive {
+                                    archive: f.path().to_path_buf(),
+                                    member: PathBuf::from(member),
+                                });
+                            }
+                        }
                     }
                 }
             }
         }
+    } else if is_patch(source) {
+        let patch =
+            std::fs::read(source).with_context(|| format!("reading patch {}", source.display()))?;
+        for (member, _) in files_created_by_patch(&patch) {
+            if member.file_name().as_ref() == target.last() {
+                candidates.push(SourceLocation::Archive {
+                    archive: source.to_path_buf(),
+                    member,
+                });
+            }
+        }
     } else if source_type.is_file() {
-        let mut archive = std::fs::File::open(source)
-            .with_context(|| format!("opening source archive {}", source.display()))?;
-        let member_list = compress_tools::list_archive_files(&mut archive)
-            .with_context(|| format!("listing files in source archive {}", source.display()))?;
-        for member in member_list {
+        if name_without_hash(source).as_ref() == target.last() {
+            // a single source file, like `src = ./main.c;`
+            return Ok(Some(SourceLocation::File(source.to_path_buf())));
+        }
+        for member in list_archive(source)? {
             if Path::new(&member).file_name().as_ref() == target.last() {
                 candidates.push(SourceLocation::Archive {
                     archive: source.to_path_buf(),
@@ -621,22 +2471,194 @@ pub fn get_file_for_source(
             }
         }
     }
+    // the server gets requests without the leading slash
+    let absolute = Path::new("/").join(&request);
+    // files of Rust dependencies are not below the prefix
+    let relative = prefix
+        .filter(|_| cargo_dependency.is_none())
+        .and_then(|prefix| absolute.strip_prefix(normalize(prefix)).ok());
+    select_source_candidate(
+        source,
+        &request,
+        relative,
+        cargo_dependency.is_some(),
+        candidates,
+    )
+}
+
+/// Lists the files of an existing source path: those of a directory relative to it, the members
+/// of an archive as listed by `list_archive`, the files created by a patch, or the name of a
+/// single source file.
+pub fn source_files(
+    source: &Path,
+    list_archive: &mut dyn FnMut(&Path) -> anyhow::Result<Vec<String>>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let source_type = source
+        .metadata()
+        .with_context(|| format!("stat({})", source.display()))?;
+    let mut files = Vec::new();
+    if source_type.is_dir() {
+        for file in walkdir::WalkDir::new(source) {
+            let file =
+                file.with_context(|| format!("failed to walk source {}", source.display()))?;
+            if file.file_type().is_dir() {
+                continue;
+            }
+            if let Ok(relative) = file.path().strip_prefix(source) {
+                files.push(relative.to_path_buf());
+            }
+        }
+    } else if is_patch(source) {
+        let patch =
+            std::fs::read(source).with_context(|| format!("reading patch {}", source.display()))?;
+        files.extend(
+            files_created_by_patch(&patch)
+                .into_iter()
+                .map(|(file, _)| file),
+        );
+    } else if source_type.is_file() {
+        match list_archive(source) {
+            Ok(members) if !members.is_empty() => {
+                files.extend(members.into_iter().map(PathBuf::from))
+            }
+            // a single source file, like `src = ./main.c;`
+            _ => files.extend(name_without_hash(source).map(PathBuf::from)),
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[test]
+fn test_source_files() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let source = dir.path().join("aaaa-source");
+    std::fs::create_dir_all(source.join("src")).unwrap();
+    std::fs::write(source.join("src/main.c"), "int main() {}").unwrap();
+    std::fs::write(source.join("Makefile"), "all:").unwrap();
+    std::fs::write(dir.path().join("bbbb-main.c"), "int main() {}").unwrap();
+    let mut no_archive = |_: &Path| -> anyhow::Result<Vec<String>> { Ok(Vec::new()) };
+    assert_eq!(
+        source_files(&source, &mut no_archive).unwrap(),
+        vec![PathBuf::from("Makefile"), PathBuf::from("src/main.c")]
+    );
+    assert_eq!(
+        source_files(&dir.path().join("bbbb-main.c"), &mut no_archive).unwrap(),
+        vec![PathBuf::from("main.c")]
+    );
+    let mut archive = |_: &Path| -> anyhow::Result<Vec<String>> {
+        Ok(vec![
+            "foo-1.0/main.c".to_owned(),
+            "foo-1.0/README".to_owned(),
+        ])
+    };
+    assert_eq!(
+        source_files(&source.join("Makefile"), &mut archive).unwrap(),
+        vec![
+            PathBuf::from("foo-1.0/README"),
+            PathBuf::from("foo-1.0/main.c")
+        ]
+    );
+}
+
+/// Lists the files in a source archive: tarballs compressed in any usual way, zip files, crates
+/// and so on.
+pub fn archive_members(archive: &Path) -> anyhow::Result<Vec<String>> {
+    let file = std::fs::File::open(archive)
+        .with_context(|| format!("opening source archive {}", archive.display()))?;
+    let members = crate::sandbox::list_archive_files(file)
+        .with_context(|| format!("listing files in source archive {}", archive.display()))?;
+    if members.is_empty() {
+        tracing::warn!(
+            "found no files in source archive {}, its format may not be supported",
+            archive.display()
+        );
+    }
+    Ok(members)
+}
+
+/// The name of a store path without its hash, like `main.c` for `/nix/store/xxx-main.c`.
+fn name_without_hash(storepath: &Path) -> Option<&OsStr> {
+    let name = storepath.file_name()?.as_bytes();
+    let position = name.iter().position(|&c| c == b'-')?;
+    Some(OsStr::from_bytes(&name[position + 1..]))
+}
+
+/// Whether `path` is the `.crate` archive of the Rust dependency whose file `dependency` is
+/// requested, relative to the cargo registry.
+fn is_crate_of(path: &Path, dependency: &Path) -> bool {
+    path.extension() == Some(OsStr::new("crate"))
+        && path.file_stem().is_some()
+        && path.file_stem() == dependency.iter().next()
+}
+
+#[test]
+fn test_is_crate_of() {
+    let dependency = Path::new("serde-1.0.197/src/lib.rs");
+    assert!(is_crate_of(
+        Path::new("/nix/store/aaaa-vendor/serde-1.0.197.crate"),
+        dependency
+    ));
+    assert!(!is_crate_of(
+        Path::new("/nix/store/aaaa-vendor/serde-1.0.196.crate"),
+        dependency
+    ));
+    assert!(!is_crate_of(
+        Path::new("/nix/store/aaaa-vendor/serde-1.0.197.tar.gz"),
+        dependency
+    ));
+}
+
+#[test]
+fn get_file_for_source_single_file() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let source = dir.path().join("aaaa-main.c");
+    std::fs::write(&source, "int main() {}").unwrap();
+    let res = get_file_for_source(&source, "/build/main.c".as_ref())
+        .unwrap()
+        .unwrap();
+    assert_eq!(res, SourceLocation::File(source));
+}
+
+/// Chooses among files of `source` with the right file name the one which is most likely to be
+/// `request`, which must be normalized.
+///
+/// `relative` is `request` relative to the root of the source, if known. If exactly one
+/// candidate is at this path, it is chosen.
+///
+/// If `cargo_dependency`, `request` is the path of a file of a Rust dependency relative to the
+/// cargo registry.
+fn select_source_candidate(
+    source: &Path,
+    request: &Path,
+    relative: Option<&Path>,
+    cargo_dependency: bool,
+    mut candidates: Vec<SourceLocation>,
+) -> anyhow::Result<Option<SourceLocation>> {
+    let target: Vec<&OsStr> = request.iter().collect();
+    if cargo_dependency {
+        // a file of the package itself with the same name is not the right one
+        candidates
+            .retain(|candidate| matching_len(candidate.member_path(), &target) + 1 >= target.len());
+    }
     if candidates.len() < 2 {
         return Ok(candidates.pop());
     }
+    if let Some(relative) = relative {
+        let mut exact = candidates
+            .iter()
+            .filter(|candidate| path_in_source(source, candidate).as_deref() == Some(relative));
+        if let (Some(candidate), None) = (exact.next(), exact.next()) {
+            return Ok(Some(candidate.clone()));
+        }
+    }
     let mut best_total_len = 0;
     let mut best_matching_len = 0;
     let mut best_candidates = Vec::new();
     for candidate in candidates {
         let member_path = candidate.member_path();
-        let total_len = member_path.iter().count();
-        let matching_len = member_path
-            .iter()
-            .rev()
-            .zip(target.iter().rev())
-            .skip(1)
-            .position(|(ref c, t)| c != t)
-            .unwrap_or(total_len - 1);
+        let total_len = normalize(member_path).iter().count();
+        let matching_len = matching_len(member_path, &target);
         if matching_len > best_matching_len
             || (matching_len == best_matching_len && total_len < best_total_len)
         {
@@ -659,6 +2681,127 @@ pub fn get_file_for_source(
     Ok(best_candidates.pop())
 }
 
+/// The path of a candidate source file relative to the root of the source, as it was unpacked
+/// during the build.
+///
+/// Archives usually contain a single top level directory, which becomes the root of the source.
+/// Patches are relative to the root of the source.
+fn path_in_source(source: &Path, candidate: &SourceLocation) -> Option<PathBuf> {
+    match candidate {
+        SourceLocation::File(path) => path.strip_prefix(source).ok().map(normalize),
+        SourceLocation::Archive { archive, member } => {
+            let member = normalize(member);
+            if is_patch(archive) {
+                Some(member)
+            } else {
+                let mut components = member.components();
+                components.next()?;
+                Some(components.as_path().to_path_buf())
+            }
+        }
+    }
+}
+
+/// Builds a little endian x86_64 ELF64 file with the specified type, program headers and
+/// sections, for tests.
+///
+/// Program headers are `(type, offset, size)`, and point to data the caller appends after the
+/// file. Sections are `(name, type, content)`, and are laid out after the program headers.
+#[cfg(test)]
+pub fn make_elf64(
+    e_type: u16,
+    program_headers: &[(u32, u64, u64)],
+    sections: &[(&str, u32, &[u8])],
+) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut names = b"\0".to_vec();
+    let mut section_headers = Vec::new();
+    let data_offset = 64 + 56 * program_headers.len();
+    for (name, sh_type, content) in sections {
+        section_headers.push((
+            names.len() as u32,
+            *sh_type,
+            data.len(),
+            content.len(),
+            4u64,
+        ));
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+        data.extend_from_slice(content);
+        data.resize(data.len().next_multiple_of(8), 0);
+    }
+    if !sections.is_empty() {
+        let name = names.len() as u32;
+        names.extend_from_slice(b".shstrtab\0");
+        section_headers.push((name, elf::SHT_STRTAB, data.len(), names.len(), 1));
+        data.extend_from_slice(&names);
+        data.resize(data.len().next_multiple_of(8), 0);
+    }
+    let (shoff, shnum) = match section_headers.len() {
+        0 => (0, 0),
+        n => ((data_offset + data.len()) as u64, n as u16 + 1),
+    };
+    let mut result = Vec::new();
+    result.extend_from_slice(b"\x7fELF\x02\x01\x01");
+    result.resize(16, 0);
+    result.extend_from_slice(&e_type.to_le_bytes());
+    result.extend_from_slice(&elf::EM_X86_64.to_le_bytes());
+    result.extend_from_slice(&1u32.to_le_bytes());
+    result.extend_from_slice(&0u64.to_le_bytes()); // entry
+    result.extend_from_slice(&64u64.to_le_bytes()); // phoff
+    result.extend_from_slice(&shoff.to_le_bytes());
+    result.extend_from_slice(&0u32.to_le_bytes()); // flags
+    result.extend_from_slice(&64u16.to_le_bytes()); // ehsize
+    result.extend_from_slice(&56u16.to_le_bytes()); // phentsize
+    result.extend_from_slice(&(program_headers.len() as u16).to_le_bytes());
+    result.extend_from_slice(&64u16.to_le_bytes()); // shentsize
+    result.extend_from_slice(&shnum.to_le_bytes());
+    result.extend_from_slice(&shnum.saturating_sub(1).to_le_bytes()); // shstrndx
+    for &(p_type, offset, size) in program_headers {
+        result.extend_from_slice(&p_type.to_le_bytes());
+        result.extend_from_slice(&0u32.to_le_bytes()); // flags
+        result.extend_from_slice(&offset.to_le_bytes());
+        result.extend_from_slice(&0u64.to_le_bytes()); // vaddr
+        result.extend_from_slice(&0u64.to_le_bytes()); // paddr
+        result.extend_from_slice(&size.to_le_bytes());
+        result.extend_from_slice(&size.to_le_bytes());
+        result.extend_from_slice(&4u64.to_le_bytes()); // align
+    }
+    result.extend_from_slice(&data);
+    if !section_headers.is_empty() {
+        // null section
+        result.resize(result.len() + 64, 0);
+    }
+    for (name, sh_type, offset, size, align) in section_headers {
+        result.extend_from_slice(&name.to_le_bytes());
+        result.extend_from_slice(&sh_type.to_le_bytes());
+        result.extend_from_slice(&0u64.to_le_bytes()); // flags
+        result.extend_from_slice(&0u64.to_le_bytes()); // addr
+        result.extend_from_slice(&((data_offset + offset) as u64).to_le_bytes());
+        result.extend_from_slice(&(size as u64).to_le_bytes());
+        result.extend_from_slice(&0u32.to_le_bytes()); // link
+        result.extend_from_slice(&0u32.to_le_bytes()); // info
+        result.extend_from_slice(&align.to_le_bytes());
+        result.extend_from_slice(&0u64.to_le_bytes()); // entsize
+    }
+    result
+}
+
+/// An ELF note with this owner, type and description, for [make_elf64]
+#[cfg(test)]
+pub fn make_note(owner: &[u8], n_type: u32, desc: &[u8]) -> Vec<u8> {
+    let mut note = Vec::new();
+    note.extend_from_slice(&(owner.len() as u32 + 1).to_le_bytes());
+    note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    note.extend_from_slice(&n_type.to_le_bytes());
+    note.extend_from_slice(owner);
+    note.push(0);
+    note.resize(note.len().next_multiple_of(4), 0);
+    note.extend_from_slice(desc);
+    note.resize(note.len().next_multiple_of(4), 0);
+    note
+}
+
 #[cfg(test)]
 fn make_test_source_path(paths: Vec<&'static str>) -> tempfile::TempDir {
     let dir = tempfile::TempDir::new().unwrap();
@@ -682,6 +2825,35 @@ fn get_file_for_source_simple() {
     );
 }
 
+#[test]
+fn get_file_for_source_patch() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let patch = dir.path().join("aaaa-add-header.patch");
+    std::fs::write(
+        &patch,
+        "--- /dev/null\n+++ b/include/new.h\n@@ -0,0 +1 @@\n+int f();\n",
+    )
+    .unwrap();
+    let res = get_file_for_source(&patch, "/build/source/include/new.h".as_ref())
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        res,
+        SourceLocation::Archive {
+            archive: patch.clone(),
+            member: PathBuf::from("include/new.h")
+        }
+    );
+    assert_eq!(
+        file_created_by_patch(&patch, Path::new("include/new.h")).unwrap(),
+        b"int f();\n"
+    );
+    assert_eq!(
+        get_file_for_source(&patch, "/build/source/main.c".as_ref()).unwrap(),
+        None
+    );
+}
+
 #[test]
 fn get_file_for_source_different_dir() {
     let dir = make_test_source_path(vec!["lib/core-net/network.c", "lib/plat/optee/network.c"]);
@@ -752,6 +2924,77 @@ fn get_file_for_source_misleading_dir() {
     );
 }
 
+#[test]
+fn get_file_for_source_rust_dependency() {
+    let dir = make_test_source_path(vec![
+        "source/src/lib.rs",
+        "vendor/serde/src/lib.rs",
+        "vendor/log-0.4.20/src/lib.rs",
+    ]);
+    let registry = "/build/.cargo/registry/src/index.crates.io-6f17d22bba15001f";
+    let res = get_file_for_source(
+        dir.path(),
+        format!("{registry}/serde-1.0.197/src/lib.rs").as_ref(),
+    );
+    assert_eq!(
+        res.unwrap().unwrap(),
+        SourceLocation::File(dir.path().join("vendor/serde/src/lib.rs"))
+    );
+    let res = get_file_for_source(
+        dir.path(),
+        format!("{registry}/log-0.4.20/src/lib.rs").as_ref(),
+    );
+    assert_eq!(
+        res.unwrap().unwrap(),
+        SourceLocation::File(dir.path().join("vendor/log-0.4.20/src/lib.rs"))
+    );
+    let res = get_file_for_source(
+        dir.path(),
+        format!("{registry}/anyhow-1.0.80/src/lib.rs").as_ref(),
+    );
+    assert_eq!(res.unwrap(), None);
+    let res = get_file_for_source(dir.path(), "/build/source/src/lib.rs".as_ref());
+    assert_eq!(
+        res.unwrap().unwrap(),
+        SourceLocation::File(dir.path().join("source/src/lib.rs"))
+    );
+}
+
+#[test]
+fn get_file_for_source_archive_members() {
+    let archive = Path::new("/nix/store/aaaa-glibc-2.37.tar.xz");
+    let candidates: Vec<SourceLocation> = [
+        "./glibc-2.37/sysdeps/unix/sysv/linux/openat64.c",
+        "./glibc-2.37/sysdeps/mach/hurd/openat64.c",
+        "./glibc-2.37/io/openat64.c",
+    ]
+    .into_iter()
+    .map(|member| SourceLocation::Archive {
+        archive: archive.to_path_buf(),
+        member: PathBuf::from(member),
+    })
+    .collect();
+    let select = |request: &str| {
+        let request = normalize(Path::new(request));
+        match select_source_candidate(archive, &request, None, false, candidates.clone()) {
+            Ok(Some(SourceLocation::Archive { member, .. })) => member,
+            other => panic!("unexpected {:?}", other),
+        }
+    };
+    assert_eq!(
+        select("/build/glibc-2.37/io/../sysdeps/unix/sysv/linux/openat64.c"),
+        Path::new("./glibc-2.37/sysdeps/unix/sysv/linux/openat64.c")
+    );
+    assert_eq!(
+        select("//build//glibc-2.37/./io/openat64.c"),
+        Path::new("./glibc-2.37/io/openat64.c")
+    );
+    assert_eq!(
+        select("/build/glibc-2.37/sysdeps/mach/hurd/../hurd/openat64.c"),
+        Path::new("./glibc-2.37/sysdeps/mach/hurd/openat64.c")
+    );
+}
+
 #[test]
 fn get_file_for_source_ambiguous() {
     let sources = vec![
@@ -773,6 +3016,61 @@ fn get_file_for_source_ambiguous() {
     }
 }
 
+#[test]
+fn get_file_for_source_prefix() {
+    // vendored directories may or may not have the version in their name
+    let dir = make_test_source_path(vec!["vendor/zlib-1.3/zconf.h", "vendor/zlib/zconf.h"]);
+    let request = Path::new("/build/source/build/../vendor/zlib-1.3/zconf.h");
+    assert!(get_file_for_source(dir.path(), request).is_err());
+    let res = get_file_for_source_with(
+        dir.path(),
+        request,
+        Some(Path::new("/build/source")),
+        &mut archive_members,
+    );
+    assert_eq!(
+        res.unwrap().unwrap(),
+        SourceLocation::File(dir.path().join("vendor/zlib-1.3/zconf.h"))
+    );
+    let res = get_file_for_source_with(
+        dir.path(),
+        request.strip_prefix("/").unwrap(),
+        Some(Path::new("/build/source")),
+        &mut archive_members,
+    );
+    assert_eq!(
+        res.unwrap().unwrap(),
+        SourceLocation::File(dir.path().join("vendor/zlib-1.3/zconf.h"))
+    );
+    // an unrelated prefix falls back to guessing
+    let res = get_file_for_source_with(
+        dir.path(),
+        request,
+        Some(Path::new("/build/other")),
+        &mut archive_members,
+    );
+    assert!(res.is_err());
+
+    let archive = Path::new("/nix/store/aaaa-foo-1.0.tar.gz");
+    let candidates: Vec<SourceLocation> = ["./foo-1.0/zlib/zconf.h", "./foo-1.0/zlib-1.3/zconf.h"]
+        .into_iter()
+        .map(|member| SourceLocation::Archive {
+            archive: archive.to_path_buf(),
+            member: PathBuf::from(member),
+        })
+        .collect();
+    let request = Path::new("/build/foo-1.0/zlib-1.3/zconf.h");
+    assert!(select_source_candidate(archive, request, None, false, candidates.clone()).is_err());
+    let res = select_source_candidate(
+        archive,
+        request,
+        Some(Path::new("zlib-1.3/zconf.h")),
+        false,
+        candidates.clone(),
+    );
+    assert_eq!(res.unwrap().as_ref(), Some(&candidates[1]));
+}
+
 /// Turns a path in the store as its topmost parent in /nix/store
 pub fn get_store_path(path: &Path) -> Option<&Path> {
     let mut ancestors = path.ancestors().peekable();
@@ -0,0 +1,157 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Diagnostics of the nix commands run by the server.
+//!
+//! Their stderr is captured instead of mixed with the logs of the server, and logged in a span
+//! naming the command. It is not included in errors, which can reach clients in the body of
+//! error responses, as it may reveal details about the server, like its substituters.
+
+use std::fmt;
+use std::process::{ExitStatus, Output, Stdio};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How many bytes of stderr are kept in errors. The end is kept, as it usually explains the
+/// failure.
+const MAX_STDERR: usize = 2000;
+
+/// The last [MAX_STDERR] bytes of this stderr, trimmed
+pub fn excerpt(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    let stderr = stderr.trim();
+    if stderr.len() <= MAX_STDERR {
+        return stderr.to_owned();
+    }
+    let mut start = stderr.len() - MAX_STDERR;
+    while !stderr.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("...{}", &stderr[start..])
+}
+
+/// Reads `stderr` to the end, keeping only its last bytes, enough for an [excerpt].
+pub async fn stderr_tail(mut stderr: impl AsyncRead + Unpin) -> std::io::Result<Vec<u8>> {
+    let mut tail = Vec::new();
+    let mut buffer = vec![0; 8 * 1024];
+    loop {
+        let n = stderr.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(tail);
+        }
+        tail.extend_from_slice(&buffer[..n]);
+        // slack for the whitespace trimmed by excerpt
+        if tail.len() > 2 * MAX_STDERR {
+            tail.drain(..tail.len() - 2 * MAX_STDERR);
+        }
+    }
+}
+
+/// Runs `command` to completion like [tokio::process::Command::output], but only keeps the
+/// end of its stderr, see [stderr_tail].
+pub async fn output(command: &mut tokio::process::Command) -> std::io::Result<Output> {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = command.spawn()?;
+    let mut stdout = child.stdout.take().expect("piped stdout");
+    let stderr = child.stderr.take().expect("piped stderr");
+    let mut out = Vec::new();
+    // concurrently, so that the command does not block writing to either
+    let (read, err) = tokio::join!(stdout.read_to_end(&mut out), stderr_tail(stderr));
+    read?;
+    let status = child.wait().await?;
+    Ok(Output {
+        status,
+        stdout: out,
+        stderr: err?,
+    })
+}
+
+/// A command which exited unsuccessfully
+#[derive(Debug)]
+pub struct CommandFailed {
+    /// the command, as formatted by [fmt::Debug]
+    pub command: String,
+    /// how it exited
+    pub status: ExitStatus,
+    /// an [excerpt] of its stderr, which is logged but not displayed
+    pub stderr: String,
+}
+
+impl fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed ({})", self.command, self.status)
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+/// Logs the stderr of `command` which produced `output`, and fails with a [CommandFailed] if it
+/// exited unsuccessfully.
+pub fn check(command: &impl fmt::Debug, output: &Output) -> anyhow::Result<()> {
+    let command = format!("{:?}", command);
+    let span = tracing::debug_span!("subprocess", command = command.as_str());
+    let _guard = span.enter();
+    let stderr = excerpt(&output.stderr);
+    if output.status.success() {
+        if !stderr.is_empty() {
+            tracing::debug!(status = %output.status, "stderr: {}", stderr);
+        }
+        Ok(())
+    } else {
+        tracing::info!(status = %output.status, "stderr: {}", stderr);
+        Err(CommandFailed {
+            command,
+            status: output.status,
+            stderr,
+        }
+        .into())
+    }
+}
+
+#[test]
+fn test_excerpt() {
+    assert_eq!(excerpt(b"  error: oops\n"), "error: oops");
+    let long = format!("é{}end", "x".repeat(MAX_STDERR));
+    let short = excerpt(long.as_bytes());
+    assert!(short.starts_with("...x"));
+    assert!(short.ends_with("xend"));
+    assert!(short.len() <= MAX_STDERR + 3);
+}
+
+#[tokio::test]
+async fn test_output() {
+    let mut cmd = tokio::process::Command::new("sh");
+    cmd.args([
+        "-c",
+        "head -c 1000000 /dev/zero | tr '\\0' x >&2; echo out; echo 'error: end' >&2",
+    ]);
+    let output = output(&mut cmd).await.unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"out\n");
+    assert!(output.stderr.len() <= 2 * MAX_STDERR);
+    assert!(output.stderr.ends_with(b"xxxerror: end\n"));
+}
+
+#[test]
+fn test_check() {
+    let mut cmd = std::process::Command::new("sh");
+    cmd.args([
+        "-c",
+        "echo progress >&2; echo 'error: no substituter' >&2; exit 3",
+    ]);
+    let output = cmd.output().unwrap();
+    let error = check(&cmd, &output).unwrap_err();
+    let failed = error.downcast_ref::<CommandFailed>().unwrap();
+    assert_eq!(failed.status.code(), Some(3));
+    assert!(failed.stderr.ends_with("progress\nerror: no substituter"));
+    assert!(error.to_string().ends_with("failed (exit status: 3)"));
+
+    let mut cmd = std::process::Command::new("sh");
+    cmd.args(["-c", "echo warning >&2"]);
+    let output = cmd.output().unwrap();
+    assert!(check(&cmd, &output).is_ok());
+}
@@ -5,26 +5,35 @@
 //! file in the for `$out/lib/debug/.build-id/sh/a1` are symlinked into `debuginfo/sha1`
 //! (on hydra) or `debuginfo/sha1.debug` (for file:/// caches crated with nix-copy).
 //! The actual nature of the symnlink can vary: it may be a json file.
+//!
+//! Other cache generators use other layouts, see [IndexLayout]. Each is tried until one is found
+//! in a substituter, and then only this one is used for this substituter.
 
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
     ffi::OsStr,
     hash::{Hash, Hasher},
-    io::{BufReader, Read},
-    os::unix::prelude::OsStrExt,
-    path::{Path, PathBuf},
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::{net::UnixStream, prelude::OsStrExt},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
 use async_recursion::async_recursion;
 use async_trait::async_trait;
 use futures_util::StreamExt;
+use once_cell::sync::Lazy;
 use reqwest::StatusCode;
 use reqwest::Url;
 use serde::Deserialize;
 use tempfile::TempDir;
 use tokio::io::{AsyncWriteExt, BufWriter};
 
+use crate::config::{parse_netrc, Netrc, NixConfig};
+use crate::db::Cache;
+use crate::log::ResultExt;
 use crate::store::{get_buildid, get_store_path};
 
 #[derive(Deserialize)]
@@ -36,18 +45,22 @@ struct DebuginfoMetadata {
     member: String,
 }
 
-/// Returns the 32 first bytes of the specified file
+/// Returns the 32 first bytes of the specified file, or all of it if it is shorter, like a
+/// small json redirect
 async fn magic(path: &Path) -> anyhow::Result<Vec<u8>> {
-    let mut res = vec![b'\0'; 32];
-    let mut file = std::fs::File::open(path)
+    let mut res = Vec::with_capacity(32);
+    let file = std::fs::File::open(path)
         .with_context(|| format!("reading magic of {}", path.display()))?;
-    file.read_exact(&mut res[..])
+    file.take(32)
+        .read_to_end(&mut res)
         .with_context(|| format!("reading start of {} to determine magic", path.display()))?;
     Ok(res)
 }
 
 const NAR_MAGIC: &[u8] = b"\x0d\x00\x00\x00\x00\x00\x00\x00nix-archive-1";
 const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ZSTD_MAGIC: &[u8] = b"\x28\xb5\x2f\xfd";
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
 
 /// API to fetch debuginfo indices from substituters
 #[async_trait]
@@ -60,25 +73,204 @@ pub trait Substituter: Send + Sync {
 
     /// the url used to construct this substituter
     fn url(&self) -> &str;
+
+    /// The content of the `nix-cache-info` file of this substituter, if it has one.
+    async fn cache_info(&self) -> anyhow::Result<Option<String>> {
+        match self.fetch(Path::new("nix-cache-info")).await? {
+            None => Ok(None),
+            Some(path) => tokio::fs::read_to_string(&path)
+                .await
+                .with_context(|| format!("reading nix-cache-info of {}", self.url()))
+                .map(Some),
+        }
+    }
+
+    /// The priority of this substituter, like in nix: lower ones are queried first.
+    ///
+    /// This is the `priority` parameter of its url, or else the `Priority` field of its
+    /// `nix-cache-info`, or else [DEFAULT_PRIORITY].
+    async fn priority(&self) -> u64 {
+        if let Some(priority) = url_priority(self.url()) {
+            return priority;
+        }
+        let info = match self.cache_info().await {
+            Ok(info) => info,
+            Err(e) => {
+                tracing::debug!("cannot fetch nix-cache-info of {}: {:#}", self.url(), e);
+                None
+            }
+        };
+        info.as_deref()
+            .and_then(cache_info_priority)
+            .unwrap_or(DEFAULT_PRIORITY)
+    }
+}
+
+/// Priority of substituters which do not specify one, as in nix
+pub const DEFAULT_PRIORITY: u64 = 50;
+
+/// The priority set by the `priority` parameter of a substituter url, like
+/// `https://cache.example.org?priority=10`
+pub fn url_priority(url: &str) -> Option<u64> {
+    let parsed = Url::parse(url).ok()?;
+    let priority = parsed
+        .query_pairs()
+        .find(|(key, _)| key == "priority")?
+        .1
+        .parse()
+        .ok();
+    priority
+}
+
+/// The `Priority` field of a `nix-cache-info` file
+fn cache_info_priority(info: &str) -> Option<u64> {
+    info.lines()
+        .find_map(|line| line.strip_prefix("Priority:"))
+        .and_then(|priority| priority.trim().parse().ok())
+}
+
+#[test]
+fn test_priority() {
+    assert_eq!(
+        url_priority("https://cache.example.org?priority=10"),
+        Some(10)
+    );
+    assert_eq!(
+        url_priority("file:///srv/cache?index-debug-info=true&priority=30"),
+        Some(30)
+    );
+    assert_eq!(url_priority("https://cache.nixos.org"), None);
+    assert_eq!(
+        cache_info_priority("StoreDir: /nix/store\nWantMassQuery: 1\nPriority: 40\n"),
+        Some(40)
+    );
+    assert_eq!(cache_info_priority("StoreDir: /nix/store\n"), None);
+}
+
+/// Where the debuginfo index of a binary cache has the entry of a buildid
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexLayout {
+    /// `debuginfo/<buildid>`, like hydra
+    Flat,
+    /// `debuginfo/<buildid>.debug`, like `file://` caches created with `nix copy`
+    FlatDebug,
+    /// `debuginfo/<2 first digits>/<other digits>.debug`, like `lib/debug/.build-id`
+    ByHash,
+    /// `buildid/<buildid>/debuginfo`, like a static mirror of a debuginfod server
+    Debuginfod,
+}
+
+impl IndexLayout {
+    /// All layouts, in the order they are tried
+    pub const ALL: [IndexLayout; 4] = [
+        IndexLayout::Flat,
+        IndexLayout::FlatDebug,
+        IndexLayout::ByHash,
+        IndexLayout::Debuginfod,
+    ];
+
+    /// The relative path of the entry of this buildid in this layout
+    fn path(self, buildid: &str) -> Option<PathBuf> {
+        Some(PathBuf::from(match self {
+            IndexLayout::Flat => format!("debuginfo/{buildid}"),
+            IndexLayout::FlatDebug => format!("debuginfo/{buildid}.debug"),
+            IndexLayout::ByHash => {
+                let (dir, file) = (buildid.get(..2)?, buildid.get(2..)?);
+                if file.is_empty() {
+                    return None;
+                }
+                format!("debuginfo/{dir}/{file}.debug")
+            }
+            IndexLayout::Debuginfod => format!("buildid/{buildid}/debuginfo"),
+        }))
+    }
+}
+
+/// The layout of the debuginfo index of each substituter, by url, once found
+static LAYOUTS: Lazy<std::sync::Mutex<HashMap<String, IndexLayout>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+
+/// The paths where the entry of this buildid may be in the index of this substituter, with their
+/// layout: only one if the layout of the substituter is known, otherwise one per layout.
+fn index_paths<T: Substituter + ?Sized>(
+    substituter: &T,
+    buildid: &str,
+) -> Vec<(IndexLayout, PathBuf)> {
+    let known = LAYOUTS.lock().unwrap().get(substituter.url()).copied();
+    let layouts = match &known {
+        Some(layout) => std::slice::from_ref(layout),
+        None => &IndexLayout::ALL[..],
+    };
+    layouts
+        .iter()
+        .filter_map(|&layout| Some((layout, layout.path(buildid)?)))
+        .collect()
+}
+
+/// The paths where the entry of this buildid may be in the index of any substituter, as
+/// recorded by [Cache::record_substituter_lookup]
+pub fn index_lookup_keys(buildid: &str) -> Vec<String> {
+    IndexLayout::ALL
+        .iter()
+        .filter_map(|layout| Some(layout.path(buildid)?.to_string_lossy().into_owned()))
+        .collect()
+}
+
+/// Remembers that the index of this substituter has this layout
+fn found_layout<T: Substituter + ?Sized>(substituter: &T, layout: IndexLayout) {
+    let previous = LAYOUTS
+        .lock()
+        .unwrap()
+        .insert(substituter.url().to_owned(), layout);
+    if previous.is_none() {
+        tracing::info!(
+            "debuginfo index of {} has layout {:?}",
+            substituter.url(),
+            layout
+        );
+    }
+}
+
+#[test]
+fn test_index_layout_path() {
+    let buildid = "ab0123";
+    let paths: Vec<PathBuf> = IndexLayout::ALL
+        .iter()
+        .filter_map(|layout| layout.path(buildid))
+        .collect();
+    assert_eq!(
+        paths,
+        [
+            "debuginfo/ab0123",
+            "debuginfo/ab0123.debug",
+            "debuginfo/ab/0123.debug",
+            "buildid/ab0123/debuginfo"
+        ]
+        .map(PathBuf::from)
+    );
+    assert_eq!(IndexLayout::ByHash.path("ab"), None);
 }
 
 /// returns a store path containing the requested debuginfo in
 /// `/lib/debug/.build-id`
+///
+/// If `private_dir` is specified, the debuginfo is not added to the store but moved to a
+/// subdirectory of `private_dir` registered in `cache` for later deletion, and this subdirectory
+/// is returned instead.
+///
+/// Missing files and json redirections are remembered in `cache` to avoid roundtrips to the
+/// substituter on later requests.
 pub async fn fetch_debuginfo<T: Substituter + ?Sized>(
     substituter: &T,
+    cache: &Cache,
+    private_dir: Option<&Path>,
     buildid: &str,
 ) -> anyhow::Result<Option<PathBuf>> {
     let mut res = Ok(None);
-    for path in [
-        // for hydra
-        PathBuf::from(format!("debuginfo/{buildid}")),
-        // for file:///path?index-debug-info=true created with nix copy
-        PathBuf::from(format!("debuginfo/{buildid}.debug")),
-    ]
-    .into_iter()
-    {
-        res = fetch_debuginfo_from(substituter, path.as_path(), 2).await;
+    for (layout, path) in index_paths(substituter, buildid) {
+        res = fetch_debuginfo_from(substituter, cache, private_dir, path.as_path(), 2).await;
         if let Ok(Some(path)) = &res {
+            found_layout(substituter, layout);
             tracing::info!(
                 "downloaded debuginfo for {} from {} into {}",
                 buildid,
@@ -91,15 +283,213 @@ pub async fn fetch_debuginfo<T: Substituter + ?Sized>(
     res
 }
 
+/// Whether this path is relative and without `..`, so that it cannot point outside of the
+/// directory it is joined to.
+fn is_plain_relative(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Resolves `.` and `..` in this path relative to the root of a substituter, lexically.
+///
+/// Fails if it is absolute or goes above the root of the substituter.
+fn normalize_relative(path: &Path) -> anyhow::Result<PathBuf> {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => result.push(name),
+            Component::CurDir => (),
+            Component::ParentDir => {
+                anyhow::ensure!(result.pop(), "{} goes above the root", path.display())
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                anyhow::bail!("{} is absolute", path.display())
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[test]
+fn test_normalize_relative() {
+    assert_eq!(
+        normalize_relative(Path::new("debuginfo/../nar/./xxxx.nar.xz")).unwrap(),
+        Path::new("nar/xxxx.nar.xz")
+    );
+    assert!(normalize_relative(Path::new("debuginfo/../../etc/shadow")).is_err());
+    assert!(normalize_relative(Path::new("/etc/shadow")).is_err());
+    assert!(is_plain_relative(Path::new("nar/xxxx.nar.xz")));
+    assert!(!is_plain_relative(Path::new("nar/../../xxxx.nar.xz")));
+    assert!(!is_plain_relative(Path::new("/nar/xxxx.nar.xz")));
+}
+
+/// Reads the json redirection `file` fetched from `path` in the substituter, and returns the
+/// relative path of the nar it points to.
+fn read_redirect<T: Substituter + ?Sized>(
+    substituter: &T,
+    path: &Path,
+    file: &Path,
+) -> anyhow::Result<PathBuf> {
+    // sync code
+    let file = std::fs::File::open(file)
+        .with_context(|| format!("opening {} to deserialize as json", path.display()))?;
+    let bufread = BufReader::new(file);
+    let metadata: DebuginfoMetadata = serde_json::from_reader(bufread)
+        .with_context(|| format!("parsing {} as json", path.display()))?;
+    let mut redirect_path = match path.parent() {
+        None => PathBuf::from("."),
+        Some(p) => p.to_path_buf(),
+    };
+    redirect_path.push(&metadata.archive);
+    // hydra redirects to `../nar/`, but not outside of the substituter
+    normalize_relative(&redirect_path).with_context(|| {
+        format!(
+            "debuginfo metadata {} from {} points outside of it: {}",
+            path.display(),
+            substituter.url(),
+            &metadata.archive
+        )
+    })
+}
+
+/// Looks up where the debuginfo of this buildid is in the debuginfo index of the substituter,
+/// like hydra's, without downloading it, and remembers the answer in `cache` so that a later
+/// [fetch_debuginfo] only needs to download the nar.
+///
+/// Returns whether the substituter has this debuginfo.
+pub async fn warm_debuginfo_lookup<T: Substituter + ?Sized>(
+    substituter: &T,
+    cache: &Cache,
+    buildid: &str,
+) -> anyhow::Result<bool> {
+    for (layout, path) in index_paths(substituter, buildid) {
+        if warm_lookup_at(substituter, cache, &path).await? {
+            found_layout(substituter, layout);
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// [warm_debuginfo_lookup] at this relative path of the index
+async fn warm_lookup_at<T: Substituter + ?Sized>(
+    substituter: &T,
+    cache: &Cache,
+    path: &Path,
+) -> anyhow::Result<bool> {
+    let key = path.to_string_lossy();
+    if let Some(target) = cache
+        .get_substituter_lookup(substituter.url(), &key)
+        .await?
+    {
+        return Ok(target.is_some());
+    }
+    let file = substituter
+        .fetch(path)
+        .await
+        .with_context(|| format!("fetching {} from {}", path.display(), substituter.url()))?;
+    let target = match file {
+        None => None,
+        Some(file) => {
+            if !magic(&file).await?.starts_with(b"{") {
+                // not an index, but the debuginfo itself
+                return Ok(true);
+            }
+            Some(read_redirect(substituter, path, &file)?)
+        }
+    };
+    cache
+        .record_substituter_lookup(
+            substituter.url(),
+            &key,
+            target
+                .as_ref()
+                .map(|target| target.to_string_lossy())
+                .as_deref(),
+        )
+        .await?;
+    Ok(target.is_some())
+}
+
+#[tokio::test]
+async fn test_warm_debuginfo_lookup() {
+    let d = TempDir::new().unwrap();
+    let substituter = FileSubstituter::from_url(&format!("file://{}", d.path().display()))
+        .await
+        .unwrap()
+        .unwrap();
+    std::fs::create_dir(d.path().join("debuginfo")).unwrap();
+    std::fs::write(
+        d.path().join("debuginfo/abcd"),
+        r#"{"archive":"../nar/xxxx.nar.xz","member":"lib/debug/.build-id/ab/cd.debug"}"#,
+    )
+    .unwrap();
+    let cache = Cache::open_in_memory().await.unwrap();
+    assert!(warm_debuginfo_lookup(&substituter, &cache, "abcd")
+        .await
+        .unwrap());
+    assert_eq!(
+        cache
+            .get_substituter_lookup(substituter.url(), "debuginfo/abcd")
+            .await
+            .unwrap(),
+        Some(Some("nar/xxxx.nar.xz".to_owned()))
+    );
+    assert!(!warm_debuginfo_lookup(&substituter, &cache, "ef01")
+        .await
+        .unwrap());
+    assert_eq!(
+        cache
+            .get_substituter_lookup(substituter.url(), "debuginfo/ef01")
+            .await
+            .unwrap(),
+        Some(None)
+    );
+}
+
 /// attempt to fetch debuginfo in this relative path inside the substituter
 ///
-/// returns a store path containing it
+/// returns a store path containing it, or a subdirectory of `private_dir` if specified
 #[async_recursion]
 async fn fetch_debuginfo_from<T: Substituter + ?Sized>(
     substituter: &T,
+    cache: &Cache,
+    private_dir: Option<&Path>,
     path: &Path,
     max_redirects: usize,
 ) -> anyhow::Result<Option<PathBuf>> {
+    let key = path.to_string_lossy();
+    match cache.get_substituter_lookup(substituter.url(), &key).await {
+        Err(e) => tracing::warn!("{:#}", e),
+        Ok(None) => (),
+        Ok(Some(None)) => {
+            tracing::debug!(
+                "{} was recently missing from {}",
+                path.display(),
+                substituter.url()
+            );
+            return Ok(None);
+        }
+        Ok(Some(Some(target))) => {
+            if max_redirects == 0 {
+                anyhow::bail!("too many redirects");
+            }
+            tracing::debug!(
+                "{} was recently redirected to {} in {}",
+                path.display(),
+                &target,
+                substituter.url()
+            );
+            return fetch_debuginfo_from(
+                substituter,
+                cache,
+                private_dir,
+                Path::new(&target),
+                max_redirects - 1,
+            )
+            .await;
+        }
+    }
     tracing::debug!(
         "attempting to fetch {} from {}",
         path.display(),
@@ -110,11 +500,16 @@ async fn fetch_debuginfo_from<T: Substituter + ?Sized>(
         .await
         .with_context(|| format!("fetching {} from {}", path.display(), substituter.url()))?;
     let file = match file {
-        None => return Ok(None),
+        None => {
+            cache
+                .record_substituter_lookup(substituter.url(), &key, None)
+                .await
+                .or_warn();
+            return Ok(None);
+        }
         Some(f) => f,
     };
     let tempdir;
-    let temppath;
     let target;
     // the logic below is taken from dwarffs, but hydra only uses json redirection -> nar.xz
     let dir_to_add = match &magic(file.as_path()).await? {
@@ -137,8 +532,8 @@ async fn fetch_debuginfo_from<T: Substituter + ?Sized>(
                 ),
                 Some(x) => x,
             };
-            let dir = TempDir::new().context("tempdir")?;
-            target = dir.path().join("target-nar");
+            tempdir = new_tempdir(private_dir)?;
+            target = tempdir.path().join("target-nar");
             let mut parent = target.join("lib/debug/.build-id");
             parent.push(&buildid[..2]);
             tokio::fs::create_dir_all(parent.as_path())
@@ -157,97 +552,47 @@ async fn fetch_debuginfo_from<T: Substituter + ?Sized>(
             if max_redirects == 0 {
                 anyhow::bail!("too many redirects");
             }
-            // sync code
-            let file = std::fs::File::open(file.as_path())
-                .with_context(|| format!("opening {} to deserialize as json", path.display()))?;
-            let bufread = BufReader::new(file);
-            let metadata: DebuginfoMetadata = serde_json::from_reader(bufread)
-                .with_context(|| format!("parsing {} as json", path.display()))?;
-            let mut redirect_path = match path.parent() {
-                None => PathBuf::from("."),
-                Some(p) => p.to_path_buf(),
-            };
-            redirect_path.push(&metadata.archive);
-            anyhow::ensure!(
-                redirect_path.is_relative(),
-                "debuginfo metadata {} from {} features an absolute path {}",
-                path.display(),
-                substituter.url(),
-                &metadata.archive
-            );
-            return fetch_debuginfo_from(substituter, redirect_path.as_path(), max_redirects - 1)
-                .await;
-        }
-        m => {
-            let nar_file = if m.starts_with(NAR_MAGIC) {
-                /***********
-                 * this is the nar file containing the debuginfo
-                 **********/
-                file.as_path() // a nar file
-            } else {
-                /***********
-                 * this is a compressed nar probably
-                 **********/
-                temppath = tempfile::NamedTempFile::new()
-                    .context("temppath")?
-                    .into_temp_path();
-                let out = tokio::fs::File::create(&temppath)
-                    .await
-                    .context("opening temppath")?;
-                let fd = tokio::fs::File::open(&file).await.context("unxz")?;
-                compress_tools::tokio_support::uncompress_data(fd, out)
-                    .await
-                    .with_context(|| {
-                        format!("unpacking {} from {}", file.display(), substituter.url())
-                    })?;
-                if magic(temppath.as_ref())
-                    .await
-                    .context("magic of uncompressed nar")?
-                    .starts_with(NAR_MAGIC)
-                {
-                    temppath.as_ref()
-                } else {
-                    anyhow::bail!("nar {} was not a compressed nar", path.display());
-                }
-            };
-            // unpack the nar
-            let fd = tokio::fs::File::open(nar_file).await?;
-            let mut cmd = tokio::process::Command::new("nix-store");
-            cmd.arg("--restore");
-            tempdir = tempfile::TempDir::new().context("tempdir")?;
+            let redirect_path = read_redirect(substituter, path, file.as_path())?;
+            cache
+                .record_substituter_lookup(
+                    substituter.url(),
+                    &key,
+                    Some(&redirect_path.to_string_lossy()),
+                )
+                .await
+                .or_warn();
+            return fetch_debuginfo_from(
+                substituter,
+                cache,
+                private_dir,
+                redirect_path.as_path(),
+                max_redirects - 1,
+            )
+            .await;
+        }
+        _ => {
+            tempdir = new_tempdir(private_dir)?;
             // FIXME: the indexer should probably not take the name of the store path into account
             target = tempdir.as_ref().join("nar-debug");
-            cmd.arg(target.as_path());
-            cmd.stdin(fd.into_std().await);
-            let status = cmd.status().await.with_context(|| {
-                format!(
-                    "running nix-store --import to unpack nar from {} in {}",
-                    nar_file.display(),
-                    substituter.url()
-                )
-            })?;
-            anyhow::ensure!(status.success(), "nix-store --import failed: {:?}", status);
-            anyhow::ensure!(
-                target.exists(),
-                "nix-store --import failed to create {}",
-                target.display()
-            );
-
+            unpack_nar(file.as_path(), target.as_path(), None)
+                .await
+                .with_context(|| format!("unpacking nar from {}", substituter.url()))?;
             target.as_path()
         }
     };
 
+    if let Some(private_dir) = private_dir {
+        return store_privately(cache, private_dir, substituter.url(), path, dir_to_add)
+            .await
+            .map(Some);
+    }
+
     // add it to the store
     let mut cmd = tokio::process::Command::new("nix-store");
     cmd.arg("--add");
     cmd.arg(dir_to_add);
     let output = cmd.output().await.context("nix-store --add")?;
-    anyhow::ensure!(
-        output.status.success(),
-        "nix-store --add failed: {:?}: {}",
-        output.status,
-        String::from_utf8_lossy(&output.stderr)
-    );
+    crate::subprocess::check(&cmd, &output)?;
     let mut storepath = &output.stdout[..];
     if storepath.ends_with(b"\n") {
         storepath = &storepath[..(storepath.len() - 1)];
@@ -265,6 +610,504 @@ async fn fetch_debuginfo_from<T: Substituter + ?Sized>(
     }
 }
 
+/// Creates a temporary directory, in `private_dir` if specified so that it can be moved there
+/// cheaply, and otherwise in [crate::db::temp_dir].
+fn new_tempdir(private_dir: Option<&Path>) -> anyhow::Result<TempDir> {
+    match private_dir {
+        None => TempDir::new_in(crate::db::temp_dir()?),
+        Some(dir) => TempDir::new_in(dir),
+    }
+    .context("tempdir")
+}
+
+/// Moves `dir`, fetched from `path` in the substituter `url`, to `private_dir`, and deletes
+/// expired directories there.
+///
+/// Returns the new location of `dir`.
+async fn store_privately(
+    cache: &Cache,
+    private_dir: &Path,
+    url: &str,
+    path: &Path,
+    dir: &Path,
+) -> anyhow::Result<PathBuf> {
+    let mut hasher = DefaultHasher::new();
+    url.hash(&mut hasher);
+    path.hash(&mut hasher);
+    // the indexer only looks for debuginfo in directories ending in -debug
+    let target = private_dir.join(format!("{:016x}-debug", hasher.finish()));
+    if target.exists() {
+        tokio::fs::remove_dir_all(&target)
+            .await
+            .with_context(|| format!("removing previous {}", target.display()))?;
+    }
+    tokio::fs::rename(dir, &target)
+        .await
+        .with_context(|| format!("moving {} to {}", dir.display(), target.display()))?;
+    let target_str = match target.to_str() {
+        Some(s) => s,
+        None => anyhow::bail!("{} is not utf8", target.display()),
+    };
+    cache
+        .register_private_path(target_str)
+        .await
+        .context("registering private debuginfo")?;
+    cache
+        .remove_expired_private_paths()
+        .await
+        .context("expiring private debuginfo")
+        .or_warn();
+    crate::dedup::deduplicate_in_cache(target.clone());
+    Ok(target)
+}
+
+/// Unpacks a nar, possibly compressed, to `target`, which must not exist.
+///
+/// If `member` is specified, only this path relative to the root of the nar is unpacked. The nar
+/// is decompressed and unpacked as it is read, without writing it anywhere.
+async fn unpack_nar(file: &Path, target: &Path, member: Option<&Path>) -> anyhow::Result<()> {
+    let file = file.to_path_buf();
+    let target = target.to_path_buf();
+    let member = member.map(Path::to_path_buf);
+    tokio::task::spawn_blocking(move || {
+        let nar = open_nar(&file)?;
+        crate::nar::restore(nar, &target, member.as_deref())
+            .with_context(|| format!("unpacking nar {}", file.display()))?;
+        anyhow::ensure!(
+            target.exists(),
+            "nar {} did not unpack to {}",
+            file.display(),
+            target.display()
+        );
+        Ok(())
+    })
+    .await
+    .context("joining nar unpacking task")?
+}
+
+/// Opens a nar, possibly compressed, for reading its uncompressed content.
+///
+/// Decompression happens in a separate thread as the returned reader is read.
+fn open_nar(file: &Path) -> anyhow::Result<Box<dyn Read + Send>> {
+    open_decompressed(file, |start| start.starts_with(NAR_MAGIC))
+}
+
+/// Opens a file, possibly compressed, for reading its uncompressed content. `is_uncompressed`
+/// tells from its first bytes whether it is not compressed.
+///
+/// Decompression happens in a separate thread as the returned reader is read.
+pub fn open_decompressed(
+    file: &Path,
+    is_uncompressed: impl FnOnce(&[u8]) -> bool,
+) -> anyhow::Result<Box<dyn Read + Send>> {
+    let fd = std::fs::File::open(file).with_context(|| format!("opening {}", file.display()))?;
+    let mut reader = BufReader::new(fd);
+    if is_uncompressed(
+        reader
+            .fill_buf()
+            .with_context(|| format!("reading start of {}", file.display()))?,
+    ) {
+        return Ok(Box::new(reader));
+    }
+    let (read_end, write_end) = UnixStream::pair().context("creating socket pair")?;
+    let display = file.display().to_string();
+    let thread = std::thread::spawn(move || {
+        let mut out = std::io::BufWriter::new(write_end);
+        if !decompress_zstd_or_gzip(&mut reader, &mut out)? {
+            crate::sandbox::uncompress_data(reader, &mut out)
+                .with_context(|| format!("uncompressing {}", display))?;
+        }
+        out.flush().context("writing decompressed data")?;
+        Ok(())
+    });
+    Ok(Box::new(Decompressed {
+        stream: read_end,
+        thread: Some(thread),
+    }))
+}
+
+/// The output of a thread decompressing a file
+struct Decompressed {
+    stream: UnixStream,
+    /// the decompressing thread, until it has been joined
+    thread: Option<std::thread::JoinHandle<anyhow::Result<()>>>,
+}
+
+impl Read for Decompressed {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.stream.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            // end of stream: report decompression errors instead of a truncated output
+            if let Some(thread) = self.thread.take() {
+                match thread.join() {
+                    Ok(Ok(())) => (),
+                    Ok(Err(e)) => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("{:#}", e),
+                        ))
+                    }
+                    Err(_) => return Err(std::io::Error::other("decompression thread panicked")),
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Decompresses `reader` to `out` if it is compressed with zstd or gzip.
+///
+/// Both formats allow several concatenated frames, which are all decompressed. Returns false
+/// without consuming `reader` if it uses another format.
+fn decompress_zstd_or_gzip(mut reader: impl BufRead, mut out: impl Write) -> anyhow::Result<bool> {
+    let start = reader
+        .fill_buf()
+        .context("reading start of compressed data")?;
+    let is_zstd = start.starts_with(ZSTD_MAGIC);
+    let is_gzip = start.starts_with(GZIP_MAGIC);
+    if !is_zstd && !is_gzip {
+        return Ok(false);
+    }
+    if is_zstd {
+        while !reader
+            .fill_buf()
+            .context("reading zstd compressed data")?
+            .is_empty()
+        {
+            let mut decoder = ruzstd::StreamingDecoder::new(&mut reader)
+                .map_err(|e| anyhow::anyhow!("{}", e))
+                .context("reading zstd frame header")?;
+            std::io::copy(&mut decoder, &mut out).context("decompressing zstd frame")?;
+        }
+    } else {
+        let mut decoder = flate2::bufread::MultiGzDecoder::new(reader);
+        std::io::copy(&mut decoder, &mut out).context("decompressing gzip")?;
+    }
+    Ok(true)
+}
+
+#[test]
+fn test_decompress_zstd_or_gzip() {
+    // `hello ` and `world\n` compressed by zstd as two separate frames
+    let zstd = b"\x28\xb5\x2f\xfd\x04\x58\x31\x00\x00\x68\x65\x6c\x6c\x6f\x20\xd2\x3b\xe1\xa9\
+        \x28\xb5\x2f\xfd\x04\x58\x31\x00\x00\x77\x6f\x72\x6c\x64\x0a\xaa\x6e\x56\x9f";
+    let mut out = Vec::new();
+    assert!(decompress_zstd_or_gzip(&zstd[..], &mut out).unwrap());
+    assert_eq!(out, b"hello world\n");
+
+    let mut gzip = Vec::new();
+    for part in [&b"hello "[..], &b"world\n"[..]] {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        std::io::Write::write_all(&mut encoder, part).unwrap();
+        gzip.extend(encoder.finish().unwrap());
+    }
+    let mut out = Vec::new();
+    assert!(decompress_zstd_or_gzip(&gzip[..], &mut out).unwrap());
+    assert_eq!(out, b"hello world\n");
+
+    let mut out = Vec::new();
+    assert!(!decompress_zstd_or_gzip(&b"\xfd7zXZ\x00"[..], &mut out).unwrap());
+    assert!(out.is_empty());
+}
+
+#[tokio::test]
+async fn test_unpack_nar() {
+    let d = TempDir::new().unwrap();
+    let mut nar = NAR_MAGIC.to_vec();
+    nar.extend(b"\x00\x00\x00");
+    for s in [
+        &b"("[..],
+        b"type",
+        b"regular",
+        b"contents",
+        b"hello\n",
+        b")",
+    ] {
+        nar.extend((s.len() as u64).to_le_bytes());
+        nar.extend(s);
+        nar.resize(nar.len() + (8 - s.len() % 8) % 8, 0);
+    }
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    std::io::Write::write_all(&mut encoder, &nar).unwrap();
+    let compressed = d.path().join("file.nar.gz");
+    std::fs::write(&compressed, encoder.finish().unwrap()).unwrap();
+    let target = d.path().join("target");
+    unpack_nar(&compressed, &target, None).await.unwrap();
+    assert_eq!(std::fs::read(&target).unwrap(), b"hello\n");
+
+    // truncated compressed data
+    let truncated = d.path().join("truncated.nar.gz");
+    let data = std::fs::read(&compressed).unwrap();
+    std::fs::write(&truncated, &data[..data.len() / 2]).unwrap();
+    assert!(unpack_nar(&truncated, &d.path().join("other"), None)
+        .await
+        .is_err());
+}
+
+/// The fields of a `.narinfo` file that we use
+#[derive(Debug, PartialEq, Eq)]
+pub struct NarInfo {
+    /// the store path this narinfo describes
+    pub store_path: String,
+    /// the relative path of the nar in the substituter
+    pub url: String,
+    /// the size of the uncompressed nar
+    pub nar_size: Option<u64>,
+}
+
+/// Parses the content of a `.narinfo` file
+pub fn parse_narinfo(text: &str) -> anyhow::Result<NarInfo> {
+    let mut store_path = None;
+    let mut url = None;
+    let mut nar_size = None;
+    for line in text.lines() {
+        if let Some((key, value)) = line.split_once(": ") {
+            match key {
+                "StorePath" => store_path = Some(value.to_owned()),
+                "URL" => url = Some(value.to_owned()),
+                "NarSize" => nar_size = value.parse().ok(),
+                _ => (),
+            }
+        }
+    }
+    match (store_path, url) {
+        (Some(store_path), Some(url)) => Ok(NarInfo {
+            store_path,
+            url,
+            nar_size,
+        }),
+        _ => anyhow::bail!("narinfo lacks StorePath or URL"),
+    }
+}
+
+#[test]
+fn test_parse_narinfo() {
+    let text = "StorePath: /nix/store/1a2b3c4d5e6f7g8h9i0jklmnopqrstuv-hello-2.12.1
+URL: nar/0cnm6f2f3d1v4x6yb9b8n1bk31vsnyfpdbj0l1jqg3sx2l6cbxk1.nar.xz
+Compression: xz
+FileHash: sha256:0cnm6f2f3d1v4x6yb9b8n1bk31vsnyfpdbj0l1jqg3sx2l6cbxk1
+FileSize: 50184
+NarHash: sha256:1i9pcgrqyd1p00ndw8mfdcqkqdd8cn8lcsvbkc2a0xngx0xvq1sv
+NarSize: 226488
+References: 1a2b3c4d5e6f7g8h9i0jklmnopqrstuv-hello-2.12.1
+Sig: cache.nixos.org-1:aaaa
+";
+    assert_eq!(
+        parse_narinfo(text).unwrap(),
+        NarInfo {
+            store_path: "/nix/store/1a2b3c4d5e6f7g8h9i0jklmnopqrstuv-hello-2.12.1".to_owned(),
+            url: "nar/0cnm6f2f3d1v4x6yb9b8n1bk31vsnyfpdbj0l1jqg3sx2l6cbxk1.nar.xz".to_owned(),
+            nar_size: Some(226488),
+        }
+    );
+    assert!(parse_narinfo("StorePath: /nix/store/foo").is_err());
+}
+
+/// Fetches and parses the narinfo of this store path in the substituter.
+///
+/// Returns None if the substituter does not have this store path.
+async fn fetch_narinfo<T: Substituter + ?Sized>(
+    substituter: &T,
+    storepath: &Path,
+) -> anyhow::Result<Option<NarInfo>> {
+    let hash = match storepath
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.get(..32))
+    {
+        Some(hash) => hash,
+        None => anyhow::bail!("{} is not a valid store path", storepath.display()),
+    };
+    let narinfo = match substituter
+        .fetch(Path::new(&format!("{hash}.narinfo")))
+        .await
+        .with_context(|| format!("fetching narinfo of {}", storepath.display()))?
+    {
+        None => return Ok(None),
+        Some(narinfo) => narinfo,
+    };
+    let narinfo = tokio::fs::read_to_string(&narinfo)
+        .await
+        .with_context(|| format!("reading narinfo of {}", storepath.display()))?;
+    let narinfo = parse_narinfo(&narinfo)
+        .with_context(|| format!("parsing narinfo of {}", storepath.display()))?;
+    anyhow::ensure!(
+        Path::new(&narinfo.store_path) == storepath,
+        "narinfo for {} describes {}",
+        storepath.display(),
+        &narinfo.store_path
+    );
+    Ok(Some(narinfo))
+}
+
+/// Returns the size of the nar of this store path according to its narinfo in the substituter.
+///
+/// Returns None if the substituter does not have this store path or does not tell its size.
+pub async fn fetch_nar_size<T: Substituter + ?Sized>(
+    substituter: &T,
+    storepath: &Path,
+) -> anyhow::Result<Option<u64>> {
+    Ok(fetch_narinfo(substituter, storepath)
+        .await?
+        .and_then(|narinfo| narinfo.nar_size))
+}
+
+/// Fetches a file inside a store path from the nar of this store path in the substituter.
+///
+/// This does not add the store path to the store. Returns a temporary directory, and the path of
+/// the requested file inside this directory. The file remains available as long as the temporary
+/// directory is not dropped.
+///
+/// Returns None if the substituter does not have this store path.
+pub async fn fetch_store_path_member<T: Substituter + ?Sized>(
+    substituter: &T,
+    file: &Path,
+) -> anyhow::Result<Option<(TempDir, PathBuf)>> {
+    let storepath = match get_store_path(file) {
+        Some(storepath) => storepath,
+        None => anyhow::bail!("{} is not in the store", file.display()),
+    };
+    let relative = file
+        .strip_prefix(storepath)
+        .context("file is not in its store path")?;
+    anyhow::ensure!(
+        is_plain_relative(relative),
+        "{} is not a plain path inside its store path",
+        file.display()
+    );
+    let narinfo = match fetch_narinfo(substituter, storepath).await? {
+        None => return Ok(None),
+        Some(narinfo) => narinfo,
+    };
+    let nar_path = Path::new(&narinfo.url);
+    anyhow::ensure!(
+        is_plain_relative(nar_path),
+        "narinfo of {} in {} features a path outside of it: {}",
+        storepath.display(),
+        substituter.url(),
+        &narinfo.url
+    );
+    let nar = match substituter
+        .fetch(nar_path)
+        .await
+        .with_context(|| format!("fetching nar of {}", storepath.display()))?
+    {
+        None => anyhow::bail!(
+            "{} has a narinfo for {} but not the nar {}",
+            substituter.url(),
+            storepath.display(),
+            &narinfo.url
+        ),
+        Some(nar) => nar,
+    };
+    let dir = TempDir::new_in(crate::db::temp_dir()?).context("tempdir")?;
+    let target = dir.path().join("nar");
+    unpack_nar(nar.as_path(), target.as_path(), Some(relative))
+        .await
+        .with_context(|| {
+            format!(
+                "unpacking nar of {} from {}",
+                storepath.display(),
+                substituter.url()
+            )
+        })?;
+    // joining an empty path would add a trailing slash
+    let member = if relative.as_os_str().is_empty() {
+        target
+    } else {
+        target.join(relative)
+    };
+    // symlinks in the nar must not make us serve files outside of it
+    let is_file = tokio::fs::symlink_metadata(&member)
+        .await
+        .is_ok_and(|metadata| metadata.is_file());
+    anyhow::ensure!(
+        is_file,
+        "nar of {} from {} does not contain {} as a regular file",
+        storepath.display(),
+        substituter.url(),
+        relative.display()
+    );
+    let root = tokio::fs::canonicalize(dir.path())
+        .await
+        .with_context(|| format!("resolving {}", dir.path().display()))?;
+    let resolved = tokio::fs::canonicalize(&member)
+        .await
+        .with_context(|| format!("resolving {}", member.display()))?;
+    anyhow::ensure!(
+        resolved.starts_with(&root),
+        "{} in the nar of {} from {} is outside of the nar",
+        relative.display(),
+        storepath.display(),
+        substituter.url()
+    );
+    tracing::info!(
+        "downloaded {} from {} into {}",
+        file.display(),
+        substituter.url(),
+        member.display()
+    );
+    Ok(Some((dir, member)))
+}
+
+#[tokio::test]
+async fn test_fetch_store_path_member_symlink() {
+    let d = TempDir::new().unwrap();
+    let substituter = FileSubstituter::from_url(&format!("file://{}", d.path().display()))
+        .await
+        .unwrap()
+        .unwrap();
+    let hash = "1a2b3c4d5e6f7g8h9i0jklmnopqrstuv";
+    let storepath = PathBuf::from(format!("/nix/store/{hash}-foo"));
+    let narinfo = |url: &str| format!("StorePath: {}\nURL: {}\n", storepath.display(), url);
+    std::fs::create_dir(d.path().join("nar")).unwrap();
+    // a nar whose root is a symlink to a file of the server
+    let mut nar = NAR_MAGIC.to_vec();
+    nar.extend(b"\x00\x00\x00");
+    for s in [
+        &b"("[..],
+        b"type",
+        b"symlink",
+        b"target",
+        b"/etc/hostname",
+        b")",
+    ] {
+        nar.extend((s.len() as u64).to_le_bytes());
+        nar.extend(s);
+        nar.resize(nar.len() + (8 - s.len() % 8) % 8, 0);
+    }
+    std::fs::write(d.path().join("nar/link.nar"), nar).unwrap();
+    std::fs::write(
+        d.path().join(format!("{hash}.narinfo")),
+        narinfo("nar/link.nar"),
+    )
+    .unwrap();
+    assert!(fetch_store_path_member(&substituter, &storepath)
+        .await
+        .is_err());
+
+    std::fs::write(
+        d.path().join(format!("{hash}.narinfo")),
+        narinfo("../outside.nar"),
+    )
+    .unwrap();
+    assert!(fetch_store_path_member(&substituter, &storepath)
+        .await
+        .is_err());
+
+    std::fs::write(d.path().join("nar/file.nar.gz"), make_compressed_nar(b"ok")).unwrap();
+    std::fs::write(
+        d.path().join(format!("{hash}.narinfo")),
+        narinfo("nar/file.nar.gz"),
+    )
+    .unwrap();
+    let (_dir, member) = fetch_store_path_member(&substituter, &storepath)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(std::fs::read(member).unwrap(), b"ok");
+}
+
 /// A file:/// substituter
 #[derive(PartialEq, Eq, Debug)]
 pub struct FileSubstituter {
@@ -304,8 +1147,8 @@ impl FileSubstituter {
 impl Substituter for FileSubstituter {
     async fn fetch(&self, path: &Path) -> anyhow::Result<Option<PathBuf>> {
         anyhow::ensure!(
-            path.is_relative(),
-            "substituter path {} should be relative",
+            is_plain_relative(path),
+            "substituter path {} should be relative, without ..",
             path.display()
         );
         let path = self.path.join(path);
@@ -363,14 +1206,271 @@ pub struct HttpSubstituter {
     http_url: Url,
     // url of the substituter, as passed to from_url
     url: String,
-    client: reqwest::Client,
+    client: HttpClient,
     cache: TempDir,
 }
 
+/// The http client shared by all [HttpSubstituter]s, so that they share a pool of connections
+/// and a download rate limit.
+///
+/// Cloning this structure returns a structure referring to the same pool and limit.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    limiter: Option<Arc<RateLimiter>>,
+    credentials: Arc<Credentials>,
+}
+
+impl HttpClient {
+    /// Creates a client using `proxy` if specified, or the proxy configured by the usual
+    /// environment variables (`https_proxy` and so on) otherwise, and downloading at most
+    /// `max_download_rate` bytes per second in total.
+    pub fn new(proxy: Option<&str>, max_download_rate: Option<u64>) -> anyhow::Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .pool_idle_timeout(Duration::from_secs(90))
+            .http2_adaptive_window(true)
+            .connect_timeout(Duration::from_secs(30));
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(
+                reqwest::Proxy::all(proxy).with_context(|| format!("parsing proxy url {proxy}"))?,
+            );
+        }
+        let client = builder.build().context("creating http client")?;
+        Ok(HttpClient {
+            client,
+            limiter: max_download_rate.map(|rate| Arc::new(RateLimiter::new(rate))),
+            credentials: Arc::new(Credentials::default()),
+        })
+    }
+
+    /// The same client, authenticating with these credentials
+    pub fn with_credentials(&self, credentials: Credentials) -> Self {
+        HttpClient {
+            credentials: Arc::new(credentials),
+            ..self.clone()
+        }
+    }
+
+    /// Starts a GET request to `url`, with credentials for its host if any.
+    pub fn get(&self, url: &Url) -> reqwest::RequestBuilder {
+        self.credentials
+            .authorize(self.client.get(url.as_str()), url)
+    }
+
+    /// Writes the body of this response to `out`, within the download rate limit.
+    pub async fn download(
+        &self,
+        response: reqwest::Response,
+        out: &mut (impl tokio::io::AsyncWrite + Unpin),
+    ) -> anyhow::Result<()> {
+        let url = response.url().clone();
+        let mut body = response.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.with_context(|| format!("downloading {}", url))?;
+            if let Some(limiter) = &self.limiter {
+                limiter.consume(chunk.len()).await;
+            }
+            out.write_all(&chunk)
+                .await
+                .with_context(|| format!("writing {} to disk", url))?;
+        }
+        out.flush()
+            .await
+            .with_context(|| format!("writing {} to disk", url))
+    }
+}
+
+/// How to authenticate to http substituters, from the `netrc-file` and `access-tokens` nix
+/// settings.
+///
+/// Logins of the netrc file are sent with basic authentication, and access tokens as bearer
+/// tokens.
+#[derive(Debug, Default)]
+pub struct Credentials {
+    netrc: Netrc,
+    /// tokens by host, optionally followed by a path prefix, like `example.com/org`
+    tokens: HashMap<String, String>,
+    /// hosts of the configured substituters, the only ones the `default` login of the netrc
+    /// file is sent to
+    default_hosts: HashSet<String>,
+}
+
+impl Credentials {
+    /// Reads the credentials configured in nix.conf.
+    ///
+    /// An unreadable netrc file is only a warning, as most substituters do not need it.
+    pub async fn from_config(config: &NixConfig) -> Self {
+        let netrc = match config.get("netrc-file").filter(|path| !path.is_empty()) {
+            None => Netrc::default(),
+            Some(path) => match tokio::fs::read_to_string(path).await {
+                Ok(text) => parse_netrc(&text),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Netrc::default(),
+                Err(e) => {
+                    tracing::warn!("cannot read netrc file {}: {:#}", path, e);
+                    Netrc::default()
+                }
+            },
+        };
+        let tokens = config
+            .get("access-tokens")
+            .map(|tokens| {
+                tokens
+                    .split_whitespace()
+                    .filter_map(|token| token.split_once('='))
+                    .map(|(host, token)| (host.to_owned(), token.to_owned()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Credentials {
+            netrc,
+            tokens,
+            default_hosts: HashSet::new(),
+        }
+    }
+
+    /// Sends the `default` login of the netrc file to the hosts of these substituter urls,
+    /// and no other host.
+    pub fn with_default_login_for<'a>(self, urls: impl IntoIterator<Item = &'a str>) -> Self {
+        let default_hosts = urls
+            .into_iter()
+            .filter_map(|url| Url::parse(url).ok()?.host_str().map(str::to_owned))
+            .collect();
+        Credentials {
+            default_hosts,
+            ..self
+        }
+    }
+
+    /// Adds the credentials for `url` to this request, if any.
+    ///
+    /// reqwest drops them when following a redirect to another host.
+    fn authorize(&self, request: reqwest::RequestBuilder, url: &Url) -> reqwest::RequestBuilder {
+        let host = match url.host_str() {
+            Some(host) => host,
+            None => return request,
+        };
+        let default = self
+            .netrc
+            .default
+            .as_ref()
+            .filter(|_| self.default_hosts.contains(host));
+        if let Some(login) = self.netrc.machines.get(host).or(default) {
+            return request.basic_auth(&login.login, Some(&login.password));
+        }
+        let location = format!("{}{}", host, url.path());
+        let token = self.tokens.iter().find(|(prefix, _)| {
+            prefix.as_str() == host || location.starts_with(&format!("{}/", prefix))
+        });
+        match token {
+            Some((_, token)) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_credentials() {
+    let config = maplit::hashmap! {
+        "access-tokens".to_owned() => "tokens.example.com=abc example.org/private=def".to_owned(),
+    };
+    let mut credentials = Credentials::from_config(&config).await;
+    credentials.netrc = parse_netrc("machine cache.example.com login alice password s3cr3t");
+    let client = reqwest::Client::new();
+    let authorization = |url: &str| {
+        let url = Url::parse(url).unwrap();
+        credentials
+            .authorize(client.get(url.as_str()), &url)
+            .build()
+            .unwrap()
+            .headers()
+            .get(reqwest::header::AUTHORIZATION)
+            .map(|value| value.to_str().unwrap().to_owned())
+    };
+    assert_eq!(
+        authorization("https://cache.example.com/nix-cache-info").as_deref(),
+        Some("Basic YWxpY2U6czNjcjN0")
+    );
+    assert_eq!(
+        authorization("https://tokens.example.com/foo.narinfo").as_deref(),
+        Some("Bearer abc")
+    );
+    assert_eq!(
+        authorization("https://example.org/private/foo.narinfo").as_deref(),
+        Some("Bearer def")
+    );
+    assert_eq!(
+        authorization("https://example.org/public/foo.narinfo"),
+        None
+    );
+    assert_eq!(authorization("https://cache.nixos.org/foo.narinfo"), None);
+
+    // the default login is only sent to substituters
+    let mut credentials = Credentials::from_config(&config)
+        .await
+        .with_default_login_for(["https://private.example.net?priority=10"]);
+    credentials.netrc = parse_netrc("default login bob password hunter2");
+    let authorization = |url: &str| {
+        let url = Url::parse(url).unwrap();
+        credentials
+            .authorize(client.get(url.as_str()), &url)
+            .build()
+            .unwrap()
+            .headers()
+            .contains_key(reqwest::header::AUTHORIZATION)
+    };
+    assert!(authorization("https://private.example.net/foo.narinfo"));
+    assert!(!authorization("https://cdn.example.com/nar/foo.nar.xz"));
+}
+
+/// Limits the rate at which bytes are downloaded, allowing bursts of one second worth of
+/// bytes.
+#[derive(Debug)]
+struct RateLimiter {
+    /// bytes per second
+    rate: u64,
+    /// when all the bytes downloaded until now would have been downloaded at exactly `rate`
+    next: std::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> Self {
+        RateLimiter {
+            rate: rate.max(1),
+            next: std::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until `bytes` more bytes can be downloaded.
+    async fn consume(&self, bytes: usize) {
+        let deadline = {
+            let mut next = self.next.lock().unwrap();
+            let start = (*next).max(Instant::now());
+            *next = start + Duration::from_secs_f64(bytes as f64 / self.rate as f64);
+            *next
+        };
+        if let Some(deadline) = deadline.checked_sub(Duration::from_secs(1)) {
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_rate_limiter() {
+    let limiter = RateLimiter::new(100_000);
+    let start = Instant::now();
+    // the first second worth of bytes is a burst
+    limiter.consume(100_000).await;
+    assert!(start.elapsed() < Duration::from_millis(100));
+    for _ in 0..5 {
+        limiter.consume(10_000).await;
+    }
+    assert!(start.elapsed() >= Duration::from_millis(450));
+}
+
 impl HttpSubstituter {
-    /// If this url starts with file:/// and is a real path then returns an instance, otherwise
-    /// None
-    pub async fn from_url(url: &str) -> anyhow::Result<Option<Self>> {
+    /// If this url starts with http:// or https:// then returns an instance fetching with
+    /// `client`, otherwise None
+    pub async fn from_url(url: &str, client: &HttpClient) -> anyhow::Result<Option<Self>> {
         let mut http_url =
             Url::parse(url).with_context(|| format!("parsing binary cache url {url}"))?;
         match http_url.scheme() {
@@ -385,14 +1485,13 @@ impl HttpSubstituter {
             http_url.set_path(&path);
         }
 
-        let cache = TempDir::new().context("tempdir")?;
-        let client = reqwest::Client::new();
+        let cache = TempDir::new_in(crate::db::temp_dir()?).context("tempdir")?;
 
         Ok(Some(HttpSubstituter {
             http_url,
             url: url.to_owned(),
             cache,
-            client,
+            client: client.clone(),
         }))
     }
 }
@@ -401,8 +1500,8 @@ impl HttpSubstituter {
 impl Substituter for HttpSubstituter {
     async fn fetch(&self, path: &Path) -> anyhow::Result<Option<PathBuf>> {
         anyhow::ensure!(
-            path.is_relative(),
-            "substituter path {} should be relative",
+            is_plain_relative(path),
+            "substituter path {} should be relative, without ..",
             path.display()
         );
         let path_str = path
@@ -427,7 +1526,7 @@ impl Substituter for HttpSubstituter {
         let mut write = BufWriter::new(fd);
 
         tracing::debug!("getting {}", &url);
-        let response = match self.client.get(url.as_str()).send().await {
+        let response = match self.client.get(&url).send().await {
             Ok(r) if r.status() == StatusCode::NOT_FOUND => {
                 tracing::debug!("{} not found in {}", path.display(), self.url());
                 return Ok(None);
@@ -460,6 +1559,9 @@ impl Substituter for HttpSubstituter {
                     self.url()
                 )
             })?;
+            if let Some(limiter) = &self.client.limiter {
+                limiter.consume(chunk.len()).await;
+            }
             write
                 .write_all(&chunk)
                 .await
@@ -477,4 +1579,281 @@ impl Substituter for HttpSubstituter {
     fn url(&self) -> &str {
         &self.url
     }
+
+    async fn cache_info(&self) -> anyhow::Result<Option<String>> {
+        // not cached by fetch, so that priorities are refreshed
+        let url = self
+            .http_url
+            .join("nix-cache-info")
+            .with_context(|| format!("cannot join nix-cache-info to {}", &self.http_url))?;
+        let response = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .with_context(|| format!("cannot fetch {}", &url))?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            StatusCode::OK => Ok(Some(
+                response
+                    .text()
+                    .await
+                    .with_context(|| format!("downloading {}", &url))?,
+            )),
+            status => anyhow::bail!("{} returned status {}", &url, status),
+        }
+    }
+}
+
+/// A fault injected by [MockCache] when serving a file
+#[cfg(test)]
+#[derive(Clone, Copy, Debug)]
+enum Fault {
+    /// answer after this delay
+    Latency(Duration),
+    /// answer `500 Internal Server Error`
+    ServerError,
+    /// send half of the file and close the connection
+    Truncated,
+}
+
+/// An in-process http binary cache, for hermetic tests of [HttpSubstituter]
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct MockCache {
+    /// content of the files, by path relative to the root of the cache
+    files: Arc<std::sync::Mutex<HashMap<String, Vec<u8>>>>,
+    /// faults injected for some paths
+    faults: Arc<std::sync::Mutex<HashMap<String, Fault>>>,
+    /// how many times each path was requested
+    requests: Arc<std::sync::Mutex<HashMap<String, usize>>>,
+}
+
+#[cfg(test)]
+impl MockCache {
+    /// Starts serving the cache on a random port, and returns a substituter for it
+    async fn start(&self) -> HttpSubstituter {
+        let app = axum::Router::new()
+            .fallback(mock_cache_handler)
+            .with_state(self.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        HttpSubstituter::from_url(&url, &HttpClient::new(None, None).unwrap())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    fn insert(&self, path: &str, content: impl Into<Vec<u8>>) {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), content.into());
+    }
+
+    fn inject(&self, path: &str, fault: Option<Fault>) {
+        let mut faults = self.faults.lock().unwrap();
+        match fault {
+            Some(fault) => faults.insert(path.to_owned(), fault),
+            None => faults.remove(path),
+        };
+    }
+
+    fn requests(&self, path: &str) -> usize {
+        self.requests
+            .lock()
+            .unwrap()
+            .get(path)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+async fn mock_cache_handler(
+    axum::extract::State(mock): axum::extract::State<MockCache>,
+    uri: http::Uri,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+    let path = uri.path().trim_start_matches('/').to_owned();
+    *mock
+        .requests
+        .lock()
+        .unwrap()
+        .entry(path.clone())
+        .or_default() += 1;
+    let fault = mock.faults.lock().unwrap().get(&path).copied();
+    let content = mock.files.lock().unwrap().get(&path).cloned();
+    match (fault, content) {
+        (Some(Fault::ServerError), _) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        (_, None) => StatusCode::NOT_FOUND.into_response(),
+        (Some(Fault::Latency(delay)), Some(content)) => {
+            tokio::time::sleep(delay).await;
+            content.into_response()
+        }
+        (Some(Fault::Truncated), Some(content)) => {
+            let chunks: Vec<std::io::Result<Vec<u8>>> = vec![
+                Ok(content[..content.len() / 2].to_vec()),
+                Err(std::io::Error::other("connection lost")),
+            ];
+            (
+                [(http::header::CONTENT_LENGTH, content.len())],
+                axum::body::Body::from_stream(futures_util::stream::iter(chunks)),
+            )
+                .into_response()
+        }
+        (None, Some(content)) => content.into_response(),
+    }
+}
+
+/// A gzip compressed nar of a regular file with this content
+#[cfg(test)]
+fn make_compressed_nar(content: &[u8]) -> Vec<u8> {
+    let mut nar = NAR_MAGIC.to_vec();
+    nar.extend(b"\x00\x00\x00");
+    for s in [&b"("[..], b"type", b"regular", b"contents", content, b")"] {
+        nar.extend((s.len() as u64).to_le_bytes());
+        nar.extend(s);
+        nar.resize(nar.len() + (8 - s.len() % 8) % 8, 0);
+    }
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder.write_all(&nar).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[tokio::test]
+async fn test_mock_cache_debuginfo() {
+    let mock = MockCache::default();
+    let substituter = mock.start().await;
+    let cache = Cache::open_in_memory().await.unwrap();
+    let private = TempDir::new().unwrap();
+    // hydra style: a json redirect to a nar
+    mock.insert(
+        "debuginfo/abcd",
+        r#"{"archive":"../nar/xxxx.nar.gz","member":"lib/debug/.build-id/ab/cd.debug"}"#,
+    );
+    mock.insert("nar/xxxx.nar.gz", make_compressed_nar(b"debug symbols"));
+    let fetched = fetch_debuginfo(&substituter, &cache, Some(private.path()), "abcd")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(std::fs::read(&fetched).unwrap(), b"debug symbols");
+    assert_eq!(mock.requests("debuginfo/abcd"), 1);
+    assert_eq!(
+        cache
+            .get_substituter_lookup(substituter.url(), "debuginfo/abcd")
+            .await
+            .unwrap(),
+        Some(Some("nar/xxxx.nar.gz".to_owned()))
+    );
+    // a redirect to the elf file itself is put where its buildid says
+    let buildid = "ef0123456789abcdef0123456789abcdef012345";
+    let mut bytes = [0u8; 20];
+    base16::decode_slice(buildid, &mut bytes).unwrap();
+    mock.insert(
+        &format!("debuginfo/{buildid}"),
+        r#"{"archive":"../debug/ef01.debug","member":""}"#,
+    );
+    let note = crate::store::make_note(
+        object::elf::ELF_NOTE_GNU,
+        object::elf::NT_GNU_BUILD_ID,
+        &bytes,
+    );
+    let elf = crate::store::make_elf64(
+        object::elf::ET_REL,
+        &[],
+        &[(".note.gnu.build-id", object::elf::SHT_NOTE, &note)],
+    );
+    mock.insert("debug/ef01.debug", elf);
+    let fetched = fetch_debuginfo(&substituter, &cache, Some(private.path()), buildid)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(fetched
+        .join(format!("lib/debug/.build-id/ef/{}.debug", &buildid[2..]))
+        .is_file());
+    // missing files are remembered
+    assert!(fetch_debuginfo(&substituter, &cache, None, "2345")
+        .await
+        .unwrap()
+        .is_none());
+    assert!(fetch_debuginfo(&substituter, &cache, None, "2345")
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(mock.requests("debuginfo/2345"), 1);
+}
+
+#[tokio::test]
+async fn test_mock_cache_index_layout() {
+    let mock = MockCache::default();
+    let substituter = mock.start().await;
+    let cache = Cache::open_in_memory().await.unwrap();
+    let private = TempDir::new().unwrap();
+    mock.insert(
+        "debuginfo/ab/cd.debug",
+        r#"{"archive":"../../nar/xxxx.nar.gz","member":"lib/debug/.build-id/ab/cd.debug"}"#,
+    );
+    mock.insert("nar/xxxx.nar.gz", make_compressed_nar(b"debug symbols"));
+    let fetched = fetch_debuginfo(&substituter, &cache, Some(private.path()), "abcd")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(std::fs::read(&fetched).unwrap(), b"debug symbols");
+    assert_eq!(mock.requests("debuginfo/abcd"), 1);
+    assert_eq!(mock.requests("debuginfo/abcd.debug"), 1);
+    // the layout is now known, other layouts are not tried anymore
+    assert!(fetch_debuginfo(&substituter, &cache, None, "ef01")
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(mock.requests("debuginfo/ef/01.debug"), 1);
+    assert_eq!(mock.requests("debuginfo/ef01"), 0);
+    assert_eq!(mock.requests("buildid/ef01/debuginfo"), 0);
+}
+
+#[tokio::test]
+async fn test_mock_cache_redirect_loop() {
+    let mock = MockCache::default();
+    let substituter = mock.start().await;
+    let cache = Cache::open_in_memory().await.unwrap();
+    mock.insert("debuginfo/loop", r#"{"archive":"loop","member":""}"#);
+    let error = fetch_debuginfo_from(&substituter, &cache, None, Path::new("debuginfo/loop"), 2)
+        .await
+        .unwrap_err();
+    assert!(format!("{:#}", error).contains("too many redirects"));
+    // the substituter answered once, later redirects come from the cache
+    assert_eq!(mock.requests("debuginfo/loop"), 1);
+}
+
+#[tokio::test]
+async fn test_mock_cache_faults() {
+    let mock = MockCache::default();
+    let substituter = mock.start().await;
+    mock.insert("file", "0123456789");
+
+    mock.inject("file", Some(Fault::ServerError));
+    assert!(substituter.fetch(Path::new("file")).await.is_err());
+
+    mock.inject("file", Some(Fault::Truncated));
+    assert!(substituter.fetch(Path::new("file")).await.is_err());
+
+    // failures are neither cached as missing nor as partial files
+    mock.inject("file", Some(Fault::Latency(Duration::from_millis(100))));
+    let start = Instant::now();
+    let fetched = substituter.fetch(Path::new("file")).await.unwrap().unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert_eq!(std::fs::read(&fetched).unwrap(), b"0123456789");
+    assert_eq!(mock.requests("file"), 3);
+    // successes are cached
+    substituter.fetch(Path::new("file")).await.unwrap().unwrap();
+    assert_eq!(mock.requests("file"), 3);
+
+    mock.inject("file", None);
+    mock.insert("nix-cache-info", "StoreDir: /nix/store\nPriority: 40\n");
+    assert_eq!(substituter.priority().await, 40);
+    // not cached, so that changes are seen when priorities are refreshed
+    mock.insert("nix-cache-info", "StoreDir: /nix/store\nPriority: 20\n");
+    assert_eq!(substituter.priority().await, 20);
 }
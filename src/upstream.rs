@@ -0,0 +1,93 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! A central cache consulted before indexing harder, configured by `--upstream`, so that
+//! several instances of this server can share what one of them indexed.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use reqwest::StatusCode;
+
+use crate::db::{Cache, Entry};
+
+/// Where to look up buildids unknown to the local cache
+pub enum Upstream {
+    /// another instance of this server, at this url
+    Server {
+        /// http client
+        client: reqwest::Client,
+        /// url of the server, without trailing slash
+        url: String,
+    },
+    /// the cache db of another instance, opened read only
+    Cache(Cache),
+}
+
+impl Upstream {
+    /// Connects to `spec`, the url of another instance of this server, or the path of its cache
+    /// db.
+    pub async fn open(spec: &str) -> anyhow::Result<Self> {
+        if spec.starts_with("http://") || spec.starts_with("https://") {
+            let client = reqwest::Client::builder()
+                .connect_timeout(Duration::from_secs(10))
+                .timeout(Duration::from_secs(30))
+                .build()
+                .context("creating http client")?;
+            Ok(Upstream::Server {
+                client,
+                url: spec.trim_end_matches('/').to_owned(),
+            })
+        } else {
+            let cache = Cache::open_read_only(Path::new(spec))
+                .await
+                .with_context(|| format!("opening upstream cache {}", spec))?;
+            Ok(Upstream::Cache(cache))
+        }
+    }
+
+    /// Returns what upstream knows about this buildid.
+    ///
+    /// Paths outside the store are dropped, see [Entry::store_paths_only].
+    pub async fn get_entry(&self, buildid: &str) -> anyhow::Result<Option<Entry>> {
+        let entry = self.get_raw_entry(buildid).await?;
+        Ok(entry.and_then(Entry::store_paths_only))
+    }
+
+    /// Returns what upstream says about this buildid, as is.
+    async fn get_raw_entry(&self, buildid: &str) -> anyhow::Result<Option<Entry>> {
+        match self {
+            Upstream::Cache(cache) => cache.get_entry(buildid).await,
+            Upstream::Server { client, url } => {
+                let url = format!("{}/buildid/{}/metadata", url, buildid);
+                let response = client
+                    .get(&url)
+                    .send()
+                    .await
+                    .with_context(|| format!("querying {}", &url))?;
+                match response.status() {
+                    StatusCode::NOT_FOUND => Ok(None),
+                    status if status.is_success() => {
+                        let body = response
+                            .bytes()
+                            .await
+                            .with_context(|| format!("reading answer of {}", &url))?;
+                        // metadata is an entry with more fields
+                        let entry: Entry = serde_json::from_slice(&body)
+                            .with_context(|| format!("parsing answer of {}", &url))?;
+                        anyhow::ensure!(
+                            entry.buildid == buildid,
+                            "{} answered about buildid {}",
+                            &url,
+                            entry.buildid
+                        );
+                        Ok(Some(entry))
+                    }
+                    status => anyhow::bail!("{} answered {}", &url, status),
+                }
+            }
+        }
+    }
+}
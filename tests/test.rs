@@ -3,6 +3,7 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use assert_cmd::prelude::*;
+use object::Object;
 use std::io::{Seek, SeekFrom, Write};
 use std::os::unix::prelude::OsStrExt;
 use std::path::Path;
@@ -365,8 +366,9 @@ fn test_invalid_deriver() {
     server.kill().unwrap();
 }
 
-#[test]
-fn test_hydra_api_file() {
+/// Checks that debuginfo is fetched from a file:// binary cache whose nars are compressed with
+/// `compression`, or the default compression of nix if None.
+fn check_hydra_api_file(compression: Option<&str>) {
     remove_debuginfo_for_buildid("10deef1d1c1e79a27c25e9636d652ca3b99dc3f5");
     let t = tempfile::tempdir().unwrap();
     let store = file_in(&t, "store");
@@ -384,7 +386,10 @@ fn test_hydra_api_file() {
     let python_debug = std::fs::read_link(real_output).unwrap();
 
     let cache_dir = file_in(&t, "cache");
-    let cache = format!("file://{}?index-debug-info=true", cache_dir.display());
+    let mut cache = format!("file://{}?index-debug-info=true", cache_dir.display());
+    if let Some(compression) = compression {
+        cache.push_str(&format!("&compression={compression}"));
+    }
 
     nix_copy(None::<PathBuf>, Some(&cache), &python_debug, Some(&store));
     nix_copy(Some(&store), None::<PathBuf>, &python, None::<PathBuf>);
@@ -400,6 +405,21 @@ fn test_hydra_api_file() {
     server.kill().unwrap();
 }
 
+#[test]
+fn test_hydra_api_file() {
+    check_hydra_api_file(None);
+}
+
+#[test]
+fn test_hydra_api_file_zstd() {
+    check_hydra_api_file(Some("zstd"));
+}
+
+#[test]
+fn test_hydra_api_file_gzip() {
+    check_hydra_api_file(Some("gzip"));
+}
+
 #[test]
 fn test_hydra_api_https() {
     remove_debuginfo_for_buildid("78218dee9fd3709104f6521a2c5507fb0a5732b2");
@@ -461,3 +481,79 @@ fn test_cache_invalidation() {
 
     server.kill().unwrap();
 }
+
+#[test]
+fn test_find() {
+    let t = tempfile::tempdir().unwrap();
+
+    let output = file_in(&t, "gnumake");
+    nix_build("gnumake", &output, None::<PathBuf>);
+    let mut exe = output;
+    exe.push("bin");
+    exe.push("make");
+
+    let find = |what: &str| {
+        let mut cmd = nixseparatedebuginfod(&t);
+        cmd.arg("find").arg(what).arg(&exe);
+        let output = dbg!(cmd).output().unwrap();
+        assert!(output.status.success());
+        PathBuf::from(String::from_utf8(output.stdout).unwrap().trim())
+    };
+
+    let debuginfo = find("debuginfo");
+    assert!(dbg!(&debuginfo).starts_with("/nix/store"));
+    assert!(debuginfo.to_str().unwrap().ends_with(".debug"));
+    assert!(debuginfo.is_file());
+
+    assert_eq!(find("executable"), std::fs::canonicalize(&exe).unwrap());
+}
+
+#[test]
+fn test_dead_substituter() {
+    let t = tempfile::tempdir().unwrap();
+
+    let output = file_in(&t, "sl");
+    nix_build("sl", &output, None::<PathBuf>);
+    let sl = std::fs::read_link(output).unwrap();
+    let output = file_in(&t, "sl_debug");
+    nix_build("sl.debug", &output, None::<PathBuf>);
+    let real_output = output.with_file_name(format!(
+        "{}-debug",
+        output.file_name().unwrap().to_str().unwrap()
+    ));
+    std::fs::remove_file(real_output).unwrap();
+
+    // register the debug output
+    populate_cache(&t);
+
+    // the debug output cannot be realised anymore
+    remove_debug_output("sl");
+
+    // nothing listens on port 1
+    let (port, mut server) = spawn_server(&t, Some(vec!["http://127.0.0.1:1"]));
+
+    let exe = sl.join("bin/sl");
+    let url = format!("http://127.0.0.1:{port}/path{}/debuginfo", exe.display());
+    let response = reqwest::blocking::get(dbg!(url)).unwrap();
+    // the client should retry later instead of caching a miss
+    assert_eq!(dbg!(response.status()), 503);
+    assert!(response.headers().contains_key("retry-after"));
+
+    // same for sections, which are read from the debuginfo
+    let data = std::fs::read(&exe).unwrap();
+    let object = object::read::File::parse(&*data).unwrap();
+    let buildid = base16::encode_lower(object.build_id().unwrap().unwrap());
+    let url = format!("http://127.0.0.1:{port}/buildid/{buildid}/section/.debug_line");
+    let response = reqwest::blocking::get(dbg!(url)).unwrap();
+    assert_eq!(dbg!(response.status()), 503);
+    assert!(response.headers().contains_key("retry-after"));
+
+    let url = format!(
+        "http://127.0.0.1:{port}/buildid/0123456789abcdef0123456789abcdef01234567/executable"
+    );
+    let response = reqwest::blocking::get(dbg!(url)).unwrap();
+    // an unknown buildid is not a transient failure
+    assert!([404, 406].contains(&dbg!(response.status()).as_u16()));
+
+    server.kill().unwrap();
+}